@@ -0,0 +1,515 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use axum::extract::{Path as UrlPath, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use graphviz_dot_parser::types::Stmt;
+use serde::{Deserialize, Serialize};
+
+use crate::args::{ApplyCommand, ConfigOverrides};
+use crate::backend::MusicBackend;
+use crate::jobs::JobStore;
+use crate::scheduler::{self, PlaylistLocks};
+use crate::traits::ResultExtension;
+use crate::types::{Action, Job, JobKind};
+use crate::{config, plan_command};
+
+/// Everything the handlers share. Runs started through the api share the playlist locks
+/// with the scheduler, so they never write to the same playlist at the same time.
+#[derive(Clone)]
+pub struct ApiState {
+    pub backend: Arc<dyn MusicBackend>,
+    pub locks: Arc<PlaylistLocks>,
+    pub jobs: Arc<JobStore>,
+    pub snapshots_dir: PathBuf,
+
+    /// The folder of the global `mixify.toml`, see `config::global_config_dir`.
+    pub global_config_dir: Option<PathBuf>,
+}
+
+pub fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/snapshots", get(list_snapshots).post(create_snapshot))
+        .route("/snapshots/:id/graph", get(get_graph))
+        .route("/snapshots/:id/plan", get(get_plan))
+        .route("/snapshots/:id/apply", post(start_apply))
+        .route("/snapshots/:id/sync", post(start_sync))
+        .route("/jobs", get(list_jobs))
+        .route("/jobs/:id", get(get_job))
+        .with_state(state)
+}
+
+/// Errors are returned as `{"error": "..."}`.
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn not_found(message: String) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            message,
+        }
+    }
+
+    /// Invalid requests and snapshots. Everything else is an internal error.
+    fn bad_request(message: String) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            message,
+        }
+    }
+}
+
+/// Errors that aren't explicitly reported as bad requests are failures of mixify itself,
+/// like a snapshot folder that can't be read.
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: e.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = Json(serde_json::json!({ "error": self.message }));
+        (self.status, body).into_response()
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+#[derive(Serialize)]
+struct SnapshotSummary {
+    id: u32,
+    name: String,
+    applied: bool,
+    schedule: Option<String>,
+
+    /// Why the mixify.toml of the snapshot can't be loaded. The schedule is unknown then.
+    config_error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SnapshotGraph {
+    id: u32,
+
+    /// `edit` if the snapshot hasn't been applied yet, `post.apply` otherwise.
+    file: String,
+    content: String,
+    nodes: Vec<GraphNode>,
+    edges: Vec<GraphEdge>,
+}
+
+#[derive(Serialize)]
+struct GraphNode {
+    name: String,
+    attributes: Vec<(String, String)>,
+}
+
+#[derive(Serialize)]
+struct GraphEdge {
+    from: String,
+    to: String,
+    attributes: Vec<(String, String)>,
+}
+
+#[derive(Deserialize)]
+struct NewSnapshot {
+    name: String,
+    content: String,
+}
+
+#[derive(Default, Deserialize)]
+struct RunOptions {
+    #[serde(default)]
+    dry_run: bool,
+
+    /// Continue an apply that failed halfway. Only used by applies.
+    #[serde(default)]
+    resume: bool,
+
+    /// Forget an apply that failed halfway and start from scratch. Only used by applies,
+    /// can't be combined with `resume`.
+    #[serde(default)]
+    discard: bool,
+}
+
+fn ensure_snapshot_exists(snapshots_dir: &Path, id: u32) -> Result<(), ApiError> {
+    if !snapshots_dir.join(id.to_string()).is_dir() {
+        return Err(ApiError::not_found(format!(
+            "snapshot {} does not exist",
+            id
+        )));
+    }
+
+    return Ok(());
+}
+
+/// The latest file of the snapshot and its suffix.
+fn find_latest_snapshot_file(snapshots_dir: &Path, id: u32) -> Result<(String, PathBuf), ApiError> {
+    ensure_snapshot_exists(snapshots_dir, id)?;
+
+    for suffix in ["edit", "post.apply"] {
+        if let Ok(path) = plan_command::find_snapshot_file(snapshots_dir, id, suffix) {
+            return Ok((suffix.to_string(), path));
+        }
+    }
+
+    return Err(ApiError::not_found(format!(
+        "snapshot {} has no snapshot file",
+        id
+    )));
+}
+
+async fn list_snapshots(State(state): State<ApiState>) -> ApiResult<Vec<SnapshotSummary>> {
+    let snapshots_dir = state.snapshots_dir.as_path();
+    let mut snapshots = vec![];
+    for id in plan_command::list_snapshot_ids(snapshots_dir)? {
+        let Ok((suffix, path)) = find_latest_snapshot_file(snapshots_dir, id) else {
+            continue;
+        };
+
+        // Files are named `<id>_<name>.<suffix>.gv`.
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        let name = file_name
+            .trim_end_matches(&format!(".{}.gv", suffix))
+            .trim_start_matches(&format!("{}_", id))
+            .to_string();
+
+        let applied = plan_command::find_snapshot_file(snapshots_dir, id, "post.apply").is_ok();
+        let config = config::load_config_in(
+            state.global_config_dir.as_deref(),
+            snapshots_dir,
+            Some(id),
+            &ConfigOverrides::default(),
+        );
+        // One broken config shouldn't hide all other snapshots.
+        let (schedule, config_error) = match config {
+            Ok(config) => (config.schedule, None),
+            Err(e) => {
+                log::warn!("Failed to load the config of snapshot {}: {}", id, e);
+                (None, Some(e.to_string()))
+            }
+        };
+        snapshots.push(SnapshotSummary {
+            id,
+            name,
+            applied,
+            schedule,
+            config_error,
+        });
+    }
+
+    return Ok(Json(snapshots));
+}
+
+async fn get_graph(
+    State(state): State<ApiState>,
+    UrlPath(id): UrlPath<u32>,
+) -> ApiResult<SnapshotGraph> {
+    let (file, path) = find_latest_snapshot_file(&state.snapshots_dir, id)?;
+    let content = std::fs::read_to_string(&path)
+        .or_error(format!("failed to read snapshot file {}", path.display()))?;
+    let gv = graphviz_dot_parser::parse(&content)
+        .or_error(String::from("failed to parse graph"))
+        .map_err(|e| ApiError::bad_request(e.to_string()))?;
+
+    let mut nodes = vec![];
+    let mut edges = vec![];
+    for stmt in &gv.stmt {
+        match stmt {
+            Stmt::Node(name, attributes) => nodes.push(GraphNode {
+                name: name.clone(),
+                attributes: attributes.clone(),
+            }),
+            Stmt::Edge(from, to, attributes) => edges.push(GraphEdge {
+                from: from.clone(),
+                to: to.clone(),
+                attributes: attributes.clone(),
+            }),
+            _ => {}
+        }
+    }
+
+    return Ok(Json(SnapshotGraph {
+        id,
+        file,
+        content,
+        nodes,
+        edges,
+    }));
+}
+
+/// Creates the next snapshot from the posted graph. The graph is validated first,
+/// so only snapshots that can be planned are stored.
+async fn create_snapshot(
+    State(state): State<ApiState>,
+    Json(snapshot): Json<NewSnapshot>,
+) -> Result<(StatusCode, Json<SnapshotSummary>), ApiError> {
+    let valid_name = !snapshot.name.is_empty()
+        && snapshot
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid_name {
+        return Err(ApiError::bad_request(format!(
+            "invalid snapshot name {:?}. Only letters, digits, '_' and '-' are allowed",
+            snapshot.name
+        )));
+    }
+
+    let gv = graphviz_dot_parser::parse(&snapshot.content)
+        .or_error(String::from("failed to parse graph"))
+        .map_err(|e| ApiError::bad_request(e.to_string()))?;
+    plan_command::create_execution_plan(&gv, &snapshot.content)
+        .map_err(|e| ApiError::bad_request(e.to_string()))?;
+
+    let snapshots_dir = state.snapshots_dir.as_path();
+    std::fs::create_dir_all(snapshots_dir).or_error_str("failed to create snapshots folder")?;
+    let mut id = plan_command::list_snapshot_ids(snapshots_dir)?
+        .last()
+        .map(|id| id + 1)
+        .unwrap_or(1);
+
+    // Creating the folder claims the id, so two requests at the same time never share a snapshot.
+    let dir = loop {
+        let dir = snapshots_dir.join(id.to_string());
+        match std::fs::create_dir(&dir) {
+            Ok(()) => break dir,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => id += 1,
+            Err(e) => {
+                return Err(anyhow::anyhow!(
+                    "failed to create snapshot folder {} with error: {:?}",
+                    dir.display(),
+                    e
+                )
+                .into())
+            }
+        }
+    };
+
+    let file_name = dir.join(format!("{}_{}.edit.gv", id, snapshot.name));
+    std::fs::write(&file_name, &snapshot.content).or_error(format!(
+        "failed to write snapshot file {}",
+        file_name.display()
+    ))?;
+    log::info!("Created snapshot {}", file_name.display());
+
+    let summary = SnapshotSummary {
+        id,
+        name: snapshot.name,
+        applied: false,
+        schedule: None,
+        config_error: None,
+    };
+    return Ok((StatusCode::CREATED, Json(summary)));
+}
+
+async fn get_plan(
+    State(state): State<ApiState>,
+    UrlPath(id): UrlPath<u32>,
+) -> ApiResult<Vec<Vec<Action>>> {
+    ensure_snapshot_exists(&state.snapshots_dir, id)?;
+
+    let content = plan_command::read_latest_snapshot_file(&state.snapshots_dir, id)?;
+    let gv = graphviz_dot_parser::parse(&content)
+        .or_error(String::from("failed to parse graph"))
+        .map_err(|e| ApiError::bad_request(e.to_string()))?;
+    let (plan, _) = plan_command::create_execution_plan(&gv, &content)
+        .map_err(|e| ApiError::bad_request(e.to_string()))?;
+
+    return Ok(Json(plan_command::without_root_node(plan)));
+}
+
+async fn start_apply(
+    state: State<ApiState>,
+    id: UrlPath<u32>,
+    options: Option<Json<RunOptions>>,
+) -> Result<(StatusCode, Json<Job>), ApiError> {
+    return start_job(state, id, options, JobKind::Apply);
+}
+
+async fn start_sync(
+    state: State<ApiState>,
+    id: UrlPath<u32>,
+    options: Option<Json<RunOptions>>,
+) -> Result<(StatusCode, Json<Job>), ApiError> {
+    return start_job(state, id, options, JobKind::Sync);
+}
+
+/// Runs the apply or sync in the background. Its progress can be followed through `/jobs/:id`.
+fn start_job(
+    State(state): State<ApiState>,
+    UrlPath(id): UrlPath<u32>,
+    options: Option<Json<RunOptions>>,
+    kind: JobKind,
+) -> Result<(StatusCode, Json<Job>), ApiError> {
+    ensure_snapshot_exists(&state.snapshots_dir, id)?;
+
+    let options = options.map(|Json(o)| o).unwrap_or_default();
+    if options.resume && options.discard {
+        return Err(ApiError::bad_request(String::from(
+            "`resume` and `discard` can't be used together",
+        )));
+    }
+
+    let is_sync = kind == JobKind::Sync;
+    let cmd = ApplyCommand {
+        id,
+        fixture: None,
+        dry_run: options.dry_run,
+        resume: options.resume && !is_sync,
+        discard: options.discard && !is_sync,
+    };
+    let job = state.jobs.create(kind, id, cmd.dry_run);
+    log::info!("Started job {} for snapshot {}", job.id, id);
+
+    let jobs = Arc::clone(&state.jobs);
+    tokio::spawn(jobs.run(job.id, async move {
+        scheduler::run_snapshot(&cmd, &state.backend, &state.locks, is_sync).await
+    }));
+
+    return Ok((StatusCode::ACCEPTED, Json(job)));
+}
+
+async fn list_jobs(State(state): State<ApiState>) -> Json<Vec<Job>> {
+    return Json(state.jobs.list());
+}
+
+async fn get_job(State(state): State<ApiState>, UrlPath(id): UrlPath<u32>) -> ApiResult<Job> {
+    let job = state
+        .jobs
+        .get(id)
+        .ok_or_else(|| ApiError::not_found(format!("job {} does not exist", id)))?;
+
+    return Ok(Json(job));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_backend::{FakeBackend, FakeLibrary};
+
+    /// Uses no global config, so the tests only see the files in `snapshots_dir`.
+    fn state(snapshots_dir: &Path) -> ApiState {
+        let library = serde_json::from_value::<FakeLibrary>(serde_json::json!({
+            "user_id": "tester",
+        }))
+        .unwrap();
+        return ApiState {
+            backend: Arc::new(FakeBackend::new(library)),
+            locks: Arc::new(PlaylistLocks::default()),
+            jobs: Arc::new(JobStore::default()),
+            snapshots_dir: snapshots_dir.to_path_buf(),
+            global_config_dir: None,
+        };
+    }
+
+    fn status(result: Result<impl IntoResponse, ApiError>) -> StatusCode {
+        return match result {
+            Ok(response) => response.into_response().status(),
+            Err(e) => e.into_response().status(),
+        };
+    }
+
+    const VALID_GRAPH: &str = r#"digraph G {
+        A [URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA"];
+        Mix;
+        A -> Mix;
+    }"#;
+
+    fn new_snapshot(name: &str, content: &str) -> Json<NewSnapshot> {
+        return Json(NewSnapshot {
+            name: name.to_string(),
+            content: content.to_string(),
+        });
+    }
+
+    #[test]
+    fn unexpected_errors_are_internal_errors() {
+        let e = ApiError::from(anyhow::anyhow!("failed to read the snapshots folder"));
+        assert_eq!(
+            e.into_response().status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_snapshots_are_bad_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshots_dir = dir.path().join("snapshots");
+        let create = |name: &str, content: &str| {
+            create_snapshot(State(state(&snapshots_dir)), new_snapshot(name, content))
+        };
+
+        assert_eq!(
+            status(create("my mix", "digraph G { A; }").await),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(create("mix", "digraph G { A -> }").await),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(create("mix", "digraph G { A -> B; B -> A; }").await),
+            StatusCode::BAD_REQUEST
+        );
+        assert!(!snapshots_dir.exists());
+
+        assert_eq!(
+            status(create("mix", VALID_GRAPH).await),
+            StatusCode::CREATED
+        );
+        assert!(snapshots_dir.join("1").join("1_mix.edit.gv").is_file());
+    }
+
+    #[tokio::test]
+    async fn snapshots_with_an_invalid_config_are_listed() {
+        let dir = tempfile::tempdir().unwrap();
+        for (id, config) in [(1, "schedule = \"0 0 * * * *\"\n"), (2, "schedule = 5\n")] {
+            let snapshot_dir = dir.path().join(id.to_string());
+            std::fs::create_dir_all(&snapshot_dir).unwrap();
+            std::fs::write(
+                snapshot_dir.join(format!("{}_mix.edit.gv", id)),
+                "digraph G {}",
+            )
+            .unwrap();
+            std::fs::write(snapshot_dir.join("mixify.toml"), config).unwrap();
+        }
+
+        let Json(snapshots) = list_snapshots(State(state(dir.path()))).await.ok().unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].name, "mix");
+        assert_eq!(snapshots[0].schedule.as_deref(), Some("0 0 * * * *"));
+        assert_eq!(snapshots[0].config_error, None);
+        assert_eq!(snapshots[1].schedule, None);
+        assert!(snapshots[1]
+            .config_error
+            .as_ref()
+            .unwrap()
+            .contains("schedule"));
+    }
+
+    #[tokio::test]
+    async fn resume_and_discard_are_rejected_together() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("1")).unwrap();
+        let state = state(dir.path());
+        let options = RunOptions {
+            dry_run: false,
+            resume: true,
+            discard: true,
+        };
+
+        let result = start_apply(State(state.clone()), UrlPath(1), Some(Json(options))).await;
+        assert_eq!(status(result), StatusCode::BAD_REQUEST);
+        assert!(state.jobs.list().is_empty());
+    }
+}
@@ -1,27 +1,38 @@
 use std::collections::{HashMap, HashSet};
-use std::time::{Duration, Instant};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use std::vec;
 
-use futures_util::stream::StreamExt;
-
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, StreamExt, TryStreamExt};
 use rspotify::model::{
-    ArtistId, FullAlbum, PlaylistId, PlaylistItem, SavedAlbum, SavedTrack, SimplifiedPlaylist,
-    SimplifiedTrack, TrackId,
+    ArtistId, AudioFeatures, FullAlbum, PlaylistId, PlaylistItem, SavedAlbum, SavedTrack,
+    SimplifiedPlaylist, SimplifiedTrack, TrackId, UserId,
 };
+use rspotify::prelude::{Id, PlayableId};
 use rspotify::ClientError;
-use rspotify::{
-    prelude::{BaseClient, OAuthClient, PlayableId},
-    AuthCodeSpotify,
+use tokio::sync::OnceCell;
+
+use crate::backend::MusicBackend;
+use crate::backup;
+use crate::journal;
+use crate::ledger;
+use crate::library_cache::{self, LibraryCache};
+use crate::plan_command::NodeData;
+use crate::traits::{OptionExtension, ResultExtension};
+use crate::types::{
+    Action, Config, Dedup, DryRunReport, ExecutionResult, GenreMatch, Journal, JournalStep, Ledger,
+    Order, Pick, PlaylistChanges, QuerySongsByArtist, QuerySongsByGenre, QuerySource, Reordering,
+    SavePlaylist, SongFilter, SongSet, Source, Track, TrackTuple,
 };
-
-use crate::types::{Config, QuerySongsByArtist, Track, TrackTuple};
-use crate::{constants, plan_command, traits::ResultExtension, types};
+use crate::{constants, dot, plan_command, types};
 
 use super::args;
 
 pub async fn handle_apply_snapshot(
     cmd: &args::ApplyCommand,
-    spotify: &AuthCodeSpotify,
+    backend: &Arc<dyn MusicBackend>,
     config: Config,
     is_sync: bool,
 ) -> Result<(), anyhow::Error> {
@@ -36,38 +47,187 @@ pub async fn handle_apply_snapshot(
         }
     };
 
-    let content = plan_command::read_snapshot_file(cmd.id, file_suffix)?;
-    let gv =
-        graphviz_dot_parser::parse(&content).or_error(String::from("failed to parse graph"))?;
-    let graph = gv.to_directed_graph().unwrap();
-    let (all_actions, nodes) = plan_command::create_execution_plan(&gv)?;
+    let snapshots_dir = Path::new(constants::SNAPSHOTS_DIR);
+    let content = plan_command::read_snapshot_file(snapshots_dir, cmd.id, file_suffix)?;
+
+    // Syncs only write to playlists that already exist, so only applies keep a journal.
+    let journal = match (
+        is_sync || cmd.dry_run,
+        journal::read_journal(snapshots_dir, cmd.id)?,
+    ) {
+        (true, _) => None,
+        (false, Some(journal)) if cmd.resume => {
+            log::info!(
+                "Resuming the apply started at {}, which created {} playlists",
+                journal.started_at,
+                journal.steps.len()
+            );
+            Some(journal)
+        }
+        (false, Some(journal)) if cmd.discard => {
+            log::warn!(
+                "Discarding the apply started at {}. The {} playlists it created are kept",
+                journal.started_at,
+                journal.steps.len()
+            );
+            journal::remove_journal(snapshots_dir, cmd.id)?;
+            Some(journal::new_journal(cmd.id))
+        }
+        (false, Some(_)) => {
+            return Err(anyhow::anyhow!(
+                "a previous apply of snapshot {} did not finish. Run `mixify apply {} --resume` to continue it, or `mixify apply {} --discard` to start from scratch",
+                cmd.id,
+                cmd.id,
+                cmd.id
+            ));
+        }
+        (false, None) => {
+            if cmd.resume {
+                log::warn!(
+                    "Snapshot {} has no unfinished apply to resume. Applying it from the start",
+                    cmd.id
+                );
+            }
+            Some(journal::new_journal(cmd.id))
+        }
+    };
+
+    let retries_before = backend.retry_summary();
+    let result = execute_snapshot(
+        snapshots_dir,
+        cmd.id,
+        &content,
+        backend,
+        &config,
+        cmd.dry_run,
+        journal,
+    )
+    .await;
+
+    let retries = backend.retry_summary().since(&retries_before);
+    if retries.requests > 0 {
+        log::info!("{}", retries);
+    }
+    let result = result?;
+
+    if let Some(report) = result.dry_run_report {
+        log::info!("{}", report);
+        return Ok(());
+    }
+
+    log::info!("Successfully applied snapshot");
+
+    if is_sync {
+        return Ok(());
+    }
+
+    let path = plan_command::find_snapshot_file(snapshots_dir, cmd.id, "edit")?;
+    let path = path.to_str().unwrap();
+    let pre_apply_path = path.replace("edit", "pre.apply");
+    let post_apply_path = path.replace("edit", "post.apply");
+
+    let new_content = create_post_apply_file(
+        &content,
+        &result.node_to_playlist_id,
+        &result.nodes_with_missing_playlists,
+    )?;
+
+    std::fs::rename(path, pre_apply_path)?;
+    std::fs::write(post_apply_path, new_content)?;
+    journal::remove_journal(snapshots_dir, cmd.id)?;
+    return Ok(());
+}
+
+/// The library of the user. Loaded once, by the first query that needs it.
+struct Library {
+    albums: Vec<SavedAlbum>,
+    liked_songs: Vec<SavedTrack>,
+    playlists: Vec<SimplifiedPlaylist>,
+}
+
+/// Everything the actions of a run share. The nodes of a stage are executed at the same time,
+/// so everything they write to is behind a lock. The locks are never held during a request.
+struct Execution<'a> {
+    backend: &'a Arc<dyn MusicBackend>,
+    snapshots_dir: &'a Path,
+    config: &'a Config,
+    dry_run: bool,
+
+    /// Songs are filtered by how long ago they were added relative to this.
+    started_at: DateTime<Utc>,
+    graph: &'a petgraph::Graph<String, ()>,
+    nodes: &'a [NodeData],
+    user_id: UserId<'static>,
 
     // TODO: For better performance, maybe create a list of tracks and use refs in the map like
+    map: Mutex<HashMap<String, Vec<TrackTuple>>>,
+    node_to_playlist_id: Mutex<HashMap<String, String>>,
+    ledger: Mutex<Ledger>,
+
+    /// Songs of each queried playlist that another user added.
+    added_by_others: Mutex<HashMap<String, Vec<TrackId<'static>>>>,
+
+    /// Queried playlists with items mixify skips, like local songs or episodes.
+    /// The positions of their songs are unknown, so they are never reordered.
+    unorderable: Mutex<HashSet<String>>,
+
+    /// The songs each incoming edge copied into a node, in the order they were copied.
+    sources: Mutex<HashMap<String, Vec<Source>>>,
+
+    /// Nodes whose songs were set by their edges or their query. Their songs are never replaced
+    /// by the songs of their playlist, even if no song is left, e.g. after an empty intersection.
+    initialized: Mutex<HashSet<String>>,
+
+    library_cache: Mutex<LibraryCache>,
+    library: OnceCell<Library>,
+
+    /// Songs of all playlists of the user. Only fetched for queries with the playlists source.
+    playlist_songs: OnceCell<Vec<PlaylistItem>>,
+
+    /// Completed writes, so a failed apply can be resumed. Not kept on dry runs and syncs.
+    journal: Mutex<Option<Journal>>,
+}
+
+/// Runs all actions of the snapshot graph in `content` against the backend.
+/// With `dry_run` set, only the read side actions are run and the writes are collected in a report.
+///
+/// The plan is executed stage by stage. The nodes of a stage don't depend on each other,
+/// so up to `config.concurrency` of them are executed at the same time.
+///
+/// Created playlists are recorded in `journal`, if given. Playlists the journal already created are reused.
+/// The ledger, journal, backups and library cache are read from and written to `snapshots_dir`.
+pub async fn execute_snapshot(
+    snapshots_dir: &Path,
+    snapshot_id: u32,
+    content: &str,
+    backend: &Arc<dyn MusicBackend>,
+    config: &Config,
+    dry_run: bool,
+    journal: Option<Journal>,
+) -> Result<ExecutionResult, anyhow::Error> {
+    let gv = graphviz_dot_parser::parse(content).or_error(String::from("failed to parse graph"))?;
+    let graph = gv.to_directed_graph().unwrap();
+    let (stages, nodes) = plan_command::create_execution_plan(&gv, content)?;
+
     let mut map: HashMap<String, Vec<TrackTuple>> = HashMap::new();
     let mut node_to_playlist_id: HashMap<String, String> = HashMap::new();
     let mut nodes_with_missing_playlists: Vec<String> = Vec::new();
 
-    let mut albums: Vec<Result<SavedAlbum, ClientError>> = vec![];
-    let mut playlists: Vec<SimplifiedPlaylist> = vec![];
-    let mut liked_songs: Vec<Result<SavedTrack, ClientError>> = vec![];
-    let mut is_cached = false;
-
-    let mut all_songs: Vec<PlaylistItem> = vec![];
-    let mut songs_are_cached = false;
-
-    let user = spotify
-        .current_user()
+    let user_id = backend
+        .current_user_id()
         .await
         .or_error_str("failed to fetch user")?;
 
+    let ledger = ledger::read_ledger(snapshots_dir, snapshot_id)?;
+
     log::debug!("------------------");
     log::debug!("list of actions:");
-    for actions in &all_actions {
+    for actions in &stages {
         for action in actions {
             log::debug!("{}", action);
 
-            if map.get(&action.node).is_none() {
-                map.insert(action.node.clone(), vec![]);
+            if let std::collections::hash_map::Entry::Vacant(e) = map.entry(action.node.clone()) {
+                e.insert(vec![]);
                 map.insert(to_local(&action.node.clone()), vec![]);
 
                 if let Some(url) = &action.playlist_url {
@@ -81,576 +241,1365 @@ pub async fn handle_apply_snapshot(
     }
     log::debug!("------------------");
 
-    for actions in all_actions {
+    if !dry_run {
+        let playlists_to_backup = stages
+            .iter()
+            .flatten()
+            .filter_map(|action| match &action.action_type {
+                types::ActionType::SaveChanges(SavePlaylist { url: Some(_), .. }) => {
+                    node_to_playlist_id
+                        .get(&action.node)
+                        .map(|id| (action.node.clone(), id.clone()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if !playlists_to_backup.is_empty() {
+            let path = backup::create_backup(
+                backend,
+                snapshots_dir,
+                snapshot_id,
+                &playlists_to_backup,
+                &ledger,
+            )
+            .await?;
+            log::info!(
+                "Backed up {} playlists to {}",
+                playlists_to_backup.len(),
+                path.display()
+            );
+        }
+    }
+
+    let execution = Execution {
+        backend,
+        snapshots_dir,
+        config,
+        dry_run,
+        started_at: Utc::now(),
+        graph: &graph,
+        nodes: &nodes,
+        user_id,
+        map: Mutex::new(map),
+        node_to_playlist_id: Mutex::new(node_to_playlist_id),
+        ledger: Mutex::new(ledger),
+        added_by_others: Mutex::new(HashMap::new()),
+        unorderable: Mutex::new(HashSet::new()),
+        sources: Mutex::new(HashMap::new()),
+        initialized: Mutex::new(HashSet::new()),
+        library_cache: Mutex::new(LibraryCache::default()),
+        library: OnceCell::new(),
+        playlist_songs: OnceCell::new(),
+        journal: Mutex::new(journal),
+    };
+
+    // When set, nothing is written to spotify. All writes are recorded in the report instead.
+    let mut dry_run_report = dry_run.then(DryRunReport::default);
+
+    for (stage, actions) in stages.into_iter().enumerate() {
+        let mut groups: Vec<Vec<Action>> = vec![];
         for action in actions {
             if action.for_node == constants::MIXIFY_TEMPORARY_ROOT_NODE_NAME {
                 continue;
             }
 
+            match groups.last_mut() {
+                Some(group) if group[0].for_node == action.for_node => group.push(action),
+                _ => groups.push(vec![action]),
+            }
+        }
+
+        log::debug!("Executing stage {} with {} nodes", stage + 1, groups.len());
+
+        // `buffered` keeps the order of the nodes, so the report is the same on every run.
+        let reports = stream::iter(groups)
+            .map(|actions| execution.execute_node(actions))
+            .buffered(config.concurrency)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        if let Some(report) = dry_run_report.as_mut() {
+            for part in reports {
+                report.created_playlists.extend(part.created_playlists);
+                report.changes.extend(part.changes);
+            }
+        }
+    }
+
+    return Ok(ExecutionResult {
+        node_to_playlist_id: execution.node_to_playlist_id.into_inner().unwrap(),
+        nodes_with_missing_playlists,
+        dry_run_report,
+    });
+}
+
+impl Execution<'_> {
+    fn record(&self, step: JournalStep) -> Result<(), anyhow::Error> {
+        if let Some(journal) = self.journal.lock().unwrap().as_mut() {
+            journal.record(self.snapshots_dir, step)?;
+        }
+
+        return Ok(());
+    }
+
+    /// Runs the actions of one node in order. Returns what the actions would have changed on a dry run.
+    async fn execute_node(&self, actions: Vec<Action>) -> Result<DryRunReport, anyhow::Error> {
+        let mut report = DryRunReport::default();
+        for action in actions {
             log::debug!("Applying action {:?}", action);
+            self.execute_action(action, &mut report).await?;
+        }
 
-            match action.action_type {
-                types::ActionType::CreatePlaylist => {
-                    let node_index = graph
-                        .node_indices()
-                        .find(|i| graph[*i] == *action.node)
-                        .unwrap();
-                    let nei = graph.neighbors_directed(node_index, petgraph::Direction::Incoming);
-                    let names = nei.map(|i| graph[i].clone()).collect::<Vec<String>>();
-
-                    let mut description = String::new();
-                    if config.write_description {
-                        description = match names.len() {
-                            0 => format!("mixify generated"),
-                            _ => format!(
-                                "generated by mixify. playlist consists of: {}.",
-                                names.join(", ")
-                            ),
-                        };
-                    }
+        return Ok(report);
+    }
 
-                    let (_, attr) = nodes
-                        .iter()
-                        .find(|(name, _)| *name == *action.node)
-                        .unwrap();
+    async fn execute_action(
+        &self,
+        action: Action,
+        report: &mut DryRunReport,
+    ) -> Result<(), anyhow::Error> {
+        let sets_songs = !matches!(
+            action.action_type,
+            types::ActionType::CreatePlaylist
+                | types::ActionType::QuerySongs(_)
+                | types::ActionType::SaveChanges(_)
+        );
+        if sets_songs {
+            self.initialized
+                .lock()
+                .unwrap()
+                .insert(action.for_node.clone());
+        }
 
-                    let playlist_name_attr = attr
+        match action.action_type {
+            types::ActionType::CreatePlaylist => self.create_playlist(&action, report).await?,
+            types::ActionType::QuerySongs(ref url) => {
+                self.query_songs(&action, url.clone()).await?
+            }
+            types::ActionType::CopySongs(ref copy) => {
+                let tracks = self.edge_songs(&action, copy.filter.as_ref()).await?;
+
+                self.sources
+                    .lock()
+                    .unwrap()
+                    .entry(action.for_node.clone())
+                    .or_default()
+                    .push(Source {
+                        node: action.node.clone(),
+                        weight: copy.weight,
+                        songs: tracks.iter().map(|t| t.id.clone()).collect(),
+                    });
+
+                let mut map = self.map.lock().unwrap();
+                let target = map.get_mut(&to_local(&action.for_node)).unwrap();
+                target.extend(tracks);
+            }
+            types::ActionType::IntersectSongs(ref combine) => {
+                let tracks = self.edge_songs(&action, combine.filter.as_ref()).await?;
+                let other = SongSet::new(combine.dedup, &tracks);
+
+                let mut map = self.map.lock().unwrap();
+                let target = map.get_mut(&to_local(&action.for_node)).unwrap();
+                target.retain(|t| other.contains(t));
+            }
+            types::ActionType::XorSongs(ref combine) => {
+                let tracks = self.edge_songs(&action, combine.filter.as_ref()).await?;
+                let other = SongSet::new(combine.dedup, &tracks);
+
+                let added = {
+                    let mut map = self.map.lock().unwrap();
+                    let target = map.get_mut(&to_local(&action.for_node)).unwrap();
+                    let own = SongSet::new(combine.dedup, target.iter());
+                    let added = tracks
                         .iter()
-                        .find(|(k, _)| k == constants::LABEL_ATTRIBUTE_KEY);
+                        .filter(|t| !own.contains(t))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    target.retain(|t| !other.contains(t));
+                    target.extend(added.iter().cloned());
+                    added
+                };
+
+                self.sources
+                    .lock()
+                    .unwrap()
+                    .entry(action.for_node.clone())
+                    .or_default()
+                    .push(Source {
+                        node: action.node.clone(),
+                        weight: None,
+                        songs: added.iter().map(|t| t.id.clone()).collect(),
+                    });
+            }
+            // We dont care if the song was added by the user or the bot we remove it anyway.
+            types::ActionType::RemoveSongs(dedup) => {
+                let mut map = self.map.lock().unwrap();
+                let remote = SongSet::new(dedup, map.get(&action.node).unwrap());
+                let local = map.get_mut(&to_local(&action.for_node)).unwrap();
+                local.retain(|t| !remote.contains(t));
+            }
+            types::ActionType::SaveChanges(ref save) => {
+                self.save_changes(&action, save, report).await?
+            }
+            types::ActionType::QuerySongsByArtist(ref q) => {
+                self.query_songs_by_artist(&action, q).await?
+            }
+            types::ActionType::QuerySongsByGenre(ref q) => {
+                self.query_songs_by_genre(&action, q).await?
+            }
+        }
 
-                    let mut playlist_name = action.node.clone();
-                    if let Some((_, v)) = playlist_name_attr {
-                        playlist_name = v.clone();
-                    }
+        return Ok(());
+    }
 
-                    let playlist = spotify
-                        .user_playlist_create(
-                            user.id.clone(),
-                            &format!("{}{}", playlist_name, config.mixstack_suffix),
-                            Some(false),
-                            Some(false),
-                            Some(&description),
-                        )
-                        .await
-                        .or_error_str("failed to create playlist")?;
-                    log::info!("Created playlist {:?}", playlist);
+    async fn create_playlist(
+        &self,
+        action: &Action,
+        report: &mut DryRunReport,
+    ) -> Result<(), anyhow::Error> {
+        let graph = self.graph;
+        let node_index = graph
+            .node_indices()
+            .find(|i| graph[*i] == *action.node)
+            .unwrap();
+        let nei = graph.neighbors_directed(node_index, petgraph::Direction::Incoming);
+        let names = nei.map(|i| graph[i].clone()).collect::<Vec<String>>();
+
+        let mut description = String::new();
+        if self.config.write_description {
+            description = match names.len() {
+                0 => "mixify generated".to_string(),
+                _ => format!(
+                    "generated by mixify. playlist consists of: {}.",
+                    names.join(", ")
+                ),
+            };
+        }
 
-                    node_to_playlist_id
-                        .insert(action.node.clone(), parse_id_from_playlist_id(&playlist.id));
+        let (_, attr) = self
+            .nodes
+            .iter()
+            .find(|(name, _)| *name == *action.node)
+            .unwrap();
+
+        let playlist_name_attr = attr
+            .iter()
+            .find(|(k, _)| k == constants::LABEL_ATTRIBUTE_KEY);
+
+        let mut playlist_name = action.node.clone();
+        if let Some((_, v)) = playlist_name_attr {
+            playlist_name = v.clone();
+        }
+
+        if self.dry_run {
+            report
+                .created_playlists
+                .push(format!("{}{}", playlist_name, self.config.mixstack_suffix));
+            return Ok(());
+        }
+
+        let created = self
+            .journal
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|journal| journal.created_playlist(&action.node).cloned());
+        if let Some(id) = created {
+            log::info!(
+                "Reusing playlist {} created for {:?} by the unfinished apply",
+                id,
+                action.node
+            );
+            let playlist_id = PlaylistId::from_id(id.clone())
+                .or_error(format!("invalid playlist id {} in the journal", id))?;
+
+            // Some songs may have been added already. They are compared against it when saving.
+            let tracks = self.fetch_tracks(&action.node, playlist_id).await;
+            self.map.lock().unwrap().insert(action.node.clone(), tracks);
+            self.node_to_playlist_id
+                .lock()
+                .unwrap()
+                .insert(action.node.clone(), id);
+            return Ok(());
+        }
+
+        let playlist_id = self
+            .backend
+            .user_playlist_create(
+                self.user_id.clone(),
+                &format!("{}{}", playlist_name, self.config.mixstack_suffix),
+                &description,
+            )
+            .await
+            .or_error_str("failed to create playlist")?;
+
+        let playlist_id = parse_id_from_playlist_id(&playlist_id);
+        self.record(JournalStep::CreatePlaylist {
+            node: action.node.clone(),
+            playlist_id: playlist_id.clone(),
+        })?;
+        self.node_to_playlist_id
+            .lock()
+            .unwrap()
+            .insert(action.node.clone(), playlist_id);
+
+        return Ok(());
+    }
+
+    async fn query_songs(&self, action: &Action, url: Option<String>) -> Result<(), anyhow::Error> {
+        if let Some(songs) = self.map.lock().unwrap().get(&action.node) {
+            // By default, the playlist should be empty.
+            if !songs.is_empty() {
+                log::warn!(
+                    "Playlist {:?} already has been queried. This should never happen. Skipping...",
+                    action.node
+                );
+                return Ok(());
+            }
+        }
+
+        log::info!("Querying songs for playlist {:?}", action.node);
+
+        let url = url
+            .or_else(|| {
+                let node_to_playlist_id = self.node_to_playlist_id.lock().unwrap();
+                node_to_playlist_id.get(&action.node).cloned()
+            })
+            .or_error_str("failed to find the playlist of the node to query")?;
+
+        let playlist_id_str = url.split('/').next_back().unwrap();
+        let playlist_id = PlaylistId::from_id(playlist_id_str.to_string()).or_error(format!(
+            "failed to parse playlist id correctly from url {}. the parsed id {}",
+            url.clone(),
+            playlist_id_str
+        ))?;
+
+        self.node_to_playlist_id
+            .lock()
+            .unwrap()
+            .insert(action.node.clone(), parse_id_from_playlist_id(&playlist_id));
+
+        let tracks = self.fetch_tracks(&action.node, playlist_id).await;
+
+        let mut map = self.map.lock().unwrap();
+        map.insert(action.node.clone(), tracks.clone());
+
+        // Base nodes pass on the songs of their playlist.
+        if !self.initialized.lock().unwrap().contains(&action.node) {
+            map.insert(to_local(&action.node), tracks);
+        }
+
+        return Ok(());
+    }
+
+    /// The songs currently in the playlist of the node. Also remembers which of them another user added.
+    async fn fetch_tracks(&self, node: &str, playlist_id: PlaylistId<'static>) -> Vec<TrackTuple> {
+        let playlist_id_str = parse_id_from_playlist_id(&playlist_id);
+        let songs = self.backend.playlist_items(playlist_id).await;
+        let count = songs.len();
+
+        let mut foreign_tracks = vec![];
+        let tracks = songs
+            .into_iter()
+            .filter_map(|t| {
+                let item = t.or_error(format!(
+                    "could not work with a song from the playlist id of {}",
+                    playlist_id_str
+                ));
+
+                if let Err(e) = item {
+                    log::warn!("{}", e);
+                    return None;
                 }
-                types::ActionType::QuerySongs(url) => {
-                    if let Some(songs) = map.get(&action.node) {
-                        // By default, the playlist should be empty.
-                        if songs.len() > 0 {
+
+                let item = item.unwrap();
+                let is_foreign = item
+                    .added_by
+                    .as_ref()
+                    .map(|user| user.id != self.user_id)
+                    .unwrap_or(false);
+
+                match item.track.unwrap() {
+                    rspotify::model::PlayableItem::Track(track) => {
+                        if track.is_local {
                             log::warn!(
-                                "Playlist {:?} already has been queried. This should never happen. Skipping...",
-                                action.node
+                                "Skipping local track {} from playlist {}",
+                                track.name,
+                                playlist_id_str
                             );
-                            continue;
+                            return None;
                         }
+
+                        if is_foreign {
+                            foreign_tracks.push(track.id.clone().unwrap());
+                        }
+
+                        TrackTuple::from_track(&track, item.added_at)
+                    }
+                    rspotify::model::PlayableItem::Episode(e) => {
+                        log::warn!("Skipping episode {:?}", e);
+                        None
                     }
+                }
+            })
+            .collect::<Vec<_>>();
 
-                    log::info!("Querying songs for playlist {:?}", action.node);
+        self.added_by_others
+            .lock()
+            .unwrap()
+            .insert(node.to_string(), foreign_tracks);
 
-                    let url = url
-                        .or_else(|| node_to_playlist_id.get(&action.node).cloned())
-                        .unwrap();
+        if tracks.len() != count {
+            self.unorderable.lock().unwrap().insert(node.to_string());
+        }
 
-                    let playlist_id_str = url.split("/").last().unwrap();
-                    let playlist_id =
-                        PlaylistId::from_id(playlist_id_str.clone()).or_error(format!(
-                            "failed to parse playlist id correctly from url {}. the parsed id {}",
-                            url.clone(),
-                            playlist_id_str
-                        ))?;
+        return tracks;
+    }
 
-                    node_to_playlist_id
-                        .insert(action.node.clone(), parse_id_from_playlist_id(&playlist_id));
+    async fn save_changes(
+        &self,
+        action: &Action,
+        save: &SavePlaylist,
+        report: &mut DryRunReport,
+    ) -> Result<(), anyhow::Error> {
+        let config = self.config;
+        let url = save.url.clone();
+        log::debug!(
+            "Saving changes of node {:?} to {}",
+            action.node,
+            url.as_deref().unwrap_or("the newly created playlist")
+        );
 
-                    let playlist = spotify.playlist_items(playlist_id.clone(), None, None);
-                    let songs = playlist.collect::<Vec<_>>().await;
+        let (remote, local) = {
+            let map = self.map.lock().unwrap();
+            let remote = map.get(&action.node).unwrap().clone();
+            let local = map.get(&to_local(&action.for_node)).unwrap().clone();
+            (remote, local)
+        };
+
+        let sources = self
+            .sources
+            .lock()
+            .unwrap()
+            .remove(&action.node)
+            .unwrap_or_default();
+        let local = dedup_songs(local, save);
+        let mut local = limit_songs(local, save, &sources, &action.node, self.started_at);
+        if !save.reorders() {
+            local.sort_by_key(|item| item.name.clone());
+        }
 
-                    let tracks = songs
-                        .into_iter()
-                        .filter_map(|t| {
-                            let item = t.or_error(format!(
-                                "could not work with a song from the playlist id of {}",
-                                playlist_id_str
-                            ));
-
-                            if let Err(e) = item {
-                                log::warn!("{}", e);
-                                return None;
-                            }
-                            match item.unwrap().track.unwrap() {
-                                rspotify::model::PlayableItem::Track(track) => {
-                                    if track.is_local {
-                                        log::warn!(
-                                            "Skipping local track {} from playlist {}",
-                                            track.name,
-                                            playlist_id_str
-                                        );
-                                        return None;
-                                    }
-
-                                    Some(TrackTuple {
-                                        id: track.id.unwrap(),
-                                        name: track.name,
-                                        album_name: track.album.name,
-                                        artist_id: track.artists[0].id.clone().unwrap(),
-                                    })
-                                }
-                                rspotify::model::PlayableItem::Episode(e) => {
-                                    log::warn!("Skipping episode {:?}", e);
-                                    None
-                                }
-                            }
-                        })
-                        .collect::<Vec<_>>();
+        // Compared by id, so a preferred version replaces the version in the playlist.
+        // New songs are added grouped by artist and album. The sort is stable,
+        // so the songs of an album stay sorted by name.
+        let mut songs_to_add = local.clone();
+        songs_to_add.retain(|t| !remote.iter().any(|r| r.id == t.id));
+        songs_to_add.sort_by_key(|item| (item.artist_id.to_string(), item.album_name.clone()));
+
+        // NOTE: Hashset can't be used because it would change the order.
+        let mut unique_songs_to_add: Vec<TrackTuple> = vec![];
+        for song in songs_to_add {
+            let added = unique_songs_to_add.iter().any(|t| t.id == song.id);
+            if save.dedup == Dedup::None || !added {
+                unique_songs_to_add.push(song);
+            }
+        }
 
-                    map.insert(action.node.clone(), tracks.clone());
+        let con_local = local.iter().map(|t| t.id.clone()).collect::<Vec<_>>();
+        let mut removed_songs = remote.clone();
+        removed_songs.retain(|t| !con_local.contains(&t.id));
+
+        // Only songs mixify added itself may be removed. Everything else was added by hand.
+        let playlist_id = self
+            .node_to_playlist_id
+            .lock()
+            .unwrap()
+            .get(&action.node)
+            .cloned();
+        let owned = playlist_id
+            .as_ref()
+            .and_then(|id| self.ledger.lock().unwrap().owned_tracks(id).cloned());
+        let owned = match owned {
+            Some(owned) => owned,
+            None => {
+                if !remote.is_empty() {
+                    log::info!(
+                        "Mixify has no record of the songs it added to playlist {:?} yet. Songs that are not part of the graph are kept.",
+                        action.node
+                    );
+                }
 
-                    let has_songs = map.get(&to_local(&action.node)).unwrap().len() > 0;
-                    if !has_songs {
-                        map.insert(to_local(&action.node), tracks);
-                    }
+                remote
+                    .iter()
+                    .filter(|t| con_local.contains(&t.id))
+                    .map(|t| t.id.id().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+        let foreign = self
+            .added_by_others
+            .lock()
+            .unwrap()
+            .get(&action.node)
+            .cloned()
+            .unwrap_or_default();
+
+        let (removed_songs, kept_songs): (Vec<TrackTuple>, Vec<TrackTuple>) =
+            removed_songs.into_iter().partition(|t| {
+                let is_foreign = config.keep_songs_added_by_others && foreign.contains(&t.id);
+                owned.contains(&t.id.id().to_string()) && !is_foreign
+            });
+        if !kept_songs.is_empty() {
+            log::info!(
+                "Keeping {} songs in playlist {:?} that were not added by mixify",
+                kept_songs.len(),
+                action.node
+            );
+        }
+
+        // A song added by hand is not added again in another version.
+        let kept = SongSet::new(save.dedup, &kept_songs);
+        unique_songs_to_add.retain(|t| !kept.contains(t));
+
+        // The playlist keeps the songs added by hand, so they are part of its state.
+        let mut state = local.clone();
+        state.extend(kept_songs.iter().cloned());
+
+        // New songs are added in order, so fewer songs have to be moved afterwards.
+        if save.reorders() {
+            self.order_songs(&mut state, save.order, &save.seed).await?;
+            if save.interleave {
+                state = interleave_songs(state, &sources);
+            }
+            let positions = state
+                .iter()
+                .enumerate()
+                .map(|(i, t)| (t.id.clone(), i))
+                .collect::<HashMap<_, _>>();
+            unique_songs_to_add.sort_by_key(|t| positions.get(&t.id).copied());
+        }
+
+        let song_ids_to_add = unique_songs_to_add
+            .iter()
+            .map(|t| t.id.clone())
+            .collect::<Vec<_>>();
+
+        // The songs of the playlist after adding and removing, in the order spotify has them.
+        let mut after_save = remote.iter().map(|t| t.id.clone()).collect::<Vec<_>>();
+        if config.allow_removing_songs {
+            after_save.retain(|id| !removed_songs.iter().any(|t| t.id == *id));
+        }
+        after_save.extend(song_ids_to_add.iter().cloned());
+
+        // Songs added by hand and songs that may not be removed count as well,
+        // so the limit of the node alone doesn't keep the playlist within the cap.
+        if after_save.len() > constants::MAX_PLAYLIST_SONGS {
+            return Err(anyhow::anyhow!(
+                "Playlist {:?} would have {} songs, but spotify only allows {}. Nothing was written to it. Lower its limit or allow removing songs.",
+                action.node,
+                after_save.len(),
+                constants::MAX_PLAYLIST_SONGS
+            ));
+        }
+
+        let reordering = match save.reorders() {
+            false => None,
+            _ if self.unorderable.lock().unwrap().contains(&action.node) => {
+                log::warn!(
+                    "Playlist {:?} has local songs or episodes, so it is not reordered",
+                    action.node
+                );
+                None
+            }
+            _ => {
+                // Songs that may not be removed stay where they are, the rest is ordered around them.
+                let stale = match config.allow_removing_songs {
+                    true => vec![],
+                    false => removed_songs.iter().map(|t| t.id.clone()).collect(),
+                };
+                let ordered = state.iter().map(|t| t.id.clone()).collect::<Vec<_>>();
+                let desired = desired_order(&after_save, &ordered, &stale);
+                plan_reordering(&after_save, &desired, !foreign.is_empty())
+                    .map(|reordering| (reordering, desired))
+            }
+        };
+
+        let songs_to_remove = removed_songs
+            .iter()
+            .map(|t| t.id.clone())
+            .collect::<Vec<_>>();
+
+        if self.dry_run {
+            report.changes.push(PlaylistChanges {
+                node: action.node.clone(),
+                playlist_url: url,
+                added: unique_songs_to_add,
+                removed: removed_songs,
+                removal_allowed: config.allow_removing_songs,
+                kept: kept_songs,
+                tracks: state.clone(),
+                reorder_calls: reordering
+                    .as_ref()
+                    .map(|(r, desired)| r.calls(desired.len()))
+                    .unwrap_or(0),
+            });
+
+            self.map
+                .lock()
+                .unwrap()
+                .insert(to_local(&action.node), state);
+            return Ok(());
+        }
+
+        let playlist_id =
+            playlist_id.or_error(format!("no playlist id found for node {:?}", action.node))?;
+
+        // Songs that are in the playlist during the save and were added by mixify.
+        let mut claimed = owned
+            .into_iter()
+            .filter(|id| remote.iter().any(|t| t.id.id() == id))
+            .collect::<Vec<_>>();
+        for song in &unique_songs_to_add {
+            let id = song.id.id().to_string();
+            if !claimed.contains(&id) {
+                claimed.push(id);
+            }
+        }
+        let owned_after = claimed
+            .iter()
+            .filter(|id| {
+                !config.allow_removing_songs || !removed_songs.iter().any(|t| t.id.id() == *id)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // Claimed before anything is written, so the songs an apply that fails halfway added
+        // are still known as added by mixify when the apply is resumed.
+        let ledger_playlist_id = playlist_id.clone();
+        {
+            let mut ledger = self.ledger.lock().unwrap();
+            ledger.set_owned_tracks(&action.node, &ledger_playlist_id, claimed);
+            ledger::write_ledger(self.snapshots_dir, &ledger)?;
+        }
+
+        let playlist_id = PlaylistId::from_id(playlist_id).unwrap();
+
+        if !song_ids_to_add.is_empty() {
+            let ids = song_ids_to_add
+                .into_iter()
+                .map(PlayableId::Track)
+                .collect::<Vec<rspotify::model::PlayableId>>();
+
+            // Spotify only accepts 100 songs per call.
+            for chunk in ids.chunks(100) {
+                let items = chunk.iter().map(|y| y.clone_static()).collect::<Vec<_>>();
+
+                let res = self
+                    .backend
+                    .playlist_add_items(playlist_id.clone(), items)
+                    .await;
+                if let Err(e) = res {
+                    return Err(anyhow::anyhow!("Failed to add songs to playlist {:?}", e));
                 }
-                types::ActionType::CopySongs => {
-                    let tracks = map.get(&to_local(&action.node)).unwrap().clone();
-                    let target = map.get_mut(&to_local(&action.for_node)).unwrap();
-                    target.extend(tracks);
+            }
+
+            log::info!("Added songs successfully");
+        } else {
+            log::info!("No songs to add to playlist {:?}", &playlist_id);
+        }
+
+        log::info!(
+            "Option allow to delete is {:?}",
+            config.allow_removing_songs
+        );
+        if !config.allow_removing_songs {
+            log::info!(
+                "Skipping removing songs {:?} from playlist {:?}",
+                songs_to_remove.len(),
+                &playlist_id
+            );
+        } else if !songs_to_remove.is_empty() {
+            let ids = songs_to_remove
+                .into_iter()
+                .map(PlayableId::Track)
+                .collect::<Vec<rspotify::model::PlayableId>>();
+
+            // TODO: Use playlist_remove_specific_occurrences_of_items instead.
+            log::info!("Removing songs to playlist {:?}", &playlist_id);
+            for chunk in ids.chunks(100) {
+                let items = chunk.iter().map(|y| y.clone_static()).collect::<Vec<_>>();
+
+                let res = self
+                    .backend
+                    .playlist_remove_all_occurrences_of_items(playlist_id.clone(), items)
+                    .await;
+                if let Err(e) = res {
+                    return Err(anyhow::anyhow!(
+                        "Failed to remove songs to playlist {:?}",
+                        e
+                    ));
                 }
-                // We dont care if the song was added by the user or the bot we remove it anyway.
-                types::ActionType::RemoveSongs => {
-                    let remote = map.get(&action.node).unwrap().clone();
-                    let local = map.get_mut(&to_local(&action.for_node)).unwrap();
-                    local.retain(|t| !remote.contains(t));
+            }
+            log::info!("Removed songs successfully");
+        } else {
+            log::info!("No songs to remove to playlist {:?}", &playlist_id);
+        }
+
+        if let Some((reordering, desired)) = reordering {
+            self.reorder_playlist(action, playlist_id, &desired, reordering)
+                .await?;
+        }
+
+        {
+            let mut ledger = self.ledger.lock().unwrap();
+            ledger.set_owned_tracks(&action.node, &ledger_playlist_id, owned_after);
+            ledger::write_ledger(self.snapshots_dir, &ledger)?;
+        }
+
+        // Set the updated playlist state.
+        self.map
+            .lock()
+            .unwrap()
+            .insert(to_local(&action.node), state);
+
+        return Ok(());
+    }
+
+    /// Sorts the songs in the given order. Songs that compare equal keep their order.
+    async fn order_songs(
+        &self,
+        songs: &mut [TrackTuple],
+        order: Order,
+        seed: &str,
+    ) -> Result<(), anyhow::Error> {
+        match order {
+            Order::AddedAt => songs.sort_by_key(|t| (t.added_at.is_none(), t.added_at)),
+            Order::ReleaseDate => songs.sort_by_key(|t| {
+                let date = t
+                    .release_date
+                    .as_deref()
+                    .and_then(types::parse_release_date);
+                (date.is_none(), date)
+            }),
+            Order::Artist => songs.sort_by_key(|t| (t.artist_id.to_string(), t.album_name.clone())),
+            Order::Popularity => {
+                songs.sort_by_key(|t| (t.popularity.is_none(), std::cmp::Reverse(t.popularity)))
+            }
+            Order::Tempo => {
+                let ids = songs
+                    .iter()
+                    .map(|t| t.id.clone())
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .collect::<Vec<_>>();
+                let features = self.audio_features(ids).await?;
+                let tempo = |t: &TrackTuple| features.get(t.id.id()).map(|f| f.tempo);
+                songs.sort_by(|a, b| match (tempo(a), tempo(b)) {
+                    (Some(a), Some(b)) => a.total_cmp(&b),
+                    (a, b) => a.is_none().cmp(&b.is_none()),
+                });
+            }
+            Order::SeededShuffle => songs.sort_by_key(|t| shuffle_key(seed, t.id.id())),
+            Order::SourceOrder | Order::Keep => {}
+        }
+
+        return Ok(());
+    }
+
+    async fn reorder_playlist(
+        &self,
+        action: &Action,
+        playlist_id: PlaylistId<'static>,
+        songs: &[TrackId<'static>],
+        reordering: Reordering,
+    ) -> Result<(), anyhow::Error> {
+        let calls = reordering.calls(songs.len());
+        log::info!("Reordering playlist {:?} with {} calls", action.node, calls);
+
+        match reordering {
+            Reordering::Moves(moves) => {
+                for (range_start, insert_before, range_length) in moves {
+                    self.backend
+                        .playlist_reorder_items(
+                            playlist_id.clone(),
+                            range_start,
+                            insert_before,
+                            range_length,
+                        )
+                        .await
+                        .or_error(format!("Failed to reorder playlist {:?}", action.node))?;
                 }
-                types::ActionType::SaveChanges(_) => {
-                    let remote = map.get(&action.node).unwrap().clone();
-                    let local = map.get_mut(&to_local(&action.for_node)).unwrap();
-
-                    // Remove duplicates. (Including same songs although they may be in different albums)
-                    local.sort_unstable_by_key(|item| (item.name.clone(), !item.is_single()));
-                    local.dedup();
-
-                    let mut songs_to_add = local.clone();
-                    songs_to_add.retain(|t| !remote.contains(t));
-                    songs_to_add.sort_unstable_by_key(|item| {
-                        (item.artist_id.to_string(), item.album_name.clone())
-                    });
+            }
+            Reordering::Replace => {
+                let mut chunks = songs.chunks(100).map(|chunk| {
+                    chunk
+                        .iter()
+                        .map(|id| PlayableId::Track(id.clone()))
+                        .collect::<Vec<_>>()
+                });
 
-                    // NOTE: Hashset can't be used because it would change the order.
-                    let mut song_ids_to_add = vec![];
-                    for song in songs_to_add {
-                        if !song_ids_to_add.contains(&song.id) {
-                            song_ids_to_add.push(song.id);
-                        }
-                    }
+                let first = chunks.next().unwrap_or_default();
+                self.backend
+                    .playlist_replace_items(playlist_id.clone(), first)
+                    .await
+                    .or_error(format!("Failed to reorder playlist {:?}", action.node))?;
+                for chunk in chunks {
+                    self.backend
+                        .playlist_add_items(playlist_id.clone(), chunk)
+                        .await
+                        .or_error(format!("Failed to reorder playlist {:?}", action.node))?;
+                }
+            }
+        }
 
-                    let mut songs_to_remove =
-                        remote.iter().map(|t| t.id.clone()).collect::<Vec<_>>();
-                    let con_local = local.iter().map(|t| t.id.clone()).collect::<Vec<_>>();
-
-                    songs_to_remove.retain(|t| !con_local.contains(t));
-
-                    let playlist_id = node_to_playlist_id.get(&action.node).unwrap();
-                    let playlist_id = PlaylistId::from_id(playlist_id).unwrap();
-
-                    if song_ids_to_add.len() != 0 {
-                        let ids = song_ids_to_add
-                            .into_iter()
-                            .map(|t| PlayableId::Track(t))
-                            .collect::<Vec<rspotify::model::PlayableId>>();
-
-                        // TODO: If too many, spotify will return an error.
-                        // We should chunk the requests.
-                        // Do the same for the remove action.
-
-                        let mut chunks = ids.chunks(100);
-                        while let Some(chunk) = chunks.next() {
-                            let items = chunk.iter().map(|y| y.clone_static()).collect::<Vec<_>>();
-
-                            let res = spotify
-                                .playlist_add_items(playlist_id.clone(), items, None)
-                                .await;
-                            if let Err(e) = res {
-                                return Err(anyhow::anyhow!(
-                                    "Failed to add songs to playlist {:?}",
-                                    e
-                                ));
-                            }
-                        }
+        return Ok(());
+    }
 
-                        log::info!("Added songs successfully");
-                    } else {
-                        log::info!("No songs to add to playlist {:?}", &playlist_id);
-                    }
+    async fn load_library(&self) -> Result<Library, anyhow::Error> {
+        log::info!("Loading the user library. Only changes since the last run are fetched.");
+        let mut cache = library_cache::read_cache(self.snapshots_dir, self.user_id.id());
+
+        let now = Instant::now();
+        cache.saved_albums = library_cache::refresh_saved_items(self.backend, &cache.saved_albums)
+            .await
+            .or_error_str("failed to fetch saved albums")?;
+        log::info!("Took {}ms to fetch all albums", now.elapsed().as_millis());
+
+        let now = Instant::now();
+        cache.saved_tracks = library_cache::refresh_saved_items(self.backend, &cache.saved_tracks)
+            .await
+            .or_error_str("failed to fetch liked songs")?;
+        log::info!(
+            "Took {}ms to fetch all liked songs",
+            now.elapsed().as_millis()
+        );
 
-                    log::info!(
-                        "Option allow to delete is {:?}",
-                        config.allow_removing_songs
+        let now = Instant::now();
+        let p = self.backend.current_user_playlists().await;
+        log::info!(
+            "Took {}ms to fetch all playlists",
+            now.elapsed().as_millis()
+        );
+
+        let mut playlists = vec![];
+        for p in p {
+            if let Ok(p) = p {
+                let name = p.name.clone();
+                if name.ends_with(&self.config.mixstack_suffix) {
+                    log::warn!(
+                        "Skipping playlist {:?} because the playlist suffix indicates that it was generated by mixify. Suffix: {:?}",
+                        p.name, self.config.mixstack_suffix
                     );
-                    if !config.allow_removing_songs {
-                        log::info!(
-                            "Skipping removing songs {:?} from playlist {:?}",
-                            songs_to_remove.len(),
-                            &playlist_id
-                        );
-                    } else if songs_to_remove.len() != 0 {
-                        let ids = songs_to_remove
-                            .into_iter()
-                            .map(|t| PlayableId::Track(t))
-                            .collect::<Vec<rspotify::model::PlayableId>>();
-
-                        // TODO: Use playlist_remove_specific_occurrences_of_items instead.
-                        log::info!("Removing songs to playlist {:?}", &playlist_id);
-                        let res = spotify
-                            .playlist_remove_all_occurrences_of_items(playlist_id, ids, None)
-                            .await;
-
-                        if let Err(e) = res {
-                            return Err(anyhow::anyhow!(
-                                "Failed to remove songs to playlist {:?}",
-                                e
-                            ));
-                        }
+                    continue;
+                }
 
-                        log::info!("Removed songs successfully");
-                    } else {
-                        log::info!("No songs to remove to playlist {:?}", &playlist_id);
-                    }
+                playlists.push(p);
+                continue;
+            }
+
+            log::warn!("failed to fetch playlist {}", p.err().unwrap());
+        }
 
-                    // Set the updated playlist state.
-                    let state = local.clone();
-                    map.insert(to_local(&action.node), state);
+        let library = Library {
+            albums: cache.saved_albums.clone(),
+            liked_songs: cache.saved_tracks.clone(),
+            playlists,
+        };
+
+        let mut current = self.library_cache.lock().unwrap();
+        // Edge filters may have fetched features while the library was loading.
+        cache
+            .audio_features
+            .extend(std::mem::take(&mut current.audio_features));
+        library_cache::write_cache(self.snapshots_dir, &cache)?;
+        *current = cache;
+
+        return Ok(library);
+    }
+
+    /// Unchanged playlists are read from the cache, only the rest is fetched.
+    async fn load_playlist_songs(
+        &self,
+        playlists: &[SimplifiedPlaylist],
+    ) -> Result<Vec<PlaylistItem>, anyhow::Error> {
+        let mut all_songs: Vec<PlaylistItem> = vec![];
+        let mut uncached_playlists = vec![];
+        {
+            let mut cache = self.library_cache.lock().unwrap();
+            cache.retain_playlists(playlists);
+            for p in playlists {
+                match cache.playlist_items(p) {
+                    Some(items) => all_songs.extend(items.iter().cloned()),
+                    None => uncached_playlists.push(p.clone()),
                 }
-                types::ActionType::QuerySongsByArtist(q) => {
-                    // TODO: Playlists should be cached, on a database or something.
-                    // TODO: Backup before starting to apply the snapshot. (Backup songs for each playlist).
-
-                    let now = Instant::now();
-                    if !is_cached {
-                        log::info!(
-                            "Fetching all songs from user library. Since there is no cache yet."
-                        );
-                        let now = Instant::now();
-                        let fetched_albums = spotify.current_user_saved_albums(None);
-                        albums = fetched_albums.collect::<Vec<_>>().await;
-                        log::info!("Took {}ms to fetch all albums", now.elapsed().as_millis());
-
-                        let now = Instant::now();
-                        let fetched_liked_songs = spotify.current_user_saved_tracks(None);
-                        liked_songs = fetched_liked_songs.collect::<Vec<_>>().await;
-                        log::info!(
-                            "Took {}ms to fetch all liked songs",
-                            now.elapsed().as_millis()
-                        );
+            }
+        }
+        log::info!(
+            "{} of {} playlists are unchanged since the last run",
+            playlists.len() - uncached_playlists.len(),
+            playlists.len()
+        );
 
-                        let now = Instant::now();
-                        let fetched_playlists = spotify.current_user_playlists();
-                        let p = fetched_playlists.collect::<Vec<_>>().await;
-                        log::info!(
-                            "Took {}ms to fetch all playlists",
-                            now.elapsed().as_millis()
-                        );
+        let mut playlists = uncached_playlists
+            .iter()
+            .map(|p| {
+                let p = p.clone();
+                (parse_id_from_playlist_id(&p.id), p.name)
+            })
+            .collect::<Vec<_>>();
+
+        let total = Instant::now();
+        let (fetched, failed) = fetch_songs_from_playlists(self.backend, playlists.clone()).await;
+
+        // The backend already retried them, so they are skipped for this run.
+        playlists.retain(|(id, _)| failed.contains(id));
+        if !failed.is_empty() {
+            log::warn!(
+                "Failed to fetch songs from the {} playlists, {:?}. Skipping them.",
+                failed.len(),
+                playlists
+                    .iter()
+                    .map(|(_, name)| name.clone())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
 
-                        for p in p {
-                            if let Ok(p) = p {
-                                let name = p.name.clone();
-                                if name.ends_with(&config.mixstack_suffix) {
-                                    log::warn!(
-                                        "Skipping playlist {:?} because the playlist suffix indicates that it was generated by mixify. Suffix: {:?}",
-                                        p.name, config.mixstack_suffix
-                                    );
-                                    continue;
-                                }
-
-                                playlists.push(p);
-                                continue;
-                            }
-
-                            log::warn!("failed to fetch playlist {}", p.err().unwrap());
-                        }
-                    }
+        {
+            let mut cache = self.library_cache.lock().unwrap();
+            for (id, items) in fetched {
+                if let Some(p) = uncached_playlists
+                    .iter()
+                    .find(|p| parse_id_from_playlist_id(&p.id) == id)
+                {
+                    cache.set_playlist_items(p, items.clone());
+                }
+                all_songs.extend(items);
+            }
+            library_cache::write_cache(self.snapshots_dir, &cache)?;
+        }
 
-                    log::info!("Took {}ms to fetch all songs", now.elapsed().as_millis());
+        log::info!(
+            "Took {}s to fetch uncached songs from.",
+            total.elapsed().as_secs(),
+        );
+
+        return Ok(all_songs);
+    }
 
-                    is_cached = true;
-                    let mut tracks: Vec<TrackTuple> = vec![];
+    async fn query_songs_by_artist(
+        &self,
+        action: &Action,
+        q: &QuerySongsByArtist,
+    ) -> Result<(), anyhow::Error> {
+        let now = Instant::now();
+        let library = self.library.get_or_try_init(|| self.load_library()).await?;
+        log::info!("Took {}ms to fetch all songs", now.elapsed().as_millis());
+
+        let liked_songs = &library.liked_songs;
+        let mut tracks: Vec<TrackTuple> = vec![];
+
+        let artist_id = rspotify::model::ArtistId::from_id(q.artist_id.clone()).or_error(
+            format!("failed to parse artist id correctly from {}", q.artist_id),
+        )?;
+
+        if (q.source.is_none() || *q.source.as_ref().unwrap() == types::QuerySource::LikedSongs)
+            || (q.must_be_liked.is_none() || q.must_be_liked.unwrap_or(false))
+        {
+            for t in liked_songs.iter().filter(|t| !t.track.is_local) {
+                if should_add_song(&t.track.clone().into(), &artist_id, q, liked_songs).is_none() {
+                    continue;
+                }
 
-                    let artist_id =
-                        rspotify::model::ArtistId::from_id(q.artist_id.clone()).or_error(
-                            format!("failed to parse artist id correctly from {}", q.artist_id),
-                        )?;
+                if let Some(tuple) = TrackTuple::from_track(&t.track, Some(t.added_at)) {
+                    tracks.push(TrackTuple {
+                        artist_id: artist_id.clone(),
+                        ..tuple
+                    });
+                }
+            }
+        }
 
-                    if (q.source.is_none()
-                        || *q.source.as_ref().unwrap() == types::QuerySource::LikedSongs)
-                        || (q.must_be_liked.is_none() || q.must_be_liked.unwrap_or(false))
+        if q.source.is_none() || *q.source.as_ref().unwrap() == types::QuerySource::Albums {
+            let res = library
+                .albums
+                .iter()
+                .inspect(|saved| {
+                    let a = &saved.album;
+                    if a.tracks.items.len() as u32 != a.tracks.total {
+                        log::error!(
+                            "album {:?} has {} songs but the total is {}. exiting...",
+                            a.name,
+                            a.tracks.items.len(),
+                            a.tracks.total
+                        );
+                        panic!();
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            for saved in res {
+                let a = &saved.album;
+                for t in &a.tracks.items {
+                    if should_add_song(
+                        &simplified_track_to_track(t.clone(), a),
+                        &artist_id,
+                        q,
+                        liked_songs,
+                    )
+                    .is_some()
                     {
-                        liked_songs.iter().for_each(|t| {
-                            if let Err(e) = t {
-                                let x = format!("failed to fetch liked song {:?}", e);
-                                panic!("{}", x);
-                            }
-
-                            let name = t.as_ref().unwrap().clone().track.name;
-                            let album_name = t.as_ref().unwrap().clone().track.album.name;
-                            let t = t.as_ref().unwrap().clone().track;
-
-                            if let Some(id) =
-                                should_add_song(&t.into(), &artist_id, &q, &liked_songs)
-                            {
-                                tracks.push(TrackTuple {
-                                    id: id.clone(),
-                                    name,
-                                    album_name,
-                                    artist_id: artist_id.clone(),
-                                });
-                            }
+                        tracks.push(TrackTuple {
+                            artist_id: artist_id.clone(),
+                            ..TrackTuple::from_album_track(t, a, Some(saved.added_at))
                         });
                     }
+                }
+            }
+        }
 
-                    if q.source.is_none()
-                        || *q.source.as_ref().unwrap() == types::QuerySource::Albums
-                    {
-                        let res = albums
-                            .iter()
-                            .map(|a| a.as_ref().unwrap().clone().album)
-                            .inspect(|a| {
-                                if a.tracks.items.len() as u32 != a.tracks.total {
-                                    log::error!(
-                                        "album {:?} has {} songs but the total is {}. exiting...",
-                                        a.name,
-                                        a.tracks.items.len(),
-                                        a.tracks.total
-                                    );
-                                    panic!();
-                                }
-                            })
-                            .collect::<Vec<_>>();
-
-                        for a in res {
-                            for t in &a.tracks.items {
-                                if let Some(id) = should_add_song(
-                                    &simplified_track_to_track(t.clone(), &a),
-                                    &artist_id,
-                                    &q,
-                                    &liked_songs,
-                                ) {
-                                    tracks.push(TrackTuple {
-                                        id: id.clone(),
-                                        name: t.name.clone(),
-                                        album_name: a.name.clone(),
-                                        artist_id: artist_id.clone(),
-                                    });
-                                }
-                            }
-                        }
+        if q.source.is_none() || *q.source.as_ref().unwrap() == types::QuerySource::Playlists {
+            let all_songs = self
+                .playlist_songs
+                .get_or_try_init(|| self.load_playlist_songs(&library.playlists))
+                .await?;
+
+            all_songs
+                .iter()
+                .filter_map(|t| {
+                    if t.track.is_none() {
+                        log::warn!("Skipping song {:?}", t);
+                        return None;
                     }
 
-                    if q.source.is_none()
-                        || *q.source.as_ref().unwrap() == types::QuerySource::Playlists
-                    {
-                        if !songs_are_cached {
-                            let mut playlists = playlists
-                                .iter()
-                                .map(|p| {
-                                    let p = p.clone();
-                                    (parse_id_from_playlist_id(&p.id), p.name)
-                                })
-                                .collect::<Vec<_>>();
-
-                            let total = Instant::now();
-                            let (success, failed) =
-                                fetch_songs_from_playlists(&spotify, playlists.clone()).await;
-                            all_songs.extend(success);
-
-                            playlists.retain(|(id, _)| failed.contains(id));
-
-                            if failed.len() != 0 {
-                                log::warn!(
-                                    "Failed to fetch songs from the {} playlists, {:?}. Trying again in 30s...",
-                                    failed.len(),
-                                    playlists.iter().map(|(_, name)| name.clone()).collect::<Vec<_>>().join(", ")
-                                );
-
-                                tokio::time::sleep(Duration::from_secs(30)).await;
-
-                                let (success, failed) =
-                                    fetch_songs_from_playlists(&spotify, playlists.clone()).await;
-                                all_songs.extend(success);
-
-                                playlists.retain(|(id, _)| !failed.contains(id));
-                                if failed.len() != 0 {
-                                    log::warn!(
-                                        "Failed to fetch songs from the {} playlists, {:?}.",
-                                        failed.len(),
-                                        playlists
-                                            .iter()
-                                            .map(|(_, name)| name.clone())
-                                            .collect::<Vec<_>>()
-                                            .join(", ")
-                                    );
-                                }
-                            }
-
-                            log::info!(
-                                "Took {}s to fetch uncached songs from.",
-                                total.elapsed().as_secs(),
-                            );
-                            songs_are_cached = true;
+                    match t.track.clone().unwrap() {
+                        rspotify::model::PlayableItem::Track(track) if track.is_local => {
+                            log::warn!("Skipping local track {}", track.name);
+                            None
+                        }
+                        rspotify::model::PlayableItem::Track(track) => Some((track, t.added_at)),
+                        rspotify::model::PlayableItem::Episode(e) => {
+                            log::warn!("Skipping episode {:?}", e);
+                            None
                         }
+                    }
+                })
+                .for_each(|(t, added_at)| {
+                    if should_add_song(&t.clone().into(), &artist_id, q, liked_songs).is_none() {
+                        return;
+                    }
 
-                        all_songs
-                            .iter()
-                            .filter_map(|t| {
-                                if t.track.is_none() {
-                                    log::warn!("Skipping song {:?}", t);
-                                    return None;
-                                }
-
-                                match t.track.clone().unwrap() {
-                                    rspotify::model::PlayableItem::Track(track) => Some(track),
-                                    rspotify::model::PlayableItem::Episode(e) => {
-                                        log::warn!("Skipping episode {:?}", e);
-                                        None
-                                    }
-                                }
-                            })
-                            .for_each(|t| {
-                                let name = t.name.clone();
-                                let album_name = t.album.name.clone();
-
-                                if let Some(id) =
-                                    should_add_song(&t.into(), &artist_id, &q, &liked_songs)
-                                {
-                                    tracks.push(TrackTuple {
-                                        id: id.clone(),
-                                        album_name,
-                                        name,
-                                        artist_id: artist_id.clone(),
-                                    });
-                                }
-                            });
+                    if let Some(tuple) = TrackTuple::from_track(&t, added_at) {
+                        tracks.push(TrackTuple {
+                            artist_id: artist_id.clone(),
+                            ..tuple
+                        });
                     }
+                });
+        }
 
-                    map.insert(to_local(&action.node), tracks);
+        let tracks = self.apply_filter(tracks, q.filter.as_ref()).await?;
+        self.map
+            .lock()
+            .unwrap()
+            .insert(to_local(&action.node), tracks);
+        return Ok(());
+    }
+
+    async fn query_songs_by_genre(
+        &self,
+        action: &Action,
+        q: &QuerySongsByGenre,
+    ) -> Result<(), anyhow::Error> {
+        let library = self.library.get_or_try_init(|| self.load_library()).await?;
+        let liked_songs = &library.liked_songs;
+        let from_source = |source: QuerySource| q.source.is_none() || q.source == Some(source);
+
+        // Every song of the sources. Local songs have no id and are skipped.
+        let mut candidates: Vec<(Track, TrackTuple)> = vec![];
+        if from_source(QuerySource::LikedSongs) {
+            for t in liked_songs.iter().filter(|t| !t.track.is_local) {
+                if let Some(tuple) = TrackTuple::from_track(&t.track, Some(t.added_at)) {
+                    candidates.push((t.track.clone().into(), tuple));
                 }
             }
         }
-    }
 
-    log::info!("Successfully applied snapshot");
-
-    if is_sync {
-        return Ok(());
-    }
+        if from_source(QuerySource::Albums) {
+            for saved in &library.albums {
+                let a = &saved.album;
+                for t in a.tracks.items.iter().filter(|t| !t.is_local) {
+                    let tuple = TrackTuple::from_album_track(t, a, Some(saved.added_at));
+                    candidates.push((simplified_track_to_track(t.clone(), a), tuple));
+                }
+            }
+        }
 
-    let paths = plan_command::list_snapshot_files(cmd.id, "edit")?;
-    let path = paths.get(0).unwrap().to_str().unwrap();
-    let pre_apply_path = path.replace("edit", "pre.apply");
-    let post_apply_path = path.replace("edit", "post.apply");
+        if from_source(QuerySource::Playlists) {
+            let all_songs = self
+                .playlist_songs
+                .get_or_try_init(|| self.load_playlist_songs(&library.playlists))
+                .await?;
 
-    let new_content = create_post_apply_file(
-        &content,
-        &node_to_playlist_id,
-        &nodes_with_missing_playlists,
-    )?;
+            for item in all_songs {
+                if let Some(rspotify::model::PlayableItem::Track(t)) = &item.track {
+                    if let Some(tuple) = TrackTuple::from_track(t, item.added_at) {
+                        candidates.push((t.clone().into(), tuple));
+                    }
+                }
+            }
+        }
 
-    std::fs::rename(path, pre_apply_path)?;
-    std::fs::write(post_apply_path, new_content)?;
-    return Ok(());
-}
+        if let Some(must_be_liked) = q.must_be_liked {
+            candidates.retain(|(t, _)| {
+                let is_liked = liked_songs.iter().any(|saved| saved.track.id == t.id);
+                is_liked == must_be_liked
+            });
+        }
 
-pub fn create_post_apply_file(
-    content: &String,
-    node_to_playlist_url: &HashMap<String, String>,
-    nodes_with_missing_playlists: &Vec<String>,
-) -> Result<String, anyhow::Error> {
-    let pfx = content
-        .chars()
-        .take_while(|&ch| ch != '{')
-        .collect::<String>();
-
-    let sfx2 = content
-        .chars()
-        .skip_while(|&ch| ch != '}')
-        .skip(1)
-        .collect::<String>();
-
-    let inner = content
-        .chars()
-        .skip_while(|&ch| ch != '{')
-        .skip(1)
-        .take_while(|&ch| ch != '}')
-        .collect::<String>();
-
-    let parts = inner
-        .split(";")
-        .map(|part| part.to_string())
-        .collect::<Vec<String>>();
-
-    let mut res_parts = parts.clone();
-    for (node, playlist_url) in node_to_playlist_url {
-        if !nodes_with_missing_playlists.contains(node) {
-            continue;
+        let mut artist_ids: Vec<ArtistId<'static>> = vec![];
+        for id in candidates
+            .iter()
+            .flat_map(|(t, _)| t.artists.iter().filter_map(|a| a.id.clone()))
+        {
+            if !artist_ids.contains(&id) {
+                artist_ids.push(id);
+            }
         }
+        let genres = self.artist_genres(artist_ids).await?;
+
+        let genre = q.genre.to_lowercase();
+        let regex = match q.genre_match {
+            GenreMatch::Regex => Some(
+                plan_command::genre_regex(&q.genre)
+                    .or_error(format!("invalid genre regex {:?}", q.genre))?,
+            ),
+            _ => None,
+        };
+        let matches = |g: &String| match q.genre_match {
+            GenreMatch::Exact => g.to_lowercase() == genre,
+            GenreMatch::Prefix => g.to_lowercase().starts_with(&genre),
+            GenreMatch::Regex => regex.as_ref().unwrap().is_match(g),
+        };
+
+        let tracks = candidates
+            .into_iter()
+            .filter(|(t, _)| {
+                t.artists
+                    .iter()
+                    .filter_map(|a| a.id.as_ref())
+                    .filter_map(|id| genres.get(id.id()))
+                    .any(|artist_genres| artist_genres.iter().any(matches))
+            })
+            .map(|(_, tuple)| tuple)
+            .collect::<Vec<_>>();
+        let tracks = self.apply_filter(tracks, q.filter.as_ref()).await?;
+
+        log::info!(
+            "Found {} songs of genre {:?} for {:?}",
+            tracks.len(),
+            q.genre,
+            action.node
+        );
 
-        let mut item: Option<(usize, usize, String)> = None;
-        for (idx, part) in parts.iter().enumerate() {
-            if part.contains("->") {
-                continue;
+        self.map
+            .lock()
+            .unwrap()
+            .insert(to_local(&action.node), tracks);
+        return Ok(());
+    }
+
+    /// The songs of the node of the action that pass the filter of its edge.
+    async fn edge_songs(
+        &self,
+        action: &Action,
+        filter: Option<&SongFilter>,
+    ) -> Result<Vec<TrackTuple>, anyhow::Error> {
+        let tracks = self
+            .map
+            .lock()
+            .unwrap()
+            .get(&to_local(&action.node))
+            .unwrap()
+            .clone();
+
+        return self.apply_filter(tracks, filter).await;
+    }
+
+    /// Keeps the songs that pass the filter, in the same order.
+    async fn apply_filter(
+        &self,
+        tracks: Vec<TrackTuple>,
+        filter: Option<&SongFilter>,
+    ) -> Result<Vec<TrackTuple>, anyhow::Error> {
+        let Some(filter) = filter else {
+            return Ok(tracks);
+        };
+
+        let features = match filter.needs_features() {
+            true => {
+                let mut seen = HashSet::new();
+                let ids = tracks
+                    .iter()
+                    .filter(|t| seen.insert(t.id.id().to_string()))
+                    .map(|t| t.id.clone())
+                    .collect::<Vec<_>>();
+                self.audio_features(ids).await?
             }
+            false => HashMap::new(),
+        };
 
-            let trimmed = part.trim_matches(|c| c == ' ' || c == '\n');
-            let node_with_quotes = format!("\"{}\"", node);
+        let count = tracks.len();
+        let tracks = tracks
+            .into_iter()
+            .filter(|t| filter.matches(t, features.get(t.id.id()), self.started_at))
+            .collect::<Vec<_>>();
+        log::info!("{} of {} songs passed the filter", tracks.len(), count);
 
-            if trimmed.starts_with(node) || trimmed.starts_with(&node_with_quotes) {
-                part.chars().enumerate().for_each(|(i, c)| {
-                    if c != '[' || item.is_some() {
-                        return;
-                    }
+        return Ok(tracks);
+    }
 
-                    let mut end = ", ";
-                    if !part.contains("]") {
-                        end = "]";
-                    }
+    /// Audio features of the songs by track id. Songs that are not in the library cache yet
+    /// are fetched in batches and added to it. Songs spotify has no features for are left out.
+    async fn audio_features(
+        &self,
+        ids: Vec<TrackId<'static>>,
+    ) -> Result<HashMap<String, AudioFeatures>, anyhow::Error> {
+        let missing = {
+            let mut cache = self.library_cache.lock().unwrap();
+            // Filters on edges between playlists don't need the library, so it may not be loaded yet.
+            if cache.user_id.is_empty() {
+                *cache = library_cache::read_cache(self.snapshots_dir, self.user_id.id());
+            }
 
-                    let s = format!(
-                        "URL=\"https://open.spotify.com/playlist/{}\"{}",
-                        playlist_url, end,
-                    );
-                    item = Some((idx, i + 1, s));
-                });
+            ids.iter()
+                .filter(|id| !cache.audio_features.contains_key(id.id()))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        if !missing.is_empty() {
+            log::info!("Fetching the audio features of {} songs", missing.len());
+            let requests = missing
+                .chunks(constants::AUDIO_FEATURES_PER_REQUEST)
+                .map(|chunk| self.backend.audio_features(chunk.to_vec()))
+                .collect::<Vec<_>>();
+            let fetched = stream::iter(requests)
+                .buffered(self.config.concurrency)
+                .try_collect::<Vec<_>>()
+                .await
+                .or_error_str("failed to fetch audio features")?;
+
+            let mut cache = self.library_cache.lock().unwrap();
+            for id in &missing {
+                cache.audio_features.insert(id.id().to_string(), None);
+            }
+            for features in fetched.into_iter().flatten() {
+                cache
+                    .audio_features
+                    .insert(features.id.id().to_string(), Some(features));
+            }
+            library_cache::write_cache(self.snapshots_dir, &cache)?;
+        }
 
-                if item.is_none() {
-                    item = Some((
-                        idx,
-                        part.len(),
-                        format!(
-                            " [URL=\"https://open.spotify.com/playlist/{}\"]",
-                            playlist_url
-                        ),
-                    ));
-                }
+        let cache = self.library_cache.lock().unwrap();
+        let features = ids
+            .iter()
+            .filter_map(|id| {
+                cache
+                    .audio_features
+                    .get(id.id())
+                    .cloned()
+                    .flatten()
+                    .map(|f| (id.id().to_string(), f))
+            })
+            .collect();
+
+        return Ok(features);
+    }
+
+    /// Genres of the artists by artist id. Artists that are not in the library cache yet
+    /// are fetched in batches and added to it.
+    async fn artist_genres(
+        &self,
+        ids: Vec<ArtistId<'static>>,
+    ) -> Result<HashMap<String, Vec<String>>, anyhow::Error> {
+        let missing = {
+            let cache = self.library_cache.lock().unwrap();
+            ids.iter()
+                .filter(|id| !cache.artist_genres.contains_key(id.id()))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        if !missing.is_empty() {
+            log::info!("Fetching the genres of {} artists", missing.len());
+            // Created up front, a closure in the stream would make the future not `Send`.
+            let requests = missing
+                .chunks(constants::ARTISTS_PER_REQUEST)
+                .map(|chunk| self.backend.artists(chunk.to_vec()))
+                .collect::<Vec<_>>();
+            let fetched = stream::iter(requests)
+                .buffered(self.config.concurrency)
+                .try_collect::<Vec<_>>()
+                .await
+                .or_error_str("failed to fetch artists")?;
+
+            let mut cache = self.library_cache.lock().unwrap();
+            for artist in fetched.into_iter().flatten() {
+                cache
+                    .artist_genres
+                    .insert(artist.id.id().to_string(), artist.genres);
             }
+            library_cache::write_cache(self.snapshots_dir, &cache)?;
         }
 
-        if let Some(item) = item {
-            let (idx, i, s) = item;
-            res_parts[idx].insert_str(i, &s);
+        let cache = self.library_cache.lock().unwrap();
+        let genres = ids
+            .iter()
+            .filter_map(|id| {
+                cache
+                    .artist_genres
+                    .get(id.id())
+                    .map(|g| (id.id().to_string(), g.clone()))
+            })
+            .collect();
+
+        return Ok(genres);
+    }
+}
 
-            log::info!("Successfully added playlist url to node {:?}", node);
-        } else {
-            log::error!(
-                "Could not find node {:?} in the graph and could not add the playlist url to it",
+/// Adds the url of every newly created playlist to its node, keeping the rest of the snapshot as it is.
+pub fn create_post_apply_file(
+    content: &str,
+    node_to_playlist_url: &HashMap<String, String>,
+    nodes_with_missing_playlists: &[String],
+) -> Result<String, anyhow::Error> {
+    let mut updates = vec![];
+    for node in nodes_with_missing_playlists {
+        if node == constants::MIXIFY_TEMPORARY_ROOT_NODE_NAME {
+            continue;
+        }
+
+        match node_to_playlist_url.get(node) {
+            Some(playlist_id) => updates.push((
+                node.clone(),
+                constants::URL_ATTRIBUTE_KEY.to_string(),
+                format!("https://open.spotify.com/playlist/{}", playlist_id),
+            )),
+            None => log::error!(
+                "No playlist was created for node {:?}, so no playlist url can be added to it",
                 node
-            );
+            ),
         }
     }
 
-    let new_content = format!("{}{}{}{}{}", pfx, "{", res_parts.join(";"), "}", sfx2);
+    let new_content = dot::set_node_attributes(content, &updates)?;
+    for (node, _, _) in &updates {
+        log::info!("Successfully added playlist url to node {:?}", node);
+    }
+
     return Ok(new_content);
 }
 
@@ -667,12 +1616,295 @@ fn to_local(s: &str) -> String {
     format!("local-{}", s)
 }
 
+/// Keeps the preferred version of every song. The songs that are kept stay in their order.
+fn dedup_songs(songs: Vec<TrackTuple>, save: &SavePlaylist) -> Vec<TrackTuple> {
+    if save.dedup == Dedup::None {
+        return songs;
+    }
+
+    let mut preferred = songs.into_iter().enumerate().collect::<Vec<_>>();
+    preferred.sort_by(|(_, a), (_, b)| save.prefer.compare(a, b));
+
+    // Looked up by key, since comparing every song to every other is too slow for big playlists.
+    let mut seen = SongSet::new(save.dedup, []);
+    let mut unique = vec![];
+    for (position, song) in preferred {
+        if seen.contains(&song) {
+            continue;
+        }
+
+        seen.insert(&song);
+        unique.push((position, song));
+    }
+
+    unique.sort_by_key(|(position, _)| *position);
+    return unique.into_iter().map(|(_, song)| song).collect();
+}
+
+/// Keeps the songs picked by the node, if it has more than its limit. Without a limit, it still
+/// keeps no more than spotify allows in a playlist. The songs that are kept stay in their order.
+///
+/// If the edges into the node have weights, every source gets its share of the limit.
+fn limit_songs(
+    songs: Vec<TrackTuple>,
+    save: &SavePlaylist,
+    sources: &[Source],
+    node: &str,
+    now: DateTime<Utc>,
+) -> Vec<TrackTuple> {
+    let limit = match save.limit {
+        Some(limit) => limit,
+        None if songs.len() > constants::MAX_PLAYLIST_SONGS => {
+            log::warn!(
+                "Playlist {:?} would have {} songs, but spotify only allows {}. Picking {} of them.",
+                node,
+                songs.len(),
+                constants::MAX_PLAYLIST_SONGS,
+                constants::MAX_PLAYLIST_SONGS
+            );
+            constants::MAX_PLAYLIST_SONGS
+        }
+        None => return songs,
+    };
+
+    if songs.len() <= limit {
+        return songs;
+    }
+
+    log::info!(
+        "Picking {} of {} songs for playlist {:?}",
+        limit,
+        songs.len(),
+        node
+    );
+    let songs = songs.into_iter().enumerate().collect::<Vec<_>>();
+    let mut picked = match sources.iter().any(|s| s.weight.is_some()) {
+        true => pick_weighted(songs, limit, save, sources, now),
+        false => {
+            let mut picked = rank_songs(songs, save, now);
+            picked.truncate(limit);
+            picked
+        }
+    };
+
+    picked.sort_by_key(|(position, _)| *position);
+    return picked.into_iter().map(|(_, song)| song).collect();
+}
+
+/// Every source gets the share of the limit its weight says, rounded so they add up to the limit.
+/// If a source has fewer songs than its share, the rest is filled with the songs picked next.
+fn pick_weighted(
+    songs: Vec<(usize, TrackTuple)>,
+    limit: usize,
+    save: &SavePlaylist,
+    sources: &[Source],
+    now: DateTime<Utc>,
+) -> Vec<(usize, TrackTuple)> {
+    let total = sources.iter().filter_map(|s| s.weight).sum::<f32>();
+    let shares = sources
+        .iter()
+        .map(|s| limit as f32 * s.weight.unwrap_or(0.0) / total)
+        .collect::<Vec<_>>();
+    let mut quotas = shares.iter().map(|s| *s as usize).collect::<Vec<_>>();
+
+    // The songs left by rounding down go to the sources that lost the most by it.
+    let mut by_remainder = (0..sources.len()).collect::<Vec<_>>();
+    by_remainder.sort_by(|a, b| (shares[*b] % 1.0).total_cmp(&(shares[*a] % 1.0)));
+    let rounded = limit.saturating_sub(quotas.iter().sum());
+    for i in by_remainder.into_iter().take(rounded) {
+        quotas[i] += 1;
+    }
+
+    let mut picked = vec![];
+    let mut remaining = songs;
+    for (source, quota) in sources.iter().zip(quotas) {
+        let (pool, rest): (Vec<_>, Vec<_>) = remaining
+            .into_iter()
+            .partition(|(_, t)| source.songs.contains(&t.id));
+        let mut pool = rank_songs(pool, save, now);
+        let unpicked = pool.split_off(quota.min(pool.len()));
+        if pool.len() < quota {
+            log::info!(
+                "{:?} only has {} of its {} songs, the rest is filled with other songs",
+                source.node,
+                pool.len(),
+                quota
+            );
+        }
+
+        picked.extend(pool);
+        remaining = rest;
+        remaining.extend(unpicked);
+    }
+
+    let mut rest = rank_songs(remaining, save, now);
+    rest.truncate(limit.saturating_sub(picked.len()));
+    picked.extend(rest);
+
+    return picked;
+}
+
+/// Sorts the songs by the pick of the node, the ones to keep first.
+fn rank_songs(
+    mut picked: Vec<(usize, TrackTuple)>,
+    save: &SavePlaylist,
+    now: DateTime<Utc>,
+) -> Vec<(usize, TrackTuple)> {
+    if picked.is_empty() {
+        return picked;
+    }
+
+    match save.pick {
+        Pick::Random => picked.sort_by_key(|(_, t)| shuffle_key(&save.seed, t.id.id())),
+        Pick::Newest => picked.sort_by_key(|(_, t)| {
+            let date = t
+                .release_date
+                .as_deref()
+                .and_then(types::parse_release_date);
+            (date.is_none(), std::cmp::Reverse(date))
+        }),
+        Pick::MostPopular => {
+            picked.sort_by_key(|(_, t)| (t.popularity.is_none(), std::cmp::Reverse(t.popularity)))
+        }
+        Pick::LeastRecentlyAdded => picked.sort_by_key(|(_, t)| (t.added_at.is_none(), t.added_at)),
+    }
+
+    // The window starts further down the picked songs every period and wraps around at the end.
+    if let Some(rotation) = &save.rotate {
+        let periods = (now.timestamp() / rotation.period.num_seconds()) as usize;
+        let start = (periods % picked.len()) * (rotation.songs % picked.len()) % picked.len();
+        picked.rotate_left(start);
+    }
+
+    return picked;
+}
+
+/// Lets the sources take turns. A source with twice the weight gets twice as many turns.
+/// Songs of no source, like the ones added by hand, come last. Every source keeps its order.
+fn interleave_songs(songs: Vec<TrackTuple>, sources: &[Source]) -> Vec<TrackTuple> {
+    let mut queues = vec![std::collections::VecDeque::new(); sources.len() + 1];
+    for song in songs {
+        let i = sources
+            .iter()
+            .position(|s| s.songs.contains(&song.id))
+            .unwrap_or(sources.len());
+        queues[i].push_back(song);
+    }
+
+    let mut interleaved = vec![];
+    let mut turns = vec![0.0; sources.len()];
+    loop {
+        // The source that is furthest behind its share goes next.
+        let next = (0..sources.len())
+            .filter(|i| !queues[*i].is_empty())
+            .min_by(|a, b| {
+                let behind = |i: usize| (turns[i] + 1.0) / sources[i].weight.unwrap_or(1.0);
+                behind(*a).total_cmp(&behind(*b))
+            });
+        let Some(i) = next else {
+            break;
+        };
+
+        interleaved.push(queues[i].pop_front().unwrap());
+        turns[i] += 1.0;
+    }
+    interleaved.extend(queues.pop().unwrap());
+
+    return interleaved;
+}
+
+/// The `current` songs of a playlist in the order of `ordered`. The `fixed` songs keep their
+/// position and the other songs take the remaining positions in order.
+fn desired_order(
+    current: &[TrackId<'static>],
+    ordered: &[TrackId<'static>],
+    fixed: &[TrackId<'static>],
+) -> Vec<TrackId<'static>> {
+    let mut ordered = ordered.iter().filter(|id| !fixed.contains(id)).cloned();
+    let mut desired = vec![];
+    for id in current {
+        match fixed.contains(id) {
+            true => desired.push(id.clone()),
+            false => desired.extend(ordered.next()),
+        }
+    }
+    desired.extend(ordered);
+
+    return desired;
+}
+
+/// The cheapest way to bring the songs of a playlist from the `current` into the `desired` order.
+/// None if the playlist already is in order or the songs don't match, e.g. because of duplicates.
+///
+/// Misplaced songs are moved one run at a time, so a run of songs that is already in order
+/// moves with a single call. Replacing all songs is only considered if no other user added songs,
+/// since it would make the user the one who added them.
+fn plan_reordering(
+    current: &[TrackId<'static>],
+    desired: &[TrackId<'static>],
+    has_foreign_songs: bool,
+) -> Option<Reordering> {
+    if current == desired {
+        return None;
+    }
+
+    let mut sorted_current = current.iter().map(|id| id.id()).collect::<Vec<_>>();
+    let mut sorted_desired = desired.iter().map(|id| id.id()).collect::<Vec<_>>();
+    sorted_current.sort_unstable();
+    sorted_desired.sort_unstable();
+    if sorted_current != sorted_desired {
+        log::warn!("The songs of the playlist don't match its state, so it is not reordered");
+        return None;
+    }
+
+    let mut songs = current.to_vec();
+    let mut moves = vec![];
+    for i in 0..desired.len() {
+        if songs[i] == desired[i] {
+            continue;
+        }
+
+        let start = i + 1 + songs[i + 1..].iter().position(|id| *id == desired[i])?;
+        let mut length = 1;
+        while start + length < songs.len()
+            && i + length < desired.len()
+            && songs[start + length] == desired[i + length]
+        {
+            length += 1;
+        }
+
+        let moved = songs.drain(start..start + length).collect::<Vec<_>>();
+        songs.splice(i..i, moved);
+        moves.push((start, i, length));
+    }
+
+    let reordering = Reordering::Moves(moves);
+    if !has_foreign_songs
+        && reordering.calls(desired.len()) > Reordering::Replace.calls(desired.len())
+    {
+        return Some(Reordering::Replace);
+    }
+
+    return Some(reordering);
+}
+
+/// A stable position of the song in a shuffle seeded by `seed`. (64 bit FNV-1a)
+fn shuffle_key(seed: &str, id: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in seed.bytes().chain([0]).chain(id.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    return hash;
+}
+
 // Sometimes an artist is a combination of multiple artists.
 // Like baby gravy or Huncho Jack.
 // In these cases, we can check the owner(s) of the album.
 // In which all the artists are listed. (This also works if the albums says "Various Artists")
 // Example here: https://open.spotify.com/album/0mDeN57X1YtJHfXNdYlJbw
-fn is_main_artist(a: &Vec<ArtistId>, artist_id: &ArtistId) -> bool {
+fn is_main_artist(a: &[ArtistId], artist_id: &ArtistId) -> bool {
     a.iter().any(|a| a == artist_id)
 }
 
@@ -680,14 +1912,14 @@ fn should_add_song(
     t: &Track,
     artist_id: &ArtistId,
     q: &QuerySongsByArtist,
-    liked_songs: &Vec<Result<SavedTrack, ClientError>>,
+    liked_songs: &[SavedTrack],
 ) -> Option<TrackId<'static>> {
     if t.is_local {
         log::warn!("Skipping local track {}", t.name);
         return None;
     }
 
-    let is_main_artist = is_main_artist(&t.album_artists_ids, &artist_id);
+    let is_main_artist = is_main_artist(&t.album_artists_ids, artist_id);
     let is_in_song = t
         .artists
         .iter()
@@ -713,10 +1945,9 @@ fn should_add_song(
     if skip {
         return None;
     } else if let Some(v) = q.must_be_liked {
-        let found = liked_songs.iter().any(|tra| {
-            let track = tra.as_ref().unwrap().clone().track;
-            track.id.unwrap() == *id
-        });
+        let found = liked_songs
+            .iter()
+            .any(|saved| saved.track.id.as_ref() == Some(id));
 
         if v {
             if !found {
@@ -747,30 +1978,29 @@ fn simplified_track_to_track(t: SimplifiedTrack, album: &FullAlbum) -> Track {
 }
 
 async fn process_fetch_playlist_songs_chunks(
-    spotify: AuthCodeSpotify,
+    backend: Arc<dyn MusicBackend>,
     chunks: Vec<(String, String)>,
-) -> (Vec<PlaylistItem>, Vec<String>, usize, usize) {
+) -> (Vec<(String, Vec<PlaylistItem>)>, Vec<String>, usize, usize) {
     let mut p = Vec::new();
     let mut failed_req = Vec::new();
     let mut success = 0;
     let mut failed = 0;
 
     for (id, name) in chunks {
-        let songs = fetch_songs_from_playlist(&spotify, id, name).await;
+        let songs = fetch_songs_from_playlist(&backend, id.clone(), name).await;
 
         match songs {
             Ok(content) => {
                 success += 1;
-                p.extend(content)
+                p.push((id, content))
             }
             Err((err, id)) => {
                 log::warn!(
-                    "Failed to fetch songs from playlist {:?}. Error: {:?}. Sleeping for 10s to prevent rate limit...",
+                    "Failed to fetch songs from playlist {:?}. Error: {:?}",
                     id,
                     err
                 );
                 failed += 1;
-                tokio::time::sleep(Duration::from_secs(10)).await;
                 failed_req.push(id)
             }
         }
@@ -780,15 +2010,14 @@ async fn process_fetch_playlist_songs_chunks(
 }
 
 async fn fetch_songs_from_playlist(
-    spotify: &AuthCodeSpotify,
+    backend: &Arc<dyn MusicBackend>,
     id: String,
     name: String,
 ) -> Result<Vec<PlaylistItem>, (ClientError, String)> {
     let id = PlaylistId::from_id(id).unwrap();
 
     let single = Instant::now();
-    let items = spotify.playlist_items(id.clone(), None, None);
-    let songs = items.collect::<Vec<_>>().await;
+    let songs = backend.playlist_items(id.clone()).await;
 
     if songs.iter().any(|t| t.is_err()) {
         let err = songs.into_iter().find(|t| t.is_err()).unwrap().unwrap_err();
@@ -802,13 +2031,7 @@ async fn fetch_songs_from_playlist(
     );
 
     let total_len = songs.len();
-    let songs = songs
-        .into_iter()
-        .filter_map(|r| match r {
-            Ok(s) => Some(s),
-            _ => None,
-        })
-        .collect::<Vec<_>>();
+    let songs = songs.into_iter().filter_map(|r| r.ok()).collect::<Vec<_>>();
 
     if total_len != songs.len() {
         log::warn!(
@@ -823,9 +2046,9 @@ async fn fetch_songs_from_playlist(
 }
 
 async fn fetch_songs_from_playlists(
-    spotify: &AuthCodeSpotify,
+    backend: &Arc<dyn MusicBackend>,
     playlists: Vec<(String, String)>,
-) -> (Vec<PlaylistItem>, Vec<String>) {
+) -> (Vec<(String, Vec<PlaylistItem>)>, Vec<String>) {
     let total = Instant::now();
 
     let num_of_playlists = playlists.len();
@@ -837,8 +2060,7 @@ async fn fetch_songs_from_playlists(
     let chunks: Vec<Vec<_>> = playlists
         .clone()
         .chunks(chunk_size)
-        .into_iter()
-        .map(|chunk| chunk.clone().to_vec())
+        .map(|chunk| chunk.to_vec())
         .collect();
 
     let chunks = chunks.clone();
@@ -862,7 +2084,7 @@ async fn fetch_songs_from_playlists(
     for chunk in chunks {
         log::info!("Spawning thread to fetch {} playlists", chunk.len());
         handles.push(tokio::spawn(process_fetch_playlist_songs_chunks(
-            spotify.clone(),
+            Arc::clone(backend),
             chunk,
         )));
     }
@@ -872,17 +2094,12 @@ async fn fetch_songs_from_playlists(
     let mut num_of_success = 0;
     let mut num_of_failure = 0;
     for handle in handles {
-        let res = handle.await.unwrap();
+        let (p, f, ns, nf) = handle.await.unwrap();
+        num_of_success += ns;
+        num_of_failure += nf;
 
-        match res {
-            (p, f, ns, nf) => {
-                num_of_success += ns;
-                num_of_failure += nf;
-
-                success.extend(p);
-                failed.extend(f);
-            }
-        }
+        success.extend(p);
+        failed.extend(f);
     }
 
     let total_len = num_of_failure + num_of_success;
@@ -897,3 +2114,262 @@ async fn fetch_songs_from_playlists(
 
     return (success, failed);
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    /// A song of its own album by the same artist as all other songs, with an ISRC.
+    fn song(id: &str, name: &str) -> TrackTuple {
+        TrackTuple {
+            id: TrackId::from_id(format!("{:0>22}", id)).unwrap(),
+            name: name.to_string(),
+            artist_id: ArtistId::from_id("artistAAAAAAAAAAAAAAAA").unwrap(),
+            album_name: format!("{} album", name),
+            isrc: Some(format!("ISRC{}", id)),
+            title: types::normalize_title(name),
+            release_date: None,
+            popularity: None,
+            duration: chrono::Duration::seconds(180),
+            explicit: false,
+            added_at: None,
+        }
+    }
+
+    fn save() -> SavePlaylist {
+        SavePlaylist {
+            url: None,
+            dedup: Dedup::default(),
+            prefer: types::Prefer::default(),
+            order: Order::default(),
+            limit: None,
+            pick: Pick::default(),
+            seed: String::from("seed"),
+            rotate: None,
+            interleave: false,
+        }
+    }
+
+    fn names(songs: &[TrackTuple]) -> Vec<&str> {
+        songs.iter().map(|t| t.name.as_str()).collect()
+    }
+
+    #[test]
+    fn dedup_keeps_the_preferred_version_in_place() {
+        let mut album_version = song("1", "Song");
+        album_version.isrc = Some(String::from("SAME"));
+        let other = song("2", "Other");
+        let mut single = song("3", "Song");
+        single.isrc = Some(String::from("SAME"));
+        single.album_name = String::from("Song");
+
+        let unique = dedup_songs(vec![album_version, other, single], &save());
+        assert_eq!(
+            unique.iter().map(|t| t.id.id()).collect::<Vec<_>>(),
+            vec!["0000000000000000000002", "0000000000000000000003"]
+        );
+    }
+
+    #[test]
+    fn dedup_compares_songs_without_isrc_by_title() {
+        let mut remaster = song("1", "Song - Remastered 2011");
+        remaster.isrc = None;
+        let original = song("2", "Song");
+        let mut other_artist = song("3", "Song");
+        other_artist.artist_id = ArtistId::from_id("artistBBBBBBBBBBBBBBBB").unwrap();
+
+        let unique = dedup_songs(vec![remaster, original, other_artist], &save());
+        assert_eq!(names(&unique), vec!["Song - Remastered 2011", "Song"]);
+        assert_eq!(unique[1].id.id(), "0000000000000000000003");
+    }
+
+    #[test]
+    fn dedup_by_id_only_drops_the_same_song() {
+        let mut save = save();
+        save.dedup = Dedup::Id;
+        let songs = vec![song("1", "Song"), song("2", "Song"), song("1", "Song")];
+
+        assert_eq!(dedup_songs(songs.clone(), &save).len(), 2);
+
+        save.dedup = Dedup::None;
+        assert_eq!(dedup_songs(songs, &save).len(), 3);
+    }
+
+    /// One song per letter, e.g. `ids("abc")`.
+    fn ids(letters: &str) -> Vec<TrackId<'static>> {
+        letters
+            .chars()
+            .map(|c| TrackId::from_id(c.to_string().repeat(22)).unwrap())
+            .collect()
+    }
+
+    /// Moves the songs like spotify does.
+    fn apply_moves(songs: &mut Vec<TrackId<'static>>, moves: &[(usize, usize, usize)]) {
+        for (range_start, insert_before, range_length) in moves {
+            let moved = songs
+                .drain(*range_start..range_start + range_length)
+                .collect::<Vec<_>>();
+            let position = match insert_before > range_start {
+                true => insert_before - range_length,
+                false => *insert_before,
+            };
+            songs.splice(position..position, moved);
+        }
+    }
+
+    #[test]
+    fn desired_order_keeps_fixed_songs_in_place() {
+        let desired = desired_order(&ids("abxcdy"), &ids("dcba"), &ids("xy"));
+        assert_eq!(desired, ids("dcxbay"));
+    }
+
+    #[test]
+    fn plan_reordering_moves_runs_of_songs_at_once() {
+        let current = ids("cdeab");
+        let desired = ids("abcde");
+        let Some(Reordering::Moves(moves)) = plan_reordering(&current, &desired, true) else {
+            panic!("expected moves");
+        };
+        assert_eq!(moves, vec![(3, 0, 2)]);
+
+        let mut songs = current;
+        apply_moves(&mut songs, &moves);
+        assert_eq!(songs, desired);
+    }
+
+    #[test]
+    fn plan_reordering_reaches_the_desired_order() {
+        let current = ids("gfedcba");
+        let desired = ids("abcdefg");
+        let Some(Reordering::Moves(moves)) = plan_reordering(&current, &desired, true) else {
+            panic!("expected moves");
+        };
+
+        let mut songs = current;
+        apply_moves(&mut songs, &moves);
+        assert_eq!(songs, desired);
+    }
+
+    #[test]
+    fn plan_reordering_replaces_if_cheaper_and_allowed() {
+        let current = ids("gfedcba");
+        let desired = ids("abcdefg");
+        assert!(matches!(
+            plan_reordering(&current, &desired, false),
+            Some(Reordering::Replace)
+        ));
+    }
+
+    #[test]
+    fn plan_reordering_skips_ordered_or_different_songs() {
+        assert!(plan_reordering(&ids("abc"), &ids("abc"), false).is_none());
+        assert!(plan_reordering(&ids("abc"), &ids("cbd"), false).is_none());
+        assert!(plan_reordering(&ids("abc"), &ids("cb"), false).is_none());
+    }
+
+    fn popular_songs() -> Vec<TrackTuple> {
+        (1..=6)
+            .map(|i| {
+                let mut song = song(&i.to_string(), &format!("Song{}", i));
+                song.popularity = Some(i * 10);
+                song
+            })
+            .collect()
+    }
+
+    fn source(node: &str, weight: f32, songs: &[TrackTuple]) -> Source {
+        Source {
+            node: node.to_string(),
+            weight: Some(weight),
+            songs: songs.iter().map(|t| t.id.clone()).collect(),
+        }
+    }
+
+    #[test]
+    fn limit_keeps_the_picked_songs_in_order() {
+        let mut save = save();
+        assert_eq!(
+            limit_songs(popular_songs(), &save, &[], "Mix", Utc::now()).len(),
+            6
+        );
+
+        save.limit = Some(3);
+        save.pick = Pick::MostPopular;
+        let picked = limit_songs(popular_songs(), &save, &[], "Mix", Utc::now());
+        assert_eq!(names(&picked), vec!["Song4", "Song5", "Song6"]);
+    }
+
+    #[test]
+    fn limit_gives_every_source_its_share() {
+        let songs = popular_songs();
+        let sources = [source("A", 3.0, &songs[..3]), source("B", 1.0, &songs[3..])];
+        let mut save = save();
+        save.limit = Some(4);
+        save.pick = Pick::MostPopular;
+
+        let picked = limit_songs(songs, &save, &sources, "Mix", Utc::now());
+        assert_eq!(names(&picked), vec!["Song1", "Song2", "Song3", "Song6"]);
+    }
+
+    #[test]
+    fn limit_fills_the_share_of_a_short_source() {
+        let songs = popular_songs();
+        let sources = [source("A", 1.0, &songs[..1]), source("B", 1.0, &songs[1..])];
+        let mut save = save();
+        save.limit = Some(4);
+        save.pick = Pick::MostPopular;
+
+        let picked = limit_songs(songs, &save, &sources, "Mix", Utc::now());
+        assert_eq!(names(&picked), vec!["Song1", "Song4", "Song5", "Song6"]);
+    }
+
+    #[test]
+    fn rotation_moves_the_window_every_period() {
+        let mut save = save();
+        save.pick = Pick::MostPopular;
+        save.rotate = Some(types::Rotation {
+            songs: 2,
+            period: chrono::Duration::days(1),
+        });
+        let songs = popular_songs().into_iter().enumerate().collect::<Vec<_>>();
+        let ranked = |day: i64| {
+            let now = Utc.timestamp_opt(day * 86400 + 60, 0).unwrap();
+            let songs = rank_songs(songs.clone(), &save, now);
+            songs.into_iter().map(|(_, t)| t.name).collect::<Vec<_>>()
+        };
+
+        assert_eq!(ranked(0)[..3], ["Song6", "Song5", "Song4"]);
+        assert_eq!(ranked(1)[..3], ["Song4", "Song3", "Song2"]);
+        assert_eq!(ranked(2)[..3], ["Song2", "Song1", "Song6"]);
+        assert_eq!(ranked(3), ranked(0));
+    }
+
+    #[test]
+    fn interleave_takes_turns_by_weight() {
+        let songs = popular_songs();
+        let sources = [source("A", 2.0, &songs[..4]), source("B", 1.0, &songs[4..])];
+
+        let interleaved = interleave_songs(songs, &sources);
+        assert_eq!(
+            names(&interleaved),
+            vec!["Song1", "Song2", "Song5", "Song3", "Song4", "Song6"]
+        );
+    }
+
+    #[test]
+    fn interleave_puts_songs_of_no_source_last() {
+        let songs = popular_songs();
+        let sources = [
+            source("A", 1.0, &songs[2..3]),
+            source("B", 1.0, &songs[4..]),
+        ];
+
+        let interleaved = interleave_songs(songs, &sources);
+        assert_eq!(
+            names(&interleaved),
+            vec!["Song3", "Song5", "Song6", "Song1", "Song2", "Song4"]
+        );
+    }
+}
@@ -1,4 +1,6 @@
-use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
 #[derive(Debug, Parser)]
 #[clap(
@@ -9,6 +11,57 @@ use clap::{Args, Parser, Subcommand};
 pub struct MixifyArgs {
     #[clap(subcommand)]
     pub entity_type: EntityType,
+
+    #[command(flatten)]
+    pub config: ConfigOverrides,
+}
+
+#[derive(Debug, Parser)]
+#[clap(
+    author = "SolomonRosemite™",
+    about = "🎧 Keeps applied mixify snapshots in sync with spotify on a schedule.",
+    version = "0.0.1"
+)]
+pub struct ServerArgs {
+    /// Only sync these snapshots, if not provided, every applied snapshot with a schedule is synced
+    #[arg(long = "snapshot")]
+    pub snapshots: Vec<u32>,
+
+    /// Sync against an in-memory library loaded from a json fixture instead of spotify
+    #[arg(long)]
+    pub fixture: Option<PathBuf>,
+
+    /// Address the http api listens on
+    #[arg(long, default_value = "127.0.0.1:7878")]
+    pub listen: std::net::SocketAddr,
+}
+
+/// Options that override the ones from mixify.toml files and env vars.
+#[derive(Debug, Default, Args)]
+pub struct ConfigOverrides {
+    /// Read this config file on top of the global and the project mixify.toml
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Remove songs from playlists that are no longer part of the graph
+    #[arg(long, global = true)]
+    pub allow_removing_songs: Option<bool>,
+
+    /// Suffix appended to the names of playlists created by mixify
+    #[arg(long, global = true)]
+    pub mixstack_suffix: Option<String>,
+
+    /// Write which playlists a created playlist consists of into its description
+    #[arg(long, global = true)]
+    pub create_playlist_description: Option<bool>,
+
+    /// Never remove songs that another user added to a playlist
+    #[arg(long, global = true)]
+    pub keep_songs_added_by_others: Option<bool>,
+
+    /// How many independent playlists are worked on at the same time
+    #[arg(long, global = true)]
+    pub concurrency: Option<usize>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -28,6 +81,23 @@ pub enum EntityType {
     /// Sync a snapshot to Spotify that has been previously applied
     #[command(arg_required_else_help = true)]
     Sync(ApplyCommand),
+
+    /// Show what changed between two snapshots
+    #[command(arg_required_else_help = true)]
+    Diff(DiffCommand),
+
+    /// Restore playlists to a backup taken before an apply or sync
+    #[command(arg_required_else_help = true)]
+    Restore(RestoreCommand),
+
+    /// Log in to spotify and remember the login for later runs
+    Login,
+
+    /// Forget the remembered spotify login
+    Logout,
+
+    /// Show which spotify account mixify is logged in with
+    Whoami,
 }
 
 #[derive(Debug, Args)]
@@ -40,10 +110,77 @@ pub struct NewCommand {
 pub struct PlanCommand {
     /// The id of the snapshot, if not provided, the latest snapshot will be used
     pub id: u32,
+
+    /// How to print the plan. Everything but text is printed to stdout, so it can be piped
+    #[arg(long, value_enum, default_value_t = PlanFormat::Text)]
+    pub format: PlanFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PlanFormat {
+    /// Log every action
+    Text,
+    /// The actions as json, for scripts and the web client
+    Json,
+    /// The graph as dot, with nodes coloured by what mixify does with them
+    Dot,
+    /// The graph as mermaid flowchart, e.g. for pull requests
+    Mermaid,
 }
 
 #[derive(Debug, Args)]
 pub struct ApplyCommand {
     /// The id of the snapshot, if not provided, the latest snapshot will be used
     pub id: u32,
+
+    /// Apply against an in-memory library loaded from a json fixture instead of spotify.
+    /// The resulting library is written next to the fixture as *.result.json
+    #[arg(long)]
+    pub fixture: Option<PathBuf>,
+
+    /// Only report which songs would be added to or removed from each playlist
+    /// and which playlists would be created, without changing anything on spotify
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Continue an apply that failed halfway, reusing the playlists it already created
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Forget an apply that failed halfway and start from scratch.
+    /// The playlists it already created are kept on spotify and have to be deleted by hand
+    #[arg(long, conflicts_with = "resume")]
+    pub discard: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct DiffCommand {
+    /// The id of the snapshot to compare from
+    pub id_a: u32,
+
+    /// The id of the snapshot to compare to
+    pub id_b: u32,
+
+    /// Also resolve the songs of both snapshots and show which songs each playlist gains or loses.
+    /// This reads the library from spotify, but doesn't change anything
+    #[arg(long)]
+    pub tracks: bool,
+
+    /// Resolve the songs against an in-memory library loaded from a json fixture instead of spotify
+    #[arg(long)]
+    pub fixture: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct RestoreCommand {
+    /// The id of the snapshot the backup belongs to
+    pub id: u32,
+
+    /// The file name of the backup or the start of its timestamp (e.g. 2024-05-01), if not provided, the latest backup will be used
+    #[arg(long)]
+    pub backup: Option<String>,
+
+    /// Only restore the playlist of this node, if not provided, all playlists of the backup are restored
+    #[arg(long)]
+    pub node: Option<String>,
 }
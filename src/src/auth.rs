@@ -0,0 +1,191 @@
+use std::io::IsTerminal;
+use std::path::PathBuf;
+
+use rspotify::prelude::{BaseClient, Id, OAuthClient};
+use rspotify::{scopes, AuthCodeSpotify, Credentials, OAuth};
+
+use crate::constants;
+use crate::traits::{OptionExtension, ResultExtension};
+
+/// The token is stored next to the global mixify.toml, so every project directory shares the login.
+fn token_cache_path() -> PathBuf {
+    match dirs::config_dir() {
+        Some(dir) => dir.join("mixify").join(constants::TOKEN_CACHE_FILE_NAME),
+        None => PathBuf::from(constants::TOKEN_CACHE_FILE_NAME),
+    }
+}
+
+/// A client that reads and writes the token cache and refreshes expired tokens on its own.
+fn create_oauth_client() -> Result<AuthCodeSpotify, anyhow::Error> {
+    let creds = Credentials::from_env().or_error_str(
+        "RSPOTIFY_CLIENT_ID and RSPOTIFY_CLIENT_SECRET env vars must be set to talk to spotify",
+    )?;
+    let redirect_uri = std::env::var("RSPOTIFY_REDIRECT_URI")
+        .or_error_str("RSPOTIFY_REDIRECT_URI env var not set")?;
+
+    let oauth = OAuth {
+        redirect_uri,
+        scopes: scopes!(
+            "playlist-modify-public",
+            "playlist-modify-private",
+            "playlist-read-private",
+            "playlist-read-collaborative",
+            "user-read-currently-playing",
+            "user-read-playback-state",
+            "user-library-read",
+            "user-read-private"
+        ),
+        ..Default::default()
+    };
+    let config = rspotify::Config {
+        cache_path: token_cache_path(),
+        token_cached: true,
+        token_refreshing: true,
+        ..Default::default()
+    };
+
+    return Ok(AuthCodeSpotify::with_config(creds, oauth, config));
+}
+
+/// Creates a client from the cached token and refreshes it if it is expired.
+/// Only prompts for a login if there is no usable token and mixify runs in a terminal,
+/// so scheduled runs fail instead of waiting for input.
+pub async fn create_spotify_client() -> Result<AuthCodeSpotify, anyhow::Error> {
+    let spotify = create_oauth_client()?;
+
+    // A missing or unreadable cache is the same as not being logged in.
+    let token = spotify.read_token_cache(true).await.ok().flatten();
+    let Some(token) = token else {
+        if std::io::stdin().is_terminal() {
+            log::info!("Not logged in to spotify yet");
+            prompt_for_login(&spotify).await?;
+            return Ok(spotify);
+        }
+
+        return Err(anyhow::anyhow!(
+            "Not logged in to spotify or the login is missing permissions. Run `mixify login` first."
+        ));
+    };
+
+    let expired = token.is_expired();
+    *spotify.get_token().lock().await.unwrap() = Some(token);
+
+    if expired {
+        log::info!("Refreshing expired spotify token");
+        spotify.refresh_token().await.or_error_str(
+            "failed to refresh the spotify token. Run `mixify login` to log in again",
+        )?;
+
+        // Without a refresh token, refreshing silently drops the token.
+        if spotify.get_token().lock().await.unwrap().is_none() {
+            return Err(anyhow::anyhow!(
+                "The spotify login expired and can't be refreshed. Run `mixify login` to log in again."
+            ));
+        }
+    }
+
+    return Ok(spotify);
+}
+
+async fn prompt_for_login(spotify: &AuthCodeSpotify) -> Result<(), anyhow::Error> {
+    let url = spotify
+        .get_authorize_url(true)
+        .or_error_str("Failed to create the url for user authentication. Make sure the spotify credentials are set correctly.")?;
+    let code = spotify
+        .get_code_from_user(&url)
+        .or_error_str("Failed to read the redirect url")?;
+    spotify
+        .request_token(&code)
+        .await
+        .or_error_str("Failed to request a token from spotify")?;
+
+    let path = token_cache_path();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .or_error(format!("failed to create folder {}", dir.display()))?;
+    }
+    spotify
+        .write_token_cache()
+        .await
+        .or_error(format!("failed to write token to {}", path.display()))?;
+    restrict_permissions(&path)?;
+
+    log::info!("Saved spotify login to {}", path.display());
+    return Ok(());
+}
+
+/// The token grants access to the spotify account, so only the user may read it.
+#[cfg(unix)]
+fn restrict_permissions(path: &std::path::Path) -> Result<(), anyhow::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).or_error(format!(
+        "failed to restrict permissions of {}",
+        path.display()
+    ))?;
+    return Ok(());
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &std::path::Path) -> Result<(), anyhow::Error> {
+    return Ok(());
+}
+
+pub async fn handle_login() -> Result<(), anyhow::Error> {
+    let spotify = create_oauth_client()?;
+    prompt_for_login(&spotify).await?;
+
+    let user = spotify
+        .current_user()
+        .await
+        .or_error_str("failed to fetch user")?;
+    log::info!(
+        "Logged in as {} ({})",
+        user.display_name.unwrap_or_default(),
+        user.id.id()
+    );
+
+    return Ok(());
+}
+
+pub fn handle_logout() -> Result<(), anyhow::Error> {
+    let path = token_cache_path();
+    if !path.exists() {
+        log::info!("Not logged in");
+        return Ok(());
+    }
+
+    std::fs::remove_file(&path).or_error(format!("failed to remove {}", path.display()))?;
+    log::info!("Logged out. Removed {}", path.display());
+
+    return Ok(());
+}
+
+pub async fn handle_whoami() -> Result<(), anyhow::Error> {
+    let spotify = create_spotify_client().await?;
+    let user = spotify
+        .current_user()
+        .await
+        .or_error_str("failed to fetch user")?;
+
+    log::info!(
+        "Logged in as {} ({})",
+        user.display_name.unwrap_or_default(),
+        user.id.id()
+    );
+
+    if let Some(token) = spotify.get_token().lock().await.unwrap().as_ref() {
+        if let Some(expires_at) = token.expires_at {
+            log::info!(
+                "Token expires at {}",
+                expires_at.with_timezone(&chrono::Local)
+            );
+        }
+
+        let mut scopes = token.scopes.iter().cloned().collect::<Vec<_>>();
+        scopes.sort();
+        log::info!("Scopes: {}", scopes.join(", "));
+    }
+
+    return Ok(());
+}
@@ -0,0 +1,279 @@
+use std::future::Future;
+
+use async_trait::async_trait;
+use rspotify::model::{
+    ArtistId, AudioFeatures, FullArtist, Page, PlayableId, PlaylistId, PlaylistItem, SavedAlbum,
+    SavedTrack, SimplifiedPlaylist, TrackId, UserId,
+};
+use rspotify::prelude::{BaseClient, OAuthClient};
+use rspotify::{AuthCodeSpotify, ClientResult};
+
+use crate::constants;
+use crate::types::RetrySummary;
+
+/// Every call the apply pipeline makes against the music library.
+///
+/// The pipeline only talks to this trait, so a snapshot can be applied against spotify
+/// (`SpotifyBackend`) or against an in-memory library (`FakeBackend`) without any changes.
+#[async_trait]
+pub trait MusicBackend: Send + Sync {
+    async fn current_user_id(&self) -> ClientResult<UserId<'static>>;
+
+    /// Returns every item of the playlist. Items that failed to load are returned as errors,
+    /// so the caller can decide whether to skip them or fail.
+    async fn playlist_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+    ) -> Vec<ClientResult<PlaylistItem>> {
+        fetch_all_pages(|offset| {
+            self.playlist_items_page(playlist_id.clone(), constants::LIBRARY_PAGE_SIZE, offset)
+        })
+        .await
+    }
+
+    /// A single page of the items of the playlist, in the order of the playlist.
+    async fn playlist_items_page(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<PlaylistItem>>;
+
+    async fn current_user_playlists(&self) -> Vec<ClientResult<SimplifiedPlaylist>> {
+        fetch_all_pages(|offset| {
+            self.current_user_playlists_page(constants::LIBRARY_PAGE_SIZE, offset)
+        })
+        .await
+    }
+
+    async fn current_user_playlists_page(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<SimplifiedPlaylist>>;
+
+    /// A single page of the saved albums, most recently added first.
+    /// Used to refresh the library cache without fetching everything again.
+    async fn current_user_saved_albums_page(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<SavedAlbum>>;
+
+    /// A single page of the liked songs, most recently added first.
+    async fn current_user_saved_tracks_page(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<SavedTrack>>;
+
+    async fn user_playlist_create(
+        &self,
+        user_id: UserId<'static>,
+        name: &str,
+        description: &str,
+    ) -> ClientResult<PlaylistId<'static>>;
+
+    /// Spotify only accepts 100 items per call, the caller is responsible for chunking.
+    async fn playlist_add_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        items: Vec<PlayableId<'static>>,
+    ) -> ClientResult<()>;
+
+    async fn playlist_remove_all_occurrences_of_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        items: Vec<PlayableId<'static>>,
+    ) -> ClientResult<()>;
+
+    /// Replaces all items of the playlist. Like adding, at most 100 items per call.
+    async fn playlist_replace_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        items: Vec<PlayableId<'static>>,
+    ) -> ClientResult<()>;
+
+    /// Moves `range_length` items starting at `range_start` in front of the item at `insert_before`.
+    async fn playlist_reorder_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        range_start: usize,
+        insert_before: usize,
+        range_length: usize,
+    ) -> ClientResult<()>;
+
+    /// Spotify only accepts `ARTISTS_PER_REQUEST` ids per call, the caller is responsible for chunking.
+    async fn artists(&self, ids: Vec<ArtistId<'static>>) -> ClientResult<Vec<FullArtist>>;
+
+    /// At most `AUDIO_FEATURES_PER_REQUEST` ids per call. Songs spotify has no features for are left out.
+    async fn audio_features(&self, ids: Vec<TrackId<'static>>) -> ClientResult<Vec<AudioFeatures>>;
+
+    /// The retries since the backend was created. Only backends that retry have any.
+    fn retry_summary(&self) -> RetrySummary {
+        RetrySummary::default()
+    }
+}
+
+/// Fetches the pages one after another, starting at offset 0.
+/// Stops at the first page that failed to load and returns its error as the last item.
+async fn fetch_all_pages<T, F, Fut>(fetch_page: F) -> Vec<ClientResult<T>>
+where
+    F: Fn(u32) -> Fut,
+    Fut: Future<Output = ClientResult<Page<T>>>,
+{
+    let mut items = vec![];
+    let mut offset = 0;
+    loop {
+        let page = match fetch_page(offset).await {
+            Ok(page) => page,
+            Err(e) => {
+                items.push(Err(e));
+                return items;
+            }
+        };
+
+        let is_last_page = page.next.is_none() || page.items.is_empty();
+        offset += page.items.len() as u32;
+        items.extend(page.items.into_iter().map(Ok));
+
+        if is_last_page {
+            return items;
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SpotifyBackend {
+    spotify: AuthCodeSpotify,
+}
+
+impl SpotifyBackend {
+    pub fn new(spotify: AuthCodeSpotify) -> Self {
+        Self { spotify }
+    }
+}
+
+#[async_trait]
+impl MusicBackend for SpotifyBackend {
+    async fn current_user_id(&self) -> ClientResult<UserId<'static>> {
+        let user = self.spotify.current_user().await?;
+        Ok(user.id)
+    }
+
+    async fn playlist_items_page(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<PlaylistItem>> {
+        self.spotify
+            .playlist_items_manual(playlist_id, None, None, Some(limit), Some(offset))
+            .await
+    }
+
+    async fn current_user_playlists_page(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<SimplifiedPlaylist>> {
+        self.spotify
+            .current_user_playlists_manual(Some(limit), Some(offset))
+            .await
+    }
+
+    async fn current_user_saved_albums_page(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<SavedAlbum>> {
+        self.spotify
+            .current_user_saved_albums_manual(None, Some(limit), Some(offset))
+            .await
+    }
+
+    async fn current_user_saved_tracks_page(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<SavedTrack>> {
+        self.spotify
+            .current_user_saved_tracks_manual(None, Some(limit), Some(offset))
+            .await
+    }
+
+    async fn user_playlist_create(
+        &self,
+        user_id: UserId<'static>,
+        name: &str,
+        description: &str,
+    ) -> ClientResult<PlaylistId<'static>> {
+        let playlist = self
+            .spotify
+            .user_playlist_create(user_id, name, Some(false), Some(false), Some(description))
+            .await?;
+        log::info!("Created playlist {:?}", playlist);
+
+        Ok(playlist.id)
+    }
+
+    async fn playlist_add_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        items: Vec<PlayableId<'static>>,
+    ) -> ClientResult<()> {
+        self.spotify
+            .playlist_add_items(playlist_id, items, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn playlist_remove_all_occurrences_of_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        items: Vec<PlayableId<'static>>,
+    ) -> ClientResult<()> {
+        self.spotify
+            .playlist_remove_all_occurrences_of_items(playlist_id, items, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn playlist_replace_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        items: Vec<PlayableId<'static>>,
+    ) -> ClientResult<()> {
+        self.spotify
+            .playlist_replace_items(playlist_id, items)
+            .await
+    }
+
+    async fn playlist_reorder_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        range_start: usize,
+        insert_before: usize,
+        range_length: usize,
+    ) -> ClientResult<()> {
+        self.spotify
+            .playlist_reorder_items(
+                playlist_id,
+                Some(range_start as i32),
+                Some(insert_before as i32),
+                Some(range_length as u32),
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn artists(&self, ids: Vec<ArtistId<'static>>) -> ClientResult<Vec<FullArtist>> {
+        self.spotify.artists(ids).await
+    }
+
+    async fn audio_features(&self, ids: Vec<TrackId<'static>>) -> ClientResult<Vec<AudioFeatures>> {
+        let features = self.spotify.tracks_features(ids).await?;
+        Ok(features.unwrap_or_default())
+    }
+}
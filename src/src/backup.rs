@@ -0,0 +1,235 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::Local;
+use rspotify::model::{PlayableItem, PlaylistId};
+use rspotify::prelude::Id;
+
+use crate::backend::MusicBackend;
+use crate::constants;
+use crate::traits::{OptionExtension, ResultExtension};
+use crate::types::{Backup, BackupTrack, Ledger, PlaylistBackup};
+
+/// Writes the current tracks of the given playlists into the snapshot folder,
+/// so they can be put back with `mixify restore` if an apply goes wrong.
+///
+/// `playlists` is a list of (node, playlist id) tuples.
+/// Only the last `KEPT_BACKUPS` backups of the snapshot are kept.
+pub async fn create_backup(
+    backend: &Arc<dyn MusicBackend>,
+    snapshots_dir: &Path,
+    snapshot_id: u32,
+    playlists: &[(String, String)],
+    ledger: &Ledger,
+) -> Result<PathBuf, anyhow::Error> {
+    let mut backup = Backup {
+        snapshot_id,
+        created_at: Local::now().to_rfc3339(),
+        playlists: vec![],
+    };
+
+    for (node, playlist_id) in playlists {
+        let id = PlaylistId::from_id(playlist_id.clone())
+            .or_error(format!("failed to parse playlist id {}", playlist_id))?;
+
+        let mut tracks = vec![];
+        for item in backend.playlist_items(id).await {
+            let item = item.or_error(format!(
+                "failed to fetch songs of playlist {:?} for the backup",
+                node
+            ))?;
+
+            match item.track {
+                Some(PlayableItem::Track(track)) => match track.id {
+                    Some(id) => tracks.push(BackupTrack {
+                        id: id.id().to_string(),
+                        name: track.name,
+                    }),
+                    None => log::warn!("Skipping local track {} in backup", track.name),
+                },
+                Some(PlayableItem::Episode(e)) => {
+                    log::warn!("Skipping episode {} in backup", e.name)
+                }
+                None => {}
+            }
+        }
+
+        backup.playlists.push(PlaylistBackup {
+            node: node.clone(),
+            playlist_id: playlist_id.clone(),
+            tracks,
+            owned_tracks: ledger.owned_tracks(playlist_id).cloned(),
+        });
+    }
+
+    // With microseconds, two applies in the same second don't overwrite each other's backup.
+    let path = snapshots_dir.join(snapshot_id.to_string()).join(format!(
+        "{}{}.json",
+        constants::BACKUP_FILE_PREFIX,
+        Local::now().format("%Y-%m-%d_%H-%M-%S%.6f")
+    ));
+
+    let content = serde_json::to_string_pretty(&backup)?;
+    std::fs::write(&path, content)
+        .or_error(format!("failed to write backup file {}", path.display()))?;
+
+    let backups = list_backups(snapshots_dir, snapshot_id)?;
+    let outdated = backups.len().saturating_sub(constants::KEPT_BACKUPS);
+    for old in &backups[..outdated] {
+        std::fs::remove_file(old)
+            .or_error(format!("failed to remove old backup {}", old.display()))?;
+        log::info!("Removed old backup {}", old.display());
+    }
+
+    return Ok(path);
+}
+
+/// Lists all backups of a snapshot, oldest first.
+pub fn list_backups(snapshots_dir: &Path, snapshot_id: u32) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let directory_path = snapshots_dir.join(snapshot_id.to_string());
+    let mut backups = std::fs::read_dir(&directory_path)
+        .or_error(format!(
+            "failed to find snapshot folder: {}. Maybe it's another id?",
+            directory_path.display()
+        ))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            name.starts_with(constants::BACKUP_FILE_PREFIX) && name.ends_with(".json")
+        })
+        .collect::<Vec<_>>();
+
+    // The timestamp in the file name sorts chronologically.
+    backups.sort();
+    return Ok(backups);
+}
+
+/// Reads the backup with the file name `name` or whose timestamp starts with `name`
+/// (e.g. `2024-05-01` for the latest backup of that day), or the latest backup of the
+/// snapshot if no name is given.
+pub fn read_backup(
+    snapshots_dir: &Path,
+    snapshot_id: u32,
+    name: Option<&str>,
+) -> Result<(PathBuf, Backup), anyhow::Error> {
+    let backups = list_backups(snapshots_dir, snapshot_id)?;
+
+    let path = match name {
+        Some(name) => backups
+            .into_iter()
+            .rfind(|path| {
+                let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+                let timestamp = file_name
+                    .trim_start_matches(constants::BACKUP_FILE_PREFIX)
+                    .trim_end_matches(".json");
+                file_name == name || timestamp.starts_with(name)
+            })
+            .or_error(format!(
+                "no backup matching {:?} found for snapshot {}",
+                name, snapshot_id
+            ))?,
+        None => backups
+            .into_iter()
+            .last()
+            .or_error(format!("no backup found for snapshot {}", snapshot_id))?,
+    };
+
+    let content = std::fs::read_to_string(&path)
+        .or_error(format!("failed to read backup {}", path.display()))?;
+    let backup = serde_json::from_str::<Backup>(&content)
+        .or_error(format!("failed to parse backup {}", path.display()))?;
+
+    return Ok((path, backup));
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::fake_backend::fixtures::{local_track, playlist, track};
+    use crate::fake_backend::{FakeBackend, FakeLibrary};
+    use crate::ledger;
+
+    fn backend() -> Arc<dyn MusicBackend> {
+        let one = track("1111111111111111111111", "One");
+        let local = local_track("Demo");
+        let library = serde_json::from_value::<FakeLibrary>(json!({
+            "user_id": "tester",
+            "playlists": [playlist("pAAAAAAAAAAAAAAAAAAAAA", "A", &[&one, &local])],
+        }))
+        .unwrap();
+        return Arc::new(FakeBackend::new(library));
+    }
+
+    async fn backup(backend: &Arc<dyn MusicBackend>, snapshots_dir: &Path) -> PathBuf {
+        let ledger = ledger::read_ledger(snapshots_dir, 1).unwrap();
+        let playlists = [(String::from("A"), String::from("pAAAAAAAAAAAAAAAAAAAAA"))];
+        return create_backup(backend, snapshots_dir, 1, &playlists, &ledger)
+            .await
+            .unwrap();
+    }
+
+    fn timestamp(path: &Path) -> String {
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        return file_name
+            .trim_start_matches(constants::BACKUP_FILE_PREFIX)
+            .trim_end_matches(".json")
+            .to_string();
+    }
+
+    #[tokio::test]
+    async fn only_the_latest_backups_are_kept() {
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(snapshots_dir.path().join("1")).unwrap();
+        let backend = backend();
+
+        let mut paths = vec![];
+        for _ in 0..constants::KEPT_BACKUPS + 2 {
+            paths.push(backup(&backend, snapshots_dir.path()).await);
+        }
+
+        let backups = list_backups(snapshots_dir.path(), 1).unwrap();
+        assert_eq!(backups, paths[2..]);
+        assert!(!paths[0].exists() && !paths[1].exists());
+
+        // Local songs can't be added again, so they aren't part of the backup.
+        let (_, latest) = read_backup(snapshots_dir.path(), 1, None).unwrap();
+        let ids = latest.playlists[0]
+            .tracks
+            .iter()
+            .map(|t| t.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["1111111111111111111111"]);
+    }
+
+    #[tokio::test]
+    async fn backups_are_found_by_file_name_or_timestamp() {
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(snapshots_dir.path().join("1")).unwrap();
+        let backend = backend();
+        let first = backup(&backend, snapshots_dir.path()).await;
+        let second = backup(&backend, snapshots_dir.path()).await;
+
+        let file_name = first.file_name().unwrap().to_str().unwrap();
+        let (path, _) = read_backup(snapshots_dir.path(), 1, Some(file_name)).unwrap();
+        assert_eq!(path, first);
+
+        let (path, _) = read_backup(snapshots_dir.path(), 1, Some(&timestamp(&first))).unwrap();
+        assert_eq!(path, first);
+
+        // A start both timestamps share, like the day they were taken on, picks the latest one.
+        let (first_timestamp, second_timestamp) = (timestamp(&first), timestamp(&second));
+        let shared = first_timestamp
+            .chars()
+            .zip(second_timestamp.chars())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let (path, _) =
+            read_backup(snapshots_dir.path(), 1, Some(&first_timestamp[..shared])).unwrap();
+        assert_eq!(path, second);
+
+        // The id of the snapshot is part of the path, but not of the file name.
+        assert!(read_backup(snapshots_dir.path(), 1, Some("1")).is_err());
+    }
+}
@@ -1,3 +1,71 @@
-fn main() {
-    println!("Hello, server!");
+#![allow(clippy::needless_return)]
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::Parser;
+use mixify::api::{self, ApiState};
+use mixify::args::ServerArgs;
+use mixify::backend::{MusicBackend, SpotifyBackend};
+use mixify::fake_backend::FakeBackend;
+use mixify::jobs::JobStore;
+use mixify::rate_limit::RateLimitedBackend;
+use mixify::scheduler::{self, PlaylistLocks};
+use mixify::traits::ResultExtension;
+use mixify::{auth, config, constants};
+
+#[tokio::main]
+async fn main() {
+    mixify::load_env_file();
+    mixify::init_logger();
+
+    let args = ServerArgs::parse();
+    if let Err(e) = run_server(&args).await {
+        log::error!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run_server(args: &ServerArgs) -> Result<(), anyhow::Error> {
+    let snapshots = scheduler::find_scheduled_snapshots(&args.snapshots)?;
+    if snapshots.is_empty() {
+        log::warn!(
+            "No applied snapshot has a schedule. Set `schedule` in the mixify.toml of a snapshot, e.g. schedule = \"0 */30 * * * *\""
+        );
+    }
+
+    for snapshot in &snapshots {
+        log::info!("Scheduling snapshot {}", snapshot.id);
+    }
+
+    let backend: Arc<dyn MusicBackend> = match &args.fixture {
+        Some(fixture) => Arc::new(FakeBackend::from_fixture(fixture)?),
+        None => {
+            let spotify = Arc::new(SpotifyBackend::new(auth::create_spotify_client().await?));
+            Arc::new(RateLimitedBackend::new(spotify))
+        }
+    };
+
+    let locks = Arc::new(PlaylistLocks::default());
+    let state = ApiState {
+        backend: Arc::clone(&backend),
+        locks: Arc::clone(&locks),
+        jobs: Arc::new(JobStore::default()),
+        snapshots_dir: PathBuf::from(constants::SNAPSHOTS_DIR),
+        global_config_dir: config::global_config_dir(),
+    };
+    let api = axum::Server::try_bind(&args.listen)
+        .or_error(format!("failed to listen on {}", args.listen))?
+        .serve(api::router(state).into_make_service());
+    log::info!("Listening on http://{}", args.listen);
+
+    // The api keeps the server running, even when no snapshot is scheduled.
+    tokio::spawn(scheduler::run_scheduler(snapshots, backend, locks));
+
+    tokio::select! {
+        res = api => res.or_error_str("the http api stopped")?,
+        _ = tokio::signal::ctrl_c() => log::info!("Stopping server"),
+    }
+
+    return Ok(());
 }
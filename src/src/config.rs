@@ -0,0 +1,352 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::args::ConfigOverrides;
+use crate::constants;
+use crate::traits::ResultExtension;
+use crate::types::Config;
+
+/// One source of options. Every option is optional, so sources can be layered on top of each other.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigLayer {
+    pub allow_removing_songs: Option<bool>,
+    pub mixstack_suffix: Option<String>,
+    pub create_playlist_description: Option<bool>,
+    pub keep_songs_added_by_others: Option<bool>,
+    pub concurrency: Option<usize>,
+
+    /// Cron expression (with seconds) for when the server syncs the snapshot,
+    /// e.g. `0 */30 * * * *` for every 30 minutes.
+    pub schedule: Option<String>,
+}
+
+impl ConfigLayer {
+    /// Options set in `higher` win over the ones in `self`.
+    fn merge(self, higher: ConfigLayer) -> ConfigLayer {
+        ConfigLayer {
+            allow_removing_songs: higher.allow_removing_songs.or(self.allow_removing_songs),
+            mixstack_suffix: higher.mixstack_suffix.or(self.mixstack_suffix),
+            create_playlist_description: higher
+                .create_playlist_description
+                .or(self.create_playlist_description),
+            keep_songs_added_by_others: higher
+                .keep_songs_added_by_others
+                .or(self.keep_songs_added_by_others),
+            concurrency: higher.concurrency.or(self.concurrency),
+            schedule: higher.schedule.or(self.schedule),
+        }
+    }
+
+    fn validate(&self, source: &str) -> Result<(), anyhow::Error> {
+        if let Some(suffix) = &self.mixstack_suffix {
+            if suffix.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "{}: invalid value for `mixstack_suffix`. The suffix must not be empty, since every playlist would be treated as generated by mixify",
+                    source
+                ));
+            }
+        }
+
+        if self.concurrency == Some(0) {
+            return Err(anyhow::anyhow!(
+                "{}: invalid value for `concurrency`. At least one node has to run at a time",
+                source
+            ));
+        }
+
+        if let Some(schedule) = &self.schedule {
+            if let Err(e) = cron::Schedule::from_str(schedule) {
+                return Err(anyhow::anyhow!(
+                    "{}: invalid value for `schedule`. {:?} is not a valid cron expression: {}",
+                    source,
+                    schedule,
+                    e
+                ));
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+/// Builds the config from all sources. From lowest to highest priority:
+///
+/// 1. defaults
+/// 2. env vars (`ALLOW_REMOVING_SONGS`, `MIXSTACK_SUFFIX`, ...), also read from `.env`
+/// 3. the global `mixify.toml` in the config directory of the user (e.g. `~/.config/mixify/`)
+/// 4. `mixify.toml` in the current directory
+/// 5. `mixify.toml` in the snapshot directory, if the command works on a snapshot
+/// 6. the file given with `--config`
+/// 7. command line flags
+pub fn load_config(
+    snapshot_id: Option<u32>,
+    overrides: &ConfigOverrides,
+) -> Result<Config, anyhow::Error> {
+    return load_config_in(
+        global_config_dir().as_deref(),
+        Path::new(constants::SNAPSHOTS_DIR),
+        snapshot_id,
+        overrides,
+    );
+}
+
+/// The folder of the global `mixify.toml`, if the system has a config directory.
+pub fn global_config_dir() -> Option<PathBuf> {
+    return dirs::config_dir().map(|dir| dir.join("mixify"));
+}
+
+/// Like `load_config`, but with the global `mixify.toml` in `global_config_dir` and the snapshots
+/// in `snapshots_dir`. The `mixify.toml` of the current directory is the one next to `snapshots_dir`.
+pub fn load_config_in(
+    global_config_dir: Option<&Path>,
+    snapshots_dir: &Path,
+    snapshot_id: Option<u32>,
+    overrides: &ConfigOverrides,
+) -> Result<Config, anyhow::Error> {
+    let env = read_env_layer(|name| std::env::var(name).ok())?;
+
+    let mut files = vec![];
+    if let Some(dir) = global_config_dir {
+        files.push(dir.join(constants::CONFIG_FILE_NAME));
+    }
+    let project_dir = snapshots_dir.parent().unwrap_or(Path::new(""));
+    files.push(project_dir.join(constants::CONFIG_FILE_NAME));
+    if let Some(id) = snapshot_id {
+        files.push(
+            snapshots_dir
+                .join(id.to_string())
+                .join(constants::CONFIG_FILE_NAME),
+        );
+    }
+
+    return build_config(env, files, overrides);
+}
+
+/// Layers the existing `files` over the env vars, then the `--config` file and the command line
+/// flags over both. Later files win over earlier ones.
+fn build_config(
+    env: ConfigLayer,
+    mut files: Vec<PathBuf>,
+    overrides: &ConfigOverrides,
+) -> Result<Config, anyhow::Error> {
+    let mut layer = env;
+
+    if let Some(path) = &overrides.config {
+        if !path.exists() {
+            return Err(anyhow::anyhow!(
+                "config file {} does not exist",
+                path.display()
+            ));
+        }
+        files.push(path.clone());
+    }
+
+    for path in files {
+        if path.exists() {
+            log::debug!("Reading config file {}", path.display());
+            layer = layer.merge(read_config_file(&path)?);
+        }
+    }
+
+    let cli = ConfigLayer {
+        allow_removing_songs: overrides.allow_removing_songs,
+        mixstack_suffix: overrides.mixstack_suffix.clone(),
+        create_playlist_description: overrides.create_playlist_description,
+        keep_songs_added_by_others: overrides.keep_songs_added_by_others,
+        concurrency: overrides.concurrency,
+        schedule: None,
+    };
+    cli.validate("command line")?;
+    let layer = layer.merge(cli);
+
+    let config = Config {
+        allow_removing_songs: layer.allow_removing_songs.unwrap_or(false),
+        mixstack_suffix: layer
+            .mixstack_suffix
+            .unwrap_or_else(|| constants::DEFAULT_MIXSTACK_SUFFIX.to_string()),
+        write_description: layer.create_playlist_description.unwrap_or(true),
+        keep_songs_added_by_others: layer.keep_songs_added_by_others.unwrap_or(true),
+        schedule: layer.schedule,
+        concurrency: layer.concurrency.unwrap_or(constants::DEFAULT_CONCURRENCY),
+    };
+    log::debug!("Using config {:?}", config);
+
+    return Ok(config);
+}
+
+fn read_config_file(path: &Path) -> Result<ConfigLayer, anyhow::Error> {
+    let content = std::fs::read_to_string(path)
+        .or_error(format!("failed to read config file {}", path.display()))?;
+
+    // The toml error already points to the line and key of the problem.
+    let layer = toml::from_str::<ConfigLayer>(&content)
+        .map_err(|e| anyhow::anyhow!("invalid config file {}: {}", path.display(), e))?;
+    layer.validate(&path.display().to_string())?;
+
+    return Ok(layer);
+}
+
+/// `var` returns the value of an env var, if it is set.
+fn read_env_layer(var: impl Fn(&str) -> Option<String>) -> Result<ConfigLayer, anyhow::Error> {
+    let layer = ConfigLayer {
+        allow_removing_songs: read_env_bool(&var, "ALLOW_REMOVING_SONGS")?,
+        mixstack_suffix: var("MIXSTACK_SUFFIX"),
+        create_playlist_description: read_env_bool(&var, "CREATE_PLAYLIST_DESCRIPTION")?,
+        keep_songs_added_by_others: read_env_bool(&var, "KEEP_SONGS_ADDED_BY_OTHERS")?,
+        concurrency: None,
+        schedule: None,
+    };
+    layer.validate("env var MIXSTACK_SUFFIX")?;
+
+    return Ok(layer);
+}
+
+fn read_env_bool(
+    var: impl Fn(&str) -> Option<String>,
+    name: &str,
+) -> Result<Option<bool>, anyhow::Error> {
+    let Some(value) = var(name) else {
+        return Ok(None);
+    };
+
+    match value.as_str() {
+        "true" => Ok(Some(true)),
+        "false" => Ok(Some(false)),
+        _ => Err(anyhow::anyhow!(
+            "Invalid value for {}. Expected 'true' or 'false' but got '{}'",
+            name,
+            value
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> ConfigLayer {
+        let vars = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+        read_env_layer(|name| vars.get(name).cloned()).unwrap()
+    }
+
+    fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        return path;
+    }
+
+    #[test]
+    fn defaults_without_any_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = vec![dir.path().join(constants::CONFIG_FILE_NAME)];
+        let config = build_config(env(&[]), missing, &ConfigOverrides::default()).unwrap();
+
+        assert!(!config.allow_removing_songs);
+        assert_eq!(config.mixstack_suffix, constants::DEFAULT_MIXSTACK_SUFFIX);
+        assert!(config.write_description);
+        assert!(config.keep_songs_added_by_others);
+        assert_eq!(config.schedule, None);
+        assert_eq!(config.concurrency, constants::DEFAULT_CONCURRENCY);
+    }
+
+    #[test]
+    fn each_layer_overrides_the_ones_below() {
+        let dir = tempfile::tempdir().unwrap();
+        let global = write(
+            dir.path(),
+            "global.toml",
+            "mixstack_suffix = \"global\"\nconcurrency = 2\nschedule = \"0 0 * * * *\"\n",
+        );
+        let cwd = write(
+            dir.path(),
+            "cwd.toml",
+            "mixstack_suffix = \"cwd\"\nconcurrency = 3\n",
+        );
+        let snapshot = write(
+            dir.path(),
+            "snapshot.toml",
+            "mixstack_suffix = \"snapshot\"\ncreate_playlist_description = false\n",
+        );
+        let explicit = write(
+            dir.path(),
+            "explicit.toml",
+            "mixstack_suffix = \"explicit\"\nkeep_songs_added_by_others = false\n",
+        );
+        let env = || env(&[("MIXSTACK_SUFFIX", "env"), ("ALLOW_REMOVING_SONGS", "true")]);
+        let files = [global, cwd, snapshot];
+
+        let suffix = |files: &[PathBuf], overrides: &ConfigOverrides| {
+            build_config(env(), files.to_vec(), overrides)
+                .unwrap()
+                .mixstack_suffix
+        };
+        let none = ConfigOverrides::default();
+        assert_eq!(suffix(&[], &none), "env");
+        assert_eq!(suffix(&files[..1], &none), "global");
+        assert_eq!(suffix(&files[..2], &none), "cwd");
+        assert_eq!(suffix(&files, &none), "snapshot");
+
+        let with_config = ConfigOverrides {
+            config: Some(explicit),
+            ..Default::default()
+        };
+        assert_eq!(suffix(&files, &with_config), "explicit");
+
+        let with_flags = ConfigOverrides {
+            mixstack_suffix: Some(String::from("cli")),
+            concurrency: Some(8),
+            ..with_config
+        };
+        assert_eq!(suffix(&files, &with_flags), "cli");
+
+        // Options a layer doesn't set come from the layers below.
+        let config = build_config(env(), files.to_vec(), &with_flags).unwrap();
+        assert!(config.allow_removing_songs);
+        assert_eq!(config.schedule.as_deref(), Some("0 0 * * * *"));
+        assert!(!config.write_description);
+        assert!(!config.keep_songs_added_by_others);
+        assert_eq!(config.concurrency, 8);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let load = |content: &str| {
+            let path = write(dir.path(), constants::CONFIG_FILE_NAME, content);
+            build_config(env(&[]), vec![path], &ConfigOverrides::default())
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert!(load("concurrency = 0\n").contains("invalid value for `concurrency`"));
+        assert!(load("schedule = \"every hour\"\n").contains("invalid value for `schedule`"));
+        assert!(load("mixstack_suffix = \"\"\n").contains("invalid value for `mixstack_suffix`"));
+        assert!(load("unknown = true\n").contains("unknown field `unknown`"));
+
+        let zero = ConfigOverrides {
+            concurrency: Some(0),
+            ..Default::default()
+        };
+        let error = build_config(env(&[]), vec![], &zero).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("command line: invalid value for `concurrency`"));
+
+        let vars = HashMap::from([("ALLOW_REMOVING_SONGS", "yes")]);
+        assert!(read_env_layer(|name| vars.get(name).map(|v| v.to_string())).is_err());
+
+        let missing = ConfigOverrides {
+            config: Some(dir.path().join("missing.toml")),
+            ..Default::default()
+        };
+        assert!(build_config(env(&[]), vec![], &missing).is_err());
+    }
+}
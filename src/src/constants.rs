@@ -8,5 +8,209 @@ pub const ARTIST_ID_ATTRIBUTE_KEY: &str = "artist_id";
 pub const INCLUDE_FEATURES_ATTRIBUTE_KEY: &str = "include_features";
 pub const SOURCE_ATTRIBUTE_KEY: &str = "source";
 pub const MUST_BE_LIKED_ATTRIBUTE_KEY: &str = "must_be_liked";
+pub const GENRE_ATTRIBUTE_KEY: &str = "genre";
+pub const GENRE_MATCH_ATTRIBUTE_KEY: &str = "genre_match";
+
+pub const MIN_TEMPO_ATTRIBUTE_KEY: &str = "min_tempo";
+pub const MAX_TEMPO_ATTRIBUTE_KEY: &str = "max_tempo";
+pub const MIN_ENERGY_ATTRIBUTE_KEY: &str = "min_energy";
+pub const MAX_ENERGY_ATTRIBUTE_KEY: &str = "max_energy";
+pub const MIN_DANCEABILITY_ATTRIBUTE_KEY: &str = "min_danceability";
+pub const MAX_DANCEABILITY_ATTRIBUTE_KEY: &str = "max_danceability";
+pub const MIN_VALENCE_ATTRIBUTE_KEY: &str = "min_valence";
+pub const MAX_VALENCE_ATTRIBUTE_KEY: &str = "max_valence";
+pub const MIN_INSTRUMENTALNESS_ATTRIBUTE_KEY: &str = "min_instrumentalness";
+pub const MAX_INSTRUMENTALNESS_ATTRIBUTE_KEY: &str = "max_instrumentalness";
+pub const KEY_ATTRIBUTE_KEY: &str = "key";
+pub const MODE_ATTRIBUTE_KEY: &str = "mode";
+pub const RELEASED_AFTER_ATTRIBUTE_KEY: &str = "released_after";
+pub const RELEASED_BEFORE_ATTRIBUTE_KEY: &str = "released_before";
+pub const MIN_POPULARITY_ATTRIBUTE_KEY: &str = "min_popularity";
+pub const MAX_POPULARITY_ATTRIBUTE_KEY: &str = "max_popularity";
+pub const MIN_DURATION_ATTRIBUTE_KEY: &str = "min_duration";
+pub const MAX_DURATION_ATTRIBUTE_KEY: &str = "max_duration";
+pub const EXPLICIT_ATTRIBUTE_KEY: &str = "explicit";
+pub const ADDED_WITHIN_ATTRIBUTE_KEY: &str = "added_within";
+
+/// Attributes of a song filter. Allowed on query nodes and edges.
+pub const FILTER_ATTRIBUTE_KEYS: &[&str] = &[
+    MIN_TEMPO_ATTRIBUTE_KEY,
+    MAX_TEMPO_ATTRIBUTE_KEY,
+    MIN_ENERGY_ATTRIBUTE_KEY,
+    MAX_ENERGY_ATTRIBUTE_KEY,
+    MIN_DANCEABILITY_ATTRIBUTE_KEY,
+    MAX_DANCEABILITY_ATTRIBUTE_KEY,
+    MIN_VALENCE_ATTRIBUTE_KEY,
+    MAX_VALENCE_ATTRIBUTE_KEY,
+    MIN_INSTRUMENTALNESS_ATTRIBUTE_KEY,
+    MAX_INSTRUMENTALNESS_ATTRIBUTE_KEY,
+    KEY_ATTRIBUTE_KEY,
+    MODE_ATTRIBUTE_KEY,
+    RELEASED_AFTER_ATTRIBUTE_KEY,
+    RELEASED_BEFORE_ATTRIBUTE_KEY,
+    MIN_POPULARITY_ATTRIBUTE_KEY,
+    MAX_POPULARITY_ATTRIBUTE_KEY,
+    MIN_DURATION_ATTRIBUTE_KEY,
+    MAX_DURATION_ATTRIBUTE_KEY,
+    EXPLICIT_ATTRIBUTE_KEY,
+    ADDED_WITHIN_ATTRIBUTE_KEY,
+];
+
+pub const DEDUP_ATTRIBUTE_KEY: &str = "dedup";
+pub const PREFER_ATTRIBUTE_KEY: &str = "prefer";
+pub const ORDER_ATTRIBUTE_KEY: &str = "order";
+pub const LIMIT_ATTRIBUTE_KEY: &str = "limit";
+pub const PICK_ATTRIBUTE_KEY: &str = "pick";
+pub const SEED_ATTRIBUTE_KEY: &str = "seed";
+pub const ROTATE_ATTRIBUTE_KEY: &str = "rotate";
+pub const INTERLEAVE_ATTRIBUTE_KEY: &str = "interleave";
+pub const WEIGHT_ATTRIBUTE_KEY: &str = "weight";
+pub const OP_ATTRIBUTE_KEY: &str = "op";
+
+/// Attributes of the playlist mixify saves to. Not allowed on nodes that are only a source.
+pub const SAVE_ATTRIBUTE_KEYS: &[&str] = &[
+    DEDUP_ATTRIBUTE_KEY,
+    PREFER_ATTRIBUTE_KEY,
+    ORDER_ATTRIBUTE_KEY,
+    LIMIT_ATTRIBUTE_KEY,
+    PICK_ATTRIBUTE_KEY,
+    SEED_ATTRIBUTE_KEY,
+    ROTATE_ATTRIBUTE_KEY,
+    INTERLEAVE_ATTRIBUTE_KEY,
+];
 
 pub const TYPE_ATTRIBUTE_KEY: &str = "type";
+
+/// Attributes mixify understands on nodes. Anything else (besides styling) is reported as an error.
+pub const NODE_ATTRIBUTE_KEYS: &[&str] = &[
+    URL_ATTRIBUTE_KEY,
+    LABEL_ATTRIBUTE_KEY,
+    TYPE_ATTRIBUTE_KEY,
+    ARTIST_ID_ATTRIBUTE_KEY,
+    INCLUDE_FEATURES_ATTRIBUTE_KEY,
+    SOURCE_ATTRIBUTE_KEY,
+    MUST_BE_LIKED_ATTRIBUTE_KEY,
+    GENRE_ATTRIBUTE_KEY,
+    GENRE_MATCH_ATTRIBUTE_KEY,
+    DEDUP_ATTRIBUTE_KEY,
+    PREFER_ATTRIBUTE_KEY,
+    ORDER_ATTRIBUTE_KEY,
+    LIMIT_ATTRIBUTE_KEY,
+    PICK_ATTRIBUTE_KEY,
+    SEED_ATTRIBUTE_KEY,
+    ROTATE_ATTRIBUTE_KEY,
+    INTERLEAVE_ATTRIBUTE_KEY,
+    MIN_TEMPO_ATTRIBUTE_KEY,
+    MAX_TEMPO_ATTRIBUTE_KEY,
+    MIN_ENERGY_ATTRIBUTE_KEY,
+    MAX_ENERGY_ATTRIBUTE_KEY,
+    MIN_DANCEABILITY_ATTRIBUTE_KEY,
+    MAX_DANCEABILITY_ATTRIBUTE_KEY,
+    MIN_VALENCE_ATTRIBUTE_KEY,
+    MAX_VALENCE_ATTRIBUTE_KEY,
+    MIN_INSTRUMENTALNESS_ATTRIBUTE_KEY,
+    MAX_INSTRUMENTALNESS_ATTRIBUTE_KEY,
+    KEY_ATTRIBUTE_KEY,
+    MODE_ATTRIBUTE_KEY,
+    RELEASED_AFTER_ATTRIBUTE_KEY,
+    RELEASED_BEFORE_ATTRIBUTE_KEY,
+    MIN_POPULARITY_ATTRIBUTE_KEY,
+    MAX_POPULARITY_ATTRIBUTE_KEY,
+    MIN_DURATION_ATTRIBUTE_KEY,
+    MAX_DURATION_ATTRIBUTE_KEY,
+    EXPLICIT_ATTRIBUTE_KEY,
+    ADDED_WITHIN_ATTRIBUTE_KEY,
+];
+
+/// Attributes mixify understands on edges.
+pub const EDGE_ATTRIBUTE_KEYS: &[&str] = &[
+    SUBTRACT_ATTRIBUTE_KEY,
+    LABEL_ATTRIBUTE_KEY,
+    WEIGHT_ATTRIBUTE_KEY,
+    OP_ATTRIBUTE_KEY,
+    MIN_TEMPO_ATTRIBUTE_KEY,
+    MAX_TEMPO_ATTRIBUTE_KEY,
+    MIN_ENERGY_ATTRIBUTE_KEY,
+    MAX_ENERGY_ATTRIBUTE_KEY,
+    MIN_DANCEABILITY_ATTRIBUTE_KEY,
+    MAX_DANCEABILITY_ATTRIBUTE_KEY,
+    MIN_VALENCE_ATTRIBUTE_KEY,
+    MAX_VALENCE_ATTRIBUTE_KEY,
+    MIN_INSTRUMENTALNESS_ATTRIBUTE_KEY,
+    MAX_INSTRUMENTALNESS_ATTRIBUTE_KEY,
+    KEY_ATTRIBUTE_KEY,
+    MODE_ATTRIBUTE_KEY,
+    RELEASED_AFTER_ATTRIBUTE_KEY,
+    RELEASED_BEFORE_ATTRIBUTE_KEY,
+    MIN_POPULARITY_ATTRIBUTE_KEY,
+    MAX_POPULARITY_ATTRIBUTE_KEY,
+    MIN_DURATION_ATTRIBUTE_KEY,
+    MAX_DURATION_ATTRIBUTE_KEY,
+    EXPLICIT_ATTRIBUTE_KEY,
+    ADDED_WITHIN_ATTRIBUTE_KEY,
+];
+
+/// Graphviz attributes that only change how the graph is rendered. Allowed on nodes and edges.
+pub const STYLE_ATTRIBUTE_KEYS: &[&str] = &[
+    "color",
+    "fillcolor",
+    "fontcolor",
+    "fontname",
+    "fontsize",
+    "penwidth",
+    "shape",
+    "style",
+    "tooltip",
+];
+
+pub const CONFIG_FILE_NAME: &str = "mixify.toml";
+pub const DEFAULT_MIXSTACK_SUFFIX: &str = "™";
+pub const TOKEN_CACHE_FILE_NAME: &str = "token.json";
+
+/// Enough to overlap the requests of independent playlists without running into the rate limit.
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+pub const BACKUP_FILE_PREFIX: &str = "backup-";
+
+/// Older backups of a snapshot are deleted when a new one is written.
+pub const KEPT_BACKUPS: usize = 10;
+pub const RUNS_FILE_NAME: &str = "runs.jsonl";
+pub const LEDGER_FILE_NAME: &str = "ledger.json";
+pub const JOURNAL_FILE_NAME: &str = "journal.json";
+
+/// Parts of a song name that only name a release of the song, like `- Remastered 2011`.
+pub const TITLE_VERSION_MARKERS: &[&str] = &[
+    "remaster",
+    "single version",
+    "album version",
+    "mono version",
+    "stereo version",
+    "deluxe",
+];
+
+/// The folder all snapshots are in, relative to the working directory.
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+
+/// Relative to the snapshots folder.
+pub const LIBRARY_CACHE_PATH: &str = ".cache/library.json";
+
+/// Spotify doesn't publish its rate limit. It is counted over a rolling 30 second window.
+pub const REQUEST_BUDGET: usize = 100;
+pub const REQUEST_BUDGET_WINDOW: std::time::Duration = std::time::Duration::from_secs(30);
+pub const MAX_REQUESTS_IN_FLIGHT: usize = 10;
+
+pub const MAX_RETRIES: u32 = 5;
+pub const RETRY_BASE_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+pub const RETRY_MAX_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The most spotify returns per page of liked songs, saved albums or playlists.
+pub const LIBRARY_PAGE_SIZE: u32 = 50;
+
+/// Spotify rejects adding songs to a playlist that already has this many.
+pub const MAX_PLAYLIST_SONGS: usize = 10_000;
+
+/// The most artists spotify returns per request.
+pub const ARTISTS_PER_REQUEST: usize = 50;
+
+/// The most audio features spotify returns per request.
+pub const AUDIO_FEATURES_PER_REQUEST: usize = 100;
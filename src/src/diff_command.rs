@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use graphviz_dot_parser::types::{Attributes, GraphAST, Stmt};
+
+use crate::apply_command;
+use crate::args;
+use crate::backend::MusicBackend;
+use crate::constants;
+use crate::plan_command;
+use crate::traits::ResultExtension;
+use crate::types::{Config, TrackTuple};
+
+/// A node name or an edge like `A -> B` with its attributes.
+type Entry = (String, Attributes);
+
+pub async fn handle_diff_snapshots(
+    cmd: &args::DiffCommand,
+    backend: Option<&Arc<dyn MusicBackend>>,
+    config: Config,
+) -> Result<(), anyhow::Error> {
+    let snapshots_dir = Path::new(constants::SNAPSHOTS_DIR);
+    let content_a = plan_command::read_latest_snapshot_file(snapshots_dir, cmd.id_a)?;
+    let content_b = plan_command::read_latest_snapshot_file(snapshots_dir, cmd.id_b)?;
+
+    let gv_a = graphviz_dot_parser::parse(&content_a)
+        .or_error(format!("failed to parse graph of snapshot {}", cmd.id_a))?;
+    let gv_b = graphviz_dot_parser::parse(&content_b)
+        .or_error(format!("failed to parse graph of snapshot {}", cmd.id_b))?;
+
+    let lines = diff_graphs(&gv_a, &gv_b);
+    match lines.is_empty() {
+        true => log::info!(
+            "The graphs of snapshot {} and {} are the same",
+            cmd.id_a,
+            cmd.id_b
+        ),
+        false => log::info!(
+            "Changes from snapshot {} to {}:\n{}",
+            cmd.id_a,
+            cmd.id_b,
+            lines.join("\n")
+        ),
+    }
+
+    let Some(backend) = backend else {
+        return Ok(());
+    };
+
+    log::info!("Resolving the songs of both snapshots");
+    let snapshots_dir = Path::new(constants::SNAPSHOTS_DIR);
+    let report_a = apply_command::execute_snapshot(
+        snapshots_dir,
+        cmd.id_a,
+        &content_a,
+        backend,
+        &config,
+        true,
+        None,
+    )
+    .await?
+    .dry_run_report
+    .unwrap();
+    let report_b = apply_command::execute_snapshot(
+        snapshots_dir,
+        cmd.id_b,
+        &content_b,
+        backend,
+        &config,
+        true,
+        None,
+    )
+    .await?
+    .dry_run_report
+    .unwrap();
+
+    let tracks_a = report_a
+        .changes
+        .into_iter()
+        .map(|c| (c.node, c.tracks))
+        .collect::<Vec<_>>();
+    let tracks_b = report_b
+        .changes
+        .into_iter()
+        .map(|c| (c.node, c.tracks))
+        .collect::<Vec<_>>();
+
+    let lines = diff_tracks(&tracks_a, &tracks_b);
+    match lines.is_empty() {
+        true => log::info!("Both snapshots result in the same songs"),
+        false => log::info!("Song changes:\n{}", lines.join("\n")),
+    }
+
+    return Ok(());
+}
+
+/// Nodes and edges of the graph in the order they appear. Duplicate statements are merged.
+fn collect_statements(gv: &GraphAST) -> (Vec<Entry>, Vec<Entry>) {
+    let mut nodes: Vec<Entry> = vec![];
+    let mut edges: Vec<Entry> = vec![];
+
+    let merge = |list: &mut Vec<Entry>, key: String, attrs: &Attributes| match list
+        .iter_mut()
+        .find(|(k, _)| *k == key)
+    {
+        Some((_, existing)) => {
+            for (k, v) in attrs {
+                match existing.iter_mut().find(|(ek, _)| ek == k) {
+                    Some((_, ev)) => *ev = v.clone(),
+                    None => existing.push((k.clone(), v.clone())),
+                }
+            }
+        }
+        None => list.push((key, attrs.clone())),
+    };
+
+    for stmt in &gv.stmt {
+        match stmt {
+            Stmt::Node(node, attrs) => merge(&mut nodes, node.clone(), attrs),
+            Stmt::Edge(from, to, attrs) => merge(&mut edges, format!("{} -> {}", from, to), attrs),
+            _ => {}
+        }
+    }
+
+    return (nodes, edges);
+}
+
+fn format_attributes(attrs: &Attributes) -> String {
+    if attrs.is_empty() {
+        return String::new();
+    }
+
+    let formatted = attrs
+        .iter()
+        .map(|(k, v)| format!("{}={:?}", k, v))
+        .collect::<Vec<_>>();
+    return format!(" [{}]", formatted.join(", "));
+}
+
+fn diff_attributes(a: &Attributes, b: &Attributes) -> Vec<String> {
+    let mut changes = vec![];
+    for (key, value_a) in a {
+        match b.iter().find(|(k, _)| k == key) {
+            Some((_, value_b)) if value_a != value_b => {
+                changes.push(format!("{} {:?} -> {:?}", key, value_a, value_b))
+            }
+            Some(_) => {}
+            None => changes.push(format!("{} {:?} -> (removed)", key, value_a)),
+        }
+    }
+
+    for (key, value_b) in b {
+        if !a.iter().any(|(k, _)| k == key) {
+            changes.push(format!("{} (added) -> {:?}", key, value_b));
+        }
+    }
+
+    return changes;
+}
+
+/// Describes the changes from graph `a` to `b` line by line.
+/// `+` marks added, `-` removed and `~` changed nodes and edges.
+fn diff_graphs(a: &GraphAST, b: &GraphAST) -> Vec<String> {
+    let (nodes_a, edges_a) = collect_statements(a);
+    let (nodes_b, edges_b) = collect_statements(b);
+
+    let mut lines = vec![];
+    for (kind, list_a, list_b) in [("node", &nodes_a, &nodes_b), ("edge", &edges_a, &edges_b)] {
+        for (key, attrs_a) in list_a {
+            match list_b.iter().find(|(k, _)| k == key) {
+                Some((_, attrs_b)) => {
+                    let changes = diff_attributes(attrs_a, attrs_b);
+                    if !changes.is_empty() {
+                        lines.push(format!("~ {} {}: {}", kind, key, changes.join(", ")));
+                    }
+                }
+                None => lines.push(format!("- {} {}{}", kind, key, format_attributes(attrs_a))),
+            }
+        }
+
+        for (key, attrs_b) in list_b {
+            if !list_a.iter().any(|(k, _)| k == key) {
+                lines.push(format!("+ {} {}{}", kind, key, format_attributes(attrs_b)));
+            }
+        }
+    }
+
+    return lines;
+}
+
+/// Compares the resulting songs of each playlist node of both snapshots.
+fn diff_tracks(a: &[(String, Vec<TrackTuple>)], b: &[(String, Vec<TrackTuple>)]) -> Vec<String> {
+    let empty = vec![];
+    let describe = |tracks: Option<&Vec<TrackTuple>>| match tracks {
+        Some(t) => format!("{} songs", t.len()),
+        None => String::from("not a mix"),
+    };
+    let tracks_a = a.iter().map(|(n, t)| (n, t)).collect::<HashMap<_, _>>();
+
+    let mut lines = vec![];
+    let mut nodes = a.iter().map(|(n, _)| n).collect::<Vec<_>>();
+    nodes.extend(
+        b.iter()
+            .map(|(n, _)| n)
+            .filter(|n| !tracks_a.contains_key(n)),
+    );
+
+    for node in nodes {
+        let before_tracks = tracks_a.get(node).copied();
+        let after_tracks = b.iter().find(|(n, _)| n == node).map(|(_, t)| t);
+        let before = before_tracks.unwrap_or(&empty);
+        let after = after_tracks.unwrap_or(&empty);
+
+        let added = after
+            .iter()
+            .filter(|t| !before.iter().any(|o| o.id == t.id))
+            .collect::<Vec<_>>();
+        let removed = before
+            .iter()
+            .filter(|t| !after.iter().any(|o| o.id == t.id))
+            .collect::<Vec<_>>();
+
+        if added.is_empty() && removed.is_empty() {
+            continue;
+        }
+
+        lines.push(format!(
+            "{}: {} -> {}",
+            node,
+            describe(before_tracks),
+            describe(after_tracks)
+        ));
+        lines.extend(
+            added
+                .iter()
+                .map(|t| format!("  + {} ({})", t.name, t.album_name)),
+        );
+        lines.extend(
+            removed
+                .iter()
+                .map(|t| format!("  - {} ({})", t.name, t.album_name)),
+        );
+    }
+
+    return lines;
+}
+
+#[cfg(test)]
+mod tests {
+    use rspotify::model::{ArtistId, TrackId};
+
+    use super::*;
+    use crate::types::normalize_title;
+
+    fn diff(a: &str, b: &str) -> Vec<String> {
+        let a = graphviz_dot_parser::parse(a).unwrap();
+        let b = graphviz_dot_parser::parse(b).unwrap();
+        diff_graphs(&a, &b)
+    }
+
+    fn song(id: &str, name: &str) -> TrackTuple {
+        TrackTuple {
+            id: TrackId::from_id(format!("{:0>22}", id)).unwrap(),
+            name: name.to_string(),
+            artist_id: ArtistId::from_id("artistAAAAAAAAAAAAAAAA").unwrap(),
+            album_name: String::from("Album"),
+            isrc: None,
+            title: normalize_title(name),
+            release_date: None,
+            popularity: None,
+            duration: chrono::Duration::seconds(180),
+            explicit: false,
+            added_at: None,
+        }
+    }
+
+    #[test]
+    fn collect_statements_merges_duplicates_in_order() {
+        let gv = graphviz_dot_parser::parse(
+            r#"digraph G {
+                B [label="b"];
+                A;
+                B [URL="x", label="c"];
+                A -> B;
+                A -> B [subtract="true"];
+            }"#,
+        )
+        .unwrap();
+
+        let (nodes, edges) = collect_statements(&gv);
+        assert_eq!(
+            nodes,
+            vec![
+                (
+                    String::from("B"),
+                    vec![
+                        (String::from("label"), String::from("c")),
+                        (String::from("URL"), String::from("x")),
+                    ]
+                ),
+                (String::from("A"), vec![]),
+            ]
+        );
+        assert_eq!(
+            edges,
+            vec![(
+                String::from("A -> B"),
+                vec![(String::from("subtract"), String::from("true"))]
+            )]
+        );
+    }
+
+    #[test]
+    fn same_graphs_have_no_changes() {
+        let content = r#"digraph G { A [URL="x"]; B; A -> B; }"#;
+        assert!(diff(content, content).is_empty());
+    }
+
+    #[test]
+    fn added_and_removed_nodes_and_edges() {
+        let lines = diff(
+            r#"digraph G { A; B; A -> B; }"#,
+            r#"digraph G { A; C [label="c"]; A -> C; }"#,
+        );
+        assert_eq!(
+            lines,
+            vec![
+                "- node B",
+                "+ node C [label=\"c\"]",
+                "- edge A -> B",
+                "+ edge A -> C",
+            ]
+        );
+    }
+
+    #[test]
+    fn changed_attributes() {
+        let lines = diff(
+            r#"digraph G { A [type="artist"]; B [label="b"]; A -> B; }"#,
+            r#"digraph G { A [type="artist", artist_id="abc"]; B; A -> B [subtract="true"]; }"#,
+        );
+        assert_eq!(
+            lines,
+            vec![
+                "~ node A: artist_id (added) -> \"abc\"",
+                "~ node B: label \"b\" -> (removed)",
+                "~ edge A -> B: subtract (added) -> \"true\"",
+            ]
+        );
+
+        let lines = diff(
+            r#"digraph G { A -> B [subtract="true"]; }"#,
+            r#"digraph G { A -> B [subtract="false"]; }"#,
+        );
+        assert_eq!(lines, vec!["~ edge A -> B: subtract \"true\" -> \"false\""]);
+    }
+
+    #[test]
+    fn diff_tracks_lists_added_and_removed_songs() {
+        let before = vec![
+            (
+                String::from("Mix"),
+                vec![song("1", "One"), song("2", "Two")],
+            ),
+            (String::from("Old"), vec![song("1", "One")]),
+            (String::from("Same"), vec![song("3", "Three")]),
+        ];
+        let after = vec![
+            (
+                String::from("Mix"),
+                vec![song("2", "Two"), song("3", "Three")],
+            ),
+            (String::from("Same"), vec![song("3", "Three")]),
+            (String::from("New"), vec![song("4", "Four")]),
+        ];
+
+        assert_eq!(
+            diff_tracks(&before, &after),
+            vec![
+                "Mix: 2 songs -> 2 songs",
+                "  + Three (Album)",
+                "  - One (Album)",
+                "Old: 1 songs -> not a mix",
+                "  - One (Album)",
+                "New: not a mix -> 1 songs",
+                "  + Four (Album)",
+            ]
+        );
+    }
+}
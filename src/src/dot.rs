@@ -0,0 +1,713 @@
+//! A lossless tokenizer and writer for DOT files.
+//!
+//! `graphviz_dot_parser` only gives us the parsed statements without any positions, comments or
+//! formatting. The tokens here keep all of that, so we can point at lines in diagnostics
+//! and change attributes of a snapshot without touching the rest of the file.
+//!
+//! Snapshots are edited by hand, so they are written by changing tokens instead of printing
+//! the `GraphAST` again. A writer driven by the AST would drop the comments and formatting
+//! of the user. In exchange, every written file is parsed again and compared against the
+//! expected AST, so a mistake of the token writer is an error instead of a broken snapshot.
+
+use graphviz_dot_parser::types::{GraphAST, Stmt};
+
+use crate::traits::{OptionExtension, ResultExtension};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Whitespace,
+    Comment,
+    Id,
+    QuotedId,
+    EdgeOp,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    Semicolon,
+    Comma,
+    Equals,
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+
+    /// 1-based line number of the first character of the token.
+    pub line: usize,
+}
+
+impl<'a> Token<'a> {
+    pub fn is_trivia(&self) -> bool {
+        matches!(self.kind, TokenKind::Whitespace | TokenKind::Comment)
+    }
+
+    /// The value of an id token. Quoted ids are returned without quotes and with escaped quotes resolved.
+    pub fn value(&self) -> String {
+        match self.kind {
+            TokenKind::QuotedId => {
+                // An unterminated quote at the end of the content is a token without closing quote.
+                let inner = self.text.strip_prefix('"').unwrap_or(self.text);
+                let inner = inner.strip_suffix('"').unwrap_or(inner);
+                inner.replace("\\\"", "\"")
+            }
+            _ => self.text.to_string(),
+        }
+    }
+}
+
+/// A node or edge statement in the content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    pub from: String,
+
+    /// Only set for edge statements.
+    pub to: Option<String>,
+    pub line: usize,
+
+    /// Index of the last id token of the statement (the node or the target of the edge).
+    pub last_id_token: usize,
+
+    /// Indices of the `[` and `]` tokens of each attribute list.
+    pub attribute_lists: Vec<(usize, usize)>,
+}
+
+fn is_id_start(c: char) -> bool {
+    c == '_' || c.is_ascii_alphabetic() || ('\u{80}'..='\u{ff}').contains(&c)
+}
+
+fn is_id_char(c: char) -> bool {
+    is_id_start(c) || c.is_ascii_digit()
+}
+
+/// Splits the content into tokens. Concatenating the text of all tokens yields the content again.
+pub fn tokenize(content: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    let chars = content.char_indices().collect::<Vec<_>>();
+    let end_of = |i: usize| chars.get(i).map(|(b, _)| *b).unwrap_or(content.len());
+
+    let mut i = 0;
+    let mut line = 1;
+    let mut at_line_start = true;
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        let next = chars.get(i + 1).map(|(_, c)| *c);
+
+        let (kind, len) = if c.is_whitespace() {
+            let len = chars[i..]
+                .iter()
+                .take_while(|(_, c)| c.is_whitespace())
+                .count();
+            (TokenKind::Whitespace, len)
+        } else if c == '/' && next == Some('/') || c == '#' && at_line_start {
+            let len = chars[i..].iter().take_while(|(_, c)| *c != '\n').count();
+            (TokenKind::Comment, len)
+        } else if c == '/' && next == Some('*') {
+            let rest = &content[start + 2..];
+            let len = match rest.find("*/") {
+                Some(p) => rest[..p].chars().count() + 4,
+                None => chars.len() - i,
+            };
+            (TokenKind::Comment, len)
+        } else if c == '"' {
+            let mut len = 1;
+            let mut escaped = false;
+            while i + len < chars.len() {
+                let ch = chars[i + len].1;
+                len += 1;
+                if ch == '"' && !escaped {
+                    break;
+                }
+                escaped = ch == '\\' && !escaped;
+            }
+            (TokenKind::QuotedId, len)
+        } else if c == '-' && (next == Some('>') || next == Some('-')) {
+            (TokenKind::EdgeOp, 2)
+        } else if is_id_start(c) {
+            let len = chars[i..]
+                .iter()
+                .take_while(|(_, c)| is_id_char(*c))
+                .count();
+            (TokenKind::Id, len)
+        } else if c.is_ascii_digit() || c == '.' || c == '-' {
+            let len = 1 + chars[i + 1..]
+                .iter()
+                .take_while(|(_, c)| c.is_ascii_digit() || *c == '.')
+                .count();
+            (TokenKind::Id, len)
+        } else {
+            let kind = match c {
+                '{' => TokenKind::LBrace,
+                '}' => TokenKind::RBrace,
+                '[' => TokenKind::LBracket,
+                ']' => TokenKind::RBracket,
+                ';' => TokenKind::Semicolon,
+                ',' => TokenKind::Comma,
+                '=' => TokenKind::Equals,
+                _ => TokenKind::Other,
+            };
+            (kind, 1)
+        };
+
+        let text = &content[start..end_of(i + len)];
+        tokens.push(Token { kind, text, line });
+
+        let newlines = text.matches('\n').count();
+        line += newlines;
+        at_line_start = newlines > 0 || (at_line_start && kind == TokenKind::Whitespace);
+        i += len;
+    }
+
+    return tokens;
+}
+
+/// Finds the node and edge statements of the graph body in the order they appear.
+/// This is the same order as the statements of the parsed `GraphAST`.
+/// Token indices of the statements refer to `tokens`, which must include trivia.
+pub fn find_statements(tokens: &[Token]) -> Vec<Statement> {
+    let significant = (0..tokens.len())
+        .filter(|i| !tokens[*i].is_trivia())
+        .collect::<Vec<_>>();
+    let kind_at = |i: usize| significant.get(i).map(|t| tokens[*t].kind);
+
+    let mut statements = vec![];
+    let Some(body_start) = significant
+        .iter()
+        .position(|t| tokens[*t].kind == TokenKind::LBrace)
+    else {
+        return statements;
+    };
+
+    let mut depth = 0;
+    let mut i = body_start + 1;
+    while i < significant.len() {
+        let token = &tokens[significant[i]];
+        match token.kind {
+            TokenKind::Id if token.text == "subgraph" => {
+                while i < significant.len() && kind_at(i) != Some(TokenKind::LBrace) {
+                    i += 1;
+                }
+                depth += 1;
+                i += 1;
+            }
+            TokenKind::Id | TokenKind::QuotedId if kind_at(i + 1) == Some(TokenKind::Equals) => {
+                // Graph attribute assignment like `rankdir=LR`.
+                i += 3;
+            }
+            TokenKind::Id | TokenKind::QuotedId => {
+                // `graph`, `node` and `edge` attribute statements are kept as well,
+                // since the parser reads them as nodes of that name.
+                let is_edge = kind_at(i + 1) == Some(TokenKind::EdgeOp);
+
+                let last_id = if is_edge { i + 2 } else { i };
+                let to = match is_edge {
+                    true => significant.get(last_id).map(|t| tokens[*t].value()),
+                    false => None,
+                };
+
+                i = last_id + 1;
+                let mut attribute_lists = vec![];
+                while kind_at(i) == Some(TokenKind::LBracket) {
+                    let open = i;
+                    while i < significant.len() && kind_at(i) != Some(TokenKind::RBracket) {
+                        i += 1;
+                    }
+                    if i < significant.len() {
+                        attribute_lists.push((significant[open], significant[i]));
+                    }
+                    i += 1;
+                }
+
+                if last_id >= significant.len() {
+                    continue;
+                }
+
+                statements.push(Statement {
+                    from: token.value(),
+                    to,
+                    line: token.line,
+                    last_id_token: significant[last_id],
+                    attribute_lists,
+                });
+            }
+            TokenKind::RBrace if depth == 0 => break,
+            TokenKind::RBrace => {
+                depth -= 1;
+                i += 1;
+            }
+            _ => i += 1,
+        }
+    }
+
+    return statements;
+}
+
+/// Shorthand for `find_statements` when only the positions are needed.
+pub fn locate_statements(content: &str) -> Vec<Statement> {
+    find_statements(&tokenize(content))
+}
+
+pub fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\\\""))
+}
+
+/// Sets the attribute `key` of the node statements to the given value.
+/// `updates` is a list of (node, key, value) tuples. Existing values are replaced in place,
+/// new attributes are put at the front of the first attribute list of the node.
+/// Everything else, including comments and formatting, is kept as it is.
+///
+/// Since the change has to survive a roundtrip through the parser, the result is parsed
+/// again and compared against the expected graph. An error is returned if they differ.
+pub fn set_node_attributes(
+    content: &str,
+    updates: &[(String, String, String)],
+) -> Result<String, anyhow::Error> {
+    let tokens = tokenize(content);
+    let statements = find_statements(&tokens);
+
+    // Text that replaces a token or is inserted after it, indexed by token.
+    let mut replacements: Vec<Option<String>> = vec![None; tokens.len()];
+    let mut insertions: Vec<String> = vec![String::new(); tokens.len()];
+
+    for (node, key, value) in updates {
+        let statement = statements
+            .iter()
+            .find(|s| s.to.is_none() && s.from == *node)
+            .or_error(format!(
+                "Could not find node {:?} in the graph to set its {} attribute",
+                node, key
+            ))?;
+
+        let existing_value = statement.attribute_lists.iter().find_map(|(open, close)| {
+            let significant = (*open + 1..*close)
+                .filter(|i| !tokens[*i].is_trivia())
+                .collect::<Vec<_>>();
+
+            significant.windows(3).find_map(|w| {
+                let is_key = matches!(tokens[w[0]].kind, TokenKind::Id | TokenKind::QuotedId)
+                    && tokens[w[0]].value() == *key
+                    && tokens[w[1]].kind == TokenKind::Equals;
+                is_key.then_some(w[2])
+            })
+        });
+
+        let attribute = format!("{}={}", key, quote(value));
+        match (existing_value, statement.attribute_lists.first()) {
+            (Some(value_token), _) => replacements[value_token] = Some(quote(value)),
+            (None, Some((open, close))) => {
+                let is_empty = (*open + 1..*close).all(|i| tokens[i].is_trivia());
+                let separator = if is_empty { "" } else { ", " };
+                insertions[*open].push_str(&format!("{}{}", attribute, separator));
+            }
+            (None, None) => {
+                insertions[statement.last_id_token].push_str(&format!(" [{}]", attribute));
+            }
+        }
+    }
+
+    let mut new_content = String::with_capacity(content.len());
+    for (i, token) in tokens.iter().enumerate() {
+        match &replacements[i] {
+            Some(text) => new_content.push_str(text),
+            None => new_content.push_str(token.text),
+        }
+        new_content.push_str(&insertions[i]);
+    }
+
+    verify_roundtrip(content, &new_content, updates)?;
+    return Ok(new_content);
+}
+
+fn verify_roundtrip(
+    content: &str,
+    new_content: &str,
+    updates: &[(String, String, String)],
+) -> Result<(), anyhow::Error> {
+    let mut expected =
+        graphviz_dot_parser::parse(content).or_error_str("failed to parse graph before writing")?;
+    let written = graphviz_dot_parser::parse(new_content)
+        .or_error_str("failed to parse the written graph")?;
+
+    for (node, key, value) in updates {
+        for stmt in expected.stmt.iter_mut() {
+            if let Stmt::Node(name, attrs) = stmt {
+                if name != node {
+                    continue;
+                }
+
+                match attrs.iter_mut().find(|(k, _)| k == key) {
+                    Some((_, v)) => *v = value.clone(),
+                    None => attrs.insert(0, (key.clone(), value.clone())),
+                }
+            }
+        }
+    }
+
+    if !is_same_graph(&expected, &written) {
+        return Err(anyhow::anyhow!(
+            "The written graph does not match the original graph. This should never happen. Written graph:\n{}",
+            new_content
+        ));
+    }
+
+    return Ok(());
+}
+
+fn is_same_graph(a: &GraphAST, b: &GraphAST) -> bool {
+    a.is_strict == b.is_strict && a.is_directed == b.is_directed && a.id == b.id && a.stmt == b.stmt
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+
+    use super::*;
+
+    fn set(content: &str, node: &str, key: &str, value: &str) -> String {
+        let updates = vec![(node.to_string(), key.to_string(), value.to_string())];
+        set_node_attributes(content, &updates).unwrap()
+    }
+
+    fn node_attributes(content: &str, node: &str) -> Vec<(String, String)> {
+        let graph = graphviz_dot_parser::parse(content).unwrap();
+        graph
+            .stmt
+            .into_iter()
+            .find_map(|stmt| match stmt {
+                Stmt::Node(name, attrs) if name == node => Some(attrs),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn tokens_keep_the_content() {
+        let content =
+            "digraph G {\n  # note\n  A [label=\"a \\\"b\\\" [c]\"]; // x\n  /* y */ A -> B;\n}\n";
+        let joined = tokenize(content).iter().map(|t| t.text).collect::<String>();
+        assert_eq!(joined, content);
+    }
+
+    #[test]
+    fn value_of_a_lone_quote_is_empty() {
+        let tokens = tokenize("\"");
+        assert_eq!(tokens[0].kind, TokenKind::QuotedId);
+        assert_eq!(tokens[0].value(), "");
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let content = "// Name: mix\ndigraph G {\n    // the mix\n    A; // no attributes yet\n}\n";
+        let written = set(content, "A", "URL", "https://x");
+        assert_eq!(
+            written,
+            "// Name: mix\ndigraph G {\n    // the mix\n    A [URL=\"https://x\"]; // no attributes yet\n}\n"
+        );
+        assert_eq!(
+            node_attributes(&written, "A"),
+            vec![("URL".to_string(), "https://x".to_string())]
+        );
+    }
+
+    #[test]
+    fn locates_statements_after_block_and_hash_comments() {
+        let content = "# generated\ndigraph G {\n    /* A;\n    B; */\n    C;\n}\n";
+        let statements = locate_statements(content);
+        assert_eq!(statements.len(), 1);
+        assert_eq!((statements[0].from.as_str(), statements[0].line), ("C", 5));
+
+        // The parser doesn't support these comments, so nothing is written.
+        let updates = vec![("C".to_string(), "URL".to_string(), "https://x".to_string())];
+        assert!(set_node_attributes(content, &updates).is_err());
+    }
+
+    #[test]
+    fn finds_quoted_ids_with_spaces_and_escaped_quotes() {
+        let content = "digraph G {\n    \"My \\\"best\\\" mix\";\n}\n";
+        let statements = locate_statements(content);
+        assert_eq!(statements[0].from, "My \"best\" mix");
+        assert_eq!(statements[0].line, 2);
+
+        let written = set(content, "My \"best\" mix", "URL", "https://x");
+        assert_eq!(
+            written,
+            "digraph G {\n    \"My \\\"best\\\" mix\" [URL=\"https://x\"];\n}\n"
+        );
+    }
+
+    #[test]
+    fn ignores_brackets_and_braces_in_quoted_labels() {
+        let content = "digraph G {\n    A [label=\"a ] b [ c } d {\"];\n    B;\n    A -> B;\n}\n";
+        let written = set(content, "A", "URL", "https://x");
+        assert_eq!(
+            node_attributes(&written, "A"),
+            vec![
+                ("URL".to_string(), "https://x".to_string()),
+                ("label".to_string(), "a ] b [ c } d {".to_string()),
+            ]
+        );
+        assert_eq!(locate_statements(&written).len(), 3);
+    }
+
+    #[test]
+    fn finds_statements_in_subgraphs() {
+        let content =
+            "digraph G {\n    subgraph cluster_0 {\n        A;\n    }\n    B;\n    A -> B;\n}\n";
+        let statements = locate_statements(content);
+        let names = statements
+            .iter()
+            .map(|s| (s.from.as_str(), s.to.as_deref(), s.line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![("A", None, 3), ("B", None, 5), ("A", Some("B"), 6)]
+        );
+
+        // The parser doesn't support subgraphs, so nothing is written.
+        let updates = vec![("A".to_string(), "URL".to_string(), "https://x".to_string())];
+        assert!(set_node_attributes(content, &updates).is_err());
+    }
+
+    #[test]
+    fn skips_graph_attribute_assignments() {
+        let content = "digraph G {\n    rankdir=LR;\n    A;\n}\n";
+        let statements = locate_statements(content);
+        assert_eq!(statements.len(), 1);
+        assert_eq!((statements[0].from.as_str(), statements[0].line), ("A", 3));
+
+        // The parser doesn't support assignments, so nothing is written.
+        let updates = vec![("A".to_string(), "URL".to_string(), "https://x".to_string())];
+        assert!(set_node_attributes(content, &updates).is_err());
+    }
+
+    #[test]
+    fn keeps_attribute_statements() {
+        let content = "digraph G {\n    graph [rankdir=LR];\n    node [shape=box];\n    A;\n}\n";
+        let graph = graphviz_dot_parser::parse(content).unwrap();
+        let statements = locate_statements(content);
+        let names = statements
+            .iter()
+            .map(|s| s.from.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["graph", "node", "A"]);
+        assert_eq!(statements.len(), graph.stmt.len());
+
+        let written = set(content, "A", "URL", "https://x");
+        assert_eq!(
+            written,
+            "digraph G {\n    graph [rankdir=LR];\n    node [shape=box];\n    A [URL=\"https://x\"];\n}\n"
+        );
+    }
+
+    #[test]
+    fn only_changes_the_node_and_not_its_edges() {
+        let content = "digraph G {\n    A;\n    B;\n    A -> B [subtract=\"true\"];\n}\n";
+        let written = set(content, "B", "URL", "https://x");
+        assert_eq!(
+            written,
+            "digraph G {\n    A;\n    B [URL=\"https://x\"];\n    A -> B [subtract=\"true\"];\n}\n"
+        );
+    }
+
+    #[test]
+    fn fills_an_empty_attribute_list() {
+        let content = "digraph G {\n    A [];\n}\n";
+        let written = set(content, "A", "URL", "https://x");
+        assert_eq!(written, "digraph G {\n    A [URL=\"https://x\"];\n}\n");
+    }
+
+    #[test]
+    fn replaces_an_existing_attribute_in_place() {
+        let content = "digraph G {\n    A [label=\"Mix\", URL=\"https://old\", color=red];\n}\n";
+        let written = set(content, "A", "URL", "https://new");
+        assert_eq!(
+            written,
+            "digraph G {\n    A [label=\"Mix\", URL=\"https://new\", color=red];\n}\n"
+        );
+        assert_eq!(
+            node_attributes(&written, "A"),
+            vec![
+                ("label".to_string(), "Mix".to_string()),
+                ("URL".to_string(), "https://new".to_string()),
+                ("color".to_string(), "red".to_string()),
+            ]
+        );
+    }
+
+    /// A node or edge statement of a generated graph, with its attribute lists.
+    #[derive(Debug, Clone)]
+    enum Item {
+        Node(String, Vec<Vec<(String, String)>>),
+        Edge(String, String, Vec<Vec<(String, String)>>),
+    }
+
+    /// Ids the parser accepts without quotes, except for keywords.
+    fn plain_id() -> impl Strategy<Value = String> {
+        "[A-Za-z_][A-Za-z0-9_]{0,5}".prop_filter("keywords are no ids", |id| {
+            let keywords = ["node", "edge", "graph", "digraph", "subgraph", "strict"];
+            !keywords.contains(&id.to_lowercase().as_str())
+        })
+    }
+
+    /// Everything but backslashes, which the parser only accepts in front of a quote.
+    fn value() -> impl Strategy<Value = String> {
+        "[a-zA-Z0-9 :./#;,=\\[\\]{}\"-]{0,12}"
+    }
+
+    fn attribute_lists() -> impl Strategy<Value = Vec<Vec<(String, String)>>> {
+        prop::collection::vec(prop::collection::vec((plain_id(), value()), 0..3), 0..3)
+    }
+
+    /// Node statements with distinct names and edges between them, in any order.
+    fn items() -> impl Strategy<Value = Vec<Item>> {
+        let name = prop_oneof![plain_id(), "[a-z ]{1,6}"];
+        prop::collection::hash_set(name, 1..6)
+            .prop_flat_map(|names| {
+                let names = names.into_iter().collect::<Vec<_>>();
+                let nodes = prop::collection::vec(attribute_lists(), names.len());
+                let edges = prop::collection::vec(
+                    (any::<Index>(), any::<Index>(), attribute_lists()),
+                    0..6,
+                );
+                (Just(names), nodes, edges)
+            })
+            .prop_flat_map(|(names, nodes, edges)| {
+                let mut items = names
+                    .iter()
+                    .cloned()
+                    .zip(nodes)
+                    .map(|(name, lists)| Item::Node(name, lists))
+                    .collect::<Vec<_>>();
+                for (from, to, lists) in edges {
+                    let from = from.get(&names).clone();
+                    let to = to.get(&names).clone();
+                    items.push(Item::Edge(from, to, lists));
+                }
+                Just(items).prop_shuffle()
+            })
+    }
+
+    fn write_id(id: &str) -> String {
+        let is_plain = id.chars().all(|c| c == '_' || c.is_ascii_alphanumeric());
+        match is_plain {
+            true => id.to_string(),
+            false => quote(id),
+        }
+    }
+
+    /// The parser only reads further attribute lists right after the `]` of the one before.
+    fn write_attribute_lists(lists: &[Vec<(String, String)>], separator: &str) -> String {
+        let lists = lists
+            .iter()
+            .map(|list| {
+                let attributes = list
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, quote(v)))
+                    .collect::<Vec<_>>();
+                format!("[{}]", attributes.join(separator))
+            })
+            .collect::<String>();
+        match lists.is_empty() {
+            true => lists,
+            false => format!(" {}", lists),
+        }
+    }
+
+    /// Writes the graph in one of the layouts people use, with trailing comments.
+    fn write_graph(items: &[Item], layout: usize) -> String {
+        let statement_end = ["; ", ";\n    ", "\n    ", " // a comment\n    "][layout % 4];
+        let attribute_separator = [", ", "; ", " "][layout % 3];
+
+        let mut content = String::from("digraph G {\n    ");
+        for item in items {
+            let (statement, lists) = match item {
+                Item::Node(name, lists) => (write_id(name), lists),
+                Item::Edge(from, to, lists) => {
+                    (format!("{} -> {}", write_id(from), write_id(to)), lists)
+                }
+            };
+            content.push_str(&statement);
+            content.push_str(&write_attribute_lists(lists, attribute_separator));
+            content.push_str(statement_end);
+        }
+        content.push_str("\n}\n");
+        return content;
+    }
+
+    fn to_stmt(item: &Item) -> Stmt {
+        match item {
+            Item::Node(name, lists) => Stmt::Node(name.clone(), lists.concat()),
+            Item::Edge(from, to, lists) => Stmt::Edge(from.clone(), to.clone(), lists.concat()),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn written_graphs_parse_to_the_same_graph(items in items(), layout in 0..12usize) {
+            let content = write_graph(&items, layout);
+            let joined = tokenize(&content).iter().map(|t| t.text).collect::<String>();
+            prop_assert_eq!(&joined, &content);
+
+            let graph = graphviz_dot_parser::parse(&content).unwrap();
+            prop_assert_eq!(graph.stmt, items.iter().map(to_stmt).collect::<Vec<_>>());
+            prop_assert_eq!(locate_statements(&content).len(), items.len());
+        }
+
+        #[test]
+        fn set_attributes_parse_to_the_updated_graph(
+            items in items(),
+            layout in 0..12usize,
+            updates in prop::collection::vec((any::<Index>(), plain_id(), value()), 0..4),
+        ) {
+            let content = write_graph(&items, layout);
+            let nodes = items
+                .iter()
+                .filter_map(|item| match item {
+                    Item::Node(name, _) => Some(name.clone()),
+                    Item::Edge(..) => None,
+                })
+                .collect::<Vec<_>>();
+
+            // One update per node, like the urls written after an apply.
+            let mut expected = items.clone();
+            let mut updated = vec![];
+            for (node, key, value) in updates {
+                let node = node.get(&nodes).clone();
+                if updated.iter().any(|(n, _, _)| *n == node) {
+                    continue;
+                }
+
+                for item in expected.iter_mut() {
+                    let Item::Node(name, lists) = item else {
+                        continue;
+                    };
+                    if *name != node {
+                        continue;
+                    }
+
+                    if let Some((_, v)) = lists.iter_mut().flatten().find(|(k, _)| *k == key) {
+                        *v = value.clone();
+                    } else if let Some(first) = lists.first_mut() {
+                        first.insert(0, (key.clone(), value.clone()));
+                    } else {
+                        lists.push(vec![(key.clone(), value.clone())]);
+                    }
+                }
+                updated.push((node, key, value));
+            }
+
+            let written = set_node_attributes(&content, &updated).unwrap();
+            let graph = graphviz_dot_parser::parse(&written).unwrap();
+            prop_assert_eq!(graph.stmt, expected.iter().map(to_stmt).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn fails_for_unknown_nodes() {
+        let updates = vec![("B".to_string(), "URL".to_string(), "https://x".to_string())];
+        assert!(set_node_attributes("digraph G {\n    A;\n}\n", &updates).is_err());
+    }
+}
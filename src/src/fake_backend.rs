@@ -0,0 +1,1202 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use rspotify::model::{
+    ArtistId, AudioFeatures, FullAlbum, FullArtist, FullTrack, Page, PlayableId, PlayableItem,
+    PlaylistId, PlaylistItem, PlaylistTracksRef, PublicUser, SavedAlbum, SavedTrack,
+    SimplifiedAlbum, SimplifiedPlaylist, SimplifiedTrack, TrackId, UserId,
+};
+use rspotify::prelude::Id;
+use rspotify::{ClientError, ClientResult};
+use serde::{Deserialize, Serialize};
+
+use crate::backend::MusicBackend;
+use crate::traits::ResultExtension;
+
+/// The content of a fixture file. All spotify objects use the exact json format of the spotify
+/// web api, so responses can be recorded and pasted into a fixture as they are.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FakeLibrary {
+    pub user_id: String,
+    #[serde(default)]
+    pub playlists: Vec<FakePlaylist>,
+    #[serde(default)]
+    pub saved_albums: Vec<SavedAlbum>,
+    #[serde(default)]
+    pub saved_tracks: Vec<SavedTrack>,
+
+    /// Tracks that are neither in a playlist nor in the library but can be added to playlists.
+    #[serde(default)]
+    pub tracks: Vec<FullTrack>,
+
+    /// Artists with their genres, for genre queries.
+    #[serde(default)]
+    pub artists: Vec<FullArtist>,
+
+    /// For feature filters. Songs without features are treated like spotify has none for them.
+    #[serde(default)]
+    pub audio_features: Vec<AudioFeatures>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FakePlaylist {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub snapshot_id: u32,
+    #[serde(default)]
+    pub items: Vec<PlaylistItem>,
+}
+
+/// An in-memory music library seeded from a json fixture.
+/// Writes are applied to the in-memory state only, which can be dumped with `write_library`.
+pub struct FakeBackend {
+    library: Mutex<FakeLibrary>,
+    created_playlists: Mutex<u32>,
+
+    /// How many more calls to `playlist_add_items` succeed. Calls fail once it is zero.
+    add_items_budget: Mutex<Option<u32>>,
+
+    /// Errors the next calls fail with, one per call.
+    failures: Mutex<VecDeque<ClientError>>,
+
+    /// Every call with its most important arguments, like `playlist_items_page <id> <offset>`.
+    calls: Mutex<Vec<String>>,
+}
+
+impl FakeBackend {
+    pub fn new(library: FakeLibrary) -> Self {
+        Self {
+            library: Mutex::new(library),
+            created_playlists: Mutex::new(0),
+            add_items_budget: Mutex::new(None),
+            failures: Mutex::new(VecDeque::new()),
+            calls: Mutex::new(vec![]),
+        }
+    }
+
+    /// Lets the given number of `playlist_add_items` calls succeed and fails all further ones,
+    /// to simulate an apply that fails halfway. `None` lets all calls succeed again.
+    pub fn fail_adding_songs_after(&self, calls: Option<u32>) {
+        *self.add_items_budget.lock().unwrap() = calls;
+    }
+
+    pub fn from_fixture(path: &Path) -> Result<Self, anyhow::Error> {
+        let content = std::fs::read_to_string(path)
+            .or_error(format!("failed to read fixture {}", path.display()))?;
+        let library = serde_json::from_str::<FakeLibrary>(&content)
+            .or_error(format!("failed to parse fixture {}", path.display()))?;
+
+        log::info!(
+            "Loaded fixture {} with {} playlists, {} saved albums and {} saved tracks",
+            path.display(),
+            library.playlists.len(),
+            library.saved_albums.len(),
+            library.saved_tracks.len()
+        );
+
+        return Ok(Self::new(library));
+    }
+
+    /// Lets the next calls fail with the given errors before they change anything, one error per call.
+    /// Used to test how failed requests are retried.
+    pub fn fail_next_calls(&self, errors: impl IntoIterator<Item = ClientError>) {
+        self.failures.lock().unwrap().extend(errors);
+    }
+
+    /// The calls made so far, to check which requests would have been sent to spotify.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn start_call(&self, call: String) -> ClientResult<()> {
+        self.calls.lock().unwrap().push(call);
+        match self.failures.lock().unwrap().pop_front() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the current state of the library in the same format as the fixture.
+    pub fn write_library(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let library = self.library.lock().unwrap();
+        let content = serde_json::to_string_pretty(&*library)?;
+        std::fs::write(path, content)
+            .or_error(format!("failed to write library to {}", path.display()))?;
+
+        return Ok(());
+    }
+
+    fn find_track(library: &FakeLibrary, id: &str) -> Option<FullTrack> {
+        let from_playlists = library
+            .playlists
+            .iter()
+            .flat_map(|p| p.items.iter())
+            .filter_map(|item| match &item.track {
+                Some(PlayableItem::Track(t)) => Some(t),
+                _ => None,
+            });
+        let from_liked_songs = library.saved_tracks.iter().map(|t| &t.track);
+
+        let found = from_playlists
+            .chain(from_liked_songs)
+            .chain(library.tracks.iter())
+            .find(|t| t.id.as_ref().map(|i| i.id()) == Some(id));
+
+        if let Some(track) = found {
+            return Some(track.clone());
+        }
+
+        for saved in &library.saved_albums {
+            for t in &saved.album.tracks.items {
+                if t.id.as_ref().map(|i| i.id()) == Some(id) {
+                    return Some(album_track_to_full_track(t, &saved.album));
+                }
+            }
+        }
+
+        return None;
+    }
+
+    fn to_playlist_items(
+        library: &FakeLibrary,
+        items: Vec<PlayableId<'static>>,
+    ) -> ClientResult<Vec<PlaylistItem>> {
+        let mut playlist_items = vec![];
+        for item in items {
+            let track = Self::find_track(library, item.id()).ok_or_else(|| {
+                ClientError::Cli(format!("track {} does not exist in the fixture", item.id()))
+            })?;
+
+            playlist_items.push(PlaylistItem {
+                added_at: None,
+                added_by: Some(user(&library.user_id)?),
+                is_local: false,
+                track: Some(PlayableItem::Track(track)),
+            });
+        }
+
+        return Ok(playlist_items);
+    }
+}
+
+fn user(id: &str) -> ClientResult<PublicUser> {
+    let user_id = UserId::from_id(id.to_string())
+        .map_err(|_| ClientError::Cli(format!("user id {:?} of the fixture is not valid", id)))?;
+
+    Ok(PublicUser {
+        display_name: None,
+        external_urls: HashMap::new(),
+        followers: None,
+        href: format!("https://api.spotify.com/v1/users/{}", id),
+        id: user_id,
+        images: vec![],
+    })
+}
+
+fn playlist_id(id: &str) -> ClientResult<PlaylistId<'static>> {
+    PlaylistId::from_id(id.to_string())
+        .map_err(|_| ClientError::Cli(format!("playlist id {:?} is not valid", id)))
+}
+
+/// Fixtures list the library most recently added first, like spotify returns it.
+fn page_of<T: Clone>(items: &[T], href: &str, limit: u32, offset: u32) -> Page<T> {
+    let start = (offset as usize).min(items.len());
+    let end = (start + limit as usize).min(items.len());
+    let next = (end < items.len()).then(|| format!("{}?offset={}&limit={}", href, end, limit));
+
+    Page {
+        href: href.to_string(),
+        items: items[start..end].to_vec(),
+        limit,
+        next,
+        offset,
+        previous: None,
+        total: items.len() as u32,
+    }
+}
+
+fn join_ids<T: Id>(ids: &[T]) -> String {
+    ids.iter().map(|id| id.id()).collect::<Vec<_>>().join(",")
+}
+
+fn playlist_not_found(id: &str) -> ClientError {
+    ClientError::Cli(format!("playlist {} does not exist in the fixture", id))
+}
+
+fn album_track_to_full_track(t: &SimplifiedTrack, album: &FullAlbum) -> FullTrack {
+    FullTrack {
+        album: SimplifiedAlbum {
+            album_group: None,
+            album_type: None,
+            artists: album.artists.clone(),
+            available_markets: vec![],
+            external_urls: album.external_urls.clone(),
+            href: Some(album.href.clone()),
+            id: Some(album.id.clone()),
+            images: album.images.clone(),
+            name: album.name.clone(),
+            release_date: Some(album.release_date.clone()),
+            release_date_precision: None,
+            restrictions: None,
+        },
+        artists: t.artists.clone(),
+        available_markets: vec![],
+        disc_number: t.disc_number,
+        duration: t.duration,
+        explicit: t.explicit,
+        external_ids: HashMap::new(),
+        external_urls: t.external_urls.clone(),
+        href: t.href.clone(),
+        id: t.id.clone(),
+        is_local: t.is_local,
+        is_playable: t.is_playable,
+        linked_from: t.linked_from.clone(),
+        restrictions: t.restrictions.clone(),
+        name: t.name.clone(),
+        popularity: album.popularity,
+        preview_url: t.preview_url.clone(),
+        track_number: t.track_number,
+    }
+}
+
+#[async_trait]
+impl MusicBackend for FakeBackend {
+    async fn current_user_id(&self) -> ClientResult<UserId<'static>> {
+        self.start_call(String::from("current_user_id"))?;
+        let library = self.library.lock().unwrap();
+        Ok(user(&library.user_id)?.id)
+    }
+
+    async fn playlist_items_page(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<PlaylistItem>> {
+        self.start_call(format!(
+            "playlist_items_page {} {}",
+            playlist_id.id(),
+            offset
+        ))?;
+        let library = self.library.lock().unwrap();
+        let playlist = library
+            .playlists
+            .iter()
+            .find(|p| p.id == playlist_id.id())
+            .ok_or_else(|| playlist_not_found(playlist_id.id()))?;
+
+        let href = format!(
+            "https://api.spotify.com/v1/playlists/{}/tracks",
+            playlist_id.id()
+        );
+        Ok(page_of(&playlist.items, &href, limit, offset))
+    }
+
+    async fn current_user_playlists_page(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<SimplifiedPlaylist>> {
+        self.start_call(format!("current_user_playlists_page {}", offset))?;
+        let library = self.library.lock().unwrap();
+        let playlists = library
+            .playlists
+            .iter()
+            .map(|p| {
+                let href = format!("https://api.spotify.com/v1/playlists/{}", p.id);
+                Ok(SimplifiedPlaylist {
+                    collaborative: false,
+                    external_urls: HashMap::new(),
+                    href: href.clone(),
+                    id: playlist_id(&p.id)?,
+                    images: vec![],
+                    name: p.name.clone(),
+                    owner: user(&library.user_id)?,
+                    public: Some(false),
+                    snapshot_id: p.snapshot_id.to_string(),
+                    tracks: PlaylistTracksRef {
+                        href: format!("{}/tracks", href),
+                        total: p.items.len() as u32,
+                    },
+                })
+            })
+            .collect::<ClientResult<Vec<_>>>()?;
+
+        let href = "https://api.spotify.com/v1/me/playlists";
+        Ok(page_of(&playlists, href, limit, offset))
+    }
+
+    async fn current_user_saved_albums_page(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<SavedAlbum>> {
+        self.start_call(format!("current_user_saved_albums_page {}", offset))?;
+        let library = self.library.lock().unwrap();
+        let href = "https://api.spotify.com/v1/me/albums";
+        Ok(page_of(&library.saved_albums, href, limit, offset))
+    }
+
+    async fn current_user_saved_tracks_page(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<SavedTrack>> {
+        self.start_call(format!("current_user_saved_tracks_page {}", offset))?;
+        let library = self.library.lock().unwrap();
+        let href = "https://api.spotify.com/v1/me/tracks";
+        Ok(page_of(&library.saved_tracks, href, limit, offset))
+    }
+
+    async fn user_playlist_create(
+        &self,
+        _user_id: UserId<'static>,
+        name: &str,
+        description: &str,
+    ) -> ClientResult<PlaylistId<'static>> {
+        self.start_call(format!("user_playlist_create {}", name))?;
+        let mut created = self.created_playlists.lock().unwrap();
+        *created += 1;
+
+        // Ids are deterministic, so two runs against the same fixture produce the same output.
+        let id = format!("mixifyfake{:012}", created);
+        let mut library = self.library.lock().unwrap();
+        library.playlists.push(FakePlaylist {
+            id: id.clone(),
+            name: name.to_string(),
+            description: description.to_string(),
+            snapshot_id: 0,
+            items: vec![],
+        });
+        log::info!("Created fake playlist {} with id {}", name, id);
+
+        playlist_id(&id)
+    }
+
+    async fn playlist_add_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        items: Vec<PlayableId<'static>>,
+    ) -> ClientResult<()> {
+        self.start_call(format!("playlist_add_items {}", playlist_id.id()))?;
+        if let Some(budget) = self.add_items_budget.lock().unwrap().as_mut() {
+            if *budget == 0 {
+                return Err(ClientError::Cli(String::from(
+                    "adding songs failed on purpose",
+                )));
+            }
+            *budget -= 1;
+        }
+
+        let mut library = self.library.lock().unwrap();
+        let new_items = Self::to_playlist_items(&library, items)?;
+
+        let playlist = library
+            .playlists
+            .iter_mut()
+            .find(|p| p.id == playlist_id.id())
+            .ok_or_else(|| playlist_not_found(playlist_id.id()))?;
+        playlist.items.extend(new_items);
+        playlist.snapshot_id += 1;
+
+        Ok(())
+    }
+
+    async fn playlist_remove_all_occurrences_of_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        items: Vec<PlayableId<'static>>,
+    ) -> ClientResult<()> {
+        self.start_call(format!(
+            "playlist_remove_all_occurrences_of_items {}",
+            playlist_id.id()
+        ))?;
+        let mut library = self.library.lock().unwrap();
+        let playlist = library
+            .playlists
+            .iter_mut()
+            .find(|p| p.id == playlist_id.id())
+            .ok_or_else(|| playlist_not_found(playlist_id.id()))?;
+
+        let ids = items.iter().map(|i| i.id()).collect::<Vec<_>>();
+        playlist.items.retain(|item| match &item.track {
+            Some(PlayableItem::Track(t)) => !t
+                .id
+                .as_ref()
+                .map(|id| ids.contains(&id.id()))
+                .unwrap_or(false),
+            _ => true,
+        });
+        playlist.snapshot_id += 1;
+
+        Ok(())
+    }
+
+    async fn playlist_replace_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        items: Vec<PlayableId<'static>>,
+    ) -> ClientResult<()> {
+        self.start_call(format!("playlist_replace_items {}", playlist_id.id()))?;
+        if let Some(budget) = self.add_items_budget.lock().unwrap().as_mut() {
+            if *budget == 0 {
+                return Err(ClientError::Cli(String::from(
+                    "adding songs failed on purpose",
+                )));
+            }
+            *budget -= 1;
+        }
+
+        let mut library = self.library.lock().unwrap();
+        let new_items = Self::to_playlist_items(&library, items)?;
+
+        let playlist = library
+            .playlists
+            .iter_mut()
+            .find(|p| p.id == playlist_id.id())
+            .ok_or_else(|| playlist_not_found(playlist_id.id()))?;
+        playlist.items = new_items;
+        playlist.snapshot_id += 1;
+
+        Ok(())
+    }
+
+    async fn playlist_reorder_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        range_start: usize,
+        insert_before: usize,
+        range_length: usize,
+    ) -> ClientResult<()> {
+        self.start_call(format!("playlist_reorder_items {}", playlist_id.id()))?;
+        let mut library = self.library.lock().unwrap();
+        let playlist = library
+            .playlists
+            .iter_mut()
+            .find(|p| p.id == playlist_id.id())
+            .ok_or_else(|| playlist_not_found(playlist_id.id()))?;
+
+        let end = range_start + range_length;
+        if end > playlist.items.len() || insert_before > playlist.items.len() {
+            return Err(ClientError::Cli(format!(
+                "range {}..{} or position {} is out of bounds of playlist {}",
+                range_start,
+                end,
+                insert_before,
+                playlist_id.id()
+            )));
+        }
+
+        // Like spotify, moving a range in front of one of its own songs changes nothing.
+        if insert_before > range_start && insert_before <= end {
+            return Ok(());
+        }
+
+        let moved = playlist.items.drain(range_start..end).collect::<Vec<_>>();
+        let position = match insert_before > range_start {
+            true => insert_before - range_length,
+            false => insert_before,
+        };
+        playlist.items.splice(position..position, moved);
+        playlist.snapshot_id += 1;
+
+        Ok(())
+    }
+
+    async fn artists(&self, ids: Vec<ArtistId<'static>>) -> ClientResult<Vec<FullArtist>> {
+        self.start_call(format!("artists {}", join_ids(&ids)))?;
+        let library = self.library.lock().unwrap();
+        ids.iter()
+            .map(|id| {
+                library
+                    .artists
+                    .iter()
+                    .find(|a| a.id == *id)
+                    .cloned()
+                    .ok_or_else(|| {
+                        ClientError::Cli(format!(
+                            "artist {} does not exist in the fixture",
+                            id.id()
+                        ))
+                    })
+            })
+            .collect()
+    }
+
+    async fn audio_features(&self, ids: Vec<TrackId<'static>>) -> ClientResult<Vec<AudioFeatures>> {
+        self.start_call(format!("audio_features {}", join_ids(&ids)))?;
+        let library = self.library.lock().unwrap();
+        Ok(library
+            .audio_features
+            .iter()
+            .filter(|f| ids.contains(&f.id))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Spotify objects in the json format of fixtures, for tests.
+#[cfg(test)]
+pub mod fixtures {
+    use serde_json::json;
+
+    pub fn track(id: &str, name: &str) -> serde_json::Value {
+        track_on(id, name, "Album")
+    }
+
+    pub fn track_on(id: &str, name: &str, album: &str) -> serde_json::Value {
+        track_by(id, name, album, "artistAAAAAAAAAAAAAAAA")
+    }
+
+    pub fn track_by(id: &str, name: &str, album: &str, artist_id: &str) -> serde_json::Value {
+        let artist = json!({"external_urls": {}, "href": null, "id": artist_id, "name": "Artist"});
+        json!({
+            "album": {"album_type": "album", "artists": [artist], "external_urls": {}, "href": null, "id": null, "images": [], "name": album},
+            "artists": [artist], "disc_number": 1, "duration_ms": 200000, "explicit": false,
+            "external_ids": {"isrc": format!("ISRC{}", name)}, "external_urls": {}, "href": null, "id": id,
+            "is_local": false, "name": name, "popularity": 50, "preview_url": null, "track_number": 1
+        })
+    }
+
+    /// A song from the computer of the user. Spotify knows no ids for it, its album or its artist.
+    pub fn local_track(name: &str) -> serde_json::Value {
+        let artist = json!({"external_urls": {}, "href": null, "id": null, "name": "Artist"});
+        json!({
+            "album": {"album_type": null, "artists": [artist], "external_urls": {}, "href": null, "id": null, "images": [], "name": ""},
+            "artists": [artist], "disc_number": 0, "duration_ms": 200000, "explicit": false,
+            "external_ids": {}, "external_urls": {}, "href": null, "id": null,
+            "is_local": true, "name": name, "popularity": 0, "preview_url": null, "track_number": 0
+        })
+    }
+
+    pub fn artist(id: &str, genres: &[&str]) -> serde_json::Value {
+        json!({
+            "external_urls": {}, "followers": {"href": null, "total": 0}, "genres": genres,
+            "href": "", "id": id, "images": [], "name": id, "popularity": 50
+        })
+    }
+
+    /// A liked song, `days` days after 2023-01-01.
+    pub fn saved_track(track: &serde_json::Value, days: i64) -> serde_json::Value {
+        let added_at = chrono::DateTime::parse_from_rfc3339("2023-01-01T00:00:00Z").unwrap()
+            + chrono::Duration::days(days);
+        json!({"added_at": added_at.to_rfc3339(), "track": track})
+    }
+
+    pub fn playlist(id: &str, name: &str, tracks: &[&serde_json::Value]) -> serde_json::Value {
+        let items = tracks
+            .iter()
+            .map(|t| json!({"added_at": "2023-01-01T00:00:00Z", "added_by": null, "is_local": false, "track": t}))
+            .collect::<Vec<_>>();
+        json!({"id": id, "name": name, "items": items})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::fixtures::{artist, local_track, playlist, saved_track, track, track_by, track_on};
+    use super::*;
+    use crate::types::{Config, JournalStep};
+    use crate::{apply_command, journal};
+
+    fn song_names(playlist: &FakePlaylist) -> Vec<String> {
+        playlist
+            .items
+            .iter()
+            .filter_map(|item| match &item.track {
+                Some(PlayableItem::Track(t)) => Some(t.name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn playlist_songs(fake: &FakeBackend, id: &str) -> Vec<String> {
+        let library = fake.library.lock().unwrap();
+        song_names(library.playlists.iter().find(|p| p.id == id).unwrap())
+    }
+
+    fn config() -> Config {
+        Config {
+            allow_removing_songs: true,
+            mixstack_suffix: String::from("mixstack"),
+            write_description: false,
+            keep_songs_added_by_others: false,
+            schedule: None,
+            concurrency: 1,
+        }
+    }
+
+    /// Applies the graph as snapshot 1, with its ledger and the other files in `snapshots_dir`.
+    async fn apply(
+        fake: &Arc<FakeBackend>,
+        snapshots_dir: &Path,
+        content: &str,
+    ) -> Result<crate::types::ExecutionResult, anyhow::Error> {
+        std::fs::create_dir_all(snapshots_dir.join("1")).unwrap();
+        let backend: Arc<dyn MusicBackend> = fake.clone();
+        apply_command::execute_snapshot(snapshots_dir, 1, content, &backend, &config(), false, None)
+            .await
+    }
+
+    /// Like `apply`, but keeps a journal like `mixify apply` does, resuming the one in `snapshots_dir`.
+    async fn apply_with_journal(
+        fake: &Arc<FakeBackend>,
+        snapshots_dir: &Path,
+        content: &str,
+    ) -> Result<crate::types::ExecutionResult, anyhow::Error> {
+        std::fs::create_dir_all(snapshots_dir.join("1")).unwrap();
+        let journal = journal::read_journal(snapshots_dir, 1)
+            .unwrap()
+            .unwrap_or_else(|| journal::new_journal(1));
+        let backend: Arc<dyn MusicBackend> = fake.clone();
+        let result = apply_command::execute_snapshot(
+            snapshots_dir,
+            1,
+            content,
+            &backend,
+            &config(),
+            false,
+            Some(journal),
+        )
+        .await?;
+        journal::remove_journal(snapshots_dir, 1).unwrap();
+        return Ok(result);
+    }
+
+    #[tokio::test]
+    async fn apply_creates_the_mixed_playlist() {
+        let one = track("1111111111111111111111", "One");
+        let two = track("2222222222222222222222", "Two");
+        let three = track("3333333333333333333333", "Three");
+        let four = track_on("4444444444444444444444", "Four", "Acoustic");
+        let library = serde_json::from_value::<FakeLibrary>(json!({
+            "user_id": "tester",
+            "playlists": [
+                playlist("pAAAAAAAAAAAAAAAAAAAAA", "A", &[&one, &two]),
+                playlist("pBBBBBBBBBBBBBBBBBBBBB", "B", &[&two, &three, &four]),
+            ],
+        }))
+        .unwrap();
+
+        let content = r#"digraph G {
+            A [URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA"];
+            B [URL="https://open.spotify.com/playlist/pBBBBBBBBBBBBBBBBBBBBB"];
+            Mix;
+            A -> Mix;
+            B -> Mix;
+        }"#;
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        let fake = Arc::new(FakeBackend::new(library));
+        let result = apply(&fake, snapshots_dir.path(), content).await.unwrap();
+
+        let created = result.node_to_playlist_id.get("Mix").unwrap();
+        let library = fake.library.lock().unwrap();
+        let mix = library.playlists.iter().find(|p| &p.id == created).unwrap();
+        // Without an order attribute, new songs are grouped by album and sorted by name within it.
+        assert_eq!(song_names(mix), vec!["Four", "One", "Three", "Two"]);
+
+        let a = library.playlists.iter().find(|p| p.name == "A").unwrap();
+        assert_eq!(song_names(a), vec!["One", "Two"]);
+    }
+
+    #[tokio::test]
+    async fn an_empty_intersection_empties_the_playlist() {
+        let one = track("1111111111111111111111", "One");
+        let two = track("2222222222222222222222", "Two");
+        let three = track("3333333333333333333333", "Three");
+        let library = serde_json::from_value::<FakeLibrary>(json!({
+            "user_id": "tester",
+            "playlists": [
+                playlist("pAAAAAAAAAAAAAAAAAAAAA", "A", &[&one, &two]),
+                playlist("pBBBBBBBBBBBBBBBBBBBBB", "B", &[&two, &three]),
+                playlist("pTTTTTTTTTTTTTTTTTTTTT", "T", &[]),
+            ],
+        }))
+        .unwrap();
+
+        let content = r#"digraph G {
+            A [URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA"];
+            B [URL="https://open.spotify.com/playlist/pBBBBBBBBBBBBBBBBBBBBB"];
+            T [URL="https://open.spotify.com/playlist/pTTTTTTTTTTTTTTTTTTTTT"];
+            A -> T;
+            B -> T [op="intersect"];
+        }"#;
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        let fake = Arc::new(FakeBackend::new(library));
+        apply(&fake, snapshots_dir.path(), content).await.unwrap();
+        assert_eq!(playlist_songs(&fake, "pTTTTTTTTTTTTTTTTTTTTT"), vec!["Two"]);
+
+        let b = playlist_id("pBBBBBBBBBBBBBBBBBBBBB").unwrap();
+        let three = TrackId::from_id("3333333333333333333333").unwrap();
+        fake.playlist_replace_items(b, vec![PlayableId::Track(three)])
+            .await
+            .unwrap();
+
+        apply(&fake, snapshots_dir.path(), content).await.unwrap();
+        assert!(playlist_songs(&fake, "pTTTTTTTTTTTTTTTTTTTTT").is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_resumed_apply_reuses_the_created_playlists() {
+        let tracks = (0..150)
+            .map(|i| track(&format!("{:0>22}", i), &format!("Song {:03}", i)))
+            .collect::<Vec<_>>();
+        let library = serde_json::from_value::<FakeLibrary>(json!({
+            "user_id": "tester",
+            "playlists": [
+                playlist("pAAAAAAAAAAAAAAAAAAAAA", "A", &tracks.iter().collect::<Vec<_>>()),
+            ],
+        }))
+        .unwrap();
+
+        let content = r#"digraph G {
+            A [URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA"];
+            First;
+            Second;
+            A -> First;
+            A -> Second;
+        }"#;
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        let fake = Arc::new(FakeBackend::new(library));
+
+        // The second chunk of 100 songs fails, after the first playlist was created.
+        fake.fail_adding_songs_after(Some(1));
+        assert!(apply_with_journal(&fake, snapshots_dir.path(), content)
+            .await
+            .is_err());
+        let journal = journal::read_journal(snapshots_dir.path(), 1)
+            .unwrap()
+            .unwrap();
+        let (node, created) = match journal.steps.as_slice() {
+            [JournalStep::CreatePlaylist { node, playlist_id }] => {
+                (node.clone(), playlist_id.clone())
+            }
+            steps => panic!("expected one created playlist, got {:?}", steps),
+        };
+        assert_eq!(playlist_songs(&fake, &created).len(), 100);
+
+        fake.fail_adding_songs_after(None);
+        let result = apply_with_journal(&fake, snapshots_dir.path(), content)
+            .await
+            .unwrap();
+
+        assert_eq!(result.node_to_playlist_id.get(&node), Some(&created));
+        assert_eq!(*fake.created_playlists.lock().unwrap(), 2);
+        assert_eq!(fake.library.lock().unwrap().playlists.len(), 3);
+        let expected = (0..150)
+            .map(|i| format!("Song {:03}", i))
+            .collect::<Vec<_>>();
+        for node in ["First", "Second"] {
+            let id = result.node_to_playlist_id.get(node).unwrap();
+            assert_eq!(playlist_songs(&fake, id), expected);
+        }
+        assert!(journal::read_journal(snapshots_dir.path(), 1)
+            .unwrap()
+            .is_none());
+    }
+
+    /// Every file in `dir` and below with its content.
+    fn files(dir: &Path) -> Vec<(std::path::PathBuf, Vec<u8>)> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(self::files(&path));
+            } else {
+                files.push((path.clone(), std::fs::read(&path).unwrap()));
+            }
+        }
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn a_dry_run_reports_the_changes_without_writing_them() {
+        let one = track("1111111111111111111111", "One");
+        let two = track("2222222222222222222222", "Two");
+        let old = track("3333333333333333333333", "Old");
+        let hand = track("4444444444444444444444", "Hand");
+        let library = serde_json::from_value::<FakeLibrary>(json!({
+            "user_id": "tester",
+            "playlists": [
+                playlist("pAAAAAAAAAAAAAAAAAAAAA", "A", &[&one, &old]),
+                playlist("pTTTTTTTTTTTTTTTTTTTTT", "T", &[&hand]),
+            ],
+            "tracks": [two],
+        }))
+        .unwrap();
+
+        let content = r#"digraph G {
+            A [URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA"];
+            T [URL="https://open.spotify.com/playlist/pTTTTTTTTTTTTTTTTTTTTT"];
+            Mix;
+            A -> T;
+            A -> Mix;
+        }"#;
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(snapshots_dir.path().join("1")).unwrap();
+        let fake = Arc::new(FakeBackend::new(library));
+        let backend: Arc<dyn MusicBackend> = fake.clone();
+        let config = config();
+        let dry_run = || {
+            apply_command::execute_snapshot(
+                snapshots_dir.path(),
+                1,
+                content,
+                &backend,
+                &config,
+                true,
+                None,
+            )
+        };
+        let writes = |calls: &[String]| {
+            let write_calls = [
+                "user_playlist_create",
+                "playlist_add_items",
+                "playlist_remove_",
+                "playlist_replace_items",
+                "playlist_reorder_items",
+            ];
+            calls
+                .iter()
+                .filter(|c| write_calls.iter().any(|w| c.starts_with(w)))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        // Without an apply before, neither a ledger nor a backup is written.
+        dry_run().await.unwrap();
+        assert!(files(snapshots_dir.path()).is_empty());
+        assert!(writes(&fake.calls()).is_empty());
+
+        apply(&fake, snapshots_dir.path(), content).await.unwrap();
+        let a = playlist_id("pAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let one_id = TrackId::from_id("1111111111111111111111").unwrap();
+        let two_id = TrackId::from_id("2222222222222222222222").unwrap();
+        fake.playlist_replace_items(
+            a,
+            vec![PlayableId::Track(one_id), PlayableId::Track(two_id)],
+        )
+        .await
+        .unwrap();
+
+        let files_before = files(snapshots_dir.path());
+        let calls_before = fake.calls().len();
+        let result = dry_run().await.unwrap();
+
+        let report = result.dry_run_report.unwrap();
+        assert_eq!(report.created_playlists, vec!["Mixmixstack"]);
+        fn names(tracks: &[crate::types::TrackTuple]) -> Vec<&str> {
+            tracks.iter().map(|t| t.name.as_str()).collect()
+        }
+        let changes = report
+            .changes
+            .iter()
+            .map(|c| {
+                (
+                    c.node.as_str(),
+                    names(&c.added),
+                    names(&c.removed),
+                    names(&c.kept),
+                )
+            })
+            .collect::<Vec<_>>();
+        let expected = vec![
+            ("Mix", vec!["One", "Two"], vec![], vec![]),
+            ("T", vec!["Two"], vec!["Old"], vec!["Hand"]),
+        ];
+        assert_eq!(changes, expected);
+
+        assert!(writes(&fake.calls()[calls_before..]).is_empty());
+        assert_eq!(files(snapshots_dir.path()), files_before);
+        let mut songs = playlist_songs(&fake, "pTTTTTTTTTTTTTTTTTTTTT");
+        songs.sort();
+        assert_eq!(songs, vec!["Hand", "Old", "One"]);
+    }
+
+    #[tokio::test]
+    async fn a_resumed_apply_adds_the_songs_of_the_failed_chunk_once() {
+        let tracks = (0..150)
+            .map(|i| track(&format!("{:0>22}", i), &format!("Song {:03}", i)))
+            .collect::<Vec<_>>();
+        let library = serde_json::from_value::<FakeLibrary>(json!({
+            "user_id": "tester",
+            "playlists": [
+                playlist("pAAAAAAAAAAAAAAAAAAAAA", "A", &tracks.iter().collect::<Vec<_>>()),
+                playlist("pTTTTTTTTTTTTTTTTTTTTT", "T", &[]),
+            ],
+        }))
+        .unwrap();
+
+        let content = r#"digraph G {
+            A [URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA"];
+            T [URL="https://open.spotify.com/playlist/pTTTTTTTTTTTTTTTTTTTTT"];
+            A -> T;
+        }"#;
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        let fake = Arc::new(FakeBackend::new(library));
+
+        // The first chunk of 100 songs is added, the second one fails.
+        fake.fail_adding_songs_after(Some(1));
+        assert!(apply_with_journal(&fake, snapshots_dir.path(), content)
+            .await
+            .is_err());
+        assert_eq!(playlist_songs(&fake, "pTTTTTTTTTTTTTTTTTTTTT").len(), 100);
+
+        fake.fail_adding_songs_after(None);
+        apply_with_journal(&fake, snapshots_dir.path(), content)
+            .await
+            .unwrap();
+
+        let expected = (0..150)
+            .map(|i| format!("Song {:03}", i))
+            .collect::<Vec<_>>();
+        assert_eq!(playlist_songs(&fake, "pTTTTTTTTTTTTTTTTTTTTT"), expected);
+        assert_eq!(*fake.created_playlists.lock().unwrap(), 0);
+        assert_eq!(fake.library.lock().unwrap().playlists.len(), 2);
+
+        // The songs of the first chunk were claimed before the failure, so they can be removed.
+        let a = playlist_id("pAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let first = TrackId::from_id(format!("{:0>22}", 0)).unwrap();
+        fake.playlist_replace_items(a, vec![PlayableId::Track(first)])
+            .await
+            .unwrap();
+        apply(&fake, snapshots_dir.path(), content).await.unwrap();
+        assert_eq!(
+            playlist_songs(&fake, "pTTTTTTTTTTTTTTTTTTTTT"),
+            vec!["Song 000"]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_changed_playlists_are_fetched_again() {
+        let one = track("1111111111111111111111", "One");
+        let two = track("2222222222222222222222", "Two");
+        let library = serde_json::from_value::<FakeLibrary>(json!({
+            "user_id": "tester",
+            "playlists": [
+                playlist("pAAAAAAAAAAAAAAAAAAAAA", "A", &[&one]),
+                playlist("pBBBBBBBBBBBBBBBBBBBBB", "B", &[&two]),
+            ],
+        }))
+        .unwrap();
+
+        let content = r#"digraph G {
+            Artist [type="query", artist_id="artistAAAAAAAAAAAAAAAA", source="playlists"];
+            Mix;
+            Artist -> Mix;
+        }"#;
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        let fake = Arc::new(FakeBackend::new(library));
+        let fetched_playlists = |calls: &[String]| {
+            let mut ids = calls
+                .iter()
+                .filter_map(|c| c.strip_prefix("playlist_items_page "))
+                .filter_map(|c| c.split(' ').next())
+                .filter(|id| id.starts_with('p'))
+                .map(String::from)
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+
+        let result = apply(&fake, snapshots_dir.path(), content).await.unwrap();
+        let mix = result.node_to_playlist_id.get("Mix").unwrap();
+        assert_eq!(playlist_songs(&fake, mix), vec!["One", "Two"]);
+        assert_eq!(
+            fetched_playlists(&fake.calls()),
+            vec!["pAAAAAAAAAAAAAAAAAAAAA", "pBBBBBBBBBBBBBBBBBBBBB"]
+        );
+
+        // Changes the snapshot id of B.
+        let b = playlist_id("pBBBBBBBBBBBBBBBBBBBBB").unwrap();
+        let one_id = TrackId::from_id("1111111111111111111111").unwrap();
+        fake.playlist_add_items(b, vec![PlayableId::Track(one_id)])
+            .await
+            .unwrap();
+
+        let before = fake.calls().len();
+        apply(&fake, snapshots_dir.path(), content).await.unwrap();
+        assert_eq!(
+            fetched_playlists(&fake.calls()[before..]),
+            vec!["pBBBBBBBBBBBBBBBBBBBBB"]
+        );
+    }
+
+    #[tokio::test]
+    async fn artist_queries_skip_local_songs() {
+        let one = track("1111111111111111111111", "One");
+        let local = local_track("Demo");
+        let library = serde_json::from_value::<FakeLibrary>(json!({
+            "user_id": "tester",
+            "playlists": [playlist("pAAAAAAAAAAAAAAAAAAAAA", "A", &[&local, &one])],
+        }))
+        .unwrap();
+
+        let content = r#"digraph G {
+            Artist [type="query", artist_id="artistAAAAAAAAAAAAAAAA", source="playlists"];
+            Mix;
+            Artist -> Mix;
+        }"#;
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        let fake = Arc::new(FakeBackend::new(library));
+        let result = apply(&fake, snapshots_dir.path(), content).await.unwrap();
+
+        let mix = result.node_to_playlist_id.get("Mix").unwrap();
+        assert_eq!(playlist_songs(&fake, mix), vec!["One"]);
+    }
+
+    #[tokio::test]
+    async fn genre_queries_match_the_genres_of_the_artists() {
+        let rock = "artistRRRRRRRRRRRRRRRR";
+        let pop = "artistPPPPPPPPPPPPPPPP";
+        let jazz = "artistJJJJJJJJJJJJJJJJ";
+        let songs = [
+            track_by("1111111111111111111111", "One", "Album", rock),
+            track_by("2222222222222222222222", "Two", "Album", pop),
+            track_by("3333333333333333333333", "Three", "Album", rock),
+            track_by("4444444444444444444444", "Four", "Album", jazz),
+        ];
+        let library = serde_json::from_value::<FakeLibrary>(json!({
+            "user_id": "tester",
+            "saved_tracks": songs.iter().enumerate().map(|(i, s)| saved_track(s, i as i64)).collect::<Vec<_>>(),
+            "artists": [
+                artist(rock, &["indie rock"]),
+                artist(pop, &["Indie Pop", "dance pop"]),
+                artist(jazz, &["jazz"]),
+            ],
+        }))
+        .unwrap();
+
+        let content = r#"digraph G {
+            Indie [type="query", genre="indie", genre_match="prefix"];
+            Rock [type="query", genre="INDIE ROCK"];
+            Pop [type="query", genre="^dance", genre_match="regex"];
+            IndieMix;
+            RockMix;
+            PopMix;
+            Indie -> IndieMix;
+            Rock -> RockMix;
+            Pop -> PopMix;
+        }"#;
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        let fake = Arc::new(FakeBackend::new(library));
+        let result = apply(&fake, snapshots_dir.path(), content).await.unwrap();
+
+        let songs = |node: &str| {
+            let mut names = playlist_songs(&fake, &result.node_to_playlist_id[node]);
+            names.sort();
+            names
+        };
+        assert_eq!(songs("IndieMix"), vec!["One", "Three", "Two"]);
+        assert_eq!(songs("RockMix"), vec!["One", "Three"]);
+        assert_eq!(songs("PopMix"), vec!["Two"]);
+
+        // The genres are fetched once for all queries and kept in the library cache.
+        let artist_calls = |fake: &FakeBackend| {
+            fake.calls()
+                .into_iter()
+                .filter(|c| c.starts_with("artists "))
+                .collect::<Vec<_>>()
+        };
+        let calls = artist_calls(&fake);
+        assert_eq!(calls.len(), 1);
+        for id in [rock, pop, jazz] {
+            assert!(calls[0].contains(id));
+        }
+
+        let before = fake.calls().len();
+        apply(&fake, snapshots_dir.path(), content).await.unwrap();
+        assert!(!fake.calls()[before..]
+            .iter()
+            .any(|c| c.starts_with("artists ")));
+        assert_eq!(artist_calls(&fake).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn songs_added_by_hand_survive_a_sync() {
+        let one = track("1111111111111111111111", "One");
+        let two = track("2222222222222222222222", "Two");
+        let old = track("3333333333333333333333", "Old");
+        let hand = track("4444444444444444444444", "Hand");
+        let library = serde_json::from_value::<FakeLibrary>(json!({
+            "user_id": "tester",
+            "playlists": [
+                playlist("pAAAAAAAAAAAAAAAAAAAAA", "A", &[&one, &two]),
+                playlist("pTTTTTTTTTTTTTTTTTTTTT", "T", &[&old]),
+            ],
+            "tracks": [hand],
+        }))
+        .unwrap();
+
+        let content = r#"digraph G {
+            A [URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA"];
+            T [URL="https://open.spotify.com/playlist/pTTTTTTTTTTTTTTTTTTTTT"];
+            A -> T;
+        }"#;
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        let fake = Arc::new(FakeBackend::new(library));
+        let songs = || {
+            let mut names = playlist_songs(&fake, "pTTTTTTTTTTTTTTTTTTTTT");
+            names.sort();
+            names
+        };
+
+        // Mixify has no record of the playlist yet, so the song that was already in it is kept.
+        apply(&fake, snapshots_dir.path(), content).await.unwrap();
+        assert_eq!(songs(), vec!["Old", "One", "Two"]);
+
+        let t = playlist_id("pTTTTTTTTTTTTTTTTTTTTT").unwrap();
+        let hand = TrackId::from_id("4444444444444444444444").unwrap();
+        fake.playlist_add_items(t, vec![PlayableId::Track(hand)])
+            .await
+            .unwrap();
+        let a = playlist_id("pAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let one = TrackId::from_id("1111111111111111111111").unwrap();
+        fake.playlist_replace_items(a, vec![PlayableId::Track(one)])
+            .await
+            .unwrap();
+
+        // Only the song mixify added is removed.
+        apply(&fake, snapshots_dir.path(), content).await.unwrap();
+        assert_eq!(songs(), vec!["Hand", "Old", "One"]);
+    }
+
+    #[tokio::test]
+    async fn reordering_a_range_in_front_of_itself_changes_nothing() {
+        let songs = ["One", "Two", "Three", "Four"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| track(&i.to_string().repeat(22), name))
+            .collect::<Vec<_>>();
+        let library = serde_json::from_value::<FakeLibrary>(json!({
+            "user_id": "tester",
+            "playlists": [playlist("pAAAAAAAAAAAAAAAAAAAAA", "A", &songs.iter().collect::<Vec<_>>())],
+        }))
+        .unwrap();
+        let fake = FakeBackend::new(library);
+        let id = playlist_id("pAAAAAAAAAAAAAAAAAAAAA").unwrap();
+
+        fake.playlist_reorder_items(id.clone(), 0, 1, 2)
+            .await
+            .unwrap();
+        fake.playlist_reorder_items(id.clone(), 0, 4, 2)
+            .await
+            .unwrap();
+
+        let library = fake.library.lock().unwrap();
+        assert_eq!(
+            song_names(&library.playlists[0]),
+            vec!["Three", "Four", "One", "Two"]
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use chrono::Local;
+use pretty_env_logger::env_logger;
+
+use crate::types::{Job, JobKind, JobStatus};
+
+type JobLogs = Arc<Mutex<Vec<String>>>;
+
+tokio::task_local! {
+    /// The log of the job that runs on the current task.
+    static JOB_LOGS: JobLogs;
+}
+
+/// Logs like env_logger, but also collects the lines logged by a job, so they can be fetched through the api.
+/// Only lines logged on the task of the job are collected, not the ones of tasks it spawns.
+pub struct JobLogger {
+    inner: env_logger::Logger,
+}
+
+impl JobLogger {
+    pub fn new(inner: env_logger::Logger) -> Self {
+        Self { inner }
+    }
+}
+
+impl log::Log for JobLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+
+        self.inner.log(record);
+        let _ = JOB_LOGS.try_with(|logs| {
+            logs.lock()
+                .unwrap()
+                .push(format!("{} {}", record.level(), record.args()))
+        });
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// All jobs since the server started. Jobs are only kept in memory.
+#[derive(Default)]
+pub struct JobStore {
+    jobs: Mutex<Vec<(Job, JobLogs)>>,
+}
+
+impl JobStore {
+    pub fn create(&self, kind: JobKind, snapshot_id: u32, dry_run: bool) -> Job {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = Job {
+            id: jobs.len() as u32 + 1,
+            kind,
+            snapshot_id,
+            dry_run,
+            status: JobStatus::Queued,
+            created_at: Local::now().to_rfc3339(),
+            started_at: None,
+            finished_at: None,
+            error: None,
+            logs: vec![],
+        };
+
+        jobs.push((job.clone(), JobLogs::default()));
+        return job;
+    }
+
+    pub fn get(&self, id: u32) -> Option<Job> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter()
+            .find(|(job, _)| job.id == id)
+            .map(|(job, logs)| {
+                let mut job = job.clone();
+                job.logs = logs.lock().unwrap().clone();
+                job
+            })
+    }
+
+    /// All jobs without their logs, newest first.
+    pub fn list(&self) -> Vec<Job> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter().rev().map(|(job, _)| job.clone()).collect()
+    }
+
+    fn update(&self, id: u32, f: impl FnOnce(&mut Job)) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some((job, _)) = jobs.iter_mut().find(|(job, _)| job.id == id) {
+            f(job);
+        }
+    }
+
+    fn logs(&self, id: u32) -> JobLogs {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter()
+            .find(|(job, _)| job.id == id)
+            .map(|(_, logs)| Arc::clone(logs))
+            .unwrap_or_default()
+    }
+
+    /// Runs the job and keeps its status and logs up to date.
+    pub async fn run<F>(self: Arc<Self>, id: u32, job: F)
+    where
+        F: Future<Output = Result<(), anyhow::Error>>,
+    {
+        self.update(id, |job| {
+            job.status = JobStatus::Running;
+            job.started_at = Some(Local::now().to_rfc3339());
+        });
+
+        let result = JOB_LOGS.scope(self.logs(id), job).await;
+        if let Err(e) = &result {
+            log::error!("Job {} failed: {}", id, e);
+        }
+
+        self.update(id, |job| {
+            job.finished_at = Some(Local::now().to_rfc3339());
+            match result {
+                Ok(_) => job.status = JobStatus::Succeeded,
+                Err(e) => {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(e.to_string());
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,93 @@
+//! Lets `mixify apply <id> --resume` continue an apply that failed halfway.
+//!
+//! Only created playlists are recorded. A resumed apply reuses them and computes every
+//! `SaveChanges` again from the current songs of the playlists on spotify, so changes that were
+//! written before the failure are not repeated. The chunks of added and removed songs don't need
+//! their own steps because of that:
+//! - Songs of a chunk that was added are in the playlist, so they are not added again.
+//! - Songs of a chunk that was removed are not in the playlist, so there is nothing to remove.
+//! - The songs a save adds are claimed in the ledger before the first chunk is written,
+//!   so songs added before the failure can still be removed by later applies.
+//!
+//! Created playlists can't be found again from spotify, since mixify can't tell them apart
+//! from playlists with the same name. A journal that should not be resumed is removed
+//! with `mixify apply <id> --discard`.
+
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+
+use crate::constants;
+use crate::traits::ResultExtension;
+use crate::types::{Journal, JournalStep};
+
+fn journal_path(snapshots_dir: &Path, snapshot_id: u32) -> PathBuf {
+    snapshots_dir
+        .join(snapshot_id.to_string())
+        .join(constants::JOURNAL_FILE_NAME)
+}
+
+pub fn new_journal(snapshot_id: u32) -> Journal {
+    Journal {
+        snapshot_id,
+        started_at: Local::now().to_rfc3339(),
+        steps: vec![],
+    }
+}
+
+/// Reads the journal of the unfinished apply of the snapshot, if there is one.
+pub fn read_journal(
+    snapshots_dir: &Path,
+    snapshot_id: u32,
+) -> Result<Option<Journal>, anyhow::Error> {
+    let path = journal_path(snapshots_dir, snapshot_id);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let content = std::fs::read_to_string(&path)
+        .or_error(format!("failed to read journal {}", path.display()))?;
+    let journal = serde_json::from_str::<Journal>(&content)
+        .or_error(format!("failed to parse journal {}", path.display()))?;
+
+    return Ok(Some(journal));
+}
+
+pub fn write_journal(snapshots_dir: &Path, journal: &Journal) -> Result<(), anyhow::Error> {
+    let path = journal_path(snapshots_dir, journal.snapshot_id);
+    let content = serde_json::to_string_pretty(journal)?;
+    std::fs::write(&path, content)
+        .or_error(format!("failed to write journal {}", path.display()))?;
+
+    return Ok(());
+}
+
+/// Called once the apply finished, so the next apply starts from scratch.
+pub fn remove_journal(snapshots_dir: &Path, snapshot_id: u32) -> Result<(), anyhow::Error> {
+    let path = journal_path(snapshots_dir, snapshot_id);
+    if path.exists() {
+        std::fs::remove_file(&path)
+            .or_error(format!("failed to remove journal {}", path.display()))?;
+    }
+
+    return Ok(());
+}
+
+impl Journal {
+    /// The playlist the unfinished apply created for the node.
+    pub fn created_playlist(&self, node: &str) -> Option<&String> {
+        self.steps.iter().find_map(|step| match step {
+            JournalStep::CreatePlaylist {
+                node: n,
+                playlist_id,
+            } if n == node => Some(playlist_id),
+            _ => None,
+        })
+    }
+
+    /// Records the step and writes the journal, so it survives a crash right after the step.
+    pub fn record(&mut self, snapshots_dir: &Path, step: JournalStep) -> Result<(), anyhow::Error> {
+        self.steps.push(step);
+        return write_journal(snapshots_dir, self);
+    }
+}
@@ -0,0 +1,71 @@
+use std::path::{Path, PathBuf};
+
+use crate::constants;
+use crate::traits::ResultExtension;
+use crate::types::{Ledger, PlaylistLedger};
+
+fn ledger_path(snapshots_dir: &Path, snapshot_id: u32) -> PathBuf {
+    snapshots_dir
+        .join(snapshot_id.to_string())
+        .join(constants::LEDGER_FILE_NAME)
+}
+
+/// Reads the ledger of the snapshot. A snapshot that has never been applied has an empty ledger.
+pub fn read_ledger(snapshots_dir: &Path, snapshot_id: u32) -> Result<Ledger, anyhow::Error> {
+    let path = ledger_path(snapshots_dir, snapshot_id);
+    if !path.exists() {
+        return Ok(Ledger {
+            snapshot_id,
+            playlists: vec![],
+        });
+    }
+
+    let content = std::fs::read_to_string(&path)
+        .or_error(format!("failed to read ledger {}", path.display()))?;
+    let ledger = serde_json::from_str::<Ledger>(&content)
+        .or_error(format!("failed to parse ledger {}", path.display()))?;
+
+    return Ok(ledger);
+}
+
+pub fn write_ledger(snapshots_dir: &Path, ledger: &Ledger) -> Result<(), anyhow::Error> {
+    let path = ledger_path(snapshots_dir, ledger.snapshot_id);
+    let content = serde_json::to_string_pretty(ledger)?;
+    std::fs::write(&path, content)
+        .or_error(format!("failed to write ledger {}", path.display()))?;
+
+    return Ok(());
+}
+
+impl Ledger {
+    /// The songs mixify owns in the playlist, or None if mixify has never written to it.
+    pub fn owned_tracks(&self, playlist_id: &str) -> Option<&Vec<String>> {
+        self.playlists
+            .iter()
+            .find(|p| p.playlist_id == playlist_id)
+            .map(|p| &p.track_ids)
+    }
+
+    pub fn set_owned_tracks(&mut self, node: &str, playlist_id: &str, track_ids: Vec<String>) {
+        match self
+            .playlists
+            .iter_mut()
+            .find(|p| p.playlist_id == playlist_id)
+        {
+            Some(p) => {
+                p.node = node.to_string();
+                p.track_ids = track_ids;
+            }
+            None => self.playlists.push(PlaylistLedger {
+                node: node.to_string(),
+                playlist_id: playlist_id.to_string(),
+                track_ids,
+            }),
+        }
+    }
+
+    /// Forgets the songs mixify added to the playlist, so all its songs are treated as added by hand.
+    pub fn remove_playlist(&mut self, playlist_id: &str) {
+        self.playlists.retain(|p| p.playlist_id != playlist_id);
+    }
+}
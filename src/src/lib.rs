@@ -0,0 +1,52 @@
+#![allow(clippy::needless_return)]
+
+pub mod api;
+pub mod apply_command;
+pub mod args;
+pub mod auth;
+pub mod backend;
+pub mod backup;
+pub mod config;
+pub mod constants;
+pub mod diff_command;
+pub mod dot;
+pub mod fake_backend;
+pub mod jobs;
+pub mod journal;
+pub mod ledger;
+pub mod library_cache;
+pub mod new_command;
+pub mod plan_command;
+pub mod rate_limit;
+pub mod restore_command;
+pub mod scheduler;
+pub mod traits;
+pub mod types;
+
+/// Sets up logging for the binaries. Without RUST_LOG only info and above is logged, RUST_LOG overrides this.
+pub fn init_logger() {
+    init_logger_with_target(pretty_env_logger::env_logger::Target::Stdout);
+}
+
+/// Commands that print machine-readable output log to stderr instead, so stdout can be piped.
+pub fn init_logger_with_target(target: pretty_env_logger::env_logger::Target) {
+    let mut builder = pretty_env_logger::env_logger::Builder::new();
+    builder.filter_level(log::LevelFilter::Info);
+    builder.parse_default_env();
+    builder.target(target);
+    builder.filter(Some("rspotify"), log::LevelFilter::Off);
+
+    let logger = builder.build();
+    let max_level = logger.filter();
+    log::set_boxed_logger(Box::new(jobs::JobLogger::new(logger))).expect("logger already set");
+    log::set_max_level(max_level);
+}
+
+/// Loads the .env file. It is optional, everything can also be set in mixify.toml.
+pub fn load_env_file() {
+    if let Err(e) = dotenv::dotenv() {
+        if !e.not_found() {
+            eprintln!("Failed to load .env file: {}", e);
+        }
+    }
+}
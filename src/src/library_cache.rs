@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rspotify::model::{
+    AudioFeatures, Page, PlaylistItem, SavedAlbum, SavedTrack, SimplifiedPlaylist,
+};
+use rspotify::prelude::Id;
+use rspotify::ClientResult;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::MusicBackend;
+use crate::constants;
+use crate::traits::ResultExtension;
+
+/// The library of the user as it was on the last run, stored in `snapshots/.cache/library.json`.
+/// Shared by all snapshots, since they all query the same library.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LibraryCache {
+    pub user_id: String,
+
+    /// Most recently added first, like spotify returns them.
+    pub saved_albums: Vec<SavedAlbum>,
+    pub saved_tracks: Vec<SavedTrack>,
+    pub playlists: Vec<CachedPlaylist>,
+
+    /// Genres of every artist a genre query looked up, by artist id.
+    /// Genres of an artist rarely change, so they are kept until the cache is dropped.
+    #[serde(default)]
+    pub artist_genres: HashMap<String, Vec<String>>,
+
+    /// Audio features by track id. None if spotify has no features for the song.
+    /// Features of a song never change.
+    #[serde(default)]
+    pub audio_features: HashMap<String, Option<AudioFeatures>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CachedPlaylist {
+    pub id: String,
+    pub name: String,
+
+    /// Spotify creates a new snapshot id on every change of the playlist,
+    /// so the items are up to date as long as the snapshot id is the same.
+    pub snapshot_id: String,
+    pub items: Vec<PlaylistItem>,
+}
+
+/// Reads the cache of the user. A missing or unreadable cache is treated as empty.
+pub fn read_cache(snapshots_dir: &Path, user_id: &str) -> LibraryCache {
+    let empty = LibraryCache {
+        user_id: user_id.to_string(),
+        ..Default::default()
+    };
+
+    let path = snapshots_dir.join(constants::LIBRARY_CACHE_PATH);
+    if !path.exists() {
+        return empty;
+    }
+
+    let cache = std::fs::read_to_string(&path)
+        .or_error(format!("failed to read library cache {}", path.display()))
+        .and_then(|content| {
+            serde_json::from_str::<LibraryCache>(&content)
+                .or_error(format!("failed to parse library cache {}", path.display()))
+        });
+
+    match cache {
+        Ok(cache) if cache.user_id == user_id => cache,
+        Ok(_) => {
+            log::info!("The library cache belongs to another user. Ignoring it.");
+            empty
+        }
+        Err(e) => {
+            log::warn!("{}. Fetching the whole library again.", e);
+            empty
+        }
+    }
+}
+
+pub fn write_cache(snapshots_dir: &Path, cache: &LibraryCache) -> Result<(), anyhow::Error> {
+    let path = snapshots_dir.join(constants::LIBRARY_CACHE_PATH);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .or_error(format!("failed to create cache folder {}", dir.display()))?;
+    }
+
+    // Written to a temporary file first, so an interrupted write never leaves a truncated cache.
+    // The process id keeps the server and a cli run from writing to the same temporary file.
+    let temp_path = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    let content = serde_json::to_string(cache)?;
+    std::fs::write(&temp_path, content).or_error(format!(
+        "failed to write library cache {}",
+        temp_path.display()
+    ))?;
+    std::fs::rename(&temp_path, &path)
+        .or_error(format!("failed to write library cache {}", path.display()))?;
+
+    return Ok(());
+}
+
+impl LibraryCache {
+    /// The cached items of the playlist, if the playlist hasn't changed since they were cached.
+    pub fn playlist_items(&self, playlist: &SimplifiedPlaylist) -> Option<&Vec<PlaylistItem>> {
+        self.playlists
+            .iter()
+            .find(|p| p.id == playlist.id.id() && p.snapshot_id == playlist.snapshot_id)
+            .map(|p| &p.items)
+    }
+
+    pub fn set_playlist_items(&mut self, playlist: &SimplifiedPlaylist, items: Vec<PlaylistItem>) {
+        self.playlists.retain(|p| p.id != playlist.id.id());
+        self.playlists.push(CachedPlaylist {
+            id: playlist.id.id().to_string(),
+            name: playlist.name.clone(),
+            snapshot_id: playlist.snapshot_id.clone(),
+            items,
+        });
+    }
+
+    /// Drops the playlists that are no longer in the library.
+    pub fn retain_playlists(&mut self, playlists: &[SimplifiedPlaylist]) {
+        self.playlists
+            .retain(|cached| playlists.iter().any(|p| p.id.id() == cached.id));
+    }
+}
+
+/// Liked songs and saved albums, which can be refreshed by only fetching the newest pages.
+#[async_trait]
+pub trait SavedItem: Clone + Send + Sync + Sized {
+    /// Identifies the item in the library. The same song can be liked again, so `added_at` is part of it.
+    fn identity(&self) -> (String, DateTime<Utc>);
+
+    async fn fetch_page(backend: &Arc<dyn MusicBackend>, offset: u32) -> ClientResult<Page<Self>>;
+}
+
+#[async_trait]
+impl SavedItem for SavedTrack {
+    fn identity(&self) -> (String, DateTime<Utc>) {
+        let id = match &self.track.id {
+            Some(id) => id.id().to_string(),
+            None => self.track.name.clone(),
+        };
+        (id, self.added_at)
+    }
+
+    async fn fetch_page(backend: &Arc<dyn MusicBackend>, offset: u32) -> ClientResult<Page<Self>> {
+        backend
+            .current_user_saved_tracks_page(constants::LIBRARY_PAGE_SIZE, offset)
+            .await
+    }
+}
+
+#[async_trait]
+impl SavedItem for SavedAlbum {
+    fn identity(&self) -> (String, DateTime<Utc>) {
+        (self.album.id.id().to_string(), self.added_at)
+    }
+
+    async fn fetch_page(backend: &Arc<dyn MusicBackend>, offset: u32) -> ClientResult<Page<Self>> {
+        backend
+            .current_user_saved_albums_page(constants::LIBRARY_PAGE_SIZE, offset)
+            .await
+    }
+}
+
+/// Fetches the items added since the cache was written and puts them in front of the cached ones.
+///
+/// Pages are fetched until the newest cached item shows up. If the total count doesn't add up
+/// at that point, something was removed from the library and the whole library is fetched instead.
+pub async fn refresh_saved_items<T: SavedItem>(
+    backend: &Arc<dyn MusicBackend>,
+    cached: &[T],
+) -> ClientResult<Vec<T>> {
+    let mut newest_cached = cached.first().map(|item| item.identity());
+    let mut items = vec![];
+    let mut offset = 0;
+
+    loop {
+        let page = T::fetch_page(backend, offset).await?;
+        let total = page.total as usize;
+        let is_last_page = page.next.is_none() || page.items.is_empty();
+        offset += page.items.len() as u32;
+
+        for item in page.items {
+            if newest_cached.is_some() && newest_cached == Some(item.identity()) {
+                if items.len() + cached.len() == total {
+                    log::info!("Found {} new items since the last run", items.len());
+                    items.extend(cached.iter().cloned());
+                    return Ok(items);
+                }
+
+                log::info!("Items were removed from the library since the last run. Fetching everything again.");
+                newest_cached = None;
+            }
+
+            items.push(item);
+        }
+
+        if is_last_page {
+            return Ok(items);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::fake_backend::fixtures::{saved_track, track};
+    use crate::fake_backend::{FakeBackend, FakeLibrary};
+
+    /// `count` liked songs, the most recently liked first.
+    fn liked_songs(count: usize) -> Vec<serde_json::Value> {
+        (0..count)
+            .rev()
+            .map(|i| {
+                let song = track(&format!("{:0>22}", i), &format!("Song {}", i));
+                saved_track(&song, i as i64)
+            })
+            .collect()
+    }
+
+    fn fake(liked: &[serde_json::Value]) -> Arc<FakeBackend> {
+        let library = serde_json::from_value::<FakeLibrary>(json!({
+            "user_id": "tester",
+            "saved_tracks": liked,
+        }))
+        .unwrap();
+        Arc::new(FakeBackend::new(library))
+    }
+
+    fn ids(items: &[SavedTrack]) -> Vec<String> {
+        items.iter().map(|t| t.identity().0).collect()
+    }
+
+    fn pages(fake: &FakeBackend) -> Vec<String> {
+        fake.calls()
+            .into_iter()
+            .filter(|c| c.starts_with("current_user_saved_tracks_page"))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_newest_cached_item() {
+        let liked = liked_songs(120);
+        let fake = fake(&liked);
+        let backend: Arc<dyn MusicBackend> = fake.clone();
+
+        // The 100 songs liked first are cached, 20 were liked since.
+        let cached = serde_json::from_value::<Vec<SavedTrack>>(json!(liked[20..])).unwrap();
+        let items = refresh_saved_items(&backend, &cached).await.unwrap();
+
+        let all = serde_json::from_value::<Vec<SavedTrack>>(json!(liked)).unwrap();
+        assert_eq!(ids(&items), ids(&all));
+        assert_eq!(pages(&fake), vec!["current_user_saved_tracks_page 0"]);
+    }
+
+    #[tokio::test]
+    async fn fetches_everything_when_items_were_removed() {
+        let liked = liked_songs(120);
+        let fake = fake(&liked);
+        let backend: Arc<dyn MusicBackend> = fake.clone();
+
+        // A song that was liked back then is gone.
+        let mut cached = liked[20..].to_vec();
+        let removed = track("9999999999999999999999", "Removed");
+        cached.push(saved_track(&removed, -1));
+        let cached = serde_json::from_value::<Vec<SavedTrack>>(json!(cached)).unwrap();
+        let items = refresh_saved_items(&backend, &cached).await.unwrap();
+
+        let all = serde_json::from_value::<Vec<SavedTrack>>(json!(liked)).unwrap();
+        assert_eq!(ids(&items), ids(&all));
+        assert_eq!(
+            pages(&fake),
+            vec![
+                "current_user_saved_tracks_page 0",
+                "current_user_saved_tracks_page 50",
+                "current_user_saved_tracks_page 100",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fetches_everything_without_cache() {
+        let liked = liked_songs(60);
+        let fake = fake(&liked);
+        let backend: Arc<dyn MusicBackend> = fake.clone();
+
+        let items = refresh_saved_items::<SavedTrack>(&backend, &[])
+            .await
+            .unwrap();
+        assert_eq!(items.len(), 60);
+        assert_eq!(pages(&fake).len(), 2);
+    }
+}
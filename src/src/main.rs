@@ -1,56 +1,46 @@
-mod apply_command;
-mod args;
-mod constants;
-mod new_command;
-mod plan_command;
-mod traits;
-mod types;
+#![allow(clippy::needless_return)]
 
-use clap::Parser;
-use dotenv::dotenv;
-use rspotify::{prelude::*, scopes, AuthCodeSpotify, Credentials, OAuth};
-use traits::ResultExtension;
-use types::Config;
+use std::path::Path;
+use std::sync::Arc;
 
-use crate::args::MixifyArgs;
+use clap::Parser;
+use mixify::args::{self, MixifyArgs};
+use mixify::backend::{MusicBackend, SpotifyBackend};
+use mixify::fake_backend::FakeBackend;
+use mixify::rate_limit::RateLimitedBackend;
+use mixify::types::Config;
+use mixify::{
+    apply_command, auth, config, constants, diff_command, new_command, plan_command,
+    restore_command,
+};
+use pretty_env_logger::env_logger::Target;
 
 #[tokio::main]
 async fn main() {
-    dotenv().expect("Failed to load .env file");
+    mixify::load_env_file();
+    let args = MixifyArgs::parse();
 
-    let mut builder = pretty_env_logger::env_logger::Builder::from_default_env();
-    builder.target(pretty_env_logger::env_logger::Target::Stdout);
-    builder.filter(Some("rspotify"), log::LevelFilter::Off);
-    builder.init();
+    match &args.entity_type {
+        args::EntityType::Plan(cmd) if cmd.format != args::PlanFormat::Text => {
+            mixify::init_logger_with_target(Target::Stderr)
+        }
+        _ => mixify::init_logger(),
+    }
 
-    let config = match parse_config() {
+    let snapshot_id = match &args.entity_type {
+        args::EntityType::Apply(cmd) | args::EntityType::Sync(cmd) => Some(cmd.id),
+        args::EntityType::Plan(cmd) => Some(cmd.id),
+        args::EntityType::Restore(cmd) => Some(cmd.id),
+        _ => None,
+    };
+    let config = match config::load_config(snapshot_id, &args.config) {
         Ok(c) => c,
         Err(e) => {
             log::error!("Following error occured when parsing config: {}", e);
             return;
         }
     };
-
-    let spotify: AuthCodeSpotify;
-    if cfg!(debug_assertions) {
-        create_and_print_token().await;
-        // return;
-        spotify = create_client_from_token();
-    } else {
-        spotify = create_spotify_token().await;
-    }
-
-    let args = MixifyArgs::parse();
-    let data = match &args.entity_type {
-        args::EntityType::New(cmd) => new_command::handle_new_snapshot(&cmd),
-        args::EntityType::Plan(cmd) => plan_command::handle_plan_snapshot(&cmd),
-        args::EntityType::Apply(cmd) | args::EntityType::Sync(cmd) => {
-            let is_sync = matches!(args.entity_type, args::EntityType::Sync(_));
-            apply_command::handle_apply_snapshot(&cmd, &spotify, config, is_sync).await
-        }
-    };
-
-    match data {
+    match run_command(&args, config).await {
         Ok(_) => {
             log::info!("Success!");
         }
@@ -60,110 +50,64 @@ async fn main() {
     }
 }
 
-async fn create_and_print_token() {
-    let spotify = create_spotify_token().await;
-    let token = spotify.get_token();
-    let idk = token.lock().await;
-    let mut x = idk.unwrap();
-    let y = x.take();
-    let z = y.unwrap().access_token;
-    println!("{:?}", z);
-
-    std::env::set_var("TEST_TOKEN", z);
-}
-
-async fn create_spotify_token() -> AuthCodeSpotify {
-    let creds = Credentials::from_env().unwrap();
-    let redirect_uri =
-        std::env::var("RSPOTIFY_REDIRECT_URI").expect("RSPOTIFY_REDIRECT_URI env var not set");
-
-    let oauth = OAuth {
-        redirect_uri,
-        scopes: scopes!(
-            "playlist-modify-public",
-            "playlist-modify-private",
-            "playlist-read-private",
-            "playlist-read-collaborative",
-            "user-read-currently-playing",
-            "user-read-playback-state",
-            "user-library-read",
-            "user-read-private"
-        ),
-        ..Default::default()
-    };
-    let spotify = AuthCodeSpotify::new(creds, oauth);
-
-    let url = spotify
-        .get_authorize_url(true)
-        .expect("Failed to get create url for user authentication. This should never happen. Make sure the spotify credentials are set correctly in the .env file.");
-    spotify
-        .prompt_for_token(&url)
-        .await
-        .expect("Failed to prompt for token");
-
-    return spotify;
+async fn run_command(args: &MixifyArgs, config: Config) -> Result<(), anyhow::Error> {
+    match &args.entity_type {
+        args::EntityType::New(cmd) => new_command::handle_new_snapshot(cmd),
+        args::EntityType::Plan(cmd) => plan_command::handle_plan_snapshot(cmd),
+        args::EntityType::Apply(cmd) | args::EntityType::Sync(cmd) => {
+            let is_sync = matches!(args.entity_type, args::EntityType::Sync(_));
+            match &cmd.fixture {
+                Some(fixture) => apply_with_fixture(cmd, fixture, config, is_sync).await,
+                None => {
+                    let backend = create_spotify_backend().await?;
+                    apply_command::handle_apply_snapshot(cmd, &backend, config, is_sync).await
+                }
+            }
+        }
+        args::EntityType::Diff(cmd) => {
+            let backend: Option<Arc<dyn MusicBackend>> = match (cmd.tracks, &cmd.fixture) {
+                (false, _) => None,
+                (true, Some(fixture)) => Some(Arc::new(FakeBackend::from_fixture(fixture)?)),
+                (true, None) => Some(create_spotify_backend().await?),
+            };
+            diff_command::handle_diff_snapshots(cmd, backend.as_ref(), config).await
+        }
+        args::EntityType::Restore(cmd) => {
+            let backend = create_spotify_backend().await?;
+            restore_command::handle_restore_snapshot(cmd, &backend).await
+        }
+        args::EntityType::Login => auth::handle_login().await,
+        args::EntityType::Logout => auth::handle_logout(),
+        args::EntityType::Whoami => auth::handle_whoami().await,
+    }
 }
 
-fn create_client_from_token() -> AuthCodeSpotify {
-    let token_str = std::env::var("TEST_TOKEN").expect("TEST_TOKEN not set");
-    let token = rspotify::model::Token {
-        access_token: token_str.to_string(),
-        refresh_token: None,
-        expires_in: chrono::Duration::seconds(0),
-        expires_at: Some(chrono::Utc::now()),
-        scopes: scopes!(
-            "playlist-read-private",
-            "playlist-read-collaborative",
-            "user-read-currently-playing",
-            "user-read-playback-state",
-            "user-library-read",
-            "user-read-private"
-        ),
-    };
-
-    return rspotify::AuthCodeSpotify::from_token(token);
+async fn create_spotify_backend() -> Result<Arc<dyn MusicBackend>, anyhow::Error> {
+    let spotify = auth::create_spotify_client().await?;
+    let backend = Arc::new(SpotifyBackend::new(spotify));
+    return Ok(Arc::new(RateLimitedBackend::new(backend)));
 }
 
-fn parse_config() -> Result<Config, anyhow::Error> {
-    let allow_removing_songs = std::env::var("ALLOW_REMOVING_SONGS")
-        .or_error_str("ALLOW_REMOVING_SONGS env var not set")?;
-
-    let allow_removing_songs = match allow_removing_songs.as_str() {
-        "true" => true,
-        "false" => false,
-        _ => {
-            return Err(anyhow::anyhow!(format!(
-                "Invalid value for ALLOW_REMOVING_SONGS. Expected 'true' or 'false' but got '{}'",
-                allow_removing_songs
-            )))
-        }
-    };
-
-    let mixstack_suffix =
-        std::env::var("MIXSTACK_SUFFIX").or_error_str("MIXSTACK_SUFFIX env var not set")?;
-    let write_description = std::env::var("CREATE_PLAYLIST_DESCRIPTION")
-        .or_error_str("CREATE_PLAYLIST_DESCRIPTION env var not set")?;
-
-    let write_description = match write_description.as_str() {
-        "true" => true,
-        "false" => false,
-        _ => {
-            return Err(anyhow::anyhow!(format!(
-                "Invalid value for CREATE_PLAYLIST_DESCRIPTION. Expected 'true' or 'false' but got '{}'",
-                allow_removing_songs
-            )))
-        }
-    };
-
-    return Ok(Config {
-        allow_removing_songs,
-        mixstack_suffix,
-        write_description,
-    });
+async fn apply_with_fixture(
+    cmd: &args::ApplyCommand,
+    fixture: &Path,
+    config: Config,
+    is_sync: bool,
+) -> Result<(), anyhow::Error> {
+    let fake = Arc::new(FakeBackend::from_fixture(fixture)?);
+    let backend: Arc<dyn MusicBackend> = fake.clone();
+    let res = apply_command::handle_apply_snapshot(cmd, &backend, config, is_sync).await;
+
+    let result_path = fixture.with_extension("result.json");
+    fake.write_library(&result_path)?;
+    log::info!("Wrote resulting library to {}", result_path.display());
+
+    return res;
 }
 
 fn _test(id: u32) -> Result<(), anyhow::Error> {
-    let content = plan_command::read_snapshot_file(id, "edit")?;
+    let snapshots_dir = Path::new(constants::SNAPSHOTS_DIR);
+    let content = plan_command::read_snapshot_file(snapshots_dir, id, "edit")?;
 
     let nodes_with_missing_playlists: Vec<String> =
         vec!["GenB".to_string(), "GenC".to_string(), "GenD1".to_string()];
@@ -179,8 +123,8 @@ fn _test(id: u32) -> Result<(), anyhow::Error> {
     .cloned()
     .collect();
 
-    let paths = plan_command::list_snapshot_files(id, "edit")?;
-    let path = paths.get(0).unwrap().to_str().unwrap();
+    let path = plan_command::find_snapshot_file(snapshots_dir, id, "edit")?;
+    let path = path.to_str().unwrap();
     let x = path.replace("edit", "test.apply");
 
     let new_content = apply_command::create_post_apply_file(
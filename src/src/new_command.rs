@@ -1,4 +1,6 @@
-use crate::plan_command;
+use std::path::Path;
+
+use crate::{constants, plan_command};
 
 use super::args;
 use chrono::prelude::*;
@@ -34,7 +36,11 @@ fn get_latest_snapshot_or_default(name: String) -> Result<(String, u32), anyhow:
 
     let content = match latest_id {
         Some(id) => {
-            let c = plan_command::read_snapshot_file(id, "post.apply")?;
+            let c = plan_command::read_snapshot_file(
+                Path::new(constants::SNAPSHOTS_DIR),
+                id,
+                "post.apply",
+            )?;
             let now = Local::now();
             let content = format!(
                 "// Name: {}
@@ -1,55 +1,379 @@
-use std::{collections::HashMap, fs, io};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
 
 use anyhow::anyhow;
 use graphviz_dot_parser::types::{GraphAST, Stmt};
 use url::Url;
 
 use crate::{
-    constants,
-    traits::ResultExtension,
-    types::{Action, ActionType, QuerySongsByArtist, QuerySource},
+    constants, dot,
+    traits::{OptionExtension, ResultExtension},
+    types::{
+        self, Action, ActionType, CombineSongs, CopySongs, Dedup, EdgeOp, GenreMatch, Mode, Order,
+        Pick, Prefer, QuerySongsByArtist, QuerySongsByGenre, QuerySource, Rotation, SavePlaylist,
+        SongFilter,
+    },
 };
 
-use super::args;
+use super::args::{self, PlanFormat};
 
 type EdgeData = (String, String, graphviz_dot_parser::types::Attributes);
-type NodeData = (String, graphviz_dot_parser::types::Attributes);
+pub type NodeData = (String, graphviz_dot_parser::types::Attributes);
 
 pub fn handle_plan_snapshot(cmd: &args::PlanCommand) -> Result<(), anyhow::Error> {
-    let content = match read_snapshot_file(cmd.id, "edit") {
-        Ok(v) => v,
-        Err(err) => {
-            log::warn!("failed to find edit snapshot. see error: {:?}", err);
-            log::info!("trying to find post snapshot instead");
-
-            read_snapshot_file(cmd.id, "post.apply").or_error(format!(
-                "failed to find post snapshot. maybe this id {} doesn't exist?.",
-                cmd.id
-            ))?
-        }
-    };
+    let content = read_latest_snapshot_file(Path::new(constants::SNAPSHOTS_DIR), cmd.id)?;
 
     let gv =
         graphviz_dot_parser::parse(&content).or_error(String::from("failed to parse graph"))?;
-    let (res, _) = create_execution_plan(&gv)?;
-
-    for actions in res {
-        let mut idx = 0;
-        for action in actions {
-            if idx != action.idx {
-                log::info!("------------------------------------");
-                idx = action.idx;
+    let (res, nodes) = create_execution_plan(&gv, &content)?;
+
+    match cmd.format {
+        PlanFormat::Text => {
+            for (stage, actions) in res.iter().enumerate() {
+                log::info!(
+                    "==================== stage {} ====================",
+                    stage + 1
+                );
+
+                let mut for_node = None;
+                for action in actions {
+                    if for_node.is_some() && for_node != Some(&action.for_node) {
+                        log::info!("------------------------------------");
+                    }
+                    for_node = Some(&action.for_node);
+
+                    log::info!("{}", action);
+                }
             }
 
-            log::info!("{}", action);
+            for (node, expression) in node_expressions(&res) {
+                log::info!("{} = {}", node, expression);
+            }
+
+            let mut shared = node_users(&res)
+                .into_iter()
+                .filter(|(_, users)| users.len() > 1)
+                .collect::<Vec<_>>();
+            shared.sort();
+            for (node, users) in shared {
+                log::info!(
+                    "{:?} is evaluated once and reused by {}",
+                    node,
+                    users.join(", ")
+                );
+            }
+        }
+        PlanFormat::Json => {
+            let plan = without_root_node(res);
+            println!("{}", serde_json::to_string_pretty(&plan)?)
         }
+        PlanFormat::Dot => print!("{}", render_dot(&gv, &nodes, &res)),
+        PlanFormat::Mermaid => print!("{}", render_mermaid(&gv, &nodes, &res)),
     }
 
     return Ok(());
 }
 
+/// What mixify does with a node. Used to colour the rendered graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum NodeRole {
+    Query,
+    Save,
+    Create,
+}
+
+impl NodeRole {
+    fn name(&self) -> &'static str {
+        match self {
+            NodeRole::Query => "query",
+            NodeRole::Save => "save",
+            NodeRole::Create => "create",
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        match self {
+            NodeRole::Query => "#9ecae1",
+            NodeRole::Save => "#fdae6b",
+            NodeRole::Create => "#a1d99b",
+        }
+    }
+}
+
+/// The most significant role of every node. Creating a playlist is more significant than
+/// saving to it, and saving more than only querying it.
+fn node_roles(plan: &[Vec<Action>]) -> HashMap<String, NodeRole> {
+    let mut roles: HashMap<String, NodeRole> = HashMap::new();
+    for action in plan.iter().flatten() {
+        let role = match action.action_type {
+            ActionType::CreatePlaylist => NodeRole::Create,
+            ActionType::SaveChanges(_) => NodeRole::Save,
+            ActionType::QuerySongs(_)
+            | ActionType::QuerySongsByArtist(_)
+            | ActionType::QuerySongsByGenre(_) => NodeRole::Query,
+            _ => continue,
+        };
+
+        let current = roles.entry(action.node.clone()).or_insert(role);
+        *current = (*current).max(role);
+    }
+
+    return roles;
+}
+
+/// The plan without the actions of the root node, which only exists to plan the graph.
+/// Stages that only held the root node are left out.
+pub fn without_root_node(plan: Vec<Vec<Action>>) -> Vec<Vec<Action>> {
+    let root = constants::MIXIFY_TEMPORARY_ROOT_NODE_NAME;
+    return plan
+        .into_iter()
+        .map(|stage| {
+            stage
+                .into_iter()
+                .filter(|a| a.node != root && a.for_node != root)
+                .collect::<Vec<_>>()
+        })
+        .filter(|stage| !stage.is_empty())
+        .collect();
+}
+
+/// The nodes every node feeds into. Nodes feeding into several nodes are only evaluated once.
+fn node_users(plan: &[Vec<Action>]) -> HashMap<String, Vec<String>> {
+    let mut users: HashMap<String, Vec<String>> = HashMap::new();
+    for action in plan.iter().flatten() {
+        let is_use = matches!(
+            action.action_type,
+            ActionType::CopySongs(_)
+                | ActionType::IntersectSongs(_)
+                | ActionType::XorSongs(_)
+                | ActionType::RemoveSongs(_)
+        );
+        if !is_use || action.for_node == constants::MIXIFY_TEMPORARY_ROOT_NODE_NAME {
+            continue;
+        }
+
+        users
+            .entry(action.node.clone())
+            .or_default()
+            .push(action.for_node.clone());
+    }
+
+    return users;
+}
+
+/// The set algebra of every node, like `Mix = ((A ∪ B) ∩ C) − Z`, in the order of the plan.
+fn node_expressions(plan: &[Vec<Action>]) -> Vec<(String, String)> {
+    let mut terms: Vec<(String, Vec<(&str, &String)>)> = vec![];
+    for action in plan.iter().flatten() {
+        let op = match action.action_type {
+            ActionType::CopySongs(_) => "∪",
+            ActionType::XorSongs(_) => "⊕",
+            ActionType::IntersectSongs(_) => "∩",
+            ActionType::RemoveSongs(_) => "−",
+            _ => continue,
+        };
+        if action.for_node == constants::MIXIFY_TEMPORARY_ROOT_NODE_NAME {
+            continue;
+        }
+
+        match terms.iter_mut().find(|(node, _)| *node == action.for_node) {
+            Some((_, node_terms)) => node_terms.push((op, &action.node)),
+            None => terms.push((action.for_node.clone(), vec![(op, &action.node)])),
+        }
+    }
+
+    return terms
+        .into_iter()
+        .map(|(node, node_terms)| {
+            let mut expression = match node_terms[0] {
+                ("∪", term) => term.clone(),
+                (op, term) => format!("∅ {} {}", op, term),
+            };
+
+            // The edges of an op come one after another, so only a change of op needs parentheses.
+            for (i, (op, term)) in node_terms.iter().enumerate().skip(1) {
+                if i > 1 && node_terms[i - 1].0 != *op {
+                    expression = format!("({})", expression);
+                }
+                expression = format!("{} {} {}", expression, op, term);
+            }
+
+            (node, expression)
+        })
+        .collect();
+}
+
+/// The role of the node, and how often it is reused, as shown in the rendered graph.
+fn describe_node(role: &NodeRole, users: Option<&Vec<String>>) -> String {
+    match users.map(|u| u.len()).unwrap_or(0) {
+        0 | 1 => role.name().to_string(),
+        n => format!("{}, reused by {}", role.name(), n),
+    }
+}
+
+/// Node names in the order they are defined, without the temporary root node.
+fn plan_node_names(nodes: &[NodeData]) -> Vec<&String> {
+    let mut names: Vec<&String> = vec![];
+    for (name, _) in nodes {
+        if name != constants::MIXIFY_TEMPORARY_ROOT_NODE_NAME && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    return names;
+}
+
+fn node_label(nodes: &[NodeData], node: &String) -> String {
+    return nodes
+        .iter()
+        .filter(|(name, _)| name == node)
+        .flat_map(|(_, attrs)| attrs.iter())
+        .find(|(k, _)| k == constants::LABEL_ATTRIBUTE_KEY)
+        .map(|(_, label)| label.clone())
+        .unwrap_or_else(|| node.clone());
+}
+
+/// The edges with the name of their operation, None for union edges.
+fn plan_edges(gv: &GraphAST) -> Vec<(&String, &String, Option<&'static str>)> {
+    return gv
+        .stmt
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Stmt::Edge(from, to, attrs) => {
+                let is_subtraction = attrs
+                    .iter()
+                    .any(|(k, v)| k == constants::SUBTRACT_ATTRIBUTE_KEY && v == "true");
+                let op = attrs
+                    .iter()
+                    .find(|(k, _)| k == constants::OP_ATTRIBUTE_KEY)
+                    .and_then(|(_, v)| v.parse::<EdgeOp>().ok());
+                let name = match (is_subtraction, op) {
+                    (true, _) => Some("subtract"),
+                    (false, Some(EdgeOp::Intersect)) => Some("intersect"),
+                    (false, Some(EdgeOp::Xor)) => Some("xor"),
+                    _ => None,
+                };
+                Some((from, to, name))
+            }
+            _ => None,
+        })
+        .collect();
+}
+
+fn edge_color(op: &str) -> &'static str {
+    match op {
+        "subtract" => "red",
+        "intersect" => "blue",
+        _ => "purple",
+    }
+}
+
+fn render_dot(gv: &GraphAST, nodes: &[NodeData], plan: &[Vec<Action>]) -> String {
+    let roles = node_roles(plan);
+    let users = node_users(plan);
+    let mut out = String::from("digraph G {\n");
+
+    for name in plan_node_names(nodes) {
+        let mut label = node_label(nodes, name);
+        let mut attrs = vec![];
+        if let Some(role) = roles.get(name) {
+            label = format!("{}\\n({})", label, describe_node(role, users.get(name)));
+            attrs.push(format!(
+                "style=filled, fillcolor={}",
+                dot::quote(role.color())
+            ));
+        }
+        attrs.insert(0, format!("label={}", dot::quote(&label)));
+        out.push_str(&format!(
+            "    {} [{}];\n",
+            dot::quote(name),
+            attrs.join(", ")
+        ));
+    }
+
+    for (from, to, op) in plan_edges(gv) {
+        let attrs = match op {
+            Some(op) => format!(
+                " [color={color}, fontcolor={color}, label={}]",
+                dot::quote(op),
+                color = edge_color(op)
+            ),
+            None => String::new(),
+        };
+        out.push_str(&format!(
+            "    {} -> {}{};\n",
+            dot::quote(from),
+            dot::quote(to),
+            attrs
+        ));
+    }
+
+    out.push_str("}\n");
+    return out;
+}
+
+fn render_mermaid(gv: &GraphAST, nodes: &[NodeData], plan: &[Vec<Action>]) -> String {
+    let roles = node_roles(plan);
+    let users = node_users(plan);
+    let names = plan_node_names(nodes);
+
+    // Mermaid ids can't contain every character dot allows, so nodes are numbered instead.
+    let id = |node: &String| format!("n{}", names.iter().position(|n| *n == node).unwrap());
+    let escape = |label: String| label.replace('"', "#quot;");
+
+    let mut out = String::from("flowchart LR\n");
+    for name in &names {
+        let label = escape(node_label(nodes, name));
+        match roles.get(*name) {
+            Some(role) => out.push_str(&format!(
+                "    {}[\"{}<br/>({})\"]:::{}\n",
+                id(name),
+                label,
+                describe_node(role, users.get(*name)),
+                role.name()
+            )),
+            None => out.push_str(&format!("    {}[\"{}\"]\n", id(name), label)),
+        }
+    }
+
+    let mut styled_links: Vec<(&str, Vec<String>)> = vec![];
+    for (i, (from, to, op)) in plan_edges(gv).into_iter().enumerate() {
+        let Some(op) = op else {
+            out.push_str(&format!("    {} --> {}\n", id(from), id(to)));
+            continue;
+        };
+
+        out.push_str(&format!("    {} -- {} --> {}\n", id(from), op, id(to)));
+        match styled_links
+            .iter_mut()
+            .find(|(color, _)| *color == edge_color(op))
+        {
+            Some((_, links)) => links.push(i.to_string()),
+            None => styled_links.push((edge_color(op), vec![i.to_string()])),
+        }
+    }
+
+    for role in [NodeRole::Query, NodeRole::Save, NodeRole::Create] {
+        out.push_str(&format!(
+            "    classDef {} fill:{}\n",
+            role.name(),
+            role.color()
+        ));
+    }
+    for (color, links) in styled_links {
+        out.push_str(&format!(
+            "    linkStyle {} stroke:{color},color:{color}\n",
+            links.join(",")
+        ));
+    }
+
+    return out;
+}
+
 pub fn create_execution_plan(
     gv: &GraphAST,
+    content: &str,
 ) -> Result<(Vec<Vec<Action>>, Vec<NodeData>), anyhow::Error> {
     let mixify_root_node = (
         constants::MIXIFY_TEMPORARY_ROOT_NODE_NAME.to_string(),
@@ -63,7 +387,7 @@ pub fn create_execution_plan(
     // NOTE: Important for this to work. All nodes must be defined in the graph. Otherwise it will panic.
     // In other words, edges that dont point to a node explicitly defined in the graph will cause a panic.
     // This is why we validate the graph before we do anything else.
-    validate_graph(&gv)?;
+    validate_graph(gv, content)?;
     let mut graph = gv.to_directed_graph().unwrap();
 
     let mut error: Option<anyhow::Error> = None;
@@ -90,6 +414,7 @@ pub fn create_execution_plan(
                     .iter()
                     .find(|(k, _)| k == constants::URL_ATTRIBUTE_KEY);
 
+                // The url itself is checked by `validate_graph`.
                 if attr.is_none() {
                     let is_query = attr
                         .iter()
@@ -101,12 +426,6 @@ pub fn create_execution_plan(
                             node
                         ));
                     }
-                } else {
-                    let (_, url) = attr.unwrap();
-                    let _ = Url::parse(url).expect(&format!(
-                        "the url attribute of {:?} is not a valid url",
-                        node
-                    ));
                 }
             }
 
@@ -131,12 +450,57 @@ pub fn create_execution_plan(
         );
     }
 
-    let mut all_actions: Vec<Vec<Action>> = Vec::new();
-    let mut memo: Vec<String> = Vec::new();
-    let res = create_node_execution_plan(1, &root, &nodes, &edges, &graph, &mut memo)?;
-    all_actions.push(res);
+    let mut planned: HashSet<String> = HashSet::new();
+    let res = create_node_execution_plan(1, &root, &nodes, &edges, &graph, &mut planned)?;
+    let stages = into_stages(res, &graph);
+
+    return Ok((stages, nodes.clone()));
+}
+
+/// Splits the actions into stages. A node only depends on the nodes feeding into it, so the nodes
+/// of a stage can be executed at the same time once all previous stages are done.
+/// The actions of a node are the ones with it as `for_node` and keep their order.
+fn into_stages(actions: Vec<Action>, graph: &petgraph::Graph<String, ()>) -> Vec<Vec<Action>> {
+    let mut groups: Vec<(String, Vec<Action>)> = vec![];
+    for action in actions {
+        match groups.last_mut() {
+            Some((node, group)) if *node == action.for_node => group.push(action),
+            _ => groups.push((action.for_node.clone(), vec![action])),
+        }
+    }
+
+    let mut depths: HashMap<String, usize> = HashMap::new();
+    let mut stages: Vec<Vec<Action>> = vec![];
+    for (node, group) in groups {
+        let stage = node_depth(&node, graph, &mut depths);
+        if stages.len() <= stage {
+            stages.resize_with(stage + 1, Vec::new);
+        }
+        stages[stage].extend(group);
+    }
+
+    return stages;
+}
+
+/// The length of the longest path from a base node to the node.
+fn node_depth(
+    node: &String,
+    graph: &petgraph::Graph<String, ()>,
+    depths: &mut HashMap<String, usize>,
+) -> usize {
+    if let Some(depth) = depths.get(node) {
+        return *depth;
+    }
+
+    let node_index = graph.node_indices().find(|i| graph[*i] == *node).unwrap();
+    let depth = graph
+        .neighbors_directed(node_index, petgraph::Direction::Incoming)
+        .map(|i| node_depth(&graph[i], graph, depths) + 1)
+        .max()
+        .unwrap_or(0);
 
-    return Ok((all_actions, nodes.clone()));
+    depths.insert(node.clone(), depth);
+    return depth;
 }
 
 fn create_node_execution_plan(
@@ -145,19 +509,26 @@ fn create_node_execution_plan(
     nodes: &Vec<NodeData>,
     edges: &Vec<EdgeData>,
     graph: &petgraph::Graph<String, ()>,
-    playlists_created_memo: &mut Vec<String>,
+    planned: &mut HashSet<String>,
 ) -> Result<Vec<Action>, anyhow::Error> {
     let mut actions: Vec<Action> = Vec::new();
 
+    // A node feeding into several nodes is only planned for the first one.
+    // The others copy the songs it already has, so it is queried and saved once per run.
+    if !planned.insert(current_node.clone()) {
+        return Ok(actions);
+    }
+
     let node_index = graph
         .node_indices()
         .find(|i| graph[*i] == *current_node)
         .unwrap();
     let nei = graph.neighbors_directed(node_index, petgraph::Direction::Incoming);
     let names = nei.map(|i| graph[i].clone()).collect::<Vec<String>>();
-    let has_neighbors = names.len() > 0;
+    let has_neighbors = !names.is_empty();
 
     let mut edges_with_subtraction: Vec<&EdgeData> = Vec::new();
+    let mut edges_with_op: Vec<(EdgeOp, &EdgeData)> = Vec::new();
     let mut is_query_node = false;
 
     let mut final_node_actions: Vec<Action> = Vec::new();
@@ -176,20 +547,34 @@ fn create_node_execution_plan(
             continue;
         }
 
-        let r = create_node_execution_plan(
-            idx + 1,
-            from_node,
-            nodes,
-            edges,
-            graph,
-            playlists_created_memo,
-        )?;
+        let edge = edges
+            .iter()
+            .find(|(from, to, _)| *from == *from_node && *to == *current_node);
+
+        // Xor and intersection need the union of the other edges, so they are done after it.
+        if let Some(edge) = edge {
+            let op = parse_edge_op(from_node, current_node, &edge.2)?;
+            if op != EdgeOp::Union {
+                edges_with_op.push((op, edge));
+                continue;
+            }
+        }
+
+        let r = create_node_execution_plan(idx + 1, from_node, nodes, edges, graph, planned)?;
         for action in r {
             actions.push(action);
         }
 
+        let copy = match edge {
+            Some((_, _, attr)) => parse_copy(from_node, current_node, attr)?,
+            None => CopySongs {
+                filter: None,
+                weight: None,
+            },
+        };
+
         final_node_actions.push(Action {
-            action_type: ActionType::CopySongs,
+            action_type: ActionType::CopySongs(copy),
             playlist_url: get_playlist_url(nodes, from_node),
             node: from_node.to_string(),
             idx,
@@ -197,15 +582,103 @@ fn create_node_execution_plan(
         });
     }
 
-    for (n, _, _) in edges_with_subtraction {
-        let r =
-            create_node_execution_plan(idx + 1, &n, nodes, edges, graph, playlists_created_memo)?;
+    let weights = final_node_actions
+        .iter()
+        .filter_map(|a| match &a.action_type {
+            ActionType::CopySongs(copy) => Some(copy.weight),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let (_, node_attr) = nodes
+        .iter()
+        .find(|(name, _)| *name == *current_node)
+        .unwrap();
+    let has_limit = node_attr
+        .iter()
+        .any(|(k, _)| k == constants::LIMIT_ATTRIBUTE_KEY);
+    if weights.iter().any(|w| w.is_some()) {
+        if weights.iter().any(|w| w.is_none()) {
+            return Err(anyhow!(
+                "Some edges into node {:?} have a weight and some don't. Either all or none should have one",
+                current_node
+            ));
+        }
+
+        if !has_limit {
+            return Err(anyhow!(
+                "Edges into node {:?} have weights, but the node has no limit to split by them",
+                current_node
+            ));
+        }
+    }
+
+    // Songs of the other edges are matched like the node dedups its songs.
+    let dedup = parse_dedup(current_node, node_attr)?;
+
+    // Without union edges, the songs of the first edge are the ones the others are combined with.
+    let has_union = !final_node_actions.is_empty();
+    edges_with_op.sort_by_key(|(op, _)| *op);
+    for (i, (op, (n, _, attr))) in edges_with_op.into_iter().enumerate() {
+        let r = create_node_execution_plan(idx + 1, n, nodes, edges, graph, planned)?;
+        for action in r {
+            actions.push(action);
+        }
+
+        let filter = parse_filter(&format!("edge {} -> {}", n, current_node), attr)?;
+        let action_type = match op {
+            _ if i == 0 && !has_union => ActionType::CopySongs(CopySongs {
+                filter,
+                weight: None,
+            }),
+            EdgeOp::Xor => ActionType::XorSongs(CombineSongs { filter, dedup }),
+            EdgeOp::Intersect => ActionType::IntersectSongs(CombineSongs { filter, dedup }),
+            EdgeOp::Union => unreachable!("union edges are copied above"),
+        };
+
+        final_node_actions.push(Action {
+            action_type,
+            playlist_url: get_playlist_url(nodes, n),
+            node: n.clone(),
+            idx,
+            for_node: current_node.clone(),
+        });
+    }
+
+    for (n, _, attr) in edges_with_subtraction {
+        if attr.iter().any(|(k, _)| k == constants::OP_ATTRIBUTE_KEY) {
+            return Err(anyhow!(
+                "Edge {} -> {} subtracts songs and can't have an op",
+                n,
+                current_node
+            ));
+        }
+
+        if has_filter(attr) {
+            return Err(anyhow!(
+                "Edge {} -> {} subtracts songs and can't have filter attributes",
+                n,
+                current_node
+            ));
+        }
+
+        if attr
+            .iter()
+            .any(|(k, _)| k == constants::WEIGHT_ATTRIBUTE_KEY)
+        {
+            return Err(anyhow!(
+                "Edge {} -> {} subtracts songs and can't have a weight",
+                n,
+                current_node
+            ));
+        }
+
+        let r = create_node_execution_plan(idx + 1, n, nodes, edges, graph, planned)?;
         for action in r {
             actions.push(action);
         }
 
         let action = Action {
-            action_type: ActionType::RemoveSongs,
+            action_type: ActionType::RemoveSongs(dedup),
             node: n.clone(),
             idx,
             for_node: current_node.clone(),
@@ -219,12 +692,18 @@ fn create_node_execution_plan(
         .find(|(name, _)| *name == *current_node)
         .unwrap();
     let playlist_already_exists = attr.iter().find(|(k, _)| k == constants::URL_ATTRIBUTE_KEY);
+    let is_query = attr
+        .iter()
+        .any(|(k, v)| k == constants::TYPE_ATTRIBUTE_KEY && v == "query");
 
-    if !has_neighbors {
-        let is_query = attr
-            .iter()
-            .any(|(k, v)| k == constants::TYPE_ATTRIBUTE_KEY && v == "query");
+    if (has_neighbors || !is_query) && has_filter(attr) {
+        return Err(anyhow!(
+            "Node {:?} has filter attributes, but they are only allowed on query nodes and edges",
+            current_node
+        ));
+    }
 
+    if !has_neighbors {
         if !is_query {
             if playlist_already_exists.is_none() {
                 return Err(anyhow!(
@@ -250,38 +729,6 @@ fn create_node_execution_plan(
                 None => None,
             };
 
-            let artist_id = attr
-                .iter()
-                .find(|(k, _)| k == constants::ARTIST_ID_ATTRIBUTE_KEY)
-                .map(|(_, v)| v.clone());
-
-            let artist_id = match artist_id {
-                Some(v) => v,
-                None => {
-                    return Err(anyhow!(
-                        "Node {:?} is a query node and should have a artist_id attribute",
-                        current_node
-                    ));
-                }
-            };
-
-            let include_features = attr
-                .iter()
-                .find(|(k, _)| k == constants::INCLUDE_FEATURES_ATTRIBUTE_KEY)
-                .map(|(_, v)| v.as_str().parse::<bool>());
-
-            let include_features = match include_features {
-                Some(Ok(v)) => Some(v),
-                Some(Err(e)) => {
-                    return Err(anyhow!(
-                        "Failed to parse include_features attribute of node {:?} with error: {:?}",
-                        current_node,
-                        e
-                    ));
-                }
-                None => None,
-            };
-
             let source = attr
                 .iter()
                 .find(|(k, _)| k == constants::SOURCE_ATTRIBUTE_KEY)
@@ -299,16 +746,48 @@ fn create_node_execution_plan(
                 None => None,
             };
 
-            let query = QuerySongsByArtist {
-                artist_id,
-                include_features,
-                source,
-                must_be_liked,
+            let artist_id = attr
+                .iter()
+                .find(|(k, _)| k == constants::ARTIST_ID_ATTRIBUTE_KEY)
+                .map(|(_, v)| v.clone());
+
+            let genre = attr
+                .iter()
+                .find(|(k, _)| k == constants::GENRE_ATTRIBUTE_KEY)
+                .map(|(_, v)| v.clone());
+
+            let action_type = match (artist_id, genre) {
+                (Some(_), Some(_)) => {
+                    return Err(anyhow!(
+                        "Node {:?} is a query node and can only have one of the artist_id and genre attributes",
+                        current_node
+                    ));
+                }
+                (None, None) => {
+                    return Err(anyhow!(
+                        "Node {:?} is a query node and should have a artist_id or genre attribute",
+                        current_node
+                    ));
+                }
+                (None, Some(genre)) => ActionType::QuerySongsByGenre(parse_genre_query(
+                    current_node,
+                    attr,
+                    genre,
+                    source,
+                    must_be_liked,
+                )?),
+                (Some(artist_id), None) => ActionType::QuerySongsByArtist(parse_artist_query(
+                    current_node,
+                    attr,
+                    artist_id,
+                    source,
+                    must_be_liked,
+                )?),
             };
 
             let url = playlist_already_exists.map(|(_, url)| url.clone());
             final_node_actions.push(Action {
-                action_type: ActionType::QuerySongsByArtist(query),
+                action_type,
                 node: current_node.clone(),
                 idx,
                 for_node: current_node.clone(),
@@ -331,33 +810,42 @@ fn create_node_execution_plan(
 
         if has_neighbors || is_query_node {
             final_node_actions.push(Action {
-                action_type: ActionType::SaveChanges(Some(url.clone())),
+                action_type: ActionType::SaveChanges(parse_save_playlist(
+                    current_node,
+                    attr,
+                    Some(url.clone()),
+                )?),
                 node: current_node.clone(),
                 idx,
                 for_node: current_node.clone(),
                 playlist_url: Some(url.clone()),
             });
+        } else if let Some((key, _)) = attr
+            .iter()
+            .find(|(k, _)| constants::SAVE_ATTRIBUTE_KEYS.contains(&k.as_str()))
+        {
+            return Err(anyhow!(
+                "Node {:?} has a {} attribute, but mixify never saves to it, because it has no incoming edges",
+                current_node,
+                key
+            ));
         }
     } else {
-        if !playlists_created_memo.iter().any(|v| v == current_node) {
-            playlists_created_memo.push(current_node.clone());
-
-            actions.push(Action {
-                action_type: ActionType::CreatePlaylist,
-                node: current_node.clone(),
-                idx,
-                for_node: current_node.clone(),
-                playlist_url: None,
-            });
+        actions.push(Action {
+            action_type: ActionType::CreatePlaylist,
+            node: current_node.clone(),
+            idx,
+            for_node: current_node.clone(),
+            playlist_url: None,
+        });
 
-            final_node_actions.push(Action {
-                action_type: ActionType::SaveChanges(None),
-                node: current_node.clone(),
-                idx,
-                for_node: current_node.clone(),
-                playlist_url: None,
-            });
-        }
+        final_node_actions.push(Action {
+            action_type: ActionType::SaveChanges(parse_save_playlist(current_node, attr, None)?),
+            node: current_node.clone(),
+            idx,
+            for_node: current_node.clone(),
+            playlist_url: None,
+        });
     }
 
     for action in final_node_actions {
@@ -367,47 +855,272 @@ fn create_node_execution_plan(
     return Ok(actions);
 }
 
-fn validate_graph(graph: &GraphAST) -> Result<(), anyhow::Error> {
+/// Checks the whole graph and reports every problem at once, each with the line it occurs in.
+/// `content` is the source the graph was parsed from and is only used to find the lines.
+fn validate_graph(graph: &GraphAST, content: &str) -> Result<(), anyhow::Error> {
+    let locations = dot::locate_statements(content);
+    let locations_match = locations.len() == graph.stmt.len()
+        && graph
+            .stmt
+            .iter()
+            .zip(&locations)
+            .all(|(stmt, loc)| match stmt {
+                Stmt::Node(node, _) => *node == loc.from && loc.to.is_none(),
+                Stmt::Edge(from, to, _) => *from == loc.from && Some(to) == loc.to.as_ref(),
+                _ => true,
+            });
+
+    // Falls back to the statement number if the lines could not be found.
+    let position = |idx: usize| -> (usize, String) {
+        match locations_match {
+            true => (locations[idx].line, format!("line {}", locations[idx].line)),
+            false => (idx + 1, format!("statement {}", idx + 1)),
+        }
+    };
+
+    let mut problems: Vec<(usize, String)> = Vec::new();
     let mut nodes: Vec<String> = Vec::new();
-    let mut nodes_from_edges: Vec<String> = Vec::new();
+    let mut node_positions: HashMap<String, String> = HashMap::new();
+    let mut edges: Vec<(usize, &String, &String)> = Vec::new();
+    let mut edge_kinds: HashMap<(&String, &String), (String, String)> = HashMap::new();
+
+    let unknown_attributes =
+        |idx: usize, what: String, attrs: &Vec<(String, String)>, known: &[&str]| {
+            let mut problems = vec![];
+            for (k, _) in attrs {
+                if known.contains(&k.as_str())
+                    || constants::STYLE_ATTRIBUTE_KEYS.contains(&k.as_str())
+                {
+                    continue;
+                }
+
+                let (line, pos) = position(idx);
+                problems.push((
+                    line,
+                    format!(
+                        "{}: unknown attribute {:?} on {}. Known attributes are: {}",
+                        pos,
+                        k,
+                        what,
+                        known.join(", ")
+                    ),
+                ));
+            }
+
+            problems
+        };
 
-    graph.stmt.iter().for_each(|stmt| match stmt {
-        Stmt::Edge(from, to, _) => {
-            if !nodes_from_edges.contains(from) {
-                nodes_from_edges.push(from.to_string());
+    for (idx, stmt) in graph.stmt.iter().enumerate() {
+        match stmt {
+            Stmt::Node(node, attrs) => {
+                problems.extend(unknown_attributes(
+                    idx,
+                    format!("node {:?}", node),
+                    attrs,
+                    constants::NODE_ATTRIBUTE_KEYS,
+                ));
+
+                let (line, pos) = position(idx);
+                for (_, url) in attrs
+                    .iter()
+                    .filter(|(k, _)| k == constants::URL_ATTRIBUTE_KEY)
+                {
+                    if Url::parse(url).is_err() {
+                        problems.push((
+                            line,
+                            format!(
+                                "{}: the url attribute {:?} of node {:?} is not a valid url",
+                                pos, url, node
+                            ),
+                        ));
+                    }
+                }
+
+                if let Some(first) = node_positions.get(node) {
+                    problems.push((
+                        line,
+                        format!(
+                            "{}: node {:?} is defined more than once. It was first defined in {}",
+                            pos, node, first
+                        ),
+                    ));
+                    continue;
+                }
+
+                node_positions.insert(node.clone(), pos);
+                nodes.push(node.clone());
+            }
+            Stmt::Edge(from, to, attrs) => {
+                problems.extend(unknown_attributes(
+                    idx,
+                    format!("edge {} -> {}", from, to),
+                    attrs,
+                    constants::EDGE_ATTRIBUTE_KEYS,
+                ));
+                edges.push((idx, from, to));
+
+                let (line, pos) = position(idx);
+                if from == to {
+                    problems.push((
+                        line,
+                        format!("{}: edge {} -> {} points to itself", pos, from, to),
+                    ));
+                    continue;
+                }
+
+                let is_subtraction = attrs
+                    .iter()
+                    .any(|(k, v)| k == constants::SUBTRACT_ATTRIBUTE_KEY && v == "true");
+                let op = attrs
+                    .iter()
+                    .find(|(k, _)| k == constants::OP_ATTRIBUTE_KEY)
+                    .map(|(_, v)| v.to_lowercase());
+                let kind = match (is_subtraction, op) {
+                    (true, _) => String::from("subtract"),
+                    (false, Some(op)) => op,
+                    (false, None) => String::from("union"),
+                };
+
+                // Only the first definition of an edge is planned, so the others must not differ.
+                match edge_kinds.get(&(from, to)) {
+                    Some((first, first_kind)) if *first_kind != kind => {
+                        problems.push((
+                            line,
+                            format!(
+                                "{}: edge {} -> {} is defined as {} and as {} edge. The other definition is in {}",
+                                pos, from, to, first_kind, kind, first
+                            ),
+                        ));
+                    }
+                    Some((first, _)) => {
+                        log::warn!(
+                            "{}: edge {} -> {} is defined more than once. The other definition is in {}",
+                            pos, from, to, first
+                        );
+                    }
+                    None => {
+                        edge_kinds.insert((from, to), (pos, kind));
+                    }
+                }
             }
-            if !nodes_from_edges.contains(to) {
-                nodes_from_edges.push(to.to_string());
+            _ => {}
+        }
+    }
+
+    let mut undefined_nodes: HashSet<&String> = HashSet::new();
+    for (idx, from, to) in &edges {
+        for node in [from, to] {
+            if nodes.contains(node) || !undefined_nodes.insert(node) {
+                continue;
             }
+
+            let (line, pos) = position(*idx);
+            problems.push((
+                line,
+                format!(
+                    "{}: node {:?} is used for an edge but not defined as node in the graph file. Please define it. It should look like this: {} [label={:?}];",
+                    pos, node, node, "a playlist name of your choice"
+                ),
+            ));
         }
-        Stmt::Node(node, _) => {
-            nodes.push(node.to_string());
+    }
+
+    for (idx, cycle) in find_cycles(&nodes, &edges) {
+        let (line, pos) = position(idx);
+        problems.push((
+            line,
+            format!("{}: the edge closes the cycle {}", pos, cycle.join(" -> ")),
+        ));
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    problems.sort_by_key(|(line, _)| *line);
+    let problems = problems
+        .into_iter()
+        .map(|(_, problem)| format!("  {}", problem))
+        .collect::<Vec<_>>();
+
+    return Err(anyhow!(
+        "The graph is invalid. Found {} problem(s):\n{}",
+        problems.len(),
+        problems.join("\n")
+    ));
+}
+
+/// Returns every cycle closed by a back edge, as the statement index of that edge and the path of nodes.
+/// Self loops are ignored since they are reported separately.
+fn find_cycles(nodes: &[String], edges: &[(usize, &String, &String)]) -> Vec<(usize, Vec<String>)> {
+    fn visit(
+        node: &String,
+        edges: &[(usize, &String, &String)],
+        stack: &mut Vec<String>,
+        done: &mut HashSet<String>,
+        cycles: &mut Vec<(usize, Vec<String>)>,
+    ) {
+        stack.push(node.clone());
+
+        for (idx, _, to) in edges
+            .iter()
+            .filter(|(_, from, to)| *from == node && from != to)
+        {
+            if let Some(start) = stack.iter().position(|n| n == *to) {
+                let mut cycle = stack[start..].to_vec();
+                cycle.push(to.to_string());
+                cycles.push((*idx, cycle));
+            } else if !done.contains(*to) {
+                visit(to, edges, stack, done, cycles);
+            }
         }
-        _ => {}
-    });
 
-    for node in nodes_from_edges {
-        if !nodes.contains(&node) {
-            let error = anyhow::anyhow!(
-                "Node {:?} is used for an edge but not defined as node in the graph file. Please define it. It should look like this: {}",
-                node, format!("{} [label={:?}];", node, "a playlist name of your choice"));
+        stack.pop();
+        done.insert(node.clone());
+    }
 
-            return Err(error);
+    let mut cycles = vec![];
+    let mut done = HashSet::new();
+    for node in nodes {
+        if !done.contains(node) {
+            visit(node, edges, &mut vec![], &mut done, &mut cycles);
         }
     }
 
-    return Ok(());
+    return cycles;
+}
+
+/// Reads the edit file of the snapshot, or the post apply file if the snapshot has already been applied.
+pub fn read_latest_snapshot_file(snapshots_dir: &Path, id: u32) -> Result<String, anyhow::Error> {
+    let content = match read_snapshot_file(snapshots_dir, id, "edit") {
+        Ok(v) => v,
+        Err(err) => {
+            log::warn!("failed to find edit snapshot. see error: {:?}", err);
+            log::info!("trying to find post snapshot instead");
+
+            read_snapshot_file(snapshots_dir, id, "post.apply").or_error(format!(
+                "failed to find post snapshot. maybe this id {} doesn't exist?.",
+                id
+            ))?
+        }
+    };
+
+    return Ok(content);
 }
 
-pub fn read_snapshot_file(id: u32, suffix: &str) -> Result<String, anyhow::Error> {
-    let data = list_snapshot_content(id, suffix)?;
+pub fn read_snapshot_file(
+    snapshots_dir: &Path,
+    id: u32,
+    suffix: &str,
+) -> Result<String, anyhow::Error> {
+    let data = list_snapshot_content(snapshots_dir, id, suffix)?;
 
-    let directory_path = format!("snapshots/{}/", id);
-    if data.len() == 0 {
+    let directory_path = snapshots_dir.join(id.to_string());
+    if data.is_empty() {
         return Err(anyhow::anyhow!(
             "No *.{}.gv file found in {} folder",
             suffix,
-            &directory_path
+            directory_path.display()
         ));
     }
 
@@ -415,7 +1128,7 @@ pub fn read_snapshot_file(id: u32, suffix: &str) -> Result<String, anyhow::Error
         return Err(anyhow::anyhow!(
             "More than one *.{}.gv file found in {} folder. Expected only one since mixify doesn't know which one to use.",
             suffix,
-            &directory_path
+            directory_path.display()
         ));
     }
 
@@ -423,46 +1136,1040 @@ pub fn read_snapshot_file(id: u32, suffix: &str) -> Result<String, anyhow::Error
     return Ok(content);
 }
 
+/// Returns the path of the single *.{suffix}.gv file of the snapshot.
+pub fn find_snapshot_file(
+    snapshots_dir: &Path,
+    id: u32,
+    suffix: &str,
+) -> Result<PathBuf, anyhow::Error> {
+    let full_suffix = format!(".{}.gv", suffix);
+    let mut paths = list_snapshot_files(snapshots_dir, id, suffix)?
+        .into_iter()
+        .filter(|path| path.is_file() && path.to_str().unwrap().ends_with(full_suffix.as_str()))
+        .collect::<Vec<_>>();
+
+    if paths.len() != 1 {
+        return Err(anyhow::anyhow!(
+            "Expected exactly one *{} file in {} but found {}",
+            full_suffix,
+            snapshots_dir.join(id.to_string()).display(),
+            paths.len()
+        ));
+    }
+
+    return Ok(paths.remove(0));
+}
+
+/// Ids of all snapshot folders, in ascending order.
+pub fn list_snapshot_ids(snapshots_dir: &Path) -> Result<Vec<u32>, anyhow::Error> {
+    let mut ids = std::fs::read_dir(snapshots_dir)
+        .or_error_str("failed to read the snapshots folder")?
+        .filter_map(|entry| {
+            entry
+                .ok()?
+                .file_name()
+                .into_string()
+                .ok()?
+                .parse::<u32>()
+                .ok()
+        })
+        .collect::<Vec<_>>();
+    ids.sort();
+
+    return Ok(ids);
+}
+
 pub fn list_snapshot_content(
+    snapshots_dir: &Path,
     id: u32,
     suffix: &str,
 ) -> Result<Vec<io::Result<String>>, anyhow::Error> {
     let full_suffix = format!("{}.gv", suffix);
-    let data = list_snapshot_files(id, suffix)?
+    let data = list_snapshot_files(snapshots_dir, id, suffix)?
         .iter()
         .filter(|path| path.is_file() && path.to_str().unwrap().ends_with(full_suffix.as_str()))
-        .map(|file_path| fs::read_to_string(file_path))
+        .map(fs::read_to_string)
         .collect::<Vec<io::Result<String>>>();
 
     return Ok(data);
 }
 
 pub fn list_snapshot_files(
+    snapshots_dir: &Path,
     id: u32,
     suffix: &str,
-) -> Result<Vec<std::path::PathBuf>, anyhow::Error> {
-    let directory_path = format!("snapshots/{}/", id);
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let directory_path = snapshots_dir.join(id.to_string());
     log::info!(
         "checking directory: {} for *.{}.gv snapshot",
-        directory_path,
+        directory_path.display(),
         suffix
     );
 
     let data = std::fs::read_dir(&directory_path)
         .or_error(format!(
             "failed to find snapshot folder: {}. Maybe it's another id?",
-            directory_path
+            directory_path.display()
         ))?
         .map(|entry| entry.unwrap().path().canonicalize().unwrap())
-        .collect::<Vec<std::path::PathBuf>>();
+        .collect::<Vec<PathBuf>>();
 
     return Ok(data);
 }
 
-fn get_playlist_url(nodes: &Vec<NodeData>, node: &String) -> Option<String> {
+fn parse_artist_query(
+    node: &String,
+    attr: &[(String, String)],
+    artist_id: String,
+    source: Option<QuerySource>,
+    must_be_liked: Option<bool>,
+) -> Result<QuerySongsByArtist, anyhow::Error> {
+    let include_features = attr
+        .iter()
+        .find(|(k, _)| k == constants::INCLUDE_FEATURES_ATTRIBUTE_KEY)
+        .map(|(_, v)| v.as_str().parse::<bool>());
+
+    let include_features = match include_features {
+        Some(Ok(v)) => Some(v),
+        Some(Err(e)) => {
+            return Err(anyhow!(
+                "Failed to parse include_features attribute of node {:?} with error: {:?}",
+                node,
+                e
+            ));
+        }
+        None => None,
+    };
+
+    return Ok(QuerySongsByArtist {
+        artist_id,
+        include_features,
+        source,
+        must_be_liked,
+        filter: parse_filter(&format!("node {:?}", node), attr)?,
+    });
+}
+
+fn parse_save_playlist(
+    node: &String,
+    attr: &[(String, String)],
+    url: Option<String>,
+) -> Result<SavePlaylist, anyhow::Error> {
+    let dedup = parse_dedup(node, attr)?;
+
+    let prefer = attr
+        .iter()
+        .find(|(k, _)| k == constants::PREFER_ATTRIBUTE_KEY)
+        .map(|(_, v)| v.as_str().parse::<Prefer>());
+
+    let prefer = match prefer {
+        Some(Ok(v)) => v,
+        Some(Err(e)) => {
+            return Err(anyhow!(
+                "Failed to parse prefer attribute of node {:?} with error: {:?}",
+                node,
+                e
+            ));
+        }
+        None => Prefer::default(),
+    };
+
+    let order = attr
+        .iter()
+        .find(|(k, _)| k == constants::ORDER_ATTRIBUTE_KEY)
+        .map(|(_, v)| v.as_str().parse::<Order>());
+
+    let order = match order {
+        Some(Ok(v)) => v,
+        Some(Err(e)) => {
+            return Err(anyhow!(
+                "Failed to parse order attribute of node {:?} with error: {:?}",
+                node,
+                e
+            ));
+        }
+        None => Order::default(),
+    };
+
+    let find = |key: &str| attr.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+
+    let limit = match find(constants::LIMIT_ATTRIBUTE_KEY).map(|v| v.parse::<usize>()) {
+        Some(Ok(v)) if (1..=constants::MAX_PLAYLIST_SONGS).contains(&v) => Some(v),
+        Some(_) => {
+            return Err(anyhow!(
+                "Failed to parse limit attribute of node {:?}. It should be a number between 1 and {}",
+                node,
+                constants::MAX_PLAYLIST_SONGS
+            ));
+        }
+        None => None,
+    };
+
+    let pick = match find(constants::PICK_ATTRIBUTE_KEY).map(|v| v.parse::<Pick>()) {
+        Some(Ok(v)) => v,
+        Some(Err(e)) => {
+            return Err(anyhow!(
+                "Failed to parse pick attribute of node {:?} with error: {:?}",
+                node,
+                e
+            ));
+        }
+        None => Pick::default(),
+    };
+
+    let rotate = match find(constants::ROTATE_ATTRIBUTE_KEY) {
+        Some(v) => Some(parse_rotation(v).or_error(format!(
+            "Failed to parse rotate attribute of node {:?}. It should look like \"20/day\", but is {:?}",
+            node, v
+        ))?),
+        None => None,
+    };
+
+    let has_pick = find(constants::PICK_ATTRIBUTE_KEY).is_some() || rotate.is_some();
+    match (limit, &rotate) {
+        (None, _) if has_pick => {
+            return Err(anyhow!(
+                "Node {:?} has a pick or rotate attribute, but no limit to pick the songs for",
+                node
+            ));
+        }
+        (Some(limit), Some(rotation)) if rotation.songs > limit => {
+            return Err(anyhow!(
+                "Node {:?} rotates {} songs, but its limit is only {}",
+                node,
+                rotation.songs,
+                limit
+            ));
+        }
+        _ => {}
+    }
+
+    let interleave = match find(constants::INTERLEAVE_ATTRIBUTE_KEY).map(|v| v.parse::<bool>()) {
+        Some(Ok(v)) => v,
+        Some(Err(e)) => {
+            return Err(anyhow!(
+                "Failed to parse interleave attribute of node {:?} with error: {:?}",
+                node,
+                e
+            ));
+        }
+        None => false,
+    };
+
+    return Ok(SavePlaylist {
+        url,
+        dedup,
+        prefer,
+        order,
+        limit,
+        pick,
+        seed: find(constants::SEED_ATTRIBUTE_KEY)
+            .unwrap_or(node)
+            .to_string(),
+        rotate,
+        interleave,
+    });
+}
+
+fn parse_dedup(node: &String, attr: &[(String, String)]) -> Result<Dedup, anyhow::Error> {
+    let dedup = attr
+        .iter()
+        .find(|(k, _)| k == constants::DEDUP_ATTRIBUTE_KEY)
+        .map(|(_, v)| v.as_str().parse::<Dedup>());
+
+    return match dedup {
+        Some(Ok(v)) => Ok(v),
+        Some(Err(e)) => Err(anyhow!(
+            "Failed to parse dedup attribute of node {:?} with error: {:?}",
+            node,
+            e
+        )),
+        None => Ok(Dedup::default()),
+    };
+}
+
+/// Parses rotations like `20/day` or `5/12h`. The period is `hour`, `day`, `week` or a duration.
+fn parse_rotation(value: &str) -> Result<Rotation, anyhow::Error> {
+    let (songs, period) = value
+        .split_once('/')
+        .or_error_str("missing the / between songs and period")?;
+    let songs = songs.trim().parse::<usize>()?;
+    let period = match period.trim() {
+        "hour" => Some(chrono::Duration::hours(1)),
+        "day" => Some(chrono::Duration::days(1)),
+        "week" => Some(chrono::Duration::weeks(1)),
+        period => parse_duration(period),
+    }
+    .or_error_str("invalid period")?;
+
+    if songs == 0 || period <= chrono::Duration::zero() {
+        return Err(anyhow!("songs and period must be more than 0"));
+    }
+
+    return Ok(Rotation { songs, period });
+}
+
+fn parse_genre_query(
+    node: &String,
+    attr: &[(String, String)],
+    genre: String,
+    source: Option<QuerySource>,
+    must_be_liked: Option<bool>,
+) -> Result<QuerySongsByGenre, anyhow::Error> {
+    let genre_match = attr
+        .iter()
+        .find(|(k, _)| k == constants::GENRE_MATCH_ATTRIBUTE_KEY)
+        .map(|(_, v)| v.as_str().parse::<GenreMatch>());
+
+    let genre_match = match genre_match {
+        Some(Ok(v)) => v,
+        Some(Err(e)) => {
+            return Err(anyhow!(
+                "Failed to parse genre_match attribute of node {:?} with error: {:?}",
+                node,
+                e
+            ));
+        }
+        None => GenreMatch::default(),
+    };
+
+    // Checked here, so an invalid pattern fails the plan instead of the apply.
+    if genre_match == GenreMatch::Regex {
+        if let Err(e) = genre_regex(&genre) {
+            return Err(anyhow!(
+                "Failed to parse genre attribute of node {:?} as regex with error: {}",
+                node,
+                e
+            ));
+        }
+    }
+
+    return Ok(QuerySongsByGenre {
+        genre,
+        genre_match,
+        source,
+        must_be_liked,
+        filter: parse_filter(&format!("node {:?}", node), attr)?,
+    });
+}
+
+fn parse_edge_op(
+    from_node: &str,
+    to_node: &str,
+    attr: &[(String, String)],
+) -> Result<EdgeOp, anyhow::Error> {
+    let op = attr
+        .iter()
+        .find(|(k, _)| k == constants::OP_ATTRIBUTE_KEY)
+        .map(|(_, v)| v.as_str().parse::<EdgeOp>());
+
+    let op = match op {
+        Some(Ok(v)) => v,
+        Some(Err(e)) => {
+            return Err(anyhow!(
+                "Failed to parse op attribute of edge {} -> {} with error: {:?}",
+                from_node,
+                to_node,
+                e
+            ));
+        }
+        None => EdgeOp::default(),
+    };
+
+    let has_weight = attr
+        .iter()
+        .any(|(k, _)| k == constants::WEIGHT_ATTRIBUTE_KEY);
+    if op != EdgeOp::Union && has_weight {
+        return Err(anyhow!(
+            "Edge {} -> {} has a weight, but only union edges can have one",
+            from_node,
+            to_node
+        ));
+    }
+
+    return Ok(op);
+}
+
+fn parse_copy(
+    from_node: &str,
+    to_node: &str,
+    attr: &[(String, String)],
+) -> Result<CopySongs, anyhow::Error> {
+    let weight = attr
+        .iter()
+        .find(|(k, _)| k == constants::WEIGHT_ATTRIBUTE_KEY)
+        .map(|(_, v)| v.as_str());
+
+    let weight = match weight.map(|v| v.parse::<f32>()) {
+        Some(Ok(v)) if v.is_finite() && v > 0.0 => Some(v),
+        Some(_) => {
+            return Err(anyhow!(
+                "Failed to parse weight attribute of edge {} -> {}. It should be a number above 0, but is {:?}",
+                from_node,
+                to_node,
+                weight.unwrap()
+            ));
+        }
+        None => None,
+    };
+
+    return Ok(CopySongs {
+        filter: parse_filter(&format!("edge {} -> {}", from_node, to_node), attr)?,
+        weight,
+    });
+}
+
+fn has_filter(attr: &[(String, String)]) -> bool {
+    attr.iter()
+        .any(|(k, _)| constants::FILTER_ATTRIBUTE_KEYS.contains(&k.as_str()))
+}
+
+/// Parses durations like `90s`, `3m30s`, `6m`, `12h` or `30d`. A number without unit is in seconds.
+fn parse_duration(value: &str) -> Option<chrono::Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u32>() {
+        return Some(chrono::Duration::seconds(seconds as i64));
+    }
+
+    let mut total = chrono::Duration::zero();
+    let mut number = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let n = number.parse::<i64>().ok()?;
+        number.clear();
+        total = total
+            + match c {
+                'w' => chrono::Duration::weeks(n),
+                'd' => chrono::Duration::days(n),
+                'h' => chrono::Duration::hours(n),
+                'm' => chrono::Duration::minutes(n),
+                's' => chrono::Duration::seconds(n),
+                _ => return None,
+            };
+    }
+
+    // Every number needs a unit.
+    if !number.is_empty() {
+        return None;
+    }
+
+    return Some(total);
+}
+
+/// The filter set by the attributes of a query node or edge. `what` names it for errors.
+fn parse_filter(
+    what: &str,
+    attr: &[(String, String)],
+) -> Result<Option<SongFilter>, anyhow::Error> {
+    if !has_filter(attr) {
+        return Ok(None);
+    }
+
+    let find = |key: &str| attr.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+
+    // Tempos have no upper bound, all other features go up to 1.
+    let number = |key: &str, max: Option<f32>| {
+        let Some(value) = find(key) else {
+            return Ok(None);
+        };
+
+        match value.parse::<f32>() {
+            Ok(n) if n >= 0.0 && max.is_none_or(|max| n <= max) => Ok(Some(n)),
+            _ => Err(anyhow!(
+                "Failed to parse {} attribute of {}. It should be a number between 0 and {}, but is {:?}",
+                key,
+                what,
+                max.map(|max| max.to_string()).unwrap_or("infinity".to_string()),
+                value
+            )),
+        }
+    };
+
+    let key = match find(constants::KEY_ATTRIBUTE_KEY) {
+        Some(value) => match value.parse::<i32>() {
+            Ok(key) if (0..=11).contains(&key) => Some(key),
+            _ => {
+                return Err(anyhow!(
+                    "Failed to parse key attribute of {}. It should be a pitch class between 0 and 11, but is {:?}",
+                    what,
+                    value
+                ));
+            }
+        },
+        None => None,
+    };
+
+    let mode = match find(constants::MODE_ATTRIBUTE_KEY).map(|v| v.parse::<Mode>()) {
+        Some(Ok(v)) => Some(v),
+        Some(Err(e)) => {
+            return Err(anyhow!(
+                "Failed to parse mode attribute of {} with error: {:?}",
+                what,
+                e
+            ));
+        }
+        None => None,
+    };
+
+    let date = |key: &str| {
+        let Some(value) = find(key) else {
+            return Ok(None);
+        };
+
+        match types::parse_release_date(value) {
+            Some(date) => Ok(Some(date)),
+            None => Err(anyhow!(
+                "Failed to parse {} attribute of {}. It should be a date like \"2020-01-31\", but is {:?}",
+                key,
+                what,
+                value
+            )),
+        }
+    };
+
+    let popularity = |key: &str| {
+        let Some(value) = find(key) else {
+            return Ok(None);
+        };
+
+        match value.parse::<u32>() {
+            Ok(n) if n <= 100 => Ok(Some(n)),
+            _ => Err(anyhow!(
+                "Failed to parse {} attribute of {}. It should be a number between 0 and 100, but is {:?}",
+                key,
+                what,
+                value
+            )),
+        }
+    };
+
+    let duration = |key: &str| {
+        let Some(value) = find(key) else {
+            return Ok(None);
+        };
+
+        match parse_duration(value) {
+            Some(duration) => Ok(Some(duration)),
+            None => Err(anyhow!(
+                "Failed to parse {} attribute of {}. It should be a duration like \"3m30s\" or \"30d\", but is {:?}",
+                key,
+                what,
+                value
+            )),
+        }
+    };
+
+    let explicit = match find(constants::EXPLICIT_ATTRIBUTE_KEY).map(|v| v.parse::<bool>()) {
+        Some(Ok(v)) => Some(v),
+        Some(Err(e)) => {
+            return Err(anyhow!(
+                "Failed to parse explicit attribute of {} with error: {:?}",
+                what,
+                e
+            ));
+        }
+        None => None,
+    };
+
+    let filter = SongFilter {
+        released_after: date(constants::RELEASED_AFTER_ATTRIBUTE_KEY)?,
+        released_before: date(constants::RELEASED_BEFORE_ATTRIBUTE_KEY)?,
+        min_popularity: popularity(constants::MIN_POPULARITY_ATTRIBUTE_KEY)?,
+        max_popularity: popularity(constants::MAX_POPULARITY_ATTRIBUTE_KEY)?,
+        min_duration: duration(constants::MIN_DURATION_ATTRIBUTE_KEY)?,
+        max_duration: duration(constants::MAX_DURATION_ATTRIBUTE_KEY)?,
+        explicit,
+        added_within: duration(constants::ADDED_WITHIN_ATTRIBUTE_KEY)?,
+        min_tempo: number(constants::MIN_TEMPO_ATTRIBUTE_KEY, None)?,
+        max_tempo: number(constants::MAX_TEMPO_ATTRIBUTE_KEY, None)?,
+        min_energy: number(constants::MIN_ENERGY_ATTRIBUTE_KEY, Some(1.0))?,
+        max_energy: number(constants::MAX_ENERGY_ATTRIBUTE_KEY, Some(1.0))?,
+        min_danceability: number(constants::MIN_DANCEABILITY_ATTRIBUTE_KEY, Some(1.0))?,
+        max_danceability: number(constants::MAX_DANCEABILITY_ATTRIBUTE_KEY, Some(1.0))?,
+        min_valence: number(constants::MIN_VALENCE_ATTRIBUTE_KEY, Some(1.0))?,
+        max_valence: number(constants::MAX_VALENCE_ATTRIBUTE_KEY, Some(1.0))?,
+        min_instrumentalness: number(constants::MIN_INSTRUMENTALNESS_ATTRIBUTE_KEY, Some(1.0))?,
+        max_instrumentalness: number(constants::MAX_INSTRUMENTALNESS_ATTRIBUTE_KEY, Some(1.0))?,
+        key,
+        mode,
+    };
+
+    let possible =
+        |min: Option<f32>, max: Option<f32>| min.zip(max).is_none_or(|(min, max)| min <= max);
+    let bounds = [
+        ("tempo", possible(filter.min_tempo, filter.max_tempo)),
+        ("energy", possible(filter.min_energy, filter.max_energy)),
+        (
+            "danceability",
+            possible(filter.min_danceability, filter.max_danceability),
+        ),
+        ("valence", possible(filter.min_valence, filter.max_valence)),
+        (
+            "instrumentalness",
+            possible(filter.min_instrumentalness, filter.max_instrumentalness),
+        ),
+        (
+            "popularity",
+            filter
+                .min_popularity
+                .zip(filter.max_popularity)
+                .is_none_or(|(min, max)| min <= max),
+        ),
+        (
+            "duration",
+            filter
+                .min_duration
+                .zip(filter.max_duration)
+                .is_none_or(|(min, max)| min <= max),
+        ),
+    ];
+    for (feature, is_possible) in bounds {
+        if !is_possible {
+            return Err(anyhow!(
+                "min_{} is bigger than max_{} on {}, so no song can pass",
+                feature,
+                feature,
+                what
+            ));
+        }
+    }
+
+    if let (Some(after), Some(before)) = (filter.released_after, filter.released_before) {
+        if after >= before {
+            return Err(anyhow!(
+                "released_after is not before released_before on {}, so no song can pass",
+                what
+            ));
+        }
+    }
+
+    return Ok(Some(filter));
+}
+
+/// Genres are matched ignoring case, like for the other match kinds.
+pub fn genre_regex(pattern: &str) -> Result<regex::Regex, regex::Error> {
+    regex::RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+}
+
+fn get_playlist_url(nodes: &[NodeData], node: &String) -> Option<String> {
     let (_, attr) = nodes.iter().find(|(name, _)| *name == *node).unwrap();
     return attr
         .iter()
         .find(|(k, _)| k == constants::URL_ATTRIBUTE_KEY)
         .map(|(_, url)| url.clone());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expressions(content: &str) -> Vec<(String, String)> {
+        let gv = graphviz_dot_parser::parse(content).unwrap();
+        let (plan, _) = create_execution_plan(&gv, content).unwrap();
+        return node_expressions(&plan);
+    }
+
+    /// The problems validate_graph reports, one per line.
+    fn problems(content: &str) -> Vec<String> {
+        let gv = graphviz_dot_parser::parse(content).unwrap();
+        let error = validate_graph(&gv, content).unwrap_err().to_string();
+        return error
+            .lines()
+            .skip(1)
+            .map(|l| l.trim().to_string())
+            .collect();
+    }
+
+    #[test]
+    fn valid_graphs_have_no_problems() {
+        let content = r#"digraph G {
+            A [URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA", color="red"];
+            B [type="artist", artist_id="abc"];
+            Mix [dedup="isrc"];
+            A -> Mix;
+            B -> Mix [subtract="true", min_tempo="120"];
+        }"#;
+        let gv = graphviz_dot_parser::parse(content).unwrap();
+        assert!(validate_graph(&gv, content).is_ok());
+    }
+
+    #[test]
+    fn reports_cycles_at_the_closing_edge() {
+        let content = "digraph G {
+            A;
+            B;
+            C;
+            A -> B;
+            B -> C;
+            C -> A;
+        }";
+        assert_eq!(
+            problems(content),
+            vec!["line 7: the edge closes the cycle A -> B -> C -> A"]
+        );
+    }
+
+    #[test]
+    fn reports_self_loops_but_not_as_cycle() {
+        let content = "digraph G {
+            A;
+            A -> A;
+        }";
+        assert_eq!(
+            problems(content),
+            vec!["line 3: edge A -> A points to itself"]
+        );
+    }
+
+    #[test]
+    fn reports_duplicate_nodes_and_undefined_nodes() {
+        let content = "digraph G {
+            A;
+            A [label=\"a\"];
+            A -> B;
+        }";
+        assert_eq!(
+            problems(content),
+            vec![
+                "line 3: node \"A\" is defined more than once. It was first defined in line 2",
+                "line 4: node \"B\" is used for an edge but not defined as node in the graph file. Please define it. It should look like this: B [label=\"a playlist name of your choice\"];",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_edges_of_the_same_kind_are_no_problem() {
+        let content = "digraph G {
+            A;
+            B;
+            A -> B [subtract=\"true\"];
+            A -> B [subtract=\"true\", color=\"red\"];
+        }";
+        let gv = graphviz_dot_parser::parse(content).unwrap();
+        assert!(validate_graph(&gv, content).is_ok());
+    }
+
+    #[test]
+    fn reports_unknown_attributes_sorted_by_line() {
+        let content = "digraph G {
+            A -> B [colour=\"red\"];
+            A [tempo=\"120\"];
+            B [URL=\"not a url\"];
+        }";
+        let problems = problems(content);
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].starts_with(
+            "line 2: unknown attribute \"colour\" on edge A -> B. Known attributes are: subtract, label"
+        ));
+        assert!(problems[1].starts_with(
+            "line 3: unknown attribute \"tempo\" on node \"A\". Known attributes are: URL, label"
+        ));
+        assert_eq!(
+            problems[2],
+            "line 4: the url attribute \"not a url\" of node \"B\" is not a valid url"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_statement_numbers_without_lines() {
+        let gv = graphviz_dot_parser::parse("digraph G { A; A -> A; }").unwrap();
+        let error = validate_graph(&gv, "").unwrap_err().to_string();
+        assert!(error.contains("statement 2: edge A -> A points to itself"));
+    }
+
+    #[test]
+    fn find_cycles_returns_every_closing_edge() {
+        let nodes = ["A", "B", "C", "D"].map(String::from);
+        let [a, b, c, d] = &nodes;
+
+        // A diamond has no cycle.
+        let edges = vec![(0, a, b), (1, a, c), (2, b, d), (3, c, d)];
+        assert!(find_cycles(&nodes, &edges).is_empty());
+
+        let edges = vec![
+            (0, a, b),
+            (1, b, a),
+            (2, b, c),
+            (3, c, c),
+            (4, c, d),
+            (5, d, b),
+        ];
+        assert_eq!(
+            find_cycles(&nodes, &edges),
+            vec![
+                (1, vec![a.clone(), b.clone(), a.clone()]),
+                (5, vec![b.clone(), c.clone(), d.clone(), b.clone()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn expressions_group_the_edges_by_op() {
+        let content = r#"digraph G {
+            A [URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA"];
+            B [URL="https://open.spotify.com/playlist/pBBBBBBBBBBBBBBBBBBBBB"];
+            C [URL="https://open.spotify.com/playlist/pCCCCCCCCCCCCCCCCCCCCC"];
+            D [URL="https://open.spotify.com/playlist/pDDDDDDDDDDDDDDDDDDDDD"];
+            E [URL="https://open.spotify.com/playlist/pEEEEEEEEEEEEEEEEEEEEE"];
+            Mix;
+            A -> Mix;
+            B -> Mix [op="xor"];
+            C -> Mix [op="intersect"];
+            D -> Mix [subtract="true"];
+            E -> Mix;
+        }"#;
+
+        // The edges of an op are listed the last defined first.
+        assert_eq!(
+            expressions(content),
+            vec![(String::from("Mix"), String::from("(((E ∪ A) ⊕ B) ∩ C) − D"))]
+        );
+    }
+
+    #[test]
+    fn expressions_start_with_the_first_op_edge_without_unions() {
+        let content = r#"digraph G {
+            A [URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA"];
+            B [URL="https://open.spotify.com/playlist/pBBBBBBBBBBBBBBBBBBBBB"];
+            C [URL="https://open.spotify.com/playlist/pCCCCCCCCCCCCCCCCCCCCC"];
+            Mix;
+            A -> Mix [op="intersect"];
+            B -> Mix [op="intersect"];
+            C -> Mix [subtract="true"];
+        }"#;
+
+        assert_eq!(
+            expressions(content),
+            vec![(String::from("Mix"), String::from("(B ∩ A) − C"))]
+        );
+    }
+
+    /// The nodes worked on in each stage, sorted by name.
+    fn stage_nodes(content: &str) -> Vec<Vec<String>> {
+        let gv = graphviz_dot_parser::parse(content).unwrap();
+        let (stages, _) = create_execution_plan(&gv, content).unwrap();
+        stages
+            .iter()
+            .map(|stage| {
+                let mut nodes = stage.iter().map(|a| a.for_node.clone()).collect::<Vec<_>>();
+                nodes.sort();
+                nodes.dedup();
+                nodes
+            })
+            .collect()
+    }
+
+    #[test]
+    fn independent_nodes_share_a_stage() {
+        let content = r#"digraph G {
+            A [URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA"];
+            B [URL="https://open.spotify.com/playlist/pBBBBBBBBBBBBBBBBBBBBB"];
+            C [URL="https://open.spotify.com/playlist/pCCCCCCCCCCCCCCCCCCCCC"];
+            First;
+            Second;
+            Both;
+            A -> First;
+            B -> First;
+            C -> Second;
+            First -> Both;
+            Second -> Both;
+            A -> Both;
+        }"#;
+
+        assert_eq!(
+            stage_nodes(content),
+            vec![
+                vec!["A", "B", "C"],
+                vec!["First", "Second"],
+                vec!["Both"],
+                // The root only ties the nodes nothing depends on together.
+                vec![constants::MIXIFY_TEMPORARY_ROOT_NODE_NAME],
+            ]
+        );
+    }
+
+    #[test]
+    fn a_node_feeding_several_nodes_is_planned_once() {
+        let (_, _, plan) = plan(
+            r#"digraph G {
+                A [URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA"];
+                Shared [URL="https://open.spotify.com/playlist/pSSSSSSSSSSSSSSSSSSSSS"];
+                First;
+                Second;
+                A -> Shared;
+                Shared -> First;
+                Shared -> Second;
+            }"#,
+        );
+
+        let shared = plan
+            .iter()
+            .flatten()
+            .filter(|a| a.node == "Shared")
+            .map(|a| match a.action_type {
+                ActionType::QuerySongs(_) => "query",
+                ActionType::SaveChanges(_) => "save",
+                ActionType::CopySongs(_) => "copy",
+                _ => "other",
+            })
+            .collect::<Vec<_>>();
+        // Queried and saved once, then only copied into both nodes.
+        assert_eq!(shared, vec!["query", "save", "copy", "copy"]);
+
+        let stage_of = |node: &str| {
+            plan.iter()
+                .position(|stage| stage.iter().any(|a| a.for_node == node))
+                .unwrap()
+        };
+        assert!(stage_of("Shared") < stage_of("First"));
+        assert!(stage_of("Shared") < stage_of("Second"));
+    }
+
+    #[test]
+    fn node_depth_is_the_longest_path_from_a_base_node() {
+        let mut graph = petgraph::Graph::<String, ()>::new();
+        let [a, b, c] = ["A", "B", "C"].map(|n| graph.add_node(n.to_string()));
+        graph.add_node(String::from("D"));
+        graph.add_edge(a, b, ());
+        graph.add_edge(b, c, ());
+        graph.add_edge(a, c, ());
+
+        let mut depths = HashMap::new();
+        let depth = |n: &str, depths: &mut HashMap<String, usize>| {
+            node_depth(&n.to_string(), &graph, depths)
+        };
+        assert_eq!(depth("C", &mut depths), 2);
+        assert_eq!(depth("B", &mut depths), 1);
+        assert_eq!(depth("A", &mut depths), 0);
+        assert_eq!(depth("D", &mut depths), 0);
+        assert_eq!(depths.len(), 4);
+    }
+
+    const RENDERED_GRAPH: &str = r#"digraph G {
+        A [label="Rock \"Classics\"", URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA"];
+        B [type="query", artist_id="artistBBBBBBBBBBBBBBBB"];
+        Mix [label="My Mix"];
+        A -> Mix;
+        B -> Mix [subtract="true"];
+    }"#;
+
+    fn plan(content: &str) -> (GraphAST, Vec<NodeData>, Vec<Vec<Action>>) {
+        let gv = graphviz_dot_parser::parse(content).unwrap();
+        let (plan, nodes) = create_execution_plan(&gv, content).unwrap();
+        return (gv, nodes, plan);
+    }
+
+    #[test]
+    fn renders_the_plan_as_dot() {
+        let (gv, nodes, plan) = plan(RENDERED_GRAPH);
+        let expected = r##"digraph G {
+    "A" [label="Rock \"Classics\"\n(query)", style=filled, fillcolor="#9ecae1"];
+    "B" [label="B\n(create)", style=filled, fillcolor="#a1d99b"];
+    "Mix" [label="My Mix\n(create)", style=filled, fillcolor="#a1d99b"];
+    "A" -> "Mix";
+    "B" -> "Mix" [color=red, fontcolor=red, label="subtract"];
+}
+"##;
+        assert_eq!(render_dot(&gv, &nodes, &plan), expected);
+    }
+
+    #[test]
+    fn renders_the_plan_as_mermaid() {
+        let (gv, nodes, plan) = plan(RENDERED_GRAPH);
+        let expected = r##"flowchart LR
+    n0["Rock #quot;Classics#quot;<br/>(query)"]:::query
+    n1["B<br/>(create)"]:::create
+    n2["My Mix<br/>(create)"]:::create
+    n0 --> n2
+    n1 -- subtract --> n2
+    classDef query fill:#9ecae1
+    classDef save fill:#fdae6b
+    classDef create fill:#a1d99b
+    linkStyle 1 stroke:red,color:red
+"##;
+        assert_eq!(render_mermaid(&gv, &nodes, &plan), expected);
+    }
+
+    #[test]
+    fn renders_the_plan_as_json() {
+        let (_, _, plan) = plan(
+            r#"digraph G {
+                A [URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA"];
+                Mix;
+                A -> Mix;
+            }"#,
+        );
+        let save = |seed: &str| {
+            return serde_json::json!({
+                "SaveChanges": {
+                    "url": null,
+                    "dedup": "isrc",
+                    "prefer": "single",
+                    "order": "keep",
+                    "limit": null,
+                    "pick": "random",
+                    "seed": seed,
+                    "rotate": null,
+                    "interleave": false
+                }
+            });
+        };
+        let url = "https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA";
+        let copy = serde_json::json!({ "CopySongs": { "filter": null, "weight": null } });
+        let expected = serde_json::json!([
+            [
+                { "action_type": { "QuerySongs": url }, "node": "A", "for_node": "A", "idx": 3, "playlist_url": url },
+            ],
+            [
+                { "action_type": "CreatePlaylist", "node": "Mix", "for_node": "Mix", "idx": 2, "playlist_url": null },
+                { "action_type": copy, "node": "A", "for_node": "Mix", "idx": 2, "playlist_url": url },
+                { "action_type": save("Mix"), "node": "Mix", "for_node": "Mix", "idx": 2, "playlist_url": null },
+            ],
+        ]);
+        let json = serde_json::to_value(without_root_node(plan)).unwrap();
+        assert_eq!(json, expected);
+        assert!(!json
+            .to_string()
+            .contains(constants::MIXIFY_TEMPORARY_ROOT_NODE_NAME));
+    }
+
+    #[test]
+    fn parallel_edges_with_different_ops_are_problems() {
+        let content = r#"digraph G {
+            A [URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA"];
+            Mix;
+            A -> Mix;
+            A -> Mix [op="intersect"];
+        }"#;
+        let gv = graphviz_dot_parser::parse(content).unwrap();
+
+        let error = create_execution_plan(&gv, content).unwrap_err().to_string();
+        assert!(error.contains(
+            "line 5: edge A -> Mix is defined as union and as intersect edge. The other definition is in line 4"
+        ));
+    }
+
+    #[test]
+    fn parse_duration_reads_seconds_and_units() {
+        assert_eq!(parse_duration("90"), Some(chrono::Duration::seconds(90)));
+        assert_eq!(parse_duration(" 2w "), Some(chrono::Duration::weeks(2)));
+        assert_eq!(
+            parse_duration("1d12h30m"),
+            Some(chrono::Duration::minutes(36 * 60 + 30))
+        );
+    }
+
+    #[test]
+    fn parse_duration_rejects_numbers_without_units() {
+        assert_eq!(parse_duration("1d12"), None);
+        assert_eq!(parse_duration("3y"), None);
+        assert_eq!(parse_duration("d"), None);
+        assert_eq!(parse_duration("-5"), None);
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rspotify::http::HttpError;
+use rspotify::model::{
+    ArtistId, AudioFeatures, FullArtist, Page, PlayableId, PlaylistId, PlaylistItem, SavedAlbum,
+    SavedTrack, SimplifiedPlaylist, TrackId, UserId,
+};
+use rspotify::{ClientError, ClientResult};
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+
+use crate::backend::MusicBackend;
+use crate::constants;
+use crate::types::RetrySummary;
+
+/// Why a request failed, and whether it is worth sending again.
+enum Failure {
+    /// Spotify answered with 429. Holds how long spotify asked to wait, if it said so.
+    RateLimited(Option<Duration>),
+
+    /// A 5xx or network error, which usually goes away on its own.
+    Transient,
+    Permanent,
+}
+
+fn classify(error: &ClientError) -> Failure {
+    match error {
+        ClientError::Http(http) => match http.as_ref() {
+            HttpError::StatusCode(response) => {
+                let status = response.status();
+                if status.as_u16() == 429 {
+                    let retry_after = response
+                        .headers()
+                        .get("retry-after")
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.trim().parse::<u64>().ok())
+                        .map(Duration::from_secs);
+                    Failure::RateLimited(retry_after)
+                } else if status.is_server_error() {
+                    Failure::Transient
+                } else {
+                    Failure::Permanent
+                }
+            }
+            HttpError::Client(_) => Failure::Transient,
+        },
+        ClientError::Io(_) => Failure::Transient,
+        _ => Failure::Permanent,
+    }
+}
+
+/// Some calls change the library. Sending them again after a 5xx could apply them twice,
+/// so they are only retried when spotify rejected them with a 429.
+#[derive(Clone, Copy, PartialEq)]
+enum Retry {
+    Always,
+    OnlyRateLimited,
+}
+
+/// Wraps a backend so every call goes through one shared request budget and failed calls
+/// are retried. Honors the `Retry-After` of 429 responses by pausing all calls, and backs off
+/// exponentially with jitter on 5xx and network errors.
+///
+/// The budget is shared by all tasks using the backend, e.g. the ones spawned to fetch playlists.
+pub struct RateLimitedBackend {
+    inner: Arc<dyn MusicBackend>,
+    in_flight: Semaphore,
+
+    /// When the last requests were sent, to stay within `REQUEST_BUDGET` per `REQUEST_BUDGET_WINDOW`.
+    sent: Mutex<VecDeque<Instant>>,
+
+    /// No request is sent before this, set when spotify asks to slow down.
+    paused_until: Mutex<Option<Instant>>,
+    summary: Mutex<RetrySummary>,
+}
+
+impl RateLimitedBackend {
+    pub fn new(inner: Arc<dyn MusicBackend>) -> Self {
+        Self {
+            inner,
+            in_flight: Semaphore::new(constants::MAX_REQUESTS_IN_FLIGHT),
+            sent: Mutex::new(VecDeque::new()),
+            paused_until: Mutex::new(None),
+            summary: Mutex::new(RetrySummary::default()),
+        }
+    }
+
+    /// Waits until the budget allows another request and books it.
+    async fn wait_for_budget(&self) {
+        loop {
+            let paused_until = *self.paused_until.lock().unwrap();
+            if let Some(until) = paused_until {
+                if until > Instant::now() {
+                    tokio::time::sleep_until(until).await;
+                    continue;
+                }
+            }
+
+            let wait = {
+                let mut sent = self.sent.lock().unwrap();
+                let now = Instant::now();
+                while sent
+                    .front()
+                    .is_some_and(|t| now.duration_since(*t) >= constants::REQUEST_BUDGET_WINDOW)
+                {
+                    sent.pop_front();
+                }
+
+                if sent.len() < constants::REQUEST_BUDGET {
+                    sent.push_back(now);
+                    return;
+                }
+
+                constants::REQUEST_BUDGET_WINDOW - now.duration_since(*sent.front().unwrap())
+            };
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn pause(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut paused_until = self.paused_until.lock().unwrap();
+        if paused_until.is_none_or(|current| current < until) {
+            *paused_until = Some(until);
+        }
+    }
+
+    /// Sends the request until it succeeds, fails permanently or runs out of retries.
+    /// `failure` picks the error to look at from the result, if there is one.
+    async fn send<R, F, Fut>(
+        &self,
+        name: &str,
+        retry: Retry,
+        request: F,
+        failure: impl Fn(&R) -> Option<&ClientError>,
+    ) -> R
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = R>,
+    {
+        let mut attempt = 0;
+        loop {
+            let result = {
+                let _permit = self.in_flight.acquire().await.unwrap();
+                self.wait_for_budget().await;
+                self.summary.lock().unwrap().requests += 1;
+                request().await
+            };
+
+            let Some(error) = failure(&result) else {
+                return result;
+            };
+
+            let wait = match (classify(error), retry) {
+                (Failure::RateLimited(retry_after), _) => {
+                    let wait = retry_after.unwrap_or_else(|| backoff(attempt));
+                    self.pause(wait);
+                    self.summary.lock().unwrap().rate_limited += 1;
+                    Some(wait)
+                }
+                (Failure::Transient, Retry::Always) => Some(backoff(attempt)),
+                _ => None,
+            };
+
+            let wait = match wait {
+                Some(wait) if attempt < constants::MAX_RETRIES => wait,
+                _ => {
+                    self.summary.lock().unwrap().failed += 1;
+                    return result;
+                }
+            };
+
+            attempt += 1;
+            log::warn!(
+                "{} failed: {}. Retrying in {}ms ({}/{})",
+                name,
+                error,
+                wait.as_millis(),
+                attempt,
+                constants::MAX_RETRIES
+            );
+            {
+                let mut summary = self.summary.lock().unwrap();
+                summary.retries += 1;
+                summary.waited += wait;
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    async fn send_one<T, F, Fut>(&self, name: &str, retry: Retry, request: F) -> ClientResult<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = ClientResult<T>>,
+    {
+        self.send(name, retry, request, |r| r.as_ref().err()).await
+    }
+}
+
+/// Exponential backoff with full jitter, so tasks that failed together don't retry together.
+fn backoff(attempt: u32) -> Duration {
+    let max = constants::RETRY_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(constants::RETRY_MAX_DELAY);
+    let random = RandomState::new().build_hasher().finish();
+
+    return max
+        .mul_f64((random % 1000) as f64 / 1000.0)
+        .max(Duration::from_millis(100));
+}
+
+#[async_trait]
+impl MusicBackend for RateLimitedBackend {
+    async fn current_user_id(&self) -> ClientResult<UserId<'static>> {
+        self.send_one("fetching the user", Retry::Always, || {
+            self.inner.current_user_id()
+        })
+        .await
+    }
+
+    async fn playlist_items_page(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<PlaylistItem>> {
+        self.send_one("fetching playlist items", Retry::Always, || {
+            self.inner
+                .playlist_items_page(playlist_id.clone(), limit, offset)
+        })
+        .await
+    }
+
+    async fn current_user_playlists_page(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<SimplifiedPlaylist>> {
+        self.send_one("fetching playlists", Retry::Always, || {
+            self.inner.current_user_playlists_page(limit, offset)
+        })
+        .await
+    }
+
+    async fn current_user_saved_albums_page(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<SavedAlbum>> {
+        self.send_one("fetching saved albums", Retry::Always, || {
+            self.inner.current_user_saved_albums_page(limit, offset)
+        })
+        .await
+    }
+
+    async fn current_user_saved_tracks_page(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> ClientResult<Page<SavedTrack>> {
+        self.send_one("fetching liked songs", Retry::Always, || {
+            self.inner.current_user_saved_tracks_page(limit, offset)
+        })
+        .await
+    }
+
+    async fn user_playlist_create(
+        &self,
+        user_id: UserId<'static>,
+        name: &str,
+        description: &str,
+    ) -> ClientResult<PlaylistId<'static>> {
+        self.send_one("creating a playlist", Retry::OnlyRateLimited, || {
+            self.inner
+                .user_playlist_create(user_id.clone(), name, description)
+        })
+        .await
+    }
+
+    async fn playlist_add_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        items: Vec<PlayableId<'static>>,
+    ) -> ClientResult<()> {
+        self.send_one("adding songs", Retry::OnlyRateLimited, || {
+            self.inner
+                .playlist_add_items(playlist_id.clone(), clone_items(&items))
+        })
+        .await
+    }
+
+    async fn playlist_remove_all_occurrences_of_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        items: Vec<PlayableId<'static>>,
+    ) -> ClientResult<()> {
+        self.send_one("removing songs", Retry::Always, || {
+            self.inner
+                .playlist_remove_all_occurrences_of_items(playlist_id.clone(), clone_items(&items))
+        })
+        .await
+    }
+
+    async fn playlist_replace_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        items: Vec<PlayableId<'static>>,
+    ) -> ClientResult<()> {
+        self.send_one("replacing songs", Retry::Always, || {
+            self.inner
+                .playlist_replace_items(playlist_id.clone(), clone_items(&items))
+        })
+        .await
+    }
+
+    async fn playlist_reorder_items(
+        &self,
+        playlist_id: PlaylistId<'static>,
+        range_start: usize,
+        insert_before: usize,
+        range_length: usize,
+    ) -> ClientResult<()> {
+        self.send_one("reordering songs", Retry::OnlyRateLimited, || {
+            self.inner.playlist_reorder_items(
+                playlist_id.clone(),
+                range_start,
+                insert_before,
+                range_length,
+            )
+        })
+        .await
+    }
+
+    async fn artists(&self, ids: Vec<ArtistId<'static>>) -> ClientResult<Vec<FullArtist>> {
+        self.send_one("fetching artists", Retry::Always, || {
+            self.inner.artists(ids.clone())
+        })
+        .await
+    }
+
+    async fn audio_features(&self, ids: Vec<TrackId<'static>>) -> ClientResult<Vec<AudioFeatures>> {
+        self.send_one("fetching audio features", Retry::Always, || {
+            self.inner.audio_features(ids.clone())
+        })
+        .await
+    }
+
+    fn retry_summary(&self) -> RetrySummary {
+        self.summary.lock().unwrap().clone()
+    }
+}
+
+fn clone_items(items: &[PlayableId<'static>]) -> Vec<PlayableId<'static>> {
+    items.iter().map(|item| item.clone_static()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rspotify::prelude::Id;
+    use serde_json::json;
+
+    use super::*;
+    use crate::fake_backend::fixtures::{playlist, track};
+    use crate::fake_backend::{FakeBackend, FakeLibrary};
+
+    fn status_error(status: u16, retry_after: Option<&str>) -> ClientError {
+        let mut response = http::Response::builder().status(status);
+        if let Some(seconds) = retry_after {
+            response = response.header("retry-after", seconds);
+        }
+        let response = reqwest::Response::from(response.body("").unwrap());
+        ClientError::Http(Box::new(HttpError::StatusCode(response)))
+    }
+
+    fn fake() -> Arc<FakeBackend> {
+        let library = serde_json::from_value::<FakeLibrary>(json!({
+            "user_id": "tester",
+            "playlists": [playlist("pAAAAAAAAAAAAAAAAAAAAA", "A", &[])],
+            "tracks": [track("1111111111111111111111", "One")],
+        }))
+        .unwrap();
+        Arc::new(FakeBackend::new(library))
+    }
+
+    fn playlist_a() -> PlaylistId<'static> {
+        PlaylistId::from_id("pAAAAAAAAAAAAAAAAAAAAA").unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limited_calls_wait_as_long_as_spotify_asks() {
+        let fake = fake();
+        fake.fail_next_calls([status_error(429, Some("7"))]);
+        let backend = RateLimitedBackend::new(fake);
+
+        let start = Instant::now();
+        backend.current_user_id().await.unwrap();
+        assert_eq!(start.elapsed().as_secs(), 7);
+
+        let summary = backend.retry_summary();
+        assert_eq!(summary.requests, 2);
+        assert_eq!(summary.retries, 1);
+        assert_eq!(summary.rate_limited, 1);
+        assert_eq!(summary.waited, Duration::from_secs(7));
+        assert_eq!(summary.failed, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn adding_songs_is_only_retried_when_rate_limited() {
+        let fake = fake();
+        let backend = RateLimitedBackend::new(fake.clone());
+        let song = || {
+            vec![PlayableId::Track(
+                TrackId::from_id("1111111111111111111111").unwrap(),
+            )]
+        };
+
+        fake.fail_next_calls([status_error(503, None)]);
+        assert!(backend
+            .playlist_add_items(playlist_a(), song())
+            .await
+            .is_err());
+        let summary = backend.retry_summary();
+        assert_eq!(
+            (summary.requests, summary.retries, summary.failed),
+            (1, 0, 1)
+        );
+
+        // Reading is safe to send again.
+        fake.fail_next_calls([status_error(503, None)]);
+        backend
+            .playlist_items_page(playlist_a(), 50, 0)
+            .await
+            .unwrap();
+        let summary = backend.retry_summary();
+        assert_eq!(
+            (summary.requests, summary.retries, summary.failed),
+            (3, 1, 1)
+        );
+
+        fake.fail_next_calls([status_error(429, Some("1"))]);
+        backend
+            .playlist_add_items(playlist_a(), song())
+            .await
+            .unwrap();
+        let summary = backend.retry_summary();
+        assert_eq!((summary.requests, summary.retries), (5, 2));
+        assert_eq!(summary.rate_limited, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_the_last_retry() {
+        let fake = fake();
+        let errors = (0..=constants::MAX_RETRIES).map(|_| status_error(500, None));
+        fake.fail_next_calls(errors);
+        let backend = RateLimitedBackend::new(fake);
+
+        assert!(backend.current_user_id().await.is_err());
+        let summary = backend.retry_summary();
+        assert_eq!(summary.requests, constants::MAX_RETRIES as usize + 1);
+        assert_eq!(summary.retries, constants::MAX_RETRIES as usize);
+        assert_eq!(summary.failed, 1);
+        assert_eq!(summary.rate_limited, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_over_the_budget_wait_for_the_window() {
+        let backend = RateLimitedBackend::new(fake());
+
+        let start = Instant::now();
+        for _ in 0..constants::REQUEST_BUDGET {
+            backend.current_user_id().await.unwrap();
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        let user = backend.current_user_id().await.unwrap();
+        assert_eq!(user.id(), "tester");
+        assert_eq!(start.elapsed(), constants::REQUEST_BUDGET_WINDOW);
+        assert_eq!(backend.retry_summary().retries, 0);
+    }
+}
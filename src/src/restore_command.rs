@@ -0,0 +1,251 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+
+use rspotify::model::{PlayableId, PlayableItem, PlaylistId, TrackId};
+use rspotify::prelude::Id;
+
+use crate::backend::MusicBackend;
+use crate::traits::ResultExtension;
+use crate::{args, backup, constants, ledger};
+
+pub async fn handle_restore_snapshot(
+    cmd: &args::RestoreCommand,
+    backend: &Arc<dyn MusicBackend>,
+) -> Result<(), anyhow::Error> {
+    return restore_backup(Path::new(constants::SNAPSHOTS_DIR), cmd, backend).await;
+}
+
+/// Puts the songs of the backup back into the playlists. The backup can't hold local files
+/// and episodes, so the playlists are restored by removing and adding only the songs that
+/// differ from the backup. Everything else, including the order of kept songs, stays as it is.
+pub async fn restore_backup(
+    snapshots_dir: &Path,
+    cmd: &args::RestoreCommand,
+    backend: &Arc<dyn MusicBackend>,
+) -> Result<(), anyhow::Error> {
+    for path in backup::list_backups(snapshots_dir, cmd.id)? {
+        log::info!("Found backup: {}", path.display());
+    }
+
+    let (path, backup) = backup::read_backup(snapshots_dir, cmd.id, cmd.backup.as_deref())?;
+    log::info!(
+        "Restoring from backup {} created at {}",
+        path.display(),
+        backup.created_at
+    );
+
+    let playlists = backup
+        .playlists
+        .into_iter()
+        .filter(|p| cmd.node.is_none() || cmd.node.as_ref() == Some(&p.node))
+        .collect::<Vec<_>>();
+
+    if playlists.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Node {:?} is not part of the backup {}",
+            cmd.node.clone().unwrap_or_default(),
+            path.display()
+        ));
+    }
+
+    // Songs mixify added after the backup are gone again, so the ledger is restored as well.
+    let mut ledger = ledger::read_ledger(snapshots_dir, cmd.id)?;
+
+    for playlist in playlists {
+        let playlist_id = PlaylistId::from_id(playlist.playlist_id.clone()).or_error(format!(
+            "failed to parse playlist id {}",
+            playlist.playlist_id
+        ))?;
+
+        let mut current = HashSet::new();
+        let mut kept = 0;
+        for item in backend.playlist_items(playlist_id.clone()).await {
+            let item = item.or_error(format!(
+                "failed to fetch songs of playlist {:?}",
+                playlist.node
+            ))?;
+
+            match item.track {
+                Some(PlayableItem::Track(track)) if track.id.is_some() => {
+                    current.insert(track.id.unwrap().id().to_string());
+                }
+                Some(_) => kept += 1,
+                None => {}
+            }
+        }
+
+        let backed_up = playlist
+            .tracks
+            .iter()
+            .map(|t| t.id.clone())
+            .collect::<HashSet<_>>();
+        let to_remove = current
+            .iter()
+            .filter(|id| !backed_up.contains(*id))
+            .map(|id| parse_track_id(id))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut to_add = vec![];
+        let mut added = HashSet::new();
+        for t in &playlist.tracks {
+            if !current.contains(&t.id) && added.insert(&t.id) {
+                to_add.push(parse_track_id(&t.id)?);
+            }
+        }
+
+        for chunk in to_remove.chunks(100) {
+            backend
+                .playlist_remove_all_occurrences_of_items(
+                    playlist_id.clone(),
+                    to_playable_ids(chunk),
+                )
+                .await
+                .or_error(format!("failed to restore playlist {:?}", playlist.node))?;
+        }
+
+        for chunk in to_add.chunks(100) {
+            backend
+                .playlist_add_items(playlist_id.clone(), to_playable_ids(chunk))
+                .await
+                .or_error(format!("failed to restore playlist {:?}", playlist.node))?;
+        }
+
+        match playlist.owned_tracks {
+            Some(owned) => ledger.set_owned_tracks(&playlist.node, &playlist.playlist_id, owned),
+            None => ledger.remove_playlist(&playlist.playlist_id),
+        }
+        ledger::write_ledger(snapshots_dir, &ledger)?;
+
+        log::info!(
+            "Restored playlist {:?}: removed {} and added {} songs, kept {} local files and episodes",
+            playlist.node,
+            to_remove.len(),
+            to_add.len(),
+            kept
+        );
+    }
+
+    return Ok(());
+}
+
+fn parse_track_id(id: &str) -> Result<TrackId<'static>, anyhow::Error> {
+    return TrackId::from_id(id.to_string()).or_error(format!("failed to parse track id {}", id));
+}
+
+fn to_playable_ids(ids: &[TrackId<'static>]) -> Vec<PlayableId<'static>> {
+    ids.iter().cloned().map(PlayableId::Track).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::fake_backend::fixtures::{local_track, playlist, track};
+    use crate::fake_backend::{FakeBackend, FakeLibrary};
+
+    const A: &str = "pAAAAAAAAAAAAAAAAAAAAA";
+
+    async fn song_names(backend: &Arc<dyn MusicBackend>) -> Vec<String> {
+        let id = PlaylistId::from_id(A).unwrap();
+        return backend
+            .playlist_items(id)
+            .await
+            .into_iter()
+            .filter_map(|item| match item.unwrap().track {
+                Some(PlayableItem::Track(t)) => Some(t.name),
+                _ => None,
+            })
+            .collect();
+    }
+
+    fn restore(node: Option<&str>) -> args::RestoreCommand {
+        return args::RestoreCommand {
+            id: 1,
+            backup: None,
+            node: node.map(String::from),
+        };
+    }
+
+    #[tokio::test]
+    async fn restoring_keeps_local_songs() {
+        let one = track("1111111111111111111111", "One");
+        let two = track("2222222222222222222222", "Two");
+        let three = track("3333333333333333333333", "Three");
+        let local = local_track("Demo");
+        let library = serde_json::from_value::<FakeLibrary>(json!({
+            "user_id": "tester",
+            "playlists": [playlist(A, "A", &[&one, &local, &two])],
+            "tracks": [two, three],
+        }))
+        .unwrap();
+        let fake = Arc::new(FakeBackend::new(library));
+        let backend: Arc<dyn MusicBackend> = fake.clone();
+
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(snapshots_dir.path().join("1")).unwrap();
+        let mut ledger = ledger::read_ledger(snapshots_dir.path(), 1).unwrap();
+        ledger.set_owned_tracks("A", A, vec![String::from("1111111111111111111111")]);
+        ledger::write_ledger(snapshots_dir.path(), &ledger).unwrap();
+        let playlists = [(String::from("A"), String::from(A))];
+        backup::create_backup(&backend, snapshots_dir.path(), 1, &playlists, &ledger)
+            .await
+            .unwrap();
+
+        // Like an apply that went wrong: a song is replaced and mixify remembers adding it.
+        let id = PlaylistId::from_id(A).unwrap();
+        let two = TrackId::from_id("2222222222222222222222").unwrap();
+        let three = TrackId::from_id("3333333333333333333333").unwrap();
+        backend
+            .playlist_remove_all_occurrences_of_items(id.clone(), vec![PlayableId::Track(two)])
+            .await
+            .unwrap();
+        backend
+            .playlist_add_items(id, vec![PlayableId::Track(three)])
+            .await
+            .unwrap();
+        let owned = vec![
+            String::from("1111111111111111111111"),
+            String::from("3333333333333333333333"),
+        ];
+        ledger.set_owned_tracks("A", A, owned);
+        ledger::write_ledger(snapshots_dir.path(), &ledger).unwrap();
+
+        restore_backup(snapshots_dir.path(), &restore(None), &backend)
+            .await
+            .unwrap();
+
+        assert_eq!(song_names(&backend).await, vec!["One", "Demo", "Two"]);
+        assert!(!fake
+            .calls()
+            .iter()
+            .any(|c| c.starts_with("playlist_replace_items")));
+        let ledger = ledger::read_ledger(snapshots_dir.path(), 1).unwrap();
+        assert_eq!(
+            ledger.owned_tracks(A),
+            Some(&vec![String::from("1111111111111111111111")])
+        );
+    }
+
+    #[tokio::test]
+    async fn restoring_a_node_that_is_not_backed_up_fails() {
+        let library = serde_json::from_value::<FakeLibrary>(json!({
+            "user_id": "tester",
+            "playlists": [playlist(A, "A", &[])],
+        }))
+        .unwrap();
+        let backend: Arc<dyn MusicBackend> = Arc::new(FakeBackend::new(library));
+
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(snapshots_dir.path().join("1")).unwrap();
+        let ledger = ledger::read_ledger(snapshots_dir.path(), 1).unwrap();
+        let playlists = [(String::from("A"), String::from(A))];
+        backup::create_backup(&backend, snapshots_dir.path(), 1, &playlists, &ledger)
+            .await
+            .unwrap();
+
+        let result = restore_backup(snapshots_dir.path(), &restore(Some("B")), &backend).await;
+        assert!(result.is_err());
+    }
+}
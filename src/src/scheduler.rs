@@ -0,0 +1,337 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use chrono::Local;
+use graphviz_dot_parser::types::{GraphAST, Stmt};
+use tokio::sync::Notify;
+
+use crate::args::{ApplyCommand, ConfigOverrides};
+use crate::backend::MusicBackend;
+use crate::traits::ResultExtension;
+use crate::types::RunRecord;
+use crate::{apply_command, config, constants, plan_command};
+
+/// An applied snapshot the server syncs on a schedule.
+pub struct ScheduledSnapshot {
+    pub id: u32,
+    pub schedule: cron::Schedule,
+}
+
+/// Snapshots and playlists that are currently written by a run.
+/// A run waits until neither its snapshot nor any of its playlists are in use,
+/// so two runs never write the same playlist or the files of the same snapshot.
+#[derive(Default)]
+pub struct PlaylistLocks {
+    busy: Mutex<HashSet<String>>,
+    released: Notify,
+}
+
+pub struct PlaylistGuard {
+    locks: Arc<PlaylistLocks>,
+    playlists: Vec<String>,
+}
+
+impl PlaylistLocks {
+    /// Takes the snapshot and all playlists at once, so runs with overlapping playlists can't deadlock each other.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        snapshot_id: u32,
+        mut playlists: Vec<String>,
+    ) -> PlaylistGuard {
+        // Playlist ids never contain a '/', so the snapshot can't be mistaken for a playlist.
+        playlists.push(format!("snapshot/{}", snapshot_id));
+
+        loop {
+            // Created before checking, so a release in between is not missed.
+            let released = self.released.notified();
+            {
+                let mut busy = self.busy.lock().unwrap();
+                if playlists.iter().all(|p| !busy.contains(p)) {
+                    busy.extend(playlists.iter().cloned());
+                    return PlaylistGuard {
+                        locks: Arc::clone(self),
+                        playlists,
+                    };
+                }
+            }
+
+            log::info!(
+                "Waiting for another run to finish writing to snapshot {} or the same playlists",
+                snapshot_id
+            );
+            released.await;
+        }
+    }
+}
+
+impl Drop for PlaylistGuard {
+    fn drop(&mut self) {
+        let mut busy = self.locks.busy.lock().unwrap();
+        for p in &self.playlists {
+            busy.remove(p);
+        }
+        self.locks.released.notify_waiters();
+    }
+}
+
+/// Ids of the playlists the snapshot file writes to. These are the playlists of all nodes with incoming edges.
+/// Playlists that don't exist yet are not included, since nothing else can write to them.
+pub fn snapshot_playlists(id: u32, suffix: &str) -> Result<Vec<String>, anyhow::Error> {
+    let content =
+        plan_command::read_snapshot_file(Path::new(constants::SNAPSHOTS_DIR), id, suffix)?;
+    let gv = graphviz_dot_parser::parse(&content)
+        .or_error(format!("failed to parse graph of snapshot {}", id))?;
+
+    return Ok(graph_playlists(&gv));
+}
+
+/// Ids in the `URL` of all nodes with incoming edges.
+fn graph_playlists(gv: &GraphAST) -> Vec<String> {
+    let targets = gv
+        .stmt
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Stmt::Edge(_, to, _) => Some(to.clone()),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+
+    let playlists = gv
+        .stmt
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Stmt::Node(node, attrs) if targets.contains(node) => attrs
+                .iter()
+                .find(|(k, _)| k == constants::URL_ATTRIBUTE_KEY)
+                .and_then(|(_, url)| url.split('/').next_back())
+                .map(|id| id.to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    return playlists;
+}
+
+/// Finds the applied snapshots that have a `schedule` in their config.
+/// If `ids` is not empty, only these snapshots are considered.
+pub fn find_scheduled_snapshots(ids: &[u32]) -> Result<Vec<ScheduledSnapshot>, anyhow::Error> {
+    let snapshots_dir = Path::new(constants::SNAPSHOTS_DIR);
+    let snapshot_ids = plan_command::list_snapshot_ids(snapshots_dir)?
+        .into_iter()
+        .filter(|id| ids.is_empty() || ids.contains(id))
+        .collect::<Vec<_>>();
+
+    for id in ids {
+        if !snapshot_ids.contains(id) {
+            return Err(anyhow::anyhow!("snapshot {} does not exist", id));
+        }
+    }
+
+    let mut snapshots = vec![];
+    for id in snapshot_ids {
+        if plan_command::find_snapshot_file(snapshots_dir, id, "post.apply").is_err() {
+            log::debug!("Snapshot {} has not been applied yet", id);
+            continue;
+        }
+
+        // One broken config shouldn't keep all other snapshots from being synced.
+        let config = match config::load_config(Some(id), &ConfigOverrides::default()) {
+            Ok(config) => config,
+            Err(e) => {
+                log::error!("Snapshot {} won't be synced: {}", id, e);
+                continue;
+            }
+        };
+        let Some(schedule) = config.schedule else {
+            if ids.contains(&id) {
+                log::warn!(
+                    "Snapshot {} has no schedule in its mixify.toml and won't be synced",
+                    id
+                );
+            }
+            continue;
+        };
+
+        // Already validated when loading the config.
+        let schedule = cron::Schedule::from_str(&schedule).unwrap();
+        snapshots.push(ScheduledSnapshot { id, schedule });
+    }
+
+    return Ok(snapshots);
+}
+
+/// Syncs the snapshot like `mixify sync <id>` and records the outcome in `snapshots/<id>/runs.jsonl`.
+pub async fn run_sync(
+    id: u32,
+    backend: &Arc<dyn MusicBackend>,
+    locks: &Arc<PlaylistLocks>,
+) -> RunRecord {
+    let started_at = Local::now();
+    log::info!("Starting scheduled sync of snapshot {}", id);
+
+    let cmd = ApplyCommand {
+        id,
+        fixture: None,
+        dry_run: false,
+        resume: false,
+        discard: false,
+    };
+    let result = run_snapshot(&cmd, backend, locks, true).await;
+
+    let record = RunRecord {
+        snapshot_id: id,
+        started_at: started_at.to_rfc3339(),
+        finished_at: Local::now().to_rfc3339(),
+        success: result.is_ok(),
+        error: result.err().map(|e| e.to_string()),
+    };
+
+    match &record.error {
+        None => log::info!("Synced snapshot {}", id),
+        Some(e) => log::error!("Sync of snapshot {} failed: {}", id, e),
+    }
+
+    if let Err(e) = write_run_record(&record) {
+        log::error!("{}", e);
+    }
+
+    return record;
+}
+
+/// Applies or syncs the snapshot once none of its playlists are written by another run.
+pub async fn run_snapshot(
+    cmd: &ApplyCommand,
+    backend: &Arc<dyn MusicBackend>,
+    locks: &Arc<PlaylistLocks>,
+    is_sync: bool,
+) -> Result<(), anyhow::Error> {
+    // Read on every run, so changes to the config are picked up without a restart.
+    let config = config::load_config(Some(cmd.id), &ConfigOverrides::default())?;
+    let suffix = if is_sync { "post.apply" } else { "edit" };
+    let playlists = snapshot_playlists(cmd.id, suffix)?;
+    let _guard = locks.acquire(cmd.id, playlists).await;
+
+    return apply_command::handle_apply_snapshot(cmd, backend, config, is_sync).await;
+}
+
+fn write_run_record(record: &RunRecord) -> Result<(), anyhow::Error> {
+    use std::io::Write;
+
+    let path = Path::new(constants::SNAPSHOTS_DIR)
+        .join(record.snapshot_id.to_string())
+        .join(constants::RUNS_FILE_NAME);
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .or_error(format!("failed to open {}", path.display()))?;
+
+    let line = serde_json::to_string(record)?;
+    writeln!(file, "{}", line).or_error(format!("failed to write run to {}", path.display()))?;
+
+    return Ok(());
+}
+
+/// Syncs every snapshot on its schedule until the process is stopped.
+/// Runs of the same snapshot never overlap, ticks missed during a long run are skipped.
+pub async fn run_scheduler(
+    snapshots: Vec<ScheduledSnapshot>,
+    backend: Arc<dyn MusicBackend>,
+    locks: Arc<PlaylistLocks>,
+) {
+    let mut handles = vec![];
+    for snapshot in snapshots {
+        let backend = Arc::clone(&backend);
+        let locks = Arc::clone(&locks);
+
+        handles.push(tokio::spawn(async move {
+            loop {
+                let Some(next) = snapshot.schedule.upcoming(Local).next() else {
+                    log::info!("Schedule of snapshot {} has no upcoming runs", snapshot.id);
+                    return;
+                };
+
+                log::info!("Next sync of snapshot {} at {}", snapshot.id, next);
+                let wait = (next - Local::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(wait).await;
+
+                run_sync(snapshot.id, &backend, &locks).await;
+            }
+        }));
+    }
+
+    for handle in handles {
+        if let Err(e) = handle.await {
+            log::error!("Scheduler task failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Whether `acquire` returns before the timeout. The clock is paused, so waiting is instant.
+    async fn acquires(locks: &Arc<PlaylistLocks>, id: u32, playlists: &[&str]) -> bool {
+        let playlists = playlists.iter().map(|p| p.to_string()).collect();
+        let acquire = locks.acquire(id, playlists);
+        return tokio::time::timeout(Duration::from_secs(1), acquire)
+            .await
+            .is_ok();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn runs_wait_for_runs_of_the_same_playlists_or_snapshot() {
+        let locks = Arc::new(PlaylistLocks::default());
+        let guard = locks
+            .acquire(1, vec![String::from("pA"), String::from("pB")])
+            .await;
+
+        assert!(!acquires(&locks, 2, &["pB", "pC"]).await);
+        assert!(!acquires(&locks, 1, &["pC"]).await);
+        assert!(acquires(&locks, 2, &["pC"]).await);
+
+        drop(guard);
+        assert!(acquires(&locks, 1, &["pA", "pB"]).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_waiting_run_starts_once_the_playlists_are_released() {
+        let locks = Arc::new(PlaylistLocks::default());
+        let guard = locks.acquire(1, vec![String::from("pA")]).await;
+
+        let waiting = tokio::spawn({
+            let locks = Arc::clone(&locks);
+            async move {
+                locks.acquire(2, vec![String::from("pA")]).await;
+            }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!waiting.is_finished());
+
+        drop(guard);
+        waiting.await.unwrap();
+    }
+
+    #[test]
+    fn only_playlists_that_are_written_to_are_locked() {
+        let gv = graphviz_dot_parser::parse(
+            r#"digraph G {
+                A [URL="https://open.spotify.com/playlist/pAAAAAAAAAAAAAAAAAAAAA"];
+                B [URL="https://open.spotify.com/playlist/pBBBBBBBBBBBBBBBBBBBBB"];
+                C [type="query", artist_id="artistAAAAAAAAAAAAAAAA"];
+                New;
+                A -> B;
+                C -> B;
+                B -> New;
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(graph_playlists(&gv), vec!["pBBBBBBBBBBBBBBBBBBBBB"]);
+    }
+}
@@ -10,11 +10,11 @@ where
     E: std::fmt::Display,
 {
     fn or_error(self, msg: String) -> Result<T, anyhow::Error> {
-        self.or_else(|e| Err(anyhow::anyhow!(format!("{}: {}", msg, e))))
+        self.map_err(|e| anyhow::anyhow!(format!("{}: {}", msg, e)))
     }
 
     fn or_error_str(self, msg: &str) -> Result<T, anyhow::Error> {
-        self.or_else(|e| Err(anyhow::anyhow!(format!("{}: {}", msg, e))))
+        self.map_err(|e| anyhow::anyhow!(format!("{}: {}", msg, e)))
     }
 }
 
@@ -41,25 +41,19 @@ impl<T> OptionExtension<T> for Option<T> {
     }
 }
 
-impl Into<crate::types::Track> for FullTrack {
-    fn into(self) -> crate::types::Track {
+impl From<FullTrack> for crate::types::Track {
+    fn from(track: FullTrack) -> Self {
         crate::types::Track {
-            id: self.id,
-            name: self.name,
-            is_local: self.is_local,
-            artists: self.artists,
-            album_artists_ids: self
+            id: track.id,
+            name: track.name,
+            is_local: track.is_local,
+            artists: track.artists,
+            album_artists_ids: track
                 .album
                 .artists
                 .into_iter()
-                .map(|artist| artist.id.unwrap())
+                .filter_map(|artist| artist.id)
                 .collect::<Vec<_>>(),
         }
     }
 }
-
-impl PartialEq for crate::types::TrackTuple {
-    fn eq(&self, other: &Self) -> bool {
-        self.artist_id == other.artist_id && self.name == other.name
-    }
-}
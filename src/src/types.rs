@@ -1,4 +1,12 @@
-use rspotify::model::{ArtistId, SimplifiedArtist, TrackId};
+use std::collections::HashMap;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use rspotify::model::{
+    ArtistId, AudioFeatures, FullAlbum, FullTrack, Modality, SimplifiedArtist, SimplifiedTrack,
+    TrackId,
+};
+use rspotify::prelude::Id;
+use serde::{Deserialize, Serialize};
 
 impl std::fmt::Display for Action {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -10,6 +18,62 @@ impl std::fmt::Display for Action {
     }
 }
 
+impl std::fmt::Display for DryRunReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Dry run finished. Nothing has been changed on spotify.")?;
+
+        for name in &self.created_playlists {
+            writeln!(f, "Would create playlist {:?}", name)?;
+        }
+
+        for changes in &self.changes {
+            let playlist = changes.playlist_url.as_deref().unwrap_or("new playlist");
+            writeln!(
+                f,
+                "Playlist {:?} ({}): {} to add, {} to remove",
+                changes.node,
+                playlist,
+                changes.added.len(),
+                changes.removed.len()
+            )?;
+
+            for t in &changes.added {
+                writeln!(f, "  + {} ({})", t.name, t.album_name)?;
+            }
+
+            for t in &changes.removed {
+                if changes.removal_allowed {
+                    writeln!(f, "  - {} ({})", t.name, t.album_name)?;
+                } else {
+                    writeln!(
+                        f,
+                        "  - {} ({}) [skipped, ALLOW_REMOVING_SONGS is false]",
+                        t.name, t.album_name
+                    )?;
+                }
+            }
+
+            for t in &changes.kept {
+                writeln!(
+                    f,
+                    "  = {} ({}) [kept, not added by mixify]",
+                    t.name, t.album_name
+                )?;
+            }
+
+            if changes.reorder_calls > 0 {
+                writeln!(
+                    f,
+                    "  Would reorder the playlist with {} calls",
+                    changes.reorder_calls
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
 impl std::str::FromStr for QuerySource {
     type Err = anyhow::Error;
 
@@ -23,7 +87,106 @@ impl std::str::FromStr for QuerySource {
     }
 }
 
-#[derive(Debug)]
+impl std::str::FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "major" => Ok(Mode::Major),
+            "minor" => Ok(Mode::Minor),
+            _ => Err(anyhow::anyhow!(format!("Invalid mode: {}", s))),
+        }
+    }
+}
+
+impl std::str::FromStr for Dedup {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "isrc" => Ok(Dedup::Isrc),
+            "title" => Ok(Dedup::Title),
+            "id" => Ok(Dedup::Id),
+            "none" => Ok(Dedup::None),
+            _ => Err(anyhow::anyhow!(format!("Invalid dedup: {}", s))),
+        }
+    }
+}
+
+impl std::str::FromStr for Prefer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "album" => Ok(Prefer::Album),
+            "single" => Ok(Prefer::Single),
+            "explicit" => Ok(Prefer::Explicit),
+            "clean" => Ok(Prefer::Clean),
+            "oldest" => Ok(Prefer::Oldest),
+            _ => Err(anyhow::anyhow!(format!("Invalid prefer: {}", s))),
+        }
+    }
+}
+
+impl std::str::FromStr for Order {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "added_at" => Ok(Order::AddedAt),
+            "release_date" => Ok(Order::ReleaseDate),
+            "artist" => Ok(Order::Artist),
+            "popularity" => Ok(Order::Popularity),
+            "tempo" => Ok(Order::Tempo),
+            "seeded_shuffle" => Ok(Order::SeededShuffle),
+            "source_order" => Ok(Order::SourceOrder),
+            "keep" => Ok(Order::Keep),
+            _ => Err(anyhow::anyhow!(format!("Invalid order: {}", s))),
+        }
+    }
+}
+
+impl std::str::FromStr for Pick {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "random" => Ok(Pick::Random),
+            "newest" => Ok(Pick::Newest),
+            "most_popular" => Ok(Pick::MostPopular),
+            "least_recently_added" => Ok(Pick::LeastRecentlyAdded),
+            _ => Err(anyhow::anyhow!(format!("Invalid pick: {}", s))),
+        }
+    }
+}
+
+impl std::str::FromStr for EdgeOp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "union" => Ok(EdgeOp::Union),
+            "intersect" => Ok(EdgeOp::Intersect),
+            "xor" => Ok(EdgeOp::Xor),
+            _ => Err(anyhow::anyhow!(format!("Invalid op: {}", s))),
+        }
+    }
+}
+
+impl std::str::FromStr for GenreMatch {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "exact" => Ok(GenreMatch::Exact),
+            "prefix" => Ok(GenreMatch::Prefix),
+            "regex" => Ok(GenreMatch::Regex),
+            _ => Err(anyhow::anyhow!(format!("Invalid genre match: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct Action {
     pub action_type: ActionType,
     pub node: String,
@@ -32,30 +195,222 @@ pub struct Action {
     pub playlist_url: Option<String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub enum ActionType {
     CreatePlaylist,
     QuerySongs(Option<String>),
     QuerySongsByArtist(QuerySongsByArtist),
+    QuerySongsByGenre(QuerySongsByGenre),
 
     /// SaveChanges is responsible for also saving the state locally.
-    SaveChanges(Option<String>),
-    CopySongs,
+    SaveChanges(SavePlaylist),
+
+    CopySongs(CopySongs),
+
+    /// Only keeps the songs of the node that the other node has too.
+    IntersectSongs(CombineSongs),
+
+    /// Removes the songs of the node the other node has too, and adds the ones it doesn't have.
+    XorSongs(CombineSongs),
+
+    /// RemoveSongs only removes the songs from the local state of the node.
+    /// SaveChanges then removes songs from the playlist, but only the ones listed in the
+    /// ledger of the snapshot, so songs added by hand are never removed.
+    /// Songs are matched by the dedup of the node the songs are removed from.
+    RemoveSongs(Dedup),
+}
 
-    /// RemoveSongs should only remove songs not added by the user. Only be the bot.
-    /// There is also a chance that song from a child playlist was added by a user.
-    /// In that case we should not remove it. (No idea how to do that yet)
-    RemoveSongs,
+/// How the songs of an edge are combined with the songs of the node it points to.
+/// Edges are applied in this order, and subtracting edges after all of them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EdgeOp {
+    #[default]
+    Union,
+    Xor,
+    Intersect,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Serialize)]
+pub struct CopySongs {
+    /// Only the songs that pass the filter of the edge are copied.
+    pub filter: Option<SongFilter>,
+
+    /// The share of the limit of the target node the songs of the edge get.
+    /// Either all or none of the copying edges into a node have one.
+    pub weight: Option<f32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CombineSongs {
+    /// Only the songs that pass the filter of the edge count.
+    pub filter: Option<SongFilter>,
+
+    /// When a song of the edge is the same as a song of the node. The dedup of the node.
+    pub dedup: Dedup,
+}
+
+/// The songs an edge copied into a node, so the node can tell where its songs come from.
+#[derive(Debug)]
+pub struct Source {
+    pub node: String,
+    pub weight: Option<f32>,
+    pub songs: std::collections::HashSet<TrackId<'static>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SavePlaylist {
+    /// None if the playlist is created by the apply.
+    pub url: Option<String>,
+    pub dedup: Dedup,
+
+    /// Which version of a song is kept if there are several.
+    pub prefer: Prefer,
+    pub order: Order,
+
+    /// The most songs mixify puts into the playlist. Songs added by hand come on top.
+    pub limit: Option<usize>,
+
+    /// Which songs are kept if there are more than the limit.
+    pub pick: Pick,
+
+    /// Seeds random picks and shuffles. Defaults to the name of the node.
+    pub seed: String,
+    pub rotate: Option<Rotation>,
+
+    /// If true, the songs of the sources take turns, as often as the weights of their edges say.
+    pub interleave: bool,
+}
+
+impl SavePlaylist {
+    /// Whether mixify brings the playlist into an order, instead of only adding at the end.
+    pub fn reorders(&self) -> bool {
+        self.order != Order::Keep || self.interleave
+    }
+}
+
+/// Serialized with the same names as the `pick` attribute.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pick {
+    /// Random, but the same as long as the seed and the songs stay the same.
+    #[default]
+    Random,
+
+    /// The latest releases.
+    Newest,
+    MostPopular,
+
+    /// The songs added the longest ago to the playlist or library they come from.
+    LeastRecentlyAdded,
+}
+
+/// Picks a window of `limit` songs out of all picked songs, which moves by `songs` every `period`.
+/// So every period the same number of songs is swapped, and the playlist stays the same in between.
+#[derive(Debug, Serialize)]
+pub struct Rotation {
+    pub songs: usize,
+
+    #[serde(serialize_with = "serialize_duration_seconds")]
+    pub period: chrono::Duration,
+}
+
+/// When two songs count as the same song and only one of them is kept.
+/// Serialized with the same names as the `dedup` attribute.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dedup {
+    /// Same ISRC, or the same artist and title if spotify doesn't know the ISRC of one of them.
+    #[default]
+    Isrc,
+
+    /// Same artist and title, ignoring remaster and version suffixes.
+    Title,
+
+    /// Same spotify song.
+    Id,
+
+    /// Every song is kept, even the same spotify song coming from several sources.
+    None,
+}
+
+/// Serialized with the same names as the `prefer` attribute.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Prefer {
+    Album,
+    #[default]
+    Single,
+    Explicit,
+    Clean,
+
+    /// The earliest release. Songs without a release date come last.
+    Oldest,
+}
+
+/// The order of the songs in a playlist. Applied to the whole playlist on every apply,
+/// so songs added later are sorted in too. Songs that lack the value come last.
+/// Serialized with the same names as the `order` attribute.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    /// When the song was added to the playlist or library it comes from, oldest first.
+    AddedAt,
+
+    /// Oldest first.
+    ReleaseDate,
+
+    /// Grouped by artist and then album.
+    Artist,
+
+    /// Most popular first.
+    Popularity,
+
+    /// Slowest first.
+    Tempo,
+
+    /// Shuffled by the seed of the node, so new songs don't reshuffle the songs already there.
+    SeededShuffle,
+
+    /// In the order the songs come from the incoming edges.
+    SourceOrder,
+
+    /// Songs already in the playlist stay where they are, new ones are added at the end.
+    #[default]
+    Keep,
+}
+
+/// How a playlist is brought into its order.
+#[derive(Debug)]
+pub enum Reordering {
+    /// Each move is `(range_start, insert_before, range_length)`, sent as one reorder call.
+    Moves(Vec<(usize, usize, usize)>),
+
+    /// All songs are replaced in order, which needs fewer calls if most songs have to move.
+    /// Resets the date the songs were added to the playlist.
+    Replace,
+}
+
+impl Reordering {
+    pub fn calls(&self, songs: usize) -> usize {
+        match self {
+            Reordering::Moves(moves) => moves.len(),
+            Reordering::Replace => songs.div_ceil(100).max(1),
+        }
+    }
+}
+
+/// Serialized with the same names as the `source` attribute.
+#[derive(Debug, PartialEq, Serialize)]
 pub enum QuerySource {
+    #[serde(rename = "liked")]
     LikedSongs,
+    #[serde(rename = "playlists")]
     Playlists,
+    #[serde(rename = "albums")]
     Albums,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct QuerySongsByArtist {
     /// The artist id.
     /// Can be found by searching for the artist on spotify and then looking at the url.
@@ -74,6 +429,89 @@ pub struct QuerySongsByArtist {
     /// If false, only includes songs that are not liked by the user.
     /// If None, includes both.
     pub must_be_liked: Option<bool>,
+
+    /// Only includes songs that pass the filter.
+    pub filter: Option<SongFilter>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QuerySongsByGenre {
+    /// Compared against the genres spotify assigns to the artists of a song, ignoring case.
+    /// Like `hip hop` or `german indie`.
+    pub genre: String,
+    pub genre_match: GenreMatch,
+
+    /// If none includes all sources.
+    pub source: Option<QuerySource>,
+
+    /// Same as `QuerySongsByArtist::must_be_liked`.
+    pub must_be_liked: Option<bool>,
+
+    /// Only includes songs that pass the filter.
+    pub filter: Option<SongFilter>,
+}
+
+/// Bounds on the songs that flow through a query node or edge. A song passes the filter
+/// if all bounds that are set hold. Songs that lack a value a bound needs never pass,
+/// e.g. songs spotify has no audio features for or songs of saved albums for popularity bounds.
+///
+/// Energy, danceability, valence and instrumentalness go from 0 to 1, the tempo is in BPM.
+#[derive(Debug, Default, Serialize)]
+pub struct SongFilter {
+    /// Release dates that are only known to the year or month count as the first day of it.
+    pub released_after: Option<NaiveDate>,
+    pub released_before: Option<NaiveDate>,
+    pub min_popularity: Option<u32>,
+    pub max_popularity: Option<u32>,
+
+    #[serde(serialize_with = "serialize_seconds")]
+    pub min_duration: Option<chrono::Duration>,
+    #[serde(serialize_with = "serialize_seconds")]
+    pub max_duration: Option<chrono::Duration>,
+    pub explicit: Option<bool>,
+
+    /// Only songs added within this time before the run.
+    #[serde(serialize_with = "serialize_seconds")]
+    pub added_within: Option<chrono::Duration>,
+
+    pub min_tempo: Option<f32>,
+    pub max_tempo: Option<f32>,
+    pub min_energy: Option<f32>,
+    pub max_energy: Option<f32>,
+    pub min_danceability: Option<f32>,
+    pub max_danceability: Option<f32>,
+    pub min_valence: Option<f32>,
+    pub max_valence: Option<f32>,
+    pub min_instrumentalness: Option<f32>,
+    pub max_instrumentalness: Option<f32>,
+
+    /// The pitch class of the song. 0 is C, 1 is C♯/D♭ and so on up to 11.
+    pub key: Option<i32>,
+    pub mode: Option<Mode>,
+}
+
+/// Serialized with the same names as the `mode` attribute.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Major,
+    Minor,
+}
+
+/// How the `genre` of a query is compared against the genres of an artist.
+/// Serialized with the same names as the `genre_match` attribute.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GenreMatch {
+    /// The genre is exactly the same.
+    #[default]
+    Exact,
+
+    /// The genre starts with it, so `hip hop` also matches `hip hop francais`.
+    Prefix,
+
+    /// The genre matches the regular expression.
+    Regex,
 }
 
 #[derive(Debug)]
@@ -93,12 +531,401 @@ pub struct TrackTuple {
     pub name: String,
     pub artist_id: ArtistId<'static>,
     pub album_name: String,
+
+    /// The International Standard Recording Code. Spotify only returns it for full tracks,
+    /// so songs of saved albums have none.
+    pub isrc: Option<String>,
+
+    /// The lowercase name without remaster and version suffixes. See `normalize_title`.
+    pub title: String,
+
+    /// As precise as spotify knows it: `2020`, `2020-05` or `2020-05-17`.
+    pub release_date: Option<String>,
+
+    /// From 0 to 100. Spotify doesn't return it for the songs of saved albums.
+    pub popularity: Option<u32>,
+    pub duration: chrono::Duration,
+    pub explicit: bool,
+
+    /// When the song was added to the playlist it was found in, liked or its album saved.
+    pub added_at: Option<DateTime<Utc>>,
+}
+
+/// Durations are written as seconds in the plan.
+fn serialize_seconds<S: serde::Serializer>(
+    duration: &Option<chrono::Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    duration.map(|d| d.num_seconds()).serialize(serializer)
+}
+
+fn serialize_duration_seconds<S: serde::Serializer>(
+    duration: &chrono::Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    duration.num_seconds().serialize(serializer)
+}
+
+impl SongFilter {
+    /// Audio features only need to be fetched if one of their bounds is set.
+    pub fn needs_features(&self) -> bool {
+        self.min_tempo.is_some()
+            || self.max_tempo.is_some()
+            || self.min_energy.is_some()
+            || self.max_energy.is_some()
+            || self.min_danceability.is_some()
+            || self.max_danceability.is_some()
+            || self.min_valence.is_some()
+            || self.max_valence.is_some()
+            || self.min_instrumentalness.is_some()
+            || self.max_instrumentalness.is_some()
+            || self.key.is_some()
+            || self.mode.is_some()
+    }
+
+    /// `features` is only looked at if `needs_features`. `now` is the start of the run,
+    /// so all songs are compared against the same time.
+    pub fn matches(
+        &self,
+        track: &TrackTuple,
+        features: Option<&AudioFeatures>,
+        now: DateTime<Utc>,
+    ) -> bool {
+        let release_date = track.release_date.as_deref().and_then(parse_release_date);
+        let dates_match = match (self.released_after, self.released_before) {
+            (None, None) => true,
+            (after, before) => release_date.is_some_and(|date| {
+                after.is_none_or(|after| date >= after) && before.is_none_or(|before| date < before)
+            }),
+        };
+
+        let popularity_match = match (self.min_popularity, self.max_popularity) {
+            (None, None) => true,
+            (min, max) => track.popularity.is_some_and(|popularity| {
+                min.is_none_or(|min| popularity >= min) && max.is_none_or(|max| popularity <= max)
+            }),
+        };
+
+        let added_match = match self.added_within {
+            None => true,
+            Some(within) => track
+                .added_at
+                .is_some_and(|added_at| added_at >= now - within),
+        };
+
+        let track_matches = dates_match
+            && popularity_match
+            && added_match
+            && self.min_duration.is_none_or(|min| track.duration >= min)
+            && self.max_duration.is_none_or(|max| track.duration <= max)
+            && self
+                .explicit
+                .is_none_or(|explicit| track.explicit == explicit);
+
+        if !track_matches || !self.needs_features() {
+            return track_matches;
+        }
+
+        let Some(features) = features else {
+            return false;
+        };
+
+        let within = |value: f32, min: Option<f32>, max: Option<f32>| {
+            min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+        };
+
+        let mode = match self.mode {
+            Some(Mode::Major) => Some(Modality::Major),
+            Some(Mode::Minor) => Some(Modality::Minor),
+            None => None,
+        };
+
+        within(features.tempo, self.min_tempo, self.max_tempo)
+            && within(features.energy, self.min_energy, self.max_energy)
+            && within(
+                features.danceability,
+                self.min_danceability,
+                self.max_danceability,
+            )
+            && within(features.valence, self.min_valence, self.max_valence)
+            && within(
+                features.instrumentalness,
+                self.min_instrumentalness,
+                self.max_instrumentalness,
+            )
+            && self.key.is_none_or(|key| features.key == key)
+            && mode.is_none_or(|mode| features.mode == mode)
+    }
+}
+
+/// Spotify gives release dates as `2020`, `2020-05` or `2020-05-17`.
+pub fn parse_release_date(date: &str) -> Option<NaiveDate> {
+    let mut parts = date.split('-').map(|p| p.parse::<u32>());
+    let year = parts.next()?.ok()? as i32;
+    let month = parts.next().unwrap_or(Ok(1)).ok()?;
+    let day = parts.next().unwrap_or(Ok(1)).ok()?;
+
+    return NaiveDate::from_ymd_opt(year, month, day);
+}
+
+/// Lowercases the name and drops suffixes like `- Remastered 2011`, `(2011 Remaster)` or
+/// `[Single Version]`, as well as punctuation, so different releases of a song compare equal.
+pub fn normalize_title(name: &str) -> String {
+    let is_version = |part: &str| {
+        crate::constants::TITLE_VERSION_MARKERS
+            .iter()
+            .any(|marker| part.contains(marker))
+    };
+
+    let mut name = name.to_lowercase();
+    if let Some(pos) = name.find(" - ") {
+        if is_version(&name[pos + 3..]) {
+            name.truncate(pos);
+        }
+    }
+
+    let mut title = String::new();
+    let mut rest = name.as_str();
+    while let Some(start) = rest.find(['(', '[']) {
+        let Some(len) = rest[start..].find([')', ']']) else {
+            break;
+        };
+
+        title.push_str(&rest[..start]);
+        if !is_version(&rest[start + 1..start + len]) {
+            title.push_str(&rest[start..=start + len]);
+        }
+        rest = &rest[start + len + 1..];
+    }
+    title.push_str(rest);
+
+    return title
+        .chars()
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+}
+
+/// What identifies a song under a dedup mode. See `TrackTuple::identity`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SongKey {
+    Isrc(String),
+
+    /// The artist id and the normalized title.
+    Title(String, String),
+    Id(String),
+}
+
+impl Dedup {
+    /// Whether the songs count as the same song. Same as `SongSet::contains` with a set of one song.
+    pub fn same_song(&self, a: &TrackTuple, b: &TrackTuple) -> bool {
+        match (self, &a.isrc, &b.isrc) {
+            (Dedup::Isrc, Some(_), None) | (Dedup::Isrc, None, Some(_)) => {
+                a.identity(Dedup::Title) == b.identity(Dedup::Title)
+            }
+            _ => a.identity(*self) == b.identity(*self),
+        }
+    }
+}
+
+/// Songs looked up by their identity under a dedup mode, like the songs of one side of an edge.
+///
+/// With `Dedup::Isrc`, songs without an ISRC are matched by title against all songs, and songs with
+/// one only match songs without one by title. So `same_song` is not transitive, and matching against
+/// a set instead of pairwise gives the same result in whatever order the songs come.
+#[derive(Debug)]
+pub struct SongSet {
+    dedup: Dedup,
+    keys: std::collections::HashSet<SongKey>,
+    titles: std::collections::HashSet<SongKey>,
+    titles_without_isrc: std::collections::HashSet<SongKey>,
+}
+
+impl SongSet {
+    pub fn new<'a>(dedup: Dedup, songs: impl IntoIterator<Item = &'a TrackTuple>) -> Self {
+        let mut set = SongSet {
+            dedup,
+            keys: Default::default(),
+            titles: Default::default(),
+            titles_without_isrc: Default::default(),
+        };
+        for song in songs {
+            set.insert(song);
+        }
+
+        return set;
+    }
+
+    pub fn insert(&mut self, song: &TrackTuple) {
+        let title = song.identity(Dedup::Title);
+        if song.isrc.is_none() {
+            self.titles_without_isrc.insert(title.clone());
+        }
+        self.titles.insert(title);
+        self.keys.insert(song.identity(self.dedup));
+    }
+
+    /// Whether the set has a song that is the same as this one.
+    pub fn contains(&self, song: &TrackTuple) -> bool {
+        let title = song.identity(Dedup::Title);
+        match (self.dedup, &song.isrc) {
+            (Dedup::Isrc, Some(_)) => {
+                self.keys.contains(&song.identity(self.dedup))
+                    || self.titles_without_isrc.contains(&title)
+            }
+            (Dedup::Isrc, None) | (Dedup::Title, _) => self.titles.contains(&title),
+            (Dedup::Id | Dedup::None, _) => self.keys.contains(&song.identity(self.dedup)),
+        }
+    }
+}
+
+impl Prefer {
+    /// Orders the preferred version of a song first.
+    pub fn compare(&self, a: &TrackTuple, b: &TrackTuple) -> std::cmp::Ordering {
+        match self {
+            Prefer::Album => a.is_single().cmp(&b.is_single()),
+            Prefer::Single => b.is_single().cmp(&a.is_single()),
+            Prefer::Explicit => b.explicit.cmp(&a.explicit),
+            Prefer::Clean => a.explicit.cmp(&b.explicit),
+            Prefer::Oldest => {
+                let date = |t: &TrackTuple| {
+                    let date = t.release_date.as_deref().and_then(parse_release_date);
+                    (date.is_none(), date)
+                };
+                date(a).cmp(&date(b))
+            }
+        }
+    }
 }
 
 impl crate::types::TrackTuple {
     pub fn is_single(&self) -> bool {
         self.name == self.album_name
     }
+
+    /// The key two songs share if they are the same song under the dedup mode. With `Dedup::Isrc`
+    /// the key of a song without an ISRC is its title, so it never equals the key of a song with one.
+    /// `Dedup::same_song` and `SongSet` also compare those by title.
+    pub fn identity(&self, dedup: Dedup) -> SongKey {
+        match (dedup, &self.isrc) {
+            (Dedup::Isrc, Some(isrc)) => SongKey::Isrc(isrc.clone()),
+            (Dedup::Isrc, None) | (Dedup::Title, _) => {
+                SongKey::Title(self.artist_id.id().to_string(), self.title.clone())
+            }
+            (Dedup::Id | Dedup::None, _) => SongKey::Id(self.id.id().to_string()),
+        }
+    }
+
+    /// The artist is the first artist of the song. Local songs have no id and can't be used,
+    /// so they are `None`.
+    pub fn from_track(track: &FullTrack, added_at: Option<DateTime<Utc>>) -> Option<Self> {
+        Some(TrackTuple {
+            id: track.id.clone()?,
+            name: track.name.clone(),
+            artist_id: track.artists.first()?.id.clone()?,
+            album_name: track.album.name.clone(),
+            isrc: track.external_ids.get("isrc").cloned(),
+            title: normalize_title(&track.name),
+            release_date: track.album.release_date.clone(),
+            popularity: Some(track.popularity),
+            duration: track.duration,
+            explicit: track.explicit,
+            added_at,
+        })
+    }
+
+    /// A song of a saved album, which has less details than a full track.
+    pub fn from_album_track(
+        track: &SimplifiedTrack,
+        album: &FullAlbum,
+        added_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        TrackTuple {
+            id: track.id.clone().unwrap(),
+            name: track.name.clone(),
+            artist_id: track.artists[0].id.clone().unwrap(),
+            album_name: album.name.clone(),
+            isrc: None,
+            title: normalize_title(&track.name),
+            release_date: Some(album.release_date.clone()),
+            popularity: None,
+            duration: track.duration,
+            explicit: track.explicit,
+            added_at,
+        }
+    }
+}
+
+/// Everything an apply would have written to spotify, collected during a dry run.
+#[derive(Debug, Default)]
+pub struct DryRunReport {
+    /// Names of the playlists that would be created. (Including the mixstack suffix)
+    pub created_playlists: Vec<String>,
+    pub changes: Vec<PlaylistChanges>,
+}
+
+#[derive(Debug)]
+pub struct PlaylistChanges {
+    pub node: String,
+
+    /// None if the playlist would be created by the apply.
+    pub playlist_url: Option<String>,
+    pub added: Vec<TrackTuple>,
+    pub removed: Vec<TrackTuple>,
+
+    /// If false the removals are only reported, since they would be skipped by a real apply.
+    pub removal_allowed: bool,
+
+    /// Songs that are not part of the graph but stay in the playlist,
+    /// because they were not added by mixify.
+    pub kept: Vec<TrackTuple>,
+
+    /// All songs the playlist would consist of after the apply.
+    pub tracks: Vec<TrackTuple>,
+
+    /// The calls needed to bring the playlist into its order. 0 if it already is.
+    pub reorder_calls: usize,
+}
+
+#[derive(Debug)]
+pub struct ExecutionResult {
+    pub node_to_playlist_id: HashMap<String, String>,
+    pub nodes_with_missing_playlists: Vec<String>,
+
+    /// Only set for dry runs.
+    pub dry_run_report: Option<DryRunReport>,
+}
+
+/// The state of playlists before an apply, written to `snapshots/<id>/backup-<timestamp>.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+    pub snapshot_id: u32,
+    pub created_at: String,
+    pub playlists: Vec<PlaylistBackup>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlaylistBackup {
+    pub node: String,
+    pub playlist_id: String,
+
+    /// In the order of the playlist.
+    pub tracks: Vec<BackupTrack>,
+
+    /// The ledger of the playlist when the backup was taken, so a restore puts it back too.
+    /// None if mixify had never written to the playlist.
+    #[serde(default)]
+    pub owned_tracks: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupTrack {
+    pub id: String,
+
+    /// Only stored to make the backup readable.
+    pub name: String,
 }
 
 #[derive(Debug)]
@@ -106,4 +933,325 @@ pub struct Config {
     pub allow_removing_songs: bool,
     pub mixstack_suffix: String,
     pub write_description: bool,
+
+    /// Never remove songs that spotify reports as added by another user (`added_by`),
+    /// even if mixify added the same song before. Only matters for collaborative playlists.
+    pub keep_songs_added_by_others: bool,
+
+    /// When the server syncs the snapshot. Snapshots without a schedule are never synced by the server.
+    pub schedule: Option<String>,
+
+    /// How many nodes of the same stage of the plan are executed at the same time.
+    pub concurrency: usize,
+}
+
+/// The songs mixify added to each playlist of a snapshot, written to `snapshots/<id>/ledger.json`.
+/// Only songs in the ledger are ever removed from a playlist, so songs added by hand are kept.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Ledger {
+    pub snapshot_id: u32,
+    pub playlists: Vec<PlaylistLedger>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlaylistLedger {
+    pub node: String,
+    pub playlist_id: String,
+
+    /// Ids of the songs mixify added that are still in the playlist.
+    pub track_ids: Vec<String>,
+}
+
+/// The outcome of a sync run by the server, appended to `snapshots/<id>/runs.jsonl`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub snapshot_id: u32,
+    pub started_at: String,
+    pub finished_at: String,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    Apply,
+    Sync,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// An apply or sync started through the api of the server.
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: u32,
+    pub kind: JobKind,
+    pub snapshot_id: u32,
+    pub dry_run: bool,
+    pub status: JobStatus,
+    pub created_at: String,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    pub error: Option<String>,
+
+    /// Everything the job logged so far.
+    pub logs: Vec<String>,
+}
+
+/// What the rate limit layer had to do to get the requests of a run through.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RetrySummary {
+    pub requests: usize,
+    pub retries: usize,
+
+    /// Responses with status 429.
+    pub rate_limited: usize,
+
+    /// Requests that still failed after all retries, or that can't be retried.
+    pub failed: usize,
+    pub waited: std::time::Duration,
+}
+
+impl RetrySummary {
+    /// What happened since `earlier` was taken. The backend is shared by all runs of the server,
+    /// so its summary covers all of them.
+    pub fn since(&self, earlier: &RetrySummary) -> RetrySummary {
+        RetrySummary {
+            requests: self.requests - earlier.requests,
+            retries: self.retries - earlier.retries,
+            rate_limited: self.rate_limited - earlier.rate_limited,
+            failed: self.failed - earlier.failed,
+            waited: self.waited.saturating_sub(earlier.waited),
+        }
+    }
+}
+
+impl std::fmt::Display for RetrySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Sent {} requests to spotify. {} were retried, {} of them because of the rate limit, waiting {}s in total. {} failed.",
+            self.requests,
+            self.retries,
+            self.rate_limited,
+            self.waited.as_secs(),
+            self.failed
+        )
+    }
+}
+
+/// Progress of an apply that hasn't finished yet, written to `snapshots/<id>/journal.json`
+/// after every created playlist. Removed once the apply succeeds.
+/// See the `journal` module for why only created playlists are recorded.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Journal {
+    pub snapshot_id: u32,
+    pub started_at: String,
+    pub steps: Vec<JournalStep>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum JournalStep {
+    CreatePlaylist { node: String, playlist_id: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(id: &str, isrc: Option<&str>) -> TrackTuple {
+        TrackTuple {
+            id: TrackId::from_id(format!("{:0>22}", id)).unwrap(),
+            name: String::from("Song - Remastered 2011"),
+            artist_id: ArtistId::from_id("artistAAAAAAAAAAAAAAAA").unwrap(),
+            album_name: String::from("Album"),
+            isrc: isrc.map(String::from),
+            title: normalize_title("Song - Remastered 2011"),
+            release_date: None,
+            popularity: None,
+            duration: chrono::Duration::seconds(180),
+            explicit: false,
+            added_at: None,
+        }
+    }
+
+    #[test]
+    fn identity_follows_the_dedup_mode() {
+        let with_isrc = song("1", Some("ISRC1"));
+        let without_isrc = song("2", None);
+        let title = SongKey::Title(String::from("artistAAAAAAAAAAAAAAAA"), String::from("song"));
+
+        assert_eq!(
+            with_isrc.identity(Dedup::Isrc),
+            SongKey::Isrc(String::from("ISRC1"))
+        );
+        assert_eq!(without_isrc.identity(Dedup::Isrc), title);
+        assert_eq!(with_isrc.identity(Dedup::Title), title);
+        assert_eq!(
+            without_isrc.identity(Dedup::Id),
+            SongKey::Id(String::from("0000000000000000000002"))
+        );
+    }
+
+    #[test]
+    fn same_song_falls_back_to_the_title_without_isrc() {
+        let a = song("1", Some("ISRC1"));
+        let b = song("2", None);
+        let c = song("3", Some("ISRC3"));
+
+        // A song without ISRC is the same as both, but they are different songs.
+        assert!(Dedup::Isrc.same_song(&a, &b));
+        assert!(Dedup::Isrc.same_song(&b, &c));
+        assert!(!Dedup::Isrc.same_song(&a, &c));
+        assert!(Dedup::Isrc.same_song(&c, &b));
+
+        assert!(Dedup::Title.same_song(&a, &c));
+        assert!(!Dedup::Id.same_song(&a, &b));
+        assert!(Dedup::None.same_song(&a, &a.clone()));
+    }
+
+    #[test]
+    fn song_set_matches_mixed_isrc_songs_in_any_order() {
+        let a = song("1", Some("ISRC1"));
+        let b = song("2", None);
+        let c = song("3", Some("ISRC3"));
+
+        for songs in [[&a, &b], [&b, &a]] {
+            let set = SongSet::new(Dedup::Isrc, songs);
+            assert!(set.contains(&a));
+            assert!(set.contains(&b));
+            assert!(set.contains(&c));
+        }
+
+        let set = SongSet::new(Dedup::Isrc, [&a]);
+        assert!(set.contains(&b));
+        assert!(!set.contains(&c));
+
+        let set = SongSet::new(Dedup::Isrc, [&b]);
+        assert!(set.contains(&a));
+        assert!(set.contains(&c));
+
+        let set = SongSet::new(Dedup::Id, [&a, &c]);
+        assert!(!set.contains(&b));
+        assert!(set.contains(&c.clone()));
+    }
+
+    #[test]
+    fn normalize_title_drops_version_suffixes() {
+        assert_eq!(normalize_title("Yesterday - Remastered 2009"), "yesterday");
+        assert_eq!(normalize_title("Yesterday (2009 Remaster)"), "yesterday");
+        assert_eq!(normalize_title("Yesterday [Single Version]"), "yesterday");
+        assert_eq!(normalize_title("Hey, Jude!"), "hey jude");
+    }
+
+    #[test]
+    fn normalize_title_keeps_other_parts() {
+        assert_eq!(
+            normalize_title("Song (feat. Someone) - Live"),
+            "song feat someone live"
+        );
+        assert_eq!(normalize_title("Song (Acoustic)"), "song acoustic");
+        assert_eq!(normalize_title("Song (unclosed"), "song unclosed");
+    }
+
+    fn features(tempo: f32, energy: f32, key: i32, mode: Modality) -> AudioFeatures {
+        AudioFeatures {
+            acousticness: 0.5,
+            analysis_url: String::new(),
+            danceability: 0.5,
+            duration: chrono::Duration::seconds(180),
+            energy,
+            id: TrackId::from_id(format!("{:0>22}", 1)).unwrap(),
+            instrumentalness: 0.0,
+            key,
+            liveness: 0.1,
+            loudness: -6.0,
+            mode,
+            speechiness: 0.05,
+            tempo,
+            time_signature: 4,
+            track_href: String::new(),
+            valence: 0.5,
+        }
+    }
+
+    #[test]
+    fn feature_bounds_include_their_limits() {
+        let filter = SongFilter {
+            min_tempo: Some(100.0),
+            max_tempo: Some(130.0),
+            min_energy: Some(0.5),
+            ..Default::default()
+        };
+        let song = song("1", None);
+        let now = Utc::now();
+        let matches = |tempo: f32, energy: f32| {
+            let f = features(tempo, energy, 0, Modality::Major);
+            filter.matches(&song, Some(&f), now)
+        };
+
+        assert!(matches(120.0, 0.8));
+        assert!(matches(100.0, 0.5));
+        assert!(matches(130.0, 1.0));
+        assert!(!matches(99.9, 0.8));
+        assert!(!matches(130.1, 0.8));
+        assert!(!matches(120.0, 0.49));
+    }
+
+    #[test]
+    fn key_and_mode_must_be_the_same() {
+        let filter = SongFilter {
+            key: Some(5),
+            mode: Some(Mode::Minor),
+            ..Default::default()
+        };
+        let song = song("1", None);
+        let now = Utc::now();
+
+        let f = features(120.0, 0.5, 5, Modality::Minor);
+        assert!(filter.matches(&song, Some(&f), now));
+        let f = features(120.0, 0.5, 4, Modality::Minor);
+        assert!(!filter.matches(&song, Some(&f), now));
+        let f = features(120.0, 0.5, 5, Modality::Major);
+        assert!(!filter.matches(&song, Some(&f), now));
+    }
+
+    #[test]
+    fn songs_without_features_only_pass_filters_without_feature_bounds() {
+        let song = song("1", None);
+        let now = Utc::now();
+
+        let filter = SongFilter {
+            max_danceability: Some(0.9),
+            ..Default::default()
+        };
+        assert!(filter.needs_features());
+        assert!(!filter.matches(&song, None, now));
+
+        let filter = SongFilter {
+            explicit: Some(false),
+            ..Default::default()
+        };
+        assert!(!filter.needs_features());
+        assert!(filter.matches(&song, None, now));
+    }
+
+    #[test]
+    fn feature_bounds_dont_replace_the_other_bounds() {
+        let filter = SongFilter {
+            min_tempo: Some(100.0),
+            explicit: Some(true),
+            ..Default::default()
+        };
+        let f = features(120.0, 0.5, 0, Modality::Major);
+        assert!(!filter.matches(&song("1", None), Some(&f), Utc::now()));
+    }
 }